@@ -0,0 +1,210 @@
+use crate::error;
+use crate::admin::json_string;
+use crate::redact::LogRedact;
+use crate::registry::Connection;
+use clap::ValueEnum;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Format of the access log, with a line per request (HTTP mode) or per connection (TCP mode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AccessLogFormat {
+    /// Common Log Format: client, time, request line, status and size
+    Common,
+    /// Combined Log Format: the Common Log Format followed by the Referer and User-Agent headers
+    Combined,
+    /// One JSON object per line, also including the server name, TLS version, duration and backend
+    Json,
+}
+
+/// A request (or connection in TCP mode) as written to the access log, filled in while it is handled
+#[derive(Debug, Default)]
+pub struct Entry {
+    /// Request line, `None` when the request could not be parsed
+    pub method: Option<String>,
+    pub target: String,
+    pub version: String,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Status of the final response, 0 until a response is sent
+    pub status: u16,
+    /// Backend the request was forwarded to, `None` when answered by the proxy
+    pub backend: Option<String>,
+}
+
+/// Writes a line for each request handled in HTTP mode, or each connection in TCP mode
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    redact: LogRedact,
+    /// File the lines are appended to instead of standard output
+    file: Option<(PathBuf, Mutex<File>)>,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, redact: LogRedact, path: Option<&Path>) -> Result<Self, String> {
+        let file = match path {
+            Some(path) => Some((path.to_path_buf(), Mutex::new(open(path)?))),
+            None => None,
+        };
+        Ok(Self { format, redact, file })
+    }
+
+    /// Open the file again, after log rotation moved it away; the current file stays in use when that fails
+    pub fn reopen(&self) -> Result<(), String> {
+        if let Some((path, file)) = &self.file {
+            *file.lock().unwrap() = open(path)?;
+        }
+        Ok(())
+    }
+
+    /// Log a request once its response was sent, `bytes` counts the response including its head
+    /// (or everything sent to the client for a connection)
+    pub fn log(&self, connection: &Connection, entry: &Entry, bytes: u64, duration: Duration) {
+        let client = self.redact.client_ip(connection.peer);
+        let now = SystemTime::now();
+        // Requests of HTTP/2 clients are handled as HTTP/1.1, but logged with the protocol the client used
+        let version = match connection.alpn.as_deref() {
+            Some("h2") => "HTTP/2.0",
+            _ => &entry.version,
+        };
+        let line = match self.format {
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                let request = match &entry.method {
+                    Some(method) => format!("{} {} {}", method, entry.target, version),
+                    None => "-".to_string(),
+                };
+                let mut line = format!("{} - - [{}] \"{}\" {} {}", client, clf_time(now), escape(&request), entry.status, bytes);
+                if self.format == AccessLogFormat::Combined {
+                    let header = |value: &Option<String>| value.as_deref().map_or("-".to_string(), escape);
+                    line.push_str(&format!(" \"{}\" \"{}\"", header(&entry.referer), header(&entry.user_agent)));
+                }
+                line
+            }
+            AccessLogFormat::Json => {
+                let optional = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+                format!(
+                    "{{\"time\":{},\"client\":{},\"sni\":{},\"tls_version\":{},\"method\":{},\"target\":{},\"protocol\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.1},\"backend\":{},\"referer\":{},\"user_agent\":{}}}",
+                    json_string(&rfc3339(now)),
+                    json_string(&client),
+                    optional(connection.sni.as_deref()),
+                    json_string(&connection.protocol),
+                    optional(entry.method.as_deref()),
+                    optional(entry.method.is_some().then_some(entry.target.as_str())),
+                    optional(entry.method.is_some().then_some(version)),
+                    entry.status,
+                    bytes,
+                    duration.as_secs_f64() * 1000.0,
+                    optional(entry.backend.as_deref()),
+                    optional(entry.referer.as_deref()),
+                    optional(entry.user_agent.as_deref())
+                )
+            }
+        };
+        match &self.file {
+            Some((path, file)) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    error!("Failed to write to access log {}: {}", path.display(), e);
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open access log {}: {}", path.display(), e))
+}
+
+/// Escape quotes, backslashes and control characters like Apache does, keeping lines parseable
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Date and time in UTC, as (year, month, day, hour, minute, second)
+fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+
+    // Proleptic Gregorian date of a day since the epoch (Howard Hinnant's civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+/// Time as in the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second) = utc(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+/// Time in RFC 3339 format, e.g. `2000-10-10T13:55:36Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// Stream wrapper counting the bytes written, to log response sizes
+pub struct Tally<S> {
+    inner: S,
+    pub written: u64,
+}
+
+impl<S> Tally<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tally<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tally<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            self.written += *n as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,512 @@
+use crate::{error, info, warn};
+use crate::admin::json_string;
+use crate::certificate;
+use crate::http_client::{self, Response};
+use crate::json::Json;
+use crate::tags;
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Extension, X509NameBuilder, X509ReqBuilder, X509};
+use rustls::crypto::CryptoProvider;
+use rustls::sign::CertifiedKey;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_rustls::TlsConnector;
+use zeroize::Zeroizing;
+
+/// ALPN protocol of the tls-alpn-01 challenge (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Let's Encrypt production directory
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Time before a failed server name is tried again
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(10 * 60);
+
+/// Interval and number of checks while waiting for the CA to validate or issue
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: u32 = 60;
+
+/// File holding the ACME account key in the storage directory
+const ACCOUNT_KEY_FILE: &str = "account.key";
+
+/// Certificates obtained on demand via ACME for server names matching an allowlist
+///
+/// The first handshake for an allowed server name starts the issuance in the background and is
+/// answered with the default certificate; once issued, the certificate is served and stored.
+pub struct OnDemand {
+    directory_url: String,
+    contact: Option<String>,
+    /// Server name patterns, exact or of the form `*.example.com`
+    allowed: Vec<String>,
+    dir: PathBuf,
+    max_per_hour: usize,
+    tls: Option<TlsConnector>,
+    provider: Arc<CryptoProvider>,
+    certs: RwLock<HashMap<String, Issued>>,
+    /// tls-alpn-01 challenge certificates of pending validations, by server name
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    issuance: Mutex<Issuance>,
+    account: tokio::sync::Mutex<Option<Account>>,
+}
+
+struct Issued {
+    key: Arc<CertifiedKey>,
+    renew_at: SystemTime,
+}
+
+/// Rate limiting state of certificate requests
+#[derive(Default)]
+struct Issuance {
+    pending: HashSet<String>,
+    failed: HashMap<String, Instant>,
+    /// Start of each issuance within the last hour
+    started: VecDeque<Instant>,
+}
+
+/// Registered ACME account
+#[derive(Clone)]
+struct Account {
+    key: EcKey<Private>,
+    /// Account URL, identifying the key in signed requests
+    kid: String,
+    thumbprint: String,
+    new_nonce: String,
+    new_order: String,
+}
+
+impl std::fmt::Debug for OnDemand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OnDemand")
+            .field("directory_url", &self.directory_url)
+            .field("allowed", &self.allowed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OnDemand {
+    /// Set up on-demand issuance, loading certificates issued before from the storage directory
+    pub fn new(
+        directory_url: &str,
+        contact: Option<String>,
+        allowed: Vec<String>,
+        dir: &Path,
+        max_per_hour: usize,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create ACME directory {}: {}", dir.display(), e))?;
+        let tls = match directory_url.starts_with("https://") {
+            true => Some(http_client::tls_connector()?),
+            false => None,
+        };
+        let on_demand = Self {
+            directory_url: directory_url.to_string(),
+            contact,
+            allowed: allowed.iter().map(|pattern| pattern.to_ascii_lowercase()).collect(),
+            dir: dir.to_path_buf(),
+            max_per_hour,
+            tls,
+            provider,
+            certs: RwLock::new(HashMap::new()),
+            challenges: RwLock::new(HashMap::new()),
+            issuance: Mutex::new(Issuance::default()),
+            account: tokio::sync::Mutex::new(None),
+        };
+        on_demand.load()?;
+        Ok(on_demand)
+    }
+
+    /// Load the `<server name>.pem` files (private key followed by the chain) from the storage directory
+    fn load(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut certs = self.certs.write().unwrap();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".pem")) else {
+                continue;
+            };
+            let pem = Zeroizing::new(std::fs::read(&path)?);
+            match self.certified_key(&pem) {
+                Ok(issued) => {
+                    certs.insert(name.to_string(), issued);
+                }
+                Err(e) => warn!("Ignoring certificate {}: {}", path.display(), e),
+            }
+        }
+        if !certs.is_empty() {
+            info!("Loaded {} on-demand certificate(s) from {}", certs.len(), self.dir.display());
+        }
+        Ok(())
+    }
+
+    fn certified_key(&self, pem: &[u8]) -> Result<Issued, Box<dyn std::error::Error + Send + Sync>> {
+        let certs = rustls_pemfile::certs(&mut &pem[..]).collect::<Result<Vec<_>, _>>()?;
+        let key = Zeroizing::new(rustls_pemfile::private_key(&mut &pem[..])?.ok_or("no private key")?);
+        // Renew once two thirds of the remaining validity have passed
+        let days = certs.first().and_then(certificate::expiry).map_or(0, |(_, days)| days.max(0) as u64);
+        let renew_at = SystemTime::now() + Duration::from_secs(days * 24 * 60 * 60 * 2 / 3);
+        let key = certificate::certified_key(certs, key)?;
+        Ok(Issued {
+            key: Arc::new(key),
+            renew_at,
+        })
+    }
+
+    /// Whether certificates may be requested for the server name
+    pub fn allows(&self, server_name: &str) -> bool {
+        self.allowed.iter().any(|pattern| tags::matches_pattern(pattern, server_name))
+    }
+
+    /// Certificate issued for the server name, starting the issuance (or renewal) when needed
+    pub fn certificate(self: &Arc<Self>, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        let issued = self
+            .certs
+            .read()
+            .unwrap()
+            .get(&server_name)
+            .map(|issued| (issued.key.clone(), SystemTime::now() >= issued.renew_at));
+        match issued {
+            Some((key, renew)) => {
+                if renew && self.allows(&server_name) {
+                    self.start(&server_name);
+                }
+                Some(key)
+            }
+            None => {
+                if self.allows(&server_name) {
+                    self.start(&server_name);
+                }
+                None
+            }
+        }
+    }
+
+    /// Challenge certificate answering a tls-alpn-01 validation for the server name
+    pub fn challenge(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        self.challenges.read().unwrap().get(&server_name.to_ascii_lowercase()).cloned()
+    }
+
+    /// Start issuing a certificate in the background, unless rate limited
+    fn start(self: &Arc<Self>, server_name: &str) {
+        let mut issuance = self.issuance.lock().unwrap();
+        let recently_failed = issuance.failed.get(server_name).is_some_and(|at| at.elapsed() < RETRY_AFTER_FAILURE);
+        if issuance.pending.contains(server_name) || recently_failed {
+            return;
+        }
+        while issuance.started.front().is_some_and(|at| at.elapsed() >= Duration::from_secs(60 * 60)) {
+            issuance.started.pop_front();
+        }
+        if issuance.started.len() >= self.max_per_hour {
+            return;
+        }
+        issuance.pending.insert(server_name.to_string());
+        issuance.started.push_back(Instant::now());
+        drop(issuance);
+
+        let on_demand = self.clone();
+        let server_name = server_name.to_string();
+        tokio::spawn(async move { on_demand.issue(server_name).await });
+    }
+
+    async fn issue(self: Arc<Self>, server_name: String) {
+        info!("Requesting certificate for {} from {}", server_name, self.directory_url);
+        let result = self.order(&server_name).await;
+        self.challenges.write().unwrap().remove(&server_name);
+        let mut issuance = self.issuance.lock().unwrap();
+        issuance.pending.remove(&server_name);
+        match result {
+            Ok(()) => info!("Issued certificate for {}", server_name),
+            Err(e) => {
+                error!("Certificate issuance for {} failed: {}", server_name, e);
+                issuance.failed.insert(server_name, Instant::now());
+            }
+        }
+    }
+
+    /// Order, validate, finalize and store a certificate for the server name
+    async fn order(&self, server_name: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let account = {
+            let mut account = self.account.lock().await;
+            if account.is_none() {
+                *account = Some(self.register().await?);
+            }
+            account.clone().unwrap()
+        };
+
+        // Step 1: Order a certificate for the server name
+        let payload = format!("{{\"identifiers\":[{{\"type\":\"dns\",\"value\":{}}}]}}", json_string(server_name));
+        let response = self.post(&account, &account.new_order, &payload).await?;
+        let order_url = response.header("Location").ok_or("order has no Location")?.to_string();
+        let order = Json::parse(&response.body)?;
+
+        // Step 2: Prove control over the server name
+        for authorization in order.get("authorizations").map(Json::as_array).unwrap_or_default() {
+            let url = authorization.as_str().ok_or("invalid authorization URL")?;
+            self.authorize(&account, server_name, url).await?;
+        }
+
+        // Step 3: Finalize with a signing request for a new key
+        let group = p256()?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let csr = signing_request(server_name, &key)?;
+        let finalize = order.get("finalize").and_then(Json::as_str).ok_or("order has no finalize URL")?;
+        self.post(&account, finalize, &format!("{{\"csr\":\"{}\"}}", base64url(&csr))).await?;
+        let order = self.poll(&account, &order_url).await?;
+
+        // Step 4: Download and store the certificate
+        let certificate_url = order.get("certificate").and_then(Json::as_str).ok_or("order has no certificate URL")?;
+        let chain = self.post(&account, certificate_url, "").await?.body;
+        let mut pem = Zeroizing::new(key.private_key_to_pem_pkcs8()?);
+        pem.extend_from_slice(&chain);
+        let issued = self.certified_key(&pem)?;
+        let path = self.dir.join(format!("{}.pem", server_name));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&pem))
+            .map_err(|e| format!("Failed to store certificate in {}: {}", path.display(), e))?;
+        self.certs.write().unwrap().insert(server_name.to_string(), issued);
+        Ok(())
+    }
+
+    /// Answer the tls-alpn-01 challenge of an authorization and wait for the CA to validate it
+    async fn authorize(&self, account: &Account, server_name: &str, url: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let authorization = Json::parse(&self.post(account, url, "").await?.body)?;
+        if authorization.get("status").and_then(Json::as_str) == Some("valid") {
+            return Ok(());
+        }
+        let challenge = authorization
+            .get("challenges")
+            .map(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .find(|challenge| challenge.get("type").and_then(Json::as_str) == Some("tls-alpn-01"))
+            .ok_or("the CA offers no tls-alpn-01 challenge")?;
+        let token = challenge.get("token").and_then(Json::as_str).ok_or("challenge has no token")?;
+        let challenge_url = challenge.get("url").and_then(Json::as_str).ok_or("challenge has no URL")?;
+
+        let key_authorization = format!("{}.{}", token, account.thumbprint);
+        let certificate = challenge_certificate(server_name, &key_authorization, &self.provider)?;
+        self.challenges.write().unwrap().insert(server_name.to_string(), Arc::new(certificate));
+        self.post(account, challenge_url, "{}").await?;
+        self.poll(account, url).await?;
+        Ok(())
+    }
+
+    /// Fetch an order or authorization until it is valid
+    async fn poll(&self, account: &Account, url: &str) -> Result<Json, Box<dyn std::error::Error + Send + Sync>> {
+        for _ in 0..MAX_POLLS {
+            let object = Json::parse(&self.post(account, url, "").await?.body)?;
+            match object.get("status").and_then(Json::as_str) {
+                Some("valid") => return Ok(object),
+                Some("invalid") => return Err(format!("rejected by the CA: {}", problem_detail(&object)).into()),
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+        Err(format!("no result from the CA after {}s", (POLL_INTERVAL * MAX_POLLS).as_secs()).into())
+    }
+
+    /// Create (or look up) the ACME account of the stored account key
+    async fn register(&self) -> Result<Account, Box<dyn std::error::Error + Send + Sync>> {
+        let response = http_client::request("GET", &self.directory_url, None, b"", self.tls.as_ref()).await?;
+        let directory = Json::parse(&response.body).map_err(|e| format!("invalid ACME directory: {}", e))?;
+        let url = |name: &str| {
+            directory
+                .get(name)
+                .and_then(Json::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| format!("ACME directory has no {}", name))
+        };
+        let (new_nonce, new_account, new_order) = (url("newNonce")?, url("newAccount")?, url("newOrder")?);
+
+        let key = self.account_key()?;
+        let jwk = jwk(&key)?;
+        let contact = match &self.contact {
+            Some(email) => format!(",\"contact\":[{}]", json_string(&format!("mailto:{}", email))),
+            None => String::new(),
+        };
+        let payload = format!("{{\"termsOfServiceAgreed\":true{}}}", contact);
+        let response = self.signed(&key, &format!("\"jwk\":{}", jwk), &new_nonce, &new_account, &payload).await?;
+        let kid = response.header("Location").ok_or("account has no Location")?.to_string();
+        Ok(Account {
+            key,
+            kid,
+            thumbprint: base64url(&openssl::sha::sha256(jwk.as_bytes())),
+            new_nonce,
+            new_order,
+        })
+    }
+
+    /// Load the account key from the storage directory, creating it on first use
+    fn account_key(&self) -> Result<EcKey<Private>, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.dir.join(ACCOUNT_KEY_FILE);
+        if let Ok(pem) = std::fs::read(&path).map(Zeroizing::new) {
+            return Ok(EcKey::private_key_from_pem(&pem)?);
+        }
+        let group = p256()?;
+        let key = EcKey::generate(&group)?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&Zeroizing::new(key.private_key_to_pem()?)))
+            .map_err(|e| format!("Failed to store ACME account key in {}: {}", path.display(), e))?;
+        Ok(key)
+    }
+
+    /// Send a request signed with the account key (an empty payload fetches a resource)
+    async fn post(&self, account: &Account, url: &str, payload: &str) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        let key_id = format!("\"kid\":{}", json_string(&account.kid));
+        self.signed(&account.key, &key_id, &account.new_nonce, url, payload).await
+    }
+
+    /// Send a JWS signed request, identifying the key by `key_id` (a `jwk` or `kid` member)
+    async fn signed(
+        &self,
+        key: &EcKey<Private>,
+        key_id: &str,
+        new_nonce: &str,
+        url: &str,
+        payload: &str,
+    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        let mut retried = false;
+        loop {
+            let nonce_response = http_client::request("HEAD", new_nonce, None, b"", self.tls.as_ref()).await?;
+            let nonce = nonce_response.header("Replay-Nonce").ok_or("the CA sent no nonce")?;
+            let protected = format!("{{\"alg\":\"ES256\",{},\"nonce\":{},\"url\":{}}}", key_id, json_string(nonce), json_string(url));
+            let body = jws(key, &protected, payload)?;
+            let response = http_client::request("POST", url, Some("application/jose+json"), body.as_bytes(), self.tls.as_ref()).await?;
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem = Json::parse(&response.body).unwrap_or(Json::Null);
+            // Nonces may expire between fetching and using them
+            let bad_nonce = problem.get("type").and_then(Json::as_str) == Some("urn:ietf:params:acme:error:badNonce");
+            if bad_nonce && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!("ACME request to {} failed with status {}: {}", url, response.status, problem_detail(&problem)).into());
+        }
+    }
+}
+
+/// Human readable error of an ACME problem document, or of the challenges of an authorization
+fn problem_detail(object: &Json) -> String {
+    let challenge_error = object
+        .get("challenges")
+        .map(Json::as_array)
+        .unwrap_or_default()
+        .iter()
+        .find_map(|challenge| challenge.get("error"));
+    let problem = challenge_error.or(object.get("error")).unwrap_or(object);
+    problem.get("detail").and_then(Json::as_str).unwrap_or("no details").to_string()
+}
+
+fn p256() -> Result<EcGroup, openssl::error::ErrorStack> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+}
+
+/// Base64url encoding without padding, as used by JWS
+fn base64url(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+/// Public key as a JSON Web Key, with its members in the order required for the thumbprint (RFC 7638)
+fn jwk(key: &EcKey<Private>) -> Result<String, openssl::error::ErrorStack> {
+    let mut context = BigNumContext::new()?;
+    let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+    key.public_key().affine_coordinates(key.group(), &mut x, &mut y, &mut context)?;
+    Ok(format!(
+        "{{\"crv\":\"P-256\",\"kty\":\"EC\",\"x\":\"{}\",\"y\":\"{}\"}}",
+        base64url(&x.to_vec_padded(32)?),
+        base64url(&y.to_vec_padded(32)?)
+    ))
+}
+
+/// Sign a request in the flattened JWS JSON serialization with ES256
+fn jws(key: &EcKey<Private>, protected: &str, payload: &str) -> Result<String, openssl::error::ErrorStack> {
+    let protected = base64url(protected.as_bytes());
+    let payload = base64url(payload.as_bytes());
+    let digest = openssl::sha::sha256(format!("{}.{}", protected, payload).as_bytes());
+    let signature = EcdsaSig::sign(&digest, key)?;
+    let mut raw = signature.r().to_vec_padded(32)?;
+    raw.extend_from_slice(&signature.s().to_vec_padded(32)?);
+    Ok(format!(
+        "{{\"protected\":\"{}\",\"payload\":\"{}\",\"signature\":\"{}\"}}",
+        protected,
+        payload,
+        base64url(&raw)
+    ))
+}
+
+/// DER encoded certificate signing request for the server name
+fn signing_request(server_name: &str, key: &PKey<Private>) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let mut request = X509ReqBuilder::new()?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, server_name)?;
+    request.set_subject_name(&name.build())?;
+    request.set_pubkey(key)?;
+    let mut extensions = Stack::new()?;
+    extensions.push(SubjectAlternativeName::new().dns(server_name).build(&request.x509v3_context(None))?)?;
+    request.add_extensions(&extensions)?;
+    request.sign(key, MessageDigest::sha256())?;
+    request.build().to_der()
+}
+
+/// Self-signed certificate carrying the acmeIdentifier extension for a tls-alpn-01 challenge
+fn challenge_certificate(
+    server_name: &str,
+    key_authorization: &str,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, Box<dyn std::error::Error + Send + Sync>> {
+    let group = p256()?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, server_name)?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = BigNum::from_u32(1)?.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(7)?);
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let alt_name = SubjectAlternativeName::new().dns(server_name).build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alt_name)?;
+    // id-pe-acmeIdentifier holding the SHA-256 digest of the key authorization as an OCTET STRING
+    let mut digest = vec![0x04, 0x20];
+    digest.extend_from_slice(&openssl::sha::sha256(key_authorization.as_bytes()));
+    let oid = Asn1Object::from_str("1.3.6.1.5.5.7.1.31")?;
+    let value = Asn1OctetString::new_from_bytes(&digest)?;
+    builder.append_extension(X509Extension::new_from_der(&oid, true, &value)?)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    let cert = CertificateDer::from(builder.build().to_der()?);
+    let key = PrivateKeyDer::Pkcs8(key.private_key_to_pkcs8()?.into());
+    // Not `CertifiedKey::from_der`, its consistency check rejects the unknown critical extension
+    Ok(CertifiedKey::new(vec![cert], provider.key_provider.load_private_key(key)?))
+}
@@ -0,0 +1,81 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+/// Parse the address to listen on: `ip:port`, `[ipv6]:port`, `hostname:port` (resolved once), or a
+/// bare port (`8443` or `:8443`) listening on all IPv4 and IPv6 addresses
+pub fn parse_listen(s: &str) -> Result<SocketAddr, String> {
+    let s = s.trim();
+    if let Some(port) = bare_port(s) {
+        return Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port?));
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let (host, port) = split_host_port(s)?;
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve '{}': {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("'{}' does not resolve to any address", host))
+}
+
+/// Normalize a backend address: `ip:port`, `[ipv6]:port` and `hostname:port` are kept (hostnames are
+/// resolved when connecting), a bare port (`8080` or `:8080`) means the local host
+pub fn normalize_backend(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if let Some(port) = bare_port(s) {
+        return Ok(format!("127.0.0.1:{}", port?));
+    }
+    if s.parse::<SocketAddr>().is_ok() {
+        return Ok(s.to_string());
+    }
+    split_host_port(s)?;
+    Ok(s.to_string())
+}
+
+/// The port of an address given as a bare port, `None` when the address has a host
+fn bare_port(s: &str) -> Option<Result<u16, String>> {
+    let port = s.strip_prefix(':').unwrap_or(s);
+    (!port.is_empty() && port.bytes().all(|b| b.is_ascii_digit())).then(|| parse_port(port))
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("invalid port '{}' (expected 1-65535)", port)),
+    }
+}
+
+/// Split `host:port`, explaining common mistakes such as IPv6 addresses without brackets
+fn split_host_port(s: &str) -> Result<(&str, u16), String> {
+    match s.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => return Err(format!("'{}' has no port (expected ip:port)", s)),
+        Ok(IpAddr::V6(_)) => return Err(format!("'{}' has no port (IPv6 addresses need brackets, e.g. [{}]:443)", s, s)),
+        Err(_) => {}
+    }
+    if let Some(rest) = s.strip_prefix('[') {
+        let (ip, port) = rest.split_once(']').ok_or_else(|| format!("missing ']' in '{}'", s))?;
+        if ip.parse::<Ipv6Addr>().is_err() {
+            return Err(format!("invalid IPv6 address '{}'", ip));
+        }
+        let port = port
+            .strip_prefix(':')
+            .ok_or_else(|| format!("'{}' has no port (expected [{}]:port)", s, ip))?;
+        parse_port(port)?;
+        return Err(format!("invalid address '{}'", s));
+    }
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("'{}' has no port (expected host:port)", s))?;
+    if host.contains(':') {
+        return Err(format!("IPv6 addresses need brackets, e.g. [{}]:{}", host, port));
+    }
+    let valid_host = !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63 && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    if !valid_host {
+        return Err(format!("invalid host name '{}'", host));
+    }
+    Ok((host, parse_port(port)?))
+}
@@ -0,0 +1,379 @@
+use crate::{error, info};
+use crate::backend::{self, Backend, Diagnosis};
+use crate::cache::Cache;
+use crate::dscp;
+use crate::http::Mode;
+use crate::metrics;
+use crate::proxy::Proxy;
+use crate::redact::LogRedact;
+use crate::registry::Registry;
+use crate::trace::TraceFilter;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Maximum size of an admin request head
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Connections traced when the trace request gives no count
+const DEFAULT_TRACED: u32 = 10;
+
+/// Connections traced at most per trace request
+const MAX_TRACED: u32 = 1000;
+
+/// Time allowed per address when diagnosing a backend without a connect timeout
+const DIAGNOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bind the admin API listener
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener, Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to bind admin API on {}: {}", addr, e))?;
+    info!("Admin API running on http://{}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Bind the listener serving only the metrics, for Prometheus to scrape
+pub async fn bind_metrics(addr: SocketAddr) -> Result<TcpListener, Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to bind metrics endpoint on {}: {}", addr, e))?;
+    info!("Metrics available on http://{}/metrics", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Serve admin API requests for all listeners of the process (the main one first) on the listener,
+/// or only `GET /metrics` when `metrics_only` is set
+pub async fn serve(listener: Arc<TcpListener>, proxies: Arc<[Arc<Proxy>]>, metrics_only: bool) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Admin API accept error: {}", e);
+                continue;
+            }
+        };
+        let proxies = proxies.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &proxies, metrics_only).await {
+                error!("Admin API error: {}", e);
+            }
+        });
+    }
+}
+
+/// Handle a single admin request
+async fn handle(mut stream: TcpStream, proxies: &[Arc<Proxy>], metrics_only: bool) -> std::io::Result<()> {
+    // Read request head
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            return respond(&mut stream, "413 Payload Too Large", "text/plain", "Request too large\n").await;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let tag_filter = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("tag="));
+
+    // The metrics endpoint may be reachable by more than the admin API, without its actions
+    if metrics_only && (method, path) != ("GET", "/metrics") {
+        return respond(&mut stream, "404 Not Found", "text/plain", "Not found\n").await;
+    }
+
+    // Connections, counters and the state toggled here are shared by all listeners, the backends
+    // and caches are per listener
+    let proxy = &proxies[0];
+    let registry = &proxy.registry;
+    let upstreams: Vec<_> = proxies.iter().map(|listening| listening.upstreams()).collect();
+    match (method, path) {
+        ("GET", "/metrics") => {
+            let mut body = proxy.metrics.render();
+            metrics::family(&mut body, "connections_active", "gauge", "Connections currently open");
+            body.push_str(&format!("connections_active {}\n", registry.list().len()));
+            body.push_str(&proxy.fds.render());
+            if let Some(memory) = &proxy.memory {
+                body.push_str(&memory.render());
+            }
+            if proxy.cache.is_some() {
+                body.push_str(&Cache::render(proxies.iter().filter_map(|listening| listening.cache.as_ref())));
+            }
+            if proxy.mode == Mode::Http {
+                body.push_str(&proxy.retry_budget.render());
+            }
+            // Each family is rendered once, over the backends of every listener using it
+            let listening = || proxies.iter().zip(&upstreams);
+            if let Some(outlier) = proxies.iter().find_map(|listening| listening.outlier.as_ref()) {
+                let pools = listening().filter(|(listening, _)| listening.outlier.is_some()).flat_map(|(_, upstreams)| upstreams.pools());
+                body.push_str(&outlier.render(&backend::by_address(pools)));
+            }
+            if let Some(health_check) = proxies.iter().find_map(|listening| listening.health_check.as_ref()) {
+                let pools = listening().filter(|(listening, _)| listening.health_check.is_some()).flat_map(|(_, upstreams)| upstreams.pools());
+                body.push_str(&health_check.render(&backend::by_address(pools)));
+            }
+            body.push_str(&backend::render_connect_errors(&backend::by_address(upstreams.iter().flat_map(|upstreams| upstreams.pools()))));
+            respond(&mut stream, "200 OK", "text/plain", &body).await
+        }
+        ("GET", "/connections") => {
+            let body = connections_json(registry, tag_filter, proxy.log_redact);
+            respond(&mut stream, "200 OK", "application/json", &body).await
+        }
+        ("DELETE", path) if path.starts_with("/connections/ip/") => {
+            match path["/connections/ip/".len()..].parse::<IpAddr>() {
+                Ok(ip) => {
+                    let count = registry.terminate_ip(ip);
+                    respond(&mut stream, "200 OK", "application/json", &format!("{{\"terminated\":{}}}\n", count)).await
+                }
+                Err(_) => respond(&mut stream, "400 Bad Request", "text/plain", "Invalid IP address\n").await,
+            }
+        }
+        ("DELETE", path) if path.starts_with("/connections/") => {
+            match path["/connections/".len()..].parse::<u64>() {
+                Ok(id) if registry.terminate(id) => {
+                    respond(&mut stream, "200 OK", "application/json", "{\"terminated\":1}\n").await
+                }
+                Ok(_) => respond(&mut stream, "404 Not Found", "text/plain", "No such connection\n").await,
+                Err(_) => respond(&mut stream, "400 Bad Request", "text/plain", "Invalid connection id\n").await,
+            }
+        }
+        ("GET", "/backends") => {
+            let backends: Vec<String> = upstreams
+                .iter()
+                .flat_map(|upstreams| upstreams.pools())
+                .flat_map(|pool| pool.backends())
+                .map(|backend| backend_json(backend))
+                .collect();
+            respond(&mut stream, "200 OK", "application/json", &format!("[{}]\n", backends.join(","))).await
+        }
+        (method, path) if path.starts_with("/backends/") => {
+            let (address, action) = match path["/backends/".len()..].split_once('/') {
+                Some((address, action)) => (address, Some(action)),
+                None => (&path["/backends/".len()..], None),
+            };
+            // The same address may be used by the default backends, by routes and by other listeners
+            let matching: Vec<_> = upstreams.iter().flat_map(|upstreams| upstreams.pools()).filter_map(|pool| pool.find(address)).collect();
+            let Some(backend) = matching.first() else {
+                return respond(&mut stream, "404 Not Found", "text/plain", "No such backend\n").await;
+            };
+            match (method, action) {
+                ("GET", None) => {}
+                ("POST", Some("drain")) => {
+                    matching.iter().for_each(|backend| backend.draining.store(true, Ordering::Relaxed));
+                    let connections: usize = matching.iter().map(|backend| backend.connections.load(Ordering::Relaxed)).sum();
+                    info!("Draining backend http://{} ({} open connections)", backend.address, connections);
+                }
+                ("DELETE", Some("drain")) => {
+                    matching.iter().for_each(|backend| backend.draining.store(false, Ordering::Relaxed));
+                    info!("Backend http://{} back in rotation", backend.address);
+                }
+                ("POST", Some("diagnose")) => {
+                    let timeout = proxy.timeouts.connect.unwrap_or(DIAGNOSE_TIMEOUT);
+                    let dscp = dscp::select(&proxy.backend_dscp, &backend.address);
+                    let diagnosis = backend::diagnose(&backend.address, proxy.backend_bind, dscp, proxy.mptcp, timeout).await;
+                    return respond(&mut stream, "200 OK", "application/json", &format!("{}\n", diagnosis_json(&diagnosis))).await;
+                }
+                (_, None | Some("drain") | Some("diagnose")) => {
+                    return respond(&mut stream, "405 Method Not Allowed", "text/plain", "Method not allowed\n").await;
+                }
+                _ => return respond(&mut stream, "404 Not Found", "text/plain", "Not found\n").await,
+            }
+            respond(&mut stream, "200 OK", "application/json", &format!("{}\n", backend_json(backend))).await
+        }
+        ("GET", "/maintenance") => {
+            respond(&mut stream, "200 OK", "application/json", &format!("{}\n", proxy.maintenance.render_json())).await
+        }
+        ("POST", "/maintenance") => {
+            let duration = match query.split('&').find_map(|param| param.strip_prefix("duration=")) {
+                Some(secs) => match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                    _ => return respond(&mut stream, "400 Bad Request", "text/plain", "Invalid duration\n").await,
+                },
+                None => None,
+            };
+            proxy.maintenance.enable(duration);
+            respond(&mut stream, "200 OK", "application/json", &format!("{}\n", proxy.maintenance.render_json())).await
+        }
+        ("DELETE", "/maintenance") => {
+            proxy.maintenance.disable();
+            respond(&mut stream, "200 OK", "application/json", &format!("{}\n", proxy.maintenance.render_json())).await
+        }
+        ("GET", "/drain") => respond(&mut stream, "200 OK", "application/json", &drain_json(proxy)).await,
+        ("POST", "/drain") => {
+            if proxy.drain.start() {
+                info!("Draining client connections ({} open)", registry.list().len());
+            }
+            respond(&mut stream, "200 OK", "application/json", &drain_json(proxy)).await
+        }
+        ("DELETE", "/drain") => {
+            proxy.drain.stop();
+            respond(&mut stream, "200 OK", "application/json", &drain_json(proxy)).await
+        }
+        ("GET", "/trace") => respond(&mut stream, "200 OK", "application/json", &format!("{}\n", proxy.tracing.render_json())).await,
+        ("POST", "/trace") => {
+            let param = |name: &str| query.split('&').find_map(|param| param.strip_prefix(name)?.strip_prefix('='));
+            let count = match param("count").map(str::parse::<u32>) {
+                None => DEFAULT_TRACED,
+                Some(Ok(count)) if (1..=MAX_TRACED).contains(&count) => count,
+                Some(_) => return respond(&mut stream, "400 Bad Request", "text/plain", "Invalid count\n").await,
+            };
+            let filter = match (param("ip"), param("sni")) {
+                (Some(_), Some(_)) => return respond(&mut stream, "400 Bad Request", "text/plain", "Filter by ip or sni, not both\n").await,
+                (Some(ip), None) => match ip.replace("%2F", "/").replace("%2f", "/").parse() {
+                    Ok(net) => TraceFilter::Ip(net),
+                    Err(_) => return respond(&mut stream, "400 Bad Request", "text/plain", "Invalid IP address\n").await,
+                },
+                (None, Some(sni)) => TraceFilter::Sni(sni.to_ascii_lowercase()),
+                (None, None) => TraceFilter::Any,
+            };
+            proxy.tracing.start(filter, count);
+            respond(&mut stream, "200 OK", "application/json", &format!("{}\n", proxy.tracing.render_json())).await
+        }
+        ("DELETE", "/trace") => {
+            proxy.tracing.stop();
+            respond(&mut stream, "200 OK", "application/json", &format!("{}\n", proxy.tracing.render_json())).await
+        }
+        ("GET", "/logs") => {
+            let Some(logs) = &proxy.logs else {
+                return respond(&mut stream, "404 Not Found", "text/plain", "Log lines are not kept (--admin-log-lines 0)\n").await;
+            };
+            let count = match query.split('&').find_map(|param| param.strip_prefix("lines=")) {
+                None => usize::MAX,
+                Some(count) => match count.parse() {
+                    Ok(count) => count,
+                    Err(_) => return respond(&mut stream, "400 Bad Request", "text/plain", "Invalid line count\n").await,
+                },
+            };
+            respond(&mut stream, "200 OK", "application/json", &logs.render_json(count)).await
+        }
+        (_, "/connections" | "/backends" | "/maintenance" | "/drain" | "/trace" | "/logs") => respond(&mut stream, "405 Method Not Allowed", "text/plain", "Method not allowed\n").await,
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found\n").await,
+    }
+}
+
+/// Write a complete response and close the connection
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Render the active connections as a JSON array, optionally only those carrying a tag
+fn connections_json(registry: &Registry, tag_filter: Option<&str>, redact: LogRedact) -> String {
+    let entries: Vec<String> = registry
+        .list()
+        .iter()
+        .filter(|c| tag_filter.is_none_or(|tag| c.tags.iter().any(|t| t == tag)))
+        .map(|c| {
+            let tags: Vec<String> = c.tags.iter().map(|t| json_string(t)).collect();
+            format!(
+                "{{\"id\":{},\"peer\":{},\"sni\":{},\"tags\":[{}],\"protocol\":{},\"alpn\":{},\"age_secs\":{:.3},\"bytes_in\":{},\"bytes_out\":{},\"backend\":{}}}",
+                c.id,
+                json_string(&redact.admin_client(c.peer)),
+                c.sni.as_deref().map_or("null".to_string(), json_string),
+                tags.join(","),
+                json_string(&c.protocol),
+                c.alpn.as_deref().map_or("null".to_string(), json_string),
+                c.started.elapsed().as_secs_f64(),
+                c.bytes_in.load(Ordering::Relaxed),
+                c.bytes_out.load(Ordering::Relaxed),
+                json_string(&c.backend.lock().unwrap()),
+            )
+        })
+        .collect();
+    format!("[{}]\n", entries.join(","))
+}
+
+/// Render whether client connections are drained, along with the connections still open
+fn drain_json(proxy: &Proxy) -> String {
+    format!(
+        "{{\"draining\":{},\"connections\":{}}}\n",
+        proxy.drain.is_draining(),
+        proxy.registry.list().len()
+    )
+}
+
+/// Render the state of a backend as a JSON object
+fn backend_json(backend: &Backend) -> String {
+    format!(
+        "{{\"address\":{},\"connections\":{},\"draining\":{},\"ejected\":{},\"healthy\":{}}}",
+        json_string(&backend.address),
+        backend.connections.load(Ordering::Relaxed),
+        backend.is_draining(),
+        backend.outlier.lock().unwrap().is_ejected(Instant::now()),
+        backend.is_healthy(),
+    )
+}
+
+fn diagnosis_json(diagnosis: &Diagnosis) -> String {
+    let millis = |duration: Duration| format!("{:.1}", duration.as_secs_f64() * 1000.0);
+    let error_json = |error: Option<&std::io::Error>| match error {
+        Some(e) => (json_string(&backend::kind_name(e.kind())), json_string(&e.to_string())),
+        None => ("null".to_string(), "null".to_string()),
+    };
+    let attempts: Vec<String> = diagnosis
+        .attempts
+        .iter()
+        .map(|(addr, elapsed, error)| {
+            let (kind, error_text) = error_json(error.as_ref());
+            format!(
+                "{{\"address\":{},\"connected\":{},\"elapsed_ms\":{},\"kind\":{},\"error\":{}}}",
+                json_string(&addr.to_string()),
+                error.is_none(),
+                millis(*elapsed),
+                kind,
+                error_text,
+            )
+        })
+        .collect();
+    let (kind, error_text) = error_json(diagnosis.resolve_error.as_ref());
+    format!(
+        "{{\"backend\":{},\"resolve_ms\":{},\"resolve_kind\":{},\"resolve_error\":{},\"attempts\":[{}],\"reachable\":{}}}",
+        json_string(&diagnosis.address),
+        millis(diagnosis.resolve_time),
+        kind,
+        error_text,
+        attempts.join(","),
+        diagnosis.attempts.iter().any(|(_, _, error)| error.is_none()),
+    )
+}
+
+/// Encode a string as a JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,168 @@
+use crate::warn;
+use crate::events::{Event, EventHooks};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Length of the window thresholds are evaluated over
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Attempts needed in a window before a failure rate is compared to its threshold
+const MIN_SAMPLES: u64 = 20;
+
+/// Quantity an alert threshold applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertMetric {
+    /// Accepted connections per minute
+    Connections,
+    /// Percentage of failed TLS handshakes
+    HandshakeFailures,
+    /// Percentage of failed backend connections and requests (connect errors, timeouts, 502-504)
+    BackendErrors,
+}
+
+impl AlertMetric {
+    pub const ALL: [AlertMetric; 3] = [AlertMetric::Connections, AlertMetric::HandshakeFailures, AlertMetric::BackendErrors];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AlertMetric::Connections => "connections",
+            AlertMetric::HandshakeFailures => "handshake_failures",
+            AlertMetric::BackendErrors => "backend_errors",
+        }
+    }
+
+    fn is_rate(self) -> bool {
+        self != AlertMetric::Connections
+    }
+
+    fn format(self, value: f64) -> String {
+        if self.is_rate() {
+            format!("{:.1}%", value)
+        } else {
+            format!("{:.0}/min", value)
+        }
+    }
+}
+
+/// Alert fired when a metric exceeds a limit within a minute
+#[derive(Debug, Clone, Copy)]
+pub struct Threshold {
+    pub metric: AlertMetric,
+    pub limit: f64,
+}
+
+/// Parse an `--alert` argument of the form `metric>limit` (e.g. `handshake_failures>10%`)
+pub fn parse_threshold(s: &str) -> Result<Threshold, String> {
+    let (name, limit) = s.split_once('>').ok_or("expected METRIC>LIMIT")?;
+    let metric = AlertMetric::ALL.into_iter().find(|m| m.name() == name.trim()).ok_or_else(|| {
+        let names: Vec<_> = AlertMetric::ALL.iter().map(|m| m.name()).collect();
+        format!("unknown metric '{}' (expected one of: {})", name.trim(), names.join(", "))
+    })?;
+    let limit = limit.trim();
+    let (number, percent) = match limit.strip_suffix('%') {
+        Some(number) => (number, true),
+        None => (limit, false),
+    };
+    if percent && !metric.is_rate() {
+        return Err(format!("{} is a count per minute, not a percentage", metric.name()));
+    }
+    let limit: f64 = number.parse().map_err(|_| format!("invalid limit '{}'", limit))?;
+    if !limit.is_finite() || limit < 0.0 || (metric.is_rate() && limit >= 100.0) {
+        return Err(format!("limit {} out of range", limit));
+    }
+    Ok(Threshold { metric, limit })
+}
+
+/// Counts of the current window
+#[derive(Debug, Default)]
+struct Counters {
+    connections: AtomicU64,
+    handshakes: AtomicU64,
+    handshake_failures: AtomicU64,
+    backend_attempts: AtomicU64,
+    backend_errors: AtomicU64,
+}
+
+/// Compares connection and error rates to thresholds every minute, logging a warning and firing
+/// the `threshold_exceeded` event when one is crossed
+#[derive(Debug)]
+pub struct Alerts {
+    thresholds: Vec<Threshold>,
+    counters: Counters,
+}
+
+impl Alerts {
+    pub fn new(thresholds: Vec<Threshold>) -> Self {
+        Self {
+            thresholds,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn record_connection(&self) {
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handshake(&self, success: bool) {
+        self.counters.handshakes.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.counters.handshake_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_backend(&self, success: bool) {
+        self.counters.backend_attempts.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.counters.backend_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Evaluate the thresholds at the end of every window, alerting once when a threshold is
+    /// crossed and logging when the metric is back below it
+    pub async fn watch(&self, events: &EventHooks) {
+        let mut exceeded = vec![false; self.thresholds.len()];
+        loop {
+            tokio::time::sleep(WINDOW).await;
+            let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+            let counters = &self.counters;
+            let connections = take(&counters.connections);
+            let handshakes = (take(&counters.handshakes), take(&counters.handshake_failures));
+            let backend = (take(&counters.backend_attempts), take(&counters.backend_errors));
+
+            for (threshold, exceeded) in self.thresholds.iter().zip(exceeded.iter_mut()) {
+                let value = match threshold.metric {
+                    AlertMetric::Connections => Some(connections as f64),
+                    AlertMetric::HandshakeFailures => percentage(handshakes),
+                    AlertMetric::BackendErrors => percentage(backend),
+                };
+                // Too few attempts to judge a rate keep the previous state
+                let Some(value) = value else { continue };
+                let metric = threshold.metric;
+                if value > threshold.limit && !*exceeded {
+                    warn!(
+                        "{} at {} in the last minute, above the alert threshold of {}",
+                        metric.name(),
+                        metric.format(value),
+                        metric.format(threshold.limit)
+                    );
+                    events.fire(
+                        Event::ThresholdExceeded,
+                        &[
+                            ("metric", metric.name().to_string()),
+                            ("value", metric.format(value)),
+                            ("threshold", metric.format(threshold.limit)),
+                        ],
+                    );
+                } else if value <= threshold.limit && *exceeded {
+                    warn!("{} back at {}, below the alert threshold", metric.name(), metric.format(value));
+                }
+                *exceeded = value > threshold.limit;
+            }
+        }
+    }
+}
+
+/// Failures as a percentage of the attempts, when there were enough attempts
+fn percentage((attempts, failures): (u64, u64)) -> Option<f64> {
+    (attempts >= MIN_SAMPLES).then(|| failures as f64 * 100.0 / attempts as f64)
+}
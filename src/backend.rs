@@ -0,0 +1,434 @@
+use crate::info;
+use crate::address;
+use crate::dscp;
+use crate::listener;
+use crate::metrics;
+use crate::health::HealthState;
+use crate::outlier::OutlierState;
+use clap::ValueEnum;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Delay between connection attempts while waiting for the backend
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Traffic share of a backend at the start of its slow-start window
+const SLOW_START_MIN_WEIGHT: f64 = 0.1;
+
+/// A backend server
+#[derive(Debug)]
+pub struct Backend {
+    pub address: String,
+    pub outlier: Mutex<OutlierState>,
+    /// Result of the active health checks
+    pub health: Mutex<HealthState>,
+    /// When the backend returns (or returned) to the rotation, starting its slow-start window
+    pub recovered_at: Mutex<Option<Instant>>,
+    /// Times the backend was offered a connection during slow-start
+    offers: AtomicU64,
+    /// Open connections to the backend
+    pub connections: AtomicUsize,
+    /// Set via the admin API to stop sending new connections to the backend
+    pub draining: AtomicBool,
+    /// Failed connections by error kind
+    pub connect_errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Backend {
+    fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            outlier: Mutex::default(),
+            health: Mutex::default(),
+            recovered_at: Mutex::default(),
+            offers: AtomicU64::new(0),
+            connections: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            connect_errors: Mutex::default(),
+        }
+    }
+
+    /// Count a connection to the backend until the returned guard is dropped
+    pub fn lease(self: &Arc<Self>) -> Lease {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        Lease { backend: self.clone() }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health.lock().unwrap().healthy
+    }
+
+    /// Count a failed connection to the backend
+    pub fn record_connect_error(&self, kind: ErrorKind) {
+        *self.connect_errors.lock().unwrap().entry(kind_name(kind)).or_default() += 1;
+    }
+
+    /// Share of its regular traffic the backend receives, ramping up linearly after it recovered
+    pub fn weight(&self, now: Instant, slow_start: Option<Duration>) -> f64 {
+        let (Some(window), Some(recovered_at)) = (slow_start, *self.recovered_at.lock().unwrap()) else {
+            return 1.0;
+        };
+        let elapsed = now.saturating_duration_since(recovered_at);
+        (elapsed.as_secs_f64() / window.as_secs_f64()).clamp(SLOW_START_MIN_WEIGHT, 1.0)
+    }
+
+    /// Whether the backend takes an offered connection, accepting `weight` of the offers evenly spread
+    fn admit(&self, weight: f64) -> bool {
+        if weight >= 1.0 {
+            return true;
+        }
+        let offers = self.offers.fetch_add(1, Ordering::Relaxed) as f64;
+        ((offers + 1.0) * weight).floor() > (offers * weight).floor()
+    }
+}
+
+/// Guard that keeps a connection counted for its backend while alive
+#[derive(Debug)]
+pub struct Lease {
+    pub backend: Arc<Backend>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.backend.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How new connections are distributed over the backends of a pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Each backend in turn
+    RoundRobin,
+    /// The backend with the fewest open connections, in turn among equals
+    LeastConnections,
+    /// A random backend
+    Random,
+}
+
+/// Backend servers that connections and requests are distributed over
+#[derive(Debug)]
+pub struct Pool {
+    backends: Vec<Arc<Backend>>,
+    next: AtomicUsize,
+    strategy: Strategy,
+    /// State of the random number generator for the random strategy
+    random: AtomicU64,
+    /// Window over which recovered backends ramp up to their full traffic share
+    slow_start: Option<Duration>,
+}
+
+impl Pool {
+    /// Create a pool from a comma-separated list of backend addresses
+    pub fn new(addresses: &str, strategy: Strategy, slow_start: Option<Duration>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let backends: Vec<_> = addresses
+            .split(',')
+            .map(str::trim)
+            .map(|address| match address {
+                "" => Err(format!("Empty backend address in '{}'", addresses)),
+                address => address::normalize_backend(address)
+                    .map(|address| Arc::new(Backend::new(&address)))
+                    .map_err(|e| format!("Invalid backend address: {}", e)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            backends,
+            next: AtomicUsize::new(0),
+            strategy,
+            // Any nonzero seed works, a per-process one keeps proxies from picking alike
+            random: AtomicU64::new(RandomState::new().build_hasher().finish() | 1),
+            slow_start,
+        })
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Use the given backends instead of the pool's own ones with the same address, keeping their state
+    pub fn adopt(&mut self, previous: &[Arc<Backend>]) {
+        for backend in &mut self.backends {
+            if let Some(adopted) = previous.iter().find(|previous| previous.address == backend.address) {
+                *backend = adopted.clone();
+            }
+        }
+    }
+
+    /// Look up a backend by its address
+    pub fn find(&self, address: &str) -> Option<&Arc<Backend>> {
+        self.backends.iter().find(|backend| backend.address == address)
+    }
+
+    /// Pick a backend according to the strategy, preferring backends not in `tried`, not draining,
+    /// healthy and not ejected, and passing over backends in slow-start according to their weight
+    pub fn pick(&self, tried: &[Arc<Backend>]) -> Arc<Backend> {
+        let count = self.backends.len();
+        let start = match self.strategy {
+            Strategy::Random => self.random() as usize,
+            Strategy::RoundRobin | Strategy::LeastConnections => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        let now = Instant::now();
+        let mut ordered: Vec<_> = (0..count).map(|i| &self.backends[(start + i) % count]).collect();
+        if self.strategy == Strategy::LeastConnections {
+            // Stable, so backends with as many connections keep taking turns
+            ordered.sort_by_key(|backend| backend.connections.load(Ordering::Relaxed));
+        }
+        let untried: Vec<_> = ordered
+            .iter()
+            .filter(|backend| !tried.iter().any(|t| Arc::ptr_eq(t, backend)) && !backend.is_draining())
+            .copied()
+            .collect();
+        let available: Vec<_> = untried
+            .iter()
+            .filter(|backend| backend.is_healthy() && !backend.outlier.lock().unwrap().is_ejected(now))
+            .copied()
+            .collect();
+        available
+            .iter()
+            .find(|backend| backend.admit(backend.weight(now, self.slow_start)))
+            .or(available.first())
+            .or(untried.first())
+            .or_else(|| ordered.iter().find(|backend| !backend.is_draining()))
+            .copied()
+            .unwrap_or(ordered[0])
+            .clone()
+    }
+
+    /// Next number of a xorshift generator, plenty for spreading connections
+    fn random(&self) -> u64 {
+        let mut x = self.random.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random.store(x, Ordering::Relaxed);
+        x
+    }
+
+    /// Addresses of all backends, for logging
+    pub fn describe(&self) -> String {
+        let addresses: Vec<_> = self.backends.iter().map(|backend| format!("http://{}", backend.address)).collect();
+        addresses.join(", ")
+    }
+}
+
+/// Backends of the pools by address, so a backend in several pools (or listeners) is reported once
+pub fn by_address<'a>(pools: impl Iterator<Item = &'a Pool>) -> BTreeMap<&'a str, Vec<&'a Backend>> {
+    let mut backends: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for backend in pools.flat_map(|pool| pool.backends()) {
+        backends.entry(backend.address.as_str()).or_default().push(backend.as_ref());
+    }
+    backends
+}
+
+/// Render the failed connections of each backend by error kind as metric lines, added up per address
+pub fn render_connect_errors(backends: &BTreeMap<&str, Vec<&Backend>>) -> String {
+    let mut out = String::new();
+    metrics::family(&mut out, "backend_connect_errors_total", "counter", "Failed backend connections per error kind");
+    for (address, backends) in backends {
+        let mut kinds: BTreeMap<String, u64> = BTreeMap::new();
+        for backend in backends {
+            for (kind, count) in backend.connect_errors.lock().unwrap().iter() {
+                *kinds.entry(kind.clone()).or_default() += count;
+            }
+        }
+        for (kind, count) in kinds {
+            out.push_str(&format!(
+                "backend_connect_errors_total{{backend=\"{}\",kind=\"{}\"}} {}\n",
+                metrics::escape(address),
+                metrics::escape(&kind),
+                count
+            ));
+        }
+    }
+    out
+}
+
+/// What connecting to a backend went through, to tell why it failed
+#[derive(Debug, Default)]
+pub struct Dial {
+    /// Addresses the backend's name resolved to, tried in this order
+    pub resolved: Vec<SocketAddr>,
+    /// Number of the resolved address being (or last) tried, counting from 1
+    pub attempt: usize,
+}
+
+/// A failed connection to a backend, along with what was tried
+#[derive(Debug)]
+pub struct DialError {
+    pub backend: String,
+    pub dial: Dial,
+    pub elapsed: Duration,
+    pub error: Error,
+}
+
+impl DialError {
+    /// Wrap the error, keeping its kind so callers can still tell timeouts and refusals apart
+    pub fn into_io(self) -> Error {
+        Error::new(self.error.kind(), self)
+    }
+}
+
+impl std::fmt::Display for DialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}", self.backend)?;
+        if let Some(addr) = self.dial.attempt.checked_sub(1).and_then(|index| self.dial.resolved.get(index)) {
+            write!(f, " ({}, attempt {} of {})", addr, self.dial.attempt, self.dial.resolved.len())?;
+        }
+        // Seconds with one decimal, so repeats of a failure log identical lines
+        write!(f, " failed after {:.1}s: {} ({})", self.elapsed.as_secs_f64(), self.error, kind_name(self.error.kind()))
+    }
+}
+
+impl std::error::Error for DialError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Name of an error kind for logs and metrics, e.g. `connection_refused`
+pub fn kind_name(kind: ErrorKind) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", kind).chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Connect to a backend, from the given source address, with the given DSCP class and over
+/// multipath TCP when set
+pub async fn connect(address: &str, bind: Option<IpAddr>, dscp: Option<u8>, mptcp: bool) -> std::io::Result<TcpStream> {
+    dial(address, bind, dscp, mptcp, &mut Dial::default()).await
+}
+
+/// Connect like `connect`, recording the resolved addresses and the attempts in `dial` (which
+/// stays readable when the connection attempt is abandoned, e.g. after a timeout)
+pub async fn dial(address: &str, bind: Option<IpAddr>, dscp: Option<u8>, mptcp: bool, dial: &mut Dial) -> std::io::Result<TcpStream> {
+    dial.resolved = resolve(address, bind).await?;
+    let mut last_error = None;
+    for (index, addr) in dial.resolved.iter().enumerate() {
+        dial.attempt = index + 1;
+        match connect_to(*addr, bind, dscp, mptcp).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| match bind {
+        Some(bind) => {
+            let family = if bind.is_ipv4() { "IPv4" } else { "IPv6" };
+            Error::new(ErrorKind::AddrNotAvailable, format!("no {} address to connect to from {}", family, bind))
+        }
+        None => Error::new(ErrorKind::AddrNotAvailable, "no address to connect to"),
+    }))
+}
+
+/// Resolve a backend address, keeping only addresses reachable from the source address
+async fn resolve(address: &str, bind: Option<IpAddr>) -> std::io::Result<Vec<SocketAddr>> {
+    // Only addresses of the source address' family can be reached from it
+    Ok(tokio::net::lookup_host(address)
+        .await?
+        .filter(|addr| bind.is_none_or(|bind| addr.is_ipv4() == bind.is_ipv4()))
+        .collect())
+}
+
+/// Connect to one of the resolved addresses of a backend
+async fn connect_to(addr: SocketAddr, bind: Option<IpAddr>, dscp: Option<u8>, mptcp: bool) -> std::io::Result<TcpStream> {
+    let socket = listener::tcp_socket(&addr, mptcp)?;
+    if let Some(class) = dscp {
+        dscp::mark(socket.as_raw_fd(), addr.is_ipv6(), class)?;
+    }
+    if let Some(bind) = bind {
+        socket.bind(SocketAddr::new(bind, 0))?;
+    }
+    socket.connect(addr).await
+}
+
+/// Outcome of connecting to every resolved address of a backend, requested through the admin API
+#[derive(Debug)]
+pub struct Diagnosis {
+    pub address: String,
+    pub resolve_time: Duration,
+    /// Why the address could not be resolved
+    pub resolve_error: Option<Error>,
+    /// Each resolved address with the time connecting took and the error, if it failed
+    pub attempts: Vec<(SocketAddr, Duration, Option<Error>)>,
+}
+
+/// Resolve the backend and connect to each of its addresses in turn, giving up on an address after the timeout
+pub async fn diagnose(address: &str, bind: Option<IpAddr>, dscp: Option<u8>, mptcp: bool, timeout: Duration) -> Diagnosis {
+    let started = Instant::now();
+    let resolved = resolve(address, bind).await;
+    let mut diagnosis = Diagnosis {
+        address: address.to_string(),
+        resolve_time: started.elapsed(),
+        resolve_error: None,
+        attempts: Vec::new(),
+    };
+    let resolved = match resolved {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            diagnosis.resolve_error = Some(e);
+            return diagnosis;
+        }
+    };
+    for addr in resolved {
+        let started = Instant::now();
+        let result = tokio::time::timeout(timeout, connect_to(addr, bind, dscp, mptcp))
+            .await
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "connect timed out")));
+        diagnosis.attempts.push((addr, started.elapsed(), result.err()));
+    }
+    diagnosis
+}
+
+/// Wait until the backend accepts TCP connections, giving up after the timeout (if any)
+pub async fn wait_until_reachable(
+    address: &str,
+    bind: Option<IpAddr>,
+    dscp: Option<u8>,
+    mptcp: bool,
+    timeout: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let mut attempts: u32 = 0;
+
+    loop {
+        attempts += 1;
+        match connect(address, bind, dscp, mptcp).await {
+            Ok(_) => {
+                if attempts > 1 {
+                    info!("Backend http://{} is reachable after {:.1}s", address, started.elapsed().as_secs_f64());
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                    return Err(format!(
+                        "Backend http://{} not reachable after {:.1}s: {}",
+                        address,
+                        started.elapsed().as_secs_f64(),
+                        e
+                    ).into());
+                }
+                // Log the first failure and then roughly every 10 seconds
+                if attempts == 1 || attempts.is_multiple_of(20) {
+                    info!("Waiting for backend http://{} ({})", address, e);
+                }
+            }
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
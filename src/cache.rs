@@ -0,0 +1,470 @@
+use crate::error;
+use crate::http::{Headers, Request, Response};
+use crate::metrics;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Headers describing a single hop, not stored with cached responses
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// A cached response
+#[derive(Debug)]
+pub struct Entry {
+    pub response: Response,
+    pub body: Vec<u8>,
+    /// When the response was received (or last revalidated)
+    pub stored: SystemTime,
+    /// Freshness lifetime from Cache-Control
+    pub ttl: Duration,
+    /// Request header values the response varies on
+    vary: Vec<(String, Option<String>)>,
+    last_used: AtomicU64,
+}
+
+impl Entry {
+    pub fn age(&self) -> Duration {
+        self.stored.elapsed().unwrap_or_default()
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.ttl
+    }
+
+    /// Time since the entry became stale
+    pub fn staleness(&self) -> Duration {
+        self.age().saturating_sub(self.ttl)
+    }
+
+    /// Whether the response can be revalidated with a conditional request
+    pub fn has_validators(&self) -> bool {
+        self.response.headers.get("ETag").is_some() || self.response.headers.get("Last-Modified").is_some()
+    }
+
+    /// Add conditional headers to revalidate this entry
+    pub fn add_conditions(&self, request: &mut Request) {
+        if let Some(etag) = self.response.headers.get("ETag") {
+            request.headers.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = self.response.headers.get("Last-Modified") {
+            request.headers.set("If-Modified-Since", last_modified);
+        }
+    }
+
+    /// Whether the client already has this response (If-None-Match matches the ETag)
+    pub fn not_modified_for(&self, request: &Request) -> bool {
+        let Some(etag) = self.response.headers.get("ETag") else {
+            return false;
+        };
+        let weak = |tag: &str| tag.trim_start_matches("W/").to_string();
+        request.headers.tokens("If-None-Match").any(|tag| tag == "*" || weak(tag) == weak(etag))
+    }
+
+    /// Response to send to a client, with the current Age
+    pub fn client_response(&self) -> Response {
+        let mut response = self.response.clone();
+        response.headers.set("Age", &self.age().as_secs().to_string());
+        response
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.headers.get(name) == value.as_deref())
+    }
+
+    fn size(&self) -> usize {
+        self.body.len() + self.response.to_bytes().len()
+    }
+}
+
+/// In-memory cache of backend GET responses, with an optional disk tier
+pub struct Cache {
+    /// Path prefixes for which responses are cached
+    prefixes: Vec<String>,
+    max_object: usize,
+    capacity: usize,
+    /// How long past their freshness responses may be served when the backend fails
+    max_stale: Duration,
+    entries: Mutex<HashMap<String, Arc<Entry>>>,
+    size: AtomicU64,
+    disk: Option<Disk>,
+    clock: AtomicU64,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub revalidated: AtomicU64,
+    pub stale_served: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(
+        prefixes: Vec<String>,
+        max_object: usize,
+        capacity: usize,
+        max_stale: Duration,
+        disk: Option<(PathBuf, u64)>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let disk = match disk {
+            Some((dir, capacity)) => Some(Disk::open(dir, capacity)?),
+            None => None,
+        };
+        Ok(Self {
+            prefixes,
+            max_object,
+            capacity,
+            max_stale,
+            entries: Mutex::new(HashMap::new()),
+            size: AtomicU64::new(0),
+            disk,
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            revalidated: AtomicU64::new(0),
+            stale_served: AtomicU64::new(0),
+        })
+    }
+
+    pub fn max_object(&self) -> usize {
+        self.max_object
+    }
+
+    /// Whether a stale entry may still be served because the backend failed
+    pub fn usable_when_failing(&self, entry: &Entry) -> bool {
+        entry.staleness() <= self.max_stale
+    }
+
+    /// Whether the cache may be used for a request
+    pub fn applies(&self, request: &Request) -> bool {
+        (request.method == "GET" || request.method == "HEAD")
+            && self.prefixes.iter().any(|prefix| request.path().starts_with(prefix.as_str()))
+            && request.headers.get("Authorization").is_none()
+            && request.headers.get("Range").is_none()
+            && !request.headers.has_token("Cache-Control", "no-store")
+            && !request.headers.has_token("Cache-Control", "no-cache")
+    }
+
+    /// Find the cached response for a request (fresh or stale)
+    pub async fn lookup(&self, request: &Request) -> Option<Arc<Entry>> {
+        let key = key(request);
+        let memory = self.entries.lock().unwrap().get(&key).cloned();
+        let entry = match memory {
+            Some(entry) => entry,
+            None => {
+                let entry = Arc::new(self.disk.as_ref()?.load(key.clone()).await?);
+                self.insert(key, entry.clone());
+                entry
+            }
+        };
+        entry.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        entry.matches(request).then_some(entry)
+    }
+
+    /// Store a complete response if it is cacheable, returns whether it was stored
+    pub fn store(&self, request: &Request, response: &Response, body: Vec<u8>) -> bool {
+        let Some(ttl) = freshness(response) else {
+            return false;
+        };
+        let Some(vary) = vary(request, response) else {
+            return false;
+        };
+        let mut stored = response.clone();
+        for name in HOP_BY_HOP {
+            stored.headers.remove(name);
+        }
+        stored.headers.remove("Age");
+        stored.headers.set("Content-Length", &body.len().to_string());
+
+        let entry = Arc::new(Entry {
+            response: stored,
+            body,
+            stored: SystemTime::now(),
+            ttl,
+            vary,
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        });
+        if entry.size() > self.max_object {
+            return false;
+        }
+        let key = key(request);
+        if let Some(disk) = &self.disk {
+            disk.save(key.clone(), entry.clone());
+        }
+        self.insert(key, entry);
+        true
+    }
+
+    /// Refresh an entry after the backend confirmed it is unchanged (304)
+    pub fn freshen(&self, request: &Request, entry: &Entry, not_modified: &Response) -> Arc<Entry> {
+        let mut response = entry.response.clone();
+        // Updated metadata from the 304 response replaces the stored headers
+        for name in ["Cache-Control", "Date", "Expires", "ETag", "Last-Modified", "Vary"] {
+            if let Some(value) = not_modified.headers.get(name) {
+                response.headers.set(name, value);
+            }
+        }
+        let fresh = Arc::new(Entry {
+            ttl: freshness(&response).unwrap_or_default(),
+            response,
+            body: entry.body.clone(),
+            stored: SystemTime::now(),
+            vary: entry.vary.clone(),
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        });
+        let key = key(request);
+        if let Some(disk) = &self.disk {
+            disk.save(key.clone(), fresh.clone());
+        }
+        self.insert(key, fresh.clone());
+        self.revalidated.fetch_add(1, Ordering::Relaxed);
+        fresh
+    }
+
+    /// Render the statistics of the caches (one per listener) added up as metric lines
+    pub fn render<'a>(caches: impl Iterator<Item = &'a Cache>) -> String {
+        let mut totals = [0u64; 6];
+        for cache in caches {
+            let values = [
+                cache.hits.load(Ordering::Relaxed),
+                cache.misses.load(Ordering::Relaxed),
+                cache.revalidated.load(Ordering::Relaxed),
+                cache.stale_served.load(Ordering::Relaxed),
+                cache.entries.lock().unwrap().len() as u64,
+                cache.size.load(Ordering::Relaxed),
+            ];
+            totals.iter_mut().zip(values).for_each(|(total, value)| *total += value);
+        }
+        let families = [
+            ("cache_hits_total", "counter", "Responses served from the cache"),
+            ("cache_misses_total", "counter", "Cacheable requests forwarded to the backend"),
+            ("cache_revalidated_total", "counter", "Cached responses revalidated with the backend"),
+            ("cache_stale_served_total", "counter", "Stale responses served while the backend failed"),
+            ("cache_entries", "gauge", "Responses in the cache"),
+            ("cache_bytes", "gauge", "Size of the responses in the cache"),
+        ];
+        let mut out = String::new();
+        for ((name, kind, help), total) in families.into_iter().zip(totals) {
+            metrics::family(&mut out, name, kind, help);
+            out.push_str(&format!("{} {}\n", name, total));
+        }
+        out
+    }
+
+    /// Insert an entry, evicting the least recently used entries beyond the capacity
+    fn insert(&self, key: String, entry: Arc<Entry>) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut size = self.size.load(Ordering::Relaxed) + entry.size() as u64;
+        if let Some(old) = entries.insert(key, entry) {
+            size -= old.size() as u64;
+        }
+        while size > self.capacity as u64 {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = entries.remove(&oldest) {
+                size -= evicted.size() as u64;
+            }
+        }
+        self.size.store(size, Ordering::Relaxed);
+    }
+}
+
+/// Cache key of a request
+fn key(request: &Request) -> String {
+    format!("{}{}", request.headers.get("Host").unwrap_or(""), request.target)
+}
+
+/// Freshness lifetime of a cacheable response, `None` when it must not be stored
+fn freshness(response: &Response) -> Option<Duration> {
+    let headers = &response.headers;
+    if response.status != 200
+        || headers.get("Set-Cookie").is_some()
+        || headers.has_token("Cache-Control", "no-store")
+        || headers.has_token("Cache-Control", "private")
+    {
+        return None;
+    }
+    let directive = |name: &str| {
+        headers
+            .tokens("Cache-Control")
+            .filter_map(|token| token.split_once('='))
+            .find(|(directive, _)| directive.trim().eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.trim().trim_matches('"').parse::<u64>().ok())
+    };
+    let validators = headers.get("ETag").is_some() || headers.get("Last-Modified").is_some();
+    if headers.has_token("Cache-Control", "no-cache") {
+        // Stored, but revalidated before every use
+        return validators.then_some(Duration::ZERO);
+    }
+    match directive("s-maxage").or_else(|| directive("max-age")) {
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None if validators => Some(Duration::ZERO),
+        None => None,
+    }
+}
+
+/// Request header values a response varies on, `None` when it varies on everything
+fn vary(request: &Request, response: &Response) -> Option<Vec<(String, Option<String>)>> {
+    let mut vary = Vec::new();
+    for name in response.headers.tokens("Vary") {
+        if name == "*" {
+            return None;
+        }
+        vary.push((name.to_string(), request.headers.get(name).map(str::to_string)));
+    }
+    Some(vary)
+}
+
+/// Disk tier keeping cached responses across restarts
+struct Disk {
+    dir: PathBuf,
+    capacity: u64,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Disk {
+    fn open(dir: PathBuf, capacity: u64) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create cache directory {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir,
+            capacity,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.entry", hasher.finish()))
+    }
+
+    /// Load an entry, `None` when missing, unreadable or stored for another key (hash collision)
+    async fn load(&self, key: String) -> Option<Entry> {
+        let path = self.path(&key);
+        tokio::task::spawn_blocking(move || {
+            let data = std::fs::read(&path).ok()?;
+            decode(&key, &data)
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Write an entry in the background, evicting the oldest files beyond the capacity
+    fn save(&self, key: String, entry: Arc<Entry>) {
+        let path = self.path(&key);
+        let (dir, capacity, lock) = (self.dir.clone(), self.capacity, self.lock.clone());
+        tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock().unwrap();
+            let temporary = path.with_extension("tmp");
+            let result = std::fs::write(&temporary, encode(&key, &entry)).and_then(|_| std::fs::rename(&temporary, &path));
+            if let Err(e) = result {
+                error!("Failed to write cache entry {}: {}", path.display(), e);
+                return;
+            }
+            evict(&dir, capacity);
+        });
+    }
+}
+
+/// Remove the least recently written entries until the directory fits the capacity
+fn evict(dir: &std::path::Path, capacity: u64) {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = read_dir
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "entry"))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort();
+    for (_, len, path) in files {
+        if total <= capacity {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            total -= len;
+        }
+    }
+}
+
+/// File format: key line, "stored ttl" line, vary line, then the response head and body
+fn encode(key: &str, entry: &Entry) -> Vec<u8> {
+    let stored = entry.stored.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let vary: Vec<String> = entry
+        .vary
+        .iter()
+        .map(|(name, value)| match value {
+            Some(value) => format!("{}:{}", name, value),
+            None => name.clone(),
+        })
+        .collect();
+    let mut out = format!("{}\n{} {}\n{}\n", key, stored, entry.ttl.as_secs(), vary.join("\t")).into_bytes();
+    out.extend_from_slice(&entry.response.to_bytes());
+    out.extend_from_slice(&entry.body);
+    out
+}
+
+fn decode(key: &str, data: &[u8]) -> Option<Entry> {
+    let mut lines = data.splitn(4, |&b| b == b'\n');
+    if lines.next()? != key.as_bytes() {
+        return None;
+    }
+    let times = std::str::from_utf8(lines.next()?).ok()?;
+    let (stored, ttl) = times.split_once(' ')?;
+    let vary = std::str::from_utf8(lines.next()?).ok()?;
+    let vary = vary
+        .split('\t')
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.split_once(':') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (v.to_string(), None),
+        })
+        .collect();
+
+    let rest = lines.next()?;
+    let head_end = rest.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let response = Response::parse(&rest[..head_end]).ok()?;
+    Some(Entry {
+        body: rest[head_end..].to_vec(),
+        response,
+        stored: UNIX_EPOCH + Duration::from_secs(stored.parse().ok()?),
+        ttl: Duration::from_secs(ttl.parse().ok()?),
+        vary,
+        last_used: AtomicU64::new(0),
+    })
+}
+
+/// Parse a `--cache` path prefix
+pub fn parse_prefix(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') {
+        return Err("path prefix must start with /".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Whether a request carries its own conditional headers
+pub fn has_conditions(headers: &Headers) -> bool {
+    ["If-None-Match", "If-Modified-Since", "If-Match", "If-Unmodified-Since", "If-Range"]
+        .iter()
+        .any(|name| headers.get(name).is_some())
+}
@@ -0,0 +1,78 @@
+use crate::info;
+use crate::http_client;
+use clap::ValueEnum;
+use rustls::crypto::CryptoProvider;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use std::sync::Arc;
+
+/// Where Cloudflare publishes the CA of the client certificates it presents for Authenticated Origin Pulls
+pub const CLOUDFLARE_ORIGIN_PULL_CA: &str = "https://developers.cloudflare.com/ssl/static/authenticated_origin_pull_ca.pem";
+
+/// CDNs whose origin pull client certificates can be required by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OriginPull {
+    /// Cloudflare Authenticated Origin Pulls (zone-level certificate)
+    Cloudflare,
+}
+
+impl OriginPull {
+    /// Location of the CA bundle the CDN's client certificates are signed by
+    pub fn ca(self) -> &'static str {
+        match self {
+            OriginPull::Cloudflare => CLOUDFLARE_ORIGIN_PULL_CA,
+        }
+    }
+}
+
+/// Load the CA bundle from a file or http(s):// URL, check it against the pinned SHA-256 and build
+/// a verifier only accepting clients with a certificate signed by one of its CAs
+///
+/// A bundle fetched from a URL is only trusted when pinned, so a compromised or spoofed download
+/// cannot let other clients in.
+pub async fn verifier(
+    source: &str,
+    pin: Option<&str>,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, Box<dyn std::error::Error + Send + Sync>> {
+    let remote = source.starts_with("https://") || source.starts_with("http://");
+    let pem = match remote {
+        true => {
+            let tls = source.starts_with("https://").then(http_client::tls_connector).transpose()?;
+            let response = http_client::request("GET", source, None, b"", tls.as_ref())
+                .await
+                .map_err(|e| format!("Failed to fetch client CA from {}: {}", source, e))?;
+            if response.status != 200 {
+                return Err(format!("Failed to fetch client CA from {}: status {}", source, response.status).into());
+            }
+            response.body
+        }
+        false => std::fs::read(source).map_err(|e| format!("Failed to read client CA {}: {}", source, e))?,
+    };
+
+    let digest: String = openssl::sha::sha256(&pem).iter().map(|b| format!("{:02x}", b)).collect();
+    match pin {
+        Some(pin) if !pin.trim().eq_ignore_ascii_case(&digest) => {
+            return Err(format!("Client CA from {} has SHA-256 {}, but {} is pinned", source, digest, pin).into());
+        }
+        None if remote => {
+            return Err(format!(
+                "Client CA fetched from {} has SHA-256 {}; verify it and pin it with --client-ca-sha256 {}",
+                source, digest, digest
+            )
+            .into());
+        }
+        _ => {}
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        roots.add(cert?).map_err(|e| format!("Invalid client CA in {}: {}", source, e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("No CA certificates found in {}", source).into());
+    }
+    info!("Requiring client certificates signed by {} ({} CA(s), SHA-256 {})", source, roots.len(), digest);
+    Ok(WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?)
+}
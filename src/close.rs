@@ -0,0 +1,77 @@
+use crate::error;
+use clap::ValueEnum;
+use std::os::fd::RawFd;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Time a client gets to answer our close_notify with its own before the connection is closed anyway
+const CLOSE_NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the TLS connection to a client is ended after forwarding finished normally
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CloseNotify {
+    /// Send close_notify and wait for the client's close_notify (or EOF) before closing
+    Wait,
+    /// Send close_notify and close right away
+    Send,
+    /// Close the TCP connection without close_notify
+    None,
+}
+
+/// How client connections are closed
+#[derive(Debug, Clone, Copy)]
+pub struct ClosePolicy {
+    pub close_notify: CloseNotify,
+    /// Close with a TCP reset instead of a FIN when forwarding failed or was aborted
+    pub reset_on_error: bool,
+}
+
+/// End a client connection according to the policy, `clean` tells whether forwarding finished normally
+///
+/// `fd` must be the socket underlying `stream`.
+pub async fn close<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, fd: RawFd, clean: bool, policy: ClosePolicy) {
+    if !clean {
+        if policy.reset_on_error {
+            if let Err(e) = set_linger(fd, Some(Duration::ZERO)) {
+                error!("Failed to reset connection: {}", e);
+            }
+        }
+        return;
+    }
+
+    if policy.close_notify == CloseNotify::None {
+        return;
+    }
+    let closing = async {
+        // Sends close_notify (unless already sent) and shuts down the write side
+        stream.shutdown().await?;
+        if policy.close_notify == CloseNotify::Wait {
+            let mut discard = [0u8; 1024];
+            while stream.read(&mut discard).await? > 0 {}
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    // Clients that close without close_notify or never answer are not worth reporting
+    let _ = tokio::time::timeout(CLOSE_NOTIFY_TIMEOUT, closing).await;
+}
+
+/// Set `SO_LINGER`, a zero duration makes closing the socket send a TCP reset
+pub fn set_linger(fd: RawFd, linger: Option<Duration>) -> std::io::Result<()> {
+    let value = libc::linger {
+        l_onoff: linger.is_some() as libc::c_int,
+        l_linger: linger.map_or(0, |linger| linger.as_secs() as libc::c_int),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &value as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
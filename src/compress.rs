@@ -0,0 +1,394 @@
+use rustls::compress::{CertCompressor, CompressionFailed, CompressionLevel};
+use rustls::CertificateCompressionAlgorithm;
+
+/// Size of the LZ77 sliding window
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Shortest and longest back-reference DEFLATE can encode
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Number of previous positions examined per match search
+const MAX_CHAIN: usize = 64;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Certificate compression (RFC 8879) using zlib
+///
+/// Certificate chains are small and compressed once per certificate (rustls caches the result),
+/// so a compact LZ77 encoder with the fixed DEFLATE Huffman codes is sufficient here.
+#[derive(Debug)]
+pub struct ZlibCompressor;
+
+impl CertCompressor for ZlibCompressor {
+    fn compress(&self, input: Vec<u8>, _level: CompressionLevel) -> Result<Vec<u8>, CompressionFailed> {
+        Ok(zlib(&input))
+    }
+
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Zlib
+    }
+}
+
+/// Compress data into a zlib stream (RFC 1950) holding a single fixed-Huffman DEFLATE block
+fn zlib(input: &[u8]) -> Vec<u8> {
+    // CMF: deflate with 32K window, FLG: no dictionary, check bits so the header is a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(input));
+    out.extend_from_slice(&adler32(input).to_be_bytes());
+    out
+}
+
+/// Compress data into a gzip stream (RFC 1952), for HTTP responses
+pub fn gzip(input: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no modification time, no extra flags, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend_from_slice(&deflate(input));
+    out.extend_from_slice(&crc32(input).to_le_bytes());
+    out.extend_from_slice(&(input.len() as u32).to_le_bytes());
+    out
+}
+
+/// Compress data into a single fixed-Huffman DEFLATE block (RFC 1951)
+fn deflate(input: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+
+    // Final block with fixed Huffman codes
+    out.write_bits(1, 1);
+    out.write_bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << 15];
+    let mut prev = vec![usize::MAX; input.len()];
+    let mut pos = 0;
+    while pos < input.len() {
+        let (length, distance) = longest_match(input, pos, &head, &prev);
+        let step = if length >= MIN_MATCH {
+            write_match(&mut out, length, distance);
+            length
+        } else {
+            write_literal(&mut out, input[pos]);
+            1
+        };
+        for p in pos..pos + step {
+            if p + MIN_MATCH <= input.len() {
+                let h = hash(&input[p..]);
+                prev[p] = head[h];
+                head[h] = p;
+            }
+        }
+        pos += step;
+    }
+    write_symbol(&mut out, 256);
+    out.flush();
+    out.bytes
+}
+
+/// Find the longest earlier occurrence of the data at `pos`, returns (length, distance)
+fn longest_match(input: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + MIN_MATCH > input.len() {
+        return (0, 0);
+    }
+    let max_length = (input.len() - pos).min(MAX_MATCH);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(&input[pos..])];
+    let mut chain = 0;
+    while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+        let length = input[candidate..]
+            .iter()
+            .zip(&input[pos..pos + max_length])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if length > best.0 {
+            best = (length, pos - candidate);
+            if length == max_length {
+                break;
+            }
+        }
+        candidate = prev[candidate];
+        chain += 1;
+    }
+    best
+}
+
+fn hash(data: &[u8]) -> usize {
+    ((data[0] as usize) << 10 ^ (data[1] as usize) << 5 ^ data[2] as usize) & ((1 << 15) - 1)
+}
+
+fn write_literal(out: &mut BitWriter, byte: u8) {
+    write_symbol(out, byte as u16);
+}
+
+fn write_match(out: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+    write_symbol(out, 257 + code as u16);
+    out.write_bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code]);
+
+    let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+    out.write_code(code as u32, 5);
+    out.write_bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code]);
+}
+
+/// Write a literal/length symbol using the fixed Huffman code
+fn write_symbol(out: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => out.write_code(0x30 + symbol, 8),
+        144..=255 => out.write_code(0x190 + symbol - 144, 9),
+        256..=279 => out.write_code(symbol - 256, 7),
+        _ => out.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// CRC-32 of every byte value, for the gzip trailer
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Writes bits least significant bit first, as DEFLATE requires
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    /// Write the low `count` bits of a value (least significant first)
+    fn write_bits(&mut self, value: u32, count: u8) {
+        for i in 0..count {
+            self.buffer |= ((value >> i) & 1) << self.count;
+            self.count += 1;
+            if self.count == 8 {
+                self.bytes.push(self.buffer as u8);
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Write a Huffman code (most significant bit first)
+    fn write_code(&mut self, code: u32, length: u8) {
+        for i in (0..length).rev() {
+            self.write_bits((code >> i) & 1, 1);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer = 0;
+            self.count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant bit first, as written by `BitWriter`
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u8) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = (self.bytes[self.position / 8] >> (self.position % 8)) & 1;
+                value |= (bit as u32) << i;
+                self.position += 1;
+            }
+            value
+        }
+
+        fn code(&mut self, length: u8) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        /// Literal/length symbol in the fixed Huffman code
+        fn symbol(&mut self) -> usize {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code as usize;
+            }
+            let code = code << 1 | self.code(1);
+            match code {
+                0x30..=0xbf => (code - 0x30) as usize,
+                0xc0..=0xc7 => (280 + code - 0xc0) as usize,
+                _ => (144 + (code << 1 | self.code(1)) - 0x190) as usize,
+            }
+        }
+    }
+
+    /// Decompress the fixed-Huffman DEFLATE blocks the encoder writes
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { bytes: data, position: 0 };
+        let mut out = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            assert_eq!(reader.bits(2), 1, "not a fixed Huffman block");
+            loop {
+                let symbol = reader.symbol();
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let code = symbol - 257;
+                        let length = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code]) as usize;
+                        let code = reader.code(5) as usize;
+                        let distance = DISTANCE_BASE[code] as usize + reader.bits(DISTANCE_EXTRA[code]) as usize;
+                        assert!((MIN_MATCH..=MAX_MATCH).contains(&length), "length {}", length);
+                        assert!(distance <= out.len() && distance <= WINDOW_SIZE, "distance {}", distance);
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last {
+                break;
+            }
+        }
+        assert_eq!(reader.position.div_ceil(8), data.len(), "data after the final block");
+        out
+    }
+
+    fn unzlib(data: &[u8]) -> Vec<u8> {
+        assert_eq!((u16::from(data[0]) << 8 | u16::from(data[1])) % 31, 0);
+        let (deflated, checksum) = data[2..].split_at(data.len() - 6);
+        let out = inflate(deflated);
+        assert_eq!(adler32(&out).to_be_bytes(), checksum);
+        out
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        assert_eq!(data[..3], [0x1f, 0x8b, 8]);
+        let (deflated, trailer) = data[10..].split_at(data.len() - 18);
+        let out = inflate(deflated);
+        assert_eq!(crc32(&out).to_le_bytes(), trailer[..4]);
+        assert_eq!((out.len() as u32).to_le_bytes(), trailer[4..]);
+        out
+    }
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        assert!(unzlib(&zlib(input)) == input);
+        assert!(gunzip(&gzip(input)) == input);
+        zlib(input)
+    }
+
+    /// Bytes without repetitions worth a back-reference
+    fn random(length: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        (0..length)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"123456789"), 0x091e_01de);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    /// Output that zlib's own inflate accepts, as checked with Python's `zlib.decompress`
+    #[test]
+    fn known_streams() {
+        assert_eq!(zlib(b""), [0x78, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(zlib(b"a"), [0x78, 0x01, 0x4b, 0x04, 0x00, 0x00, 0x62, 0x00, 0x62]);
+    }
+
+    #[test]
+    fn short_inputs() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"ab");
+        round_trip(b"abc");
+        round_trip(b"abcabc");
+    }
+
+    #[test]
+    fn long_runs() {
+        // Longer than the longest back-reference, split over several matches
+        let compressed = round_trip(&[b'x'; 1000]);
+        assert!(compressed.len() < 30, "{} bytes", compressed.len());
+        round_trip(&[b'x'; MAX_MATCH + 1]);
+        round_trip(&[b'x'; MAX_MATCH + MIN_MATCH - 1]);
+        round_trip(&b"abc".repeat(500));
+    }
+
+    #[test]
+    fn beyond_the_window() {
+        // Repeats further back than the window may not be referenced
+        let mut input = random(WINDOW_SIZE + 10_000);
+        input.extend_from_within(..1000);
+        round_trip(&input);
+
+        // A repeat right at the edge of the window
+        let mut input = random(WINDOW_SIZE);
+        input.extend_from_within(..1000);
+        let compressed = round_trip(&input);
+        assert!(compressed.len() < WINDOW_SIZE * 9 / 8 + 100, "{} bytes", compressed.len());
+
+        let text: Vec<u8> = (0..5000).flat_map(|i| format!("line {} of a larger text\n", i % 700).into_bytes()).collect();
+        assert!(text.len() > 3 * WINDOW_SIZE);
+        round_trip(&text);
+    }
+
+    #[test]
+    fn incompressible() {
+        let input = random(100_000);
+        let compressed = round_trip(&input);
+        // Fixed Huffman codes take 8 or 9 bits per literal
+        assert!(compressed.len() <= input.len() * 9 / 8 + 16, "{} bytes", compressed.len());
+        round_trip(&(0..=255).collect::<Vec<u8>>());
+    }
+}
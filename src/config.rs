@@ -0,0 +1,421 @@
+use clap::parser::ValueSource;
+use clap::{ArgAction, Command};
+use std::path::Path;
+
+/// A value in the configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// A setting of the configuration file, with the line it was found on for error messages
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// Command line arguments with the settings of the `--config` file (if any) merged in
+///
+/// Every setting is named after a command line option (`read-timeout = 30` for `--read-timeout 30`,
+/// `input-address`, `output-address`, `certificate` and `password-or-key` for the positional
+/// arguments), and is only used when that option is not given on the command line, so command line
+/// options override the file.
+pub fn merge(command: Command, cli: Vec<String>) -> Result<Vec<String>, String> {
+    // The positional arguments may all come from the file, so they are optional until it is read
+    let positionals: Vec<clap::Arg> = command.get_positionals().cloned().collect();
+    let relaxed = positionals
+        .iter()
+        .fold(command.clone(), |command, arg| command.mut_arg(arg.get_id().clone(), |arg| arg.required(false)));
+    // Errors are reported when the merged arguments are parsed
+    let Ok(matches) = relaxed.try_get_matches_from(&cli) else {
+        return Ok(cli);
+    };
+    let Some(path) = matches.get_raw("config").and_then(|mut values| values.next()) else {
+        return Ok(cli);
+    };
+    let path = path.to_string_lossy().into_owned();
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+    let path = Path::new(&path);
+    let settings = parse(&text).map_err(|e| format!("{} {}", path.display(), e))?;
+
+    let mut positional_values: Vec<Option<String>> = vec![None; positionals.len()];
+    let mut options = Vec::new();
+    for setting in settings {
+        let name = setting.key.replace('_', "-");
+        let unknown = || format!("{} line {}: unknown setting '{}'", path.display(), setting.line, setting.key);
+        let arg = command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set() && arg.get_id() != "config" && !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version))
+            .find(|arg| match arg.get_long() {
+                Some(long) => long == name,
+                None => arg.get_id().as_str().replace('_', "-") == name,
+            })
+            .ok_or_else(unknown)?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        // Arrays of positional arguments are comma-separated lists, e.g. several backends
+        if let Some(index) = positionals.iter().position(|positional| positional.get_id() == arg.get_id()) {
+            positional_values[index] = Some(setting.value.to_string());
+            continue;
+        }
+        let long = name;
+        let values = match setting.value {
+            Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => values,
+            Value::Array(_) => return Err(format!("{} line {}: '{}' takes a single value", path.display(), setting.line, setting.key)),
+            value => vec![value],
+        };
+        for value in values {
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, Value::Boolean(true)) => options.push(format!("--{}", long)),
+                (ArgAction::SetTrue, Value::Boolean(false)) => {}
+                (ArgAction::SetTrue, _) => {
+                    return Err(format!("{} line {}: '{}' is either true or false", path.display(), setting.line, setting.key));
+                }
+                // Options with an optional value, enabled with their default
+                (_, Value::Boolean(true)) if arg.get_num_args().is_some_and(|range| range.min_values() == 0) => options.push(format!("--{}", long)),
+                (_, value) => options.push(format!("--{}={}", long, value)),
+            }
+        }
+    }
+
+    // The file's options, the command line as given, and the positional arguments it leaves to the file
+    let given = positionals
+        .iter()
+        .take_while(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .count();
+    let missing: Vec<String> = positional_values.into_iter().skip(given).map_while(|value| value).collect();
+    let mut merged = vec![cli.first().cloned().unwrap_or_default()];
+    merged.extend(options);
+    let separated = cli.iter().skip(1).any(|arg| arg == "--");
+    merged.extend(cli.into_iter().skip(1));
+    if !missing.is_empty() && !separated {
+        merged.push("--".to_string());
+    }
+    merged.extend(missing);
+    Ok(merged)
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "{}", values.join(","))
+            }
+        }
+    }
+}
+
+/// Parse the subset of TOML used by the configuration file: `key = value` lines with strings,
+/// integers, floats, booleans and (possibly multi-line) arrays of these, and `#` comments
+pub fn parse(text: &str) -> Result<Vec<Setting>, String> {
+    let mut settings: Vec<Setting> = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line_number = index + 1;
+        let error = |message: &str| format!("line {}: {}", line_number, message);
+        let line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(error("tables are not supported, settings are plain `key = value` lines (several listeners are a `listener` array)"));
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error("expected `key = value`"))?;
+        let key = key.trim();
+        let key = match key.strip_prefix('"').and_then(|key| key.strip_suffix('"')) {
+            Some(quoted) => quoted,
+            None => key,
+        };
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(error(&format!("invalid key '{}'", key)));
+        }
+        if settings.iter().any(|setting| setting.key.replace('_', "-") == key.replace('_', "-")) {
+            return Err(error(&format!("'{}' is set twice", key)));
+        }
+
+        // Arrays may continue on the following lines until their closing bracket
+        let mut value = value.trim().to_string();
+        while value.starts_with('[') && !brackets_closed(&value) {
+            let Some((_, next)) = lines.next() else {
+                return Err(error("unclosed array"));
+            };
+            value.push(' ');
+            value.push_str(strip_comment(next).trim());
+        }
+        let mut chars = value.chars().peekable();
+        let parsed = parse_value(&mut chars).map_err(|e| error(&e))?;
+        if chars.any(|c| !c.is_whitespace()) {
+            return Err(error("unexpected characters after the value"));
+        }
+        settings.push(Setting {
+            key: key.to_string(),
+            value: parsed,
+            line: line_number,
+        });
+    }
+    Ok(settings)
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn parse_value(chars: &mut Chars) -> Result<Value, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => return Ok(Value::String(s)),
+                    Some('\\') => match chars.next() {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some('r') => s.push('\r'),
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or("invalid \\u escape")?;
+                            s.push(c);
+                        }
+                        _ => return Err("invalid escape in string".to_string()),
+                    },
+                    Some(c) => s.push(c),
+                    None => return Err("unclosed string".to_string()),
+                }
+            }
+        }
+        Some('\'') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') => return Ok(Value::String(s)),
+                    Some(c) => s.push(c),
+                    None => return Err("unclosed string".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            loop {
+                skip_whitespace(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Value::Array(values));
+                }
+                match parse_value(chars)? {
+                    Value::Array(_) => return Err("nested arrays are not supported".to_string()),
+                    value => values.push(value),
+                }
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Value::Array(values)),
+                    _ => return Err("expected ',' or ']' in array".to_string()),
+                }
+            }
+        }
+        Some(_) => {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == ']' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            match word.as_str() {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                _ => {
+                    let number = word.replace('_', "");
+                    if let Ok(n) = number.parse::<i64>() {
+                        Ok(Value::Integer(n))
+                    } else if let Ok(n) = number.parse::<f64>() {
+                        Ok(Value::Float(n))
+                    } else {
+                        Err(format!("invalid value '{}' (strings need quotes)", word))
+                    }
+                }
+            }
+        }
+        None => Err("missing value".to_string()),
+    }
+}
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// The line without its comment, keeping `#` inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Whether the brackets of an array value are balanced (outside of strings)
+fn brackets_closed(value: &str) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn value(text: &str) -> Result<Value, String> {
+        let settings = parse(&format!("key = {}", text))?;
+        Ok(settings.into_iter().next().unwrap().value)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    /// A command line shaped like the proxy's: positional addresses and every kind of option
+    fn command() -> Command {
+        Command::new("https-wrapper")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("input_address").required(true))
+            .arg(Arg::new("output_address").required(true))
+            .arg(Arg::new("read_timeout").long("read-timeout"))
+            .arg(Arg::new("tags").long("tag").action(ArgAction::Append))
+            .arg(Arg::new("forwarded_headers").long("forwarded-headers").action(ArgAction::SetTrue))
+    }
+
+    /// Merge the command line with a configuration file holding `text`
+    fn merged(text: &str, cli: &[&str]) -> Result<Vec<String>, String> {
+        let path = std::env::temp_dir().join(format!("https-wrapper-config-{}-{}.toml", std::process::id(), text.len()));
+        std::fs::write(&path, text).unwrap();
+        let mut args = vec!["https-wrapper".to_string(), format!("--config={}", path.display())];
+        args.extend(cli.iter().map(|arg| arg.to_string()));
+        let merged = merge(command(), args);
+        std::fs::remove_file(&path).unwrap();
+        merged
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(value("\"text\"").unwrap(), string("text"));
+        assert_eq!(value("'C:\\path'").unwrap(), string("C:\\path"));
+        assert_eq!(value("\"a\\\"b\\\\c\\n\\u00e9\"").unwrap(), string("a\"b\\c\né"));
+        assert_eq!(value("30").unwrap(), Value::Integer(30));
+        assert_eq!(value("1_000").unwrap(), Value::Integer(1000));
+        assert_eq!(value("-2").unwrap(), Value::Integer(-2));
+        assert_eq!(value("0.5").unwrap(), Value::Float(0.5));
+        assert_eq!(value("true").unwrap(), Value::Boolean(true));
+        assert_eq!(value("[]").unwrap(), Value::Array(Vec::new()));
+        assert_eq!(value("[1, \"a\",]").unwrap(), Value::Array(vec![Value::Integer(1), string("a")]));
+    }
+
+    #[test]
+    fn parse_invalid_values() {
+        for text in ["", "text", "\"unclosed", "'unclosed", "\"\\x\"", "\"\\u12\"", "[1, [2]]", "[1 2]", "\"a\" \"b\"", "[1,"] {
+            assert!(value(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_comments_and_lines() {
+        let text = "# proxy settings\n\nmode = \"http\"  # trailing comment\ntag = \"a#b=c\" # a # in a string\n  \"read_timeout\" = 30\n";
+        let settings = parse(text).unwrap();
+        let keys: Vec<_> = settings.iter().map(|setting| (setting.key.as_str(), setting.line)).collect();
+        assert_eq!(keys, [("mode", 3), ("tag", 4), ("read_timeout", 5)]);
+        assert_eq!(settings[1].value, string("a#b=c"));
+    }
+
+    #[test]
+    fn parse_multi_line_arrays() {
+        let text = "output-address = [\n  \"127.0.0.1:8080\", # first\n  \"127.0.0.1:8081\",\n  \"[::1]:8082\"\n]\nmode = \"http\"\n";
+        let settings = parse(text).unwrap();
+        assert_eq!(settings[0].value, Value::Array(vec![string("127.0.0.1:8080"), string("127.0.0.1:8081"), string("[::1]:8082")]));
+        assert_eq!((settings[1].key.as_str(), settings[1].line), ("mode", 6));
+        assert_eq!(parse("tag = [\n\"a=b\",\n").unwrap_err(), "line 1: unclosed array");
+    }
+
+    #[test]
+    fn parse_rejected() {
+        assert!(parse("[listener]\ninput = \"0.0.0.0:443\"\n").unwrap_err().starts_with("line 1: tables are not supported"));
+        assert!(parse("mode = \"http\"\n[[listener]]\n").unwrap_err().starts_with("line 2: tables are not supported"));
+        assert_eq!(parse("mode = \"http\"\nmode = \"tcp\"\n").unwrap_err(), "line 2: 'mode' is set twice");
+        assert_eq!(parse("read-timeout = 1\nread_timeout = 2\n").unwrap_err(), "line 2: 'read_timeout' is set twice");
+        assert_eq!(parse("mode\n").unwrap_err(), "line 1: expected `key = value`");
+        assert_eq!(parse("a.b = 1\n").unwrap_err(), "line 1: invalid key 'a.b'");
+    }
+
+    #[test]
+    fn merge_settings() {
+        let text = "input-address = \"0.0.0.0:443\"\noutput_address = [\"127.0.0.1:8080\", \"127.0.0.1:8081\"]\nread-timeout = 30\ntag = [\"a=b\", \"c=d\"]\nforwarded-headers = true\n";
+        let merged = merged(text, &[]).unwrap();
+        assert_eq!(&merged[1..5], ["--read-timeout=30", "--tag=a=b", "--tag=c=d", "--forwarded-headers"]);
+        assert_eq!(&merged[6..], ["--", "0.0.0.0:443", "127.0.0.1:8080,127.0.0.1:8081"]);
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let text = "input-address = \"0.0.0.0:443\"\noutput-address = \"127.0.0.1:8080\"\nread-timeout = 30\ntag = [\"a=b\", \"c=d\"]\n";
+        let merged = merged(text, &["[::]:8443", "--read-timeout", "60", "--tag=e=f"]).unwrap();
+        let matches = command().try_get_matches_from(&merged).unwrap();
+        let get = |id: &str| matches.get_many::<String>(id).unwrap().cloned().collect::<Vec<_>>();
+        assert_eq!(get("input_address"), ["[::]:8443"]);
+        assert_eq!(get("output_address"), ["127.0.0.1:8080"]);
+        assert_eq!(get("read_timeout"), ["60"]);
+        // Repeatable options are replaced as a whole
+        assert_eq!(get("tags"), ["e=f"]);
+    }
+
+    #[test]
+    fn merge_rejected() {
+        assert_eq!(merged("unknown = 1\n", &["a", "b"]).unwrap_err().split_once(' ').unwrap().1, "line 1: unknown setting 'unknown'");
+        assert!(merged("read-timeout = [1, 2]\n", &["a", "b"]).unwrap_err().ends_with("line 1: 'read-timeout' takes a single value"));
+        assert!(merged("forwarded-headers = 1\n", &["a", "b"]).unwrap_err().ends_with("line 1: 'forwarded-headers' is either true or false"));
+        assert!(merged("config = \"other.toml\"\n", &["a", "b"]).unwrap_err().ends_with("line 1: unknown setting 'config'"));
+    }
+}
@@ -0,0 +1,39 @@
+use tokio::sync::watch;
+
+/// Whether the proxy is winding down its client connections, e.g. before being taken out of a
+/// load balancer: kept-alive HTTP/1.1 clients are told to close after their next response, idle
+/// ones are closed right away and HTTP/2 clients receive GOAWAY
+#[derive(Debug)]
+pub struct Drain {
+    draining: watch::Sender<bool>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            draining: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Drain {
+    /// Start draining, returns whether it was not draining before
+    pub fn start(&self) -> bool {
+        self.draining.send_if_modified(|draining| !std::mem::replace(draining, true))
+    }
+
+    /// Stop draining, connections opened from now on are kept alive again
+    pub fn stop(&self) {
+        self.draining.send_replace(false);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Completes once draining started
+    pub async fn started(&self) {
+        // The sender lives as long as self, so waiting cannot fail
+        let _ = self.draining.subscribe().wait_for(|draining| *draining).await;
+    }
+}
@@ -0,0 +1,61 @@
+use crate::listener;
+use std::os::fd::RawFd;
+
+/// DSCP class to mark the traffic of a listener or backend with, or of all of them without an address
+#[derive(Debug, Clone)]
+pub struct DscpRule {
+    pub address: Option<String>,
+    pub class: u8,
+}
+
+/// Parse a `--dscp` or `--backend-dscp` argument of the form `[ADDRESS=]CLASS`
+pub fn parse_rule(s: &str) -> Result<DscpRule, String> {
+    let (address, class) = match s.split_once('=') {
+        Some((address, class)) if !address.is_empty() => (Some(address.to_string()), class),
+        Some(_) => return Err("expected [ADDRESS=]CLASS".to_string()),
+        None => (None, s),
+    };
+    Ok(DscpRule {
+        address,
+        class: parse_class(class)?,
+    })
+}
+
+/// Parse a DSCP class given as a number (0-63) or by name (EF, AF11-AF43, CS0-CS7, LE, VA)
+fn parse_class(s: &str) -> Result<u8, String> {
+    let invalid = || format!("invalid DSCP class '{}' (expected 0-63, EF, AF11-AF43, CS0-CS7, LE or VA)", s);
+    if let Ok(value) = s.parse::<u8>() {
+        return (value < 64).then_some(value).ok_or_else(invalid);
+    }
+    let name = s.to_ascii_uppercase();
+    let digits: Vec<u8> = name.bytes().skip(2).map(|b| b.wrapping_sub(b'0')).collect();
+    match (name.get(..2).unwrap_or_default(), digits.as_slice()) {
+        ("EF", []) => Ok(46),
+        ("LE", []) => Ok(1),
+        ("VA", []) => Ok(44),
+        ("CS", [class]) if *class <= 7 => Ok(class * 8),
+        ("AF", [class, drop]) if (1..=4).contains(class) && (1..=3).contains(drop) => Ok(class * 8 + drop * 2),
+        _ => Err(invalid()),
+    }
+}
+
+/// The class for an address: from a rule for that address, or else from a rule without address
+pub fn select(rules: &[DscpRule], address: &str) -> Option<u8> {
+    rules
+        .iter()
+        .find(|rule| rule.address.as_deref() == Some(address))
+        .or_else(|| rules.iter().find(|rule| rule.address.is_none()))
+        .map(|rule| rule.class)
+}
+
+/// Mark the traffic sent over a socket with the class
+pub fn mark(fd: RawFd, ipv6: bool, class: u8) -> std::io::Result<()> {
+    let tos = libc::c_int::from(class) << 2;
+    if !ipv6 {
+        return listener::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos);
+    }
+    listener::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+    // IPv4 peers of a dual-stack socket are reached with the IPv4 setting (not supported everywhere)
+    let _ = listener::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos);
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use crate::compress;
+use crate::http::{Body, Request, Response};
+
+/// Smallest body worth compressing, smaller ones barely shrink
+const MIN_SIZE: u64 = 1024;
+
+/// Largest body compressed, as bodies are compressed in memory
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Content types that are compressed already, compressing them again only costs CPU
+const COMPRESSED_TYPES: [&str; 16] = [
+    "image/*",
+    "video/*",
+    "audio/*",
+    "font/woff",
+    "font/woff2",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/zstd",
+    "application/pdf",
+    "application/octet-stream",
+    "application/vnd.rar",
+];
+
+/// Content types that compress well despite their kind
+const TEXT_TYPES: [&str; 1] = ["image/svg+xml"];
+
+/// gzip compression of responses for clients accepting it
+#[derive(Debug)]
+pub struct Compression {
+    /// Content types never compressed in addition to the compressed ones, `type/*` for all subtypes
+    skip_types: Vec<String>,
+}
+
+impl Compression {
+    pub fn new(skip_types: &[String]) -> Self {
+        Self {
+            skip_types: skip_types.iter().map(|t| t.trim().to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// Whether to compress the response: a complete `200` response of a compressible content type
+    /// between 1 KiB and 1 MiB, not encoded by the backend already, for a client accepting gzip
+    pub fn applies(&self, request: &Request, response: &Response, body: Body) -> bool {
+        let Body::Length(length) = body else { return false };
+        request.method != "HEAD"
+            && response.status == 200
+            && (MIN_SIZE..=MAX_SIZE).contains(&length)
+            && accepts_gzip(request)
+            && response.headers.get("Content-Encoding").is_none_or(|encoding| encoding.trim().eq_ignore_ascii_case("identity"))
+            && !response.headers.has_token("Cache-Control", "no-transform")
+            && response.headers.get("Content-Type").is_some_and(|content_type| !self.skips(content_type))
+    }
+
+    /// Whether a content type is not compressed
+    fn skips(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        let matches = |pattern: &str| match pattern.strip_suffix("/*") {
+            Some(kind) => media_type.split('/').next() == Some(kind),
+            None => pattern == media_type,
+        };
+        self.skip_types.iter().any(|t| matches(t)) || (COMPRESSED_TYPES.iter().any(|t| matches(t)) && !TEXT_TYPES.contains(&media_type.as_str()))
+    }
+}
+
+/// Compress the body with gzip and adjust the response to it, unless that does not make it smaller
+pub fn compress(response: &mut Response, body: &[u8]) -> Option<Vec<u8>> {
+    let compressed = compress::gzip(body);
+    if compressed.len() >= body.len() {
+        return None;
+    }
+    response.headers.set("Content-Encoding", "gzip");
+    response.headers.set("Content-Length", &compressed.len().to_string());
+    if !response.headers.has_token("Vary", "Accept-Encoding") && !response.headers.has_token("Vary", "*") {
+        let vary = response.headers.get("Vary").map_or("Accept-Encoding".to_string(), |vary| format!("{}, Accept-Encoding", vary));
+        response.headers.set("Vary", &vary);
+    }
+    // The compressed body is another representation, which a strong validator would claim is identical
+    if let Some(etag) = response.headers.get("ETag").filter(|etag| etag.starts_with('"')).map(str::to_string) {
+        response.headers.set("ETag", &format!("W/{}", etag));
+    }
+    Some(compressed)
+}
+
+/// Whether the client accepts gzip (or any encoding) without ruling it out with `q=0`
+fn accepts_gzip(request: &Request) -> bool {
+    let mut accepted = None;
+    for token in request.headers.tokens("Accept-Encoding") {
+        let mut parts = token.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "gzip" | "x-gzip" => return quality > 0.0,
+            "*" => accepted = Some(quality > 0.0),
+            _ => {}
+        }
+    }
+    accepted.unwrap_or(false)
+}
@@ -0,0 +1,134 @@
+use crate::error;
+use crate::webhook::Webhook;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::process::Command;
+
+/// Events that can trigger hook commands and webhooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The served certificate expires within the warning period
+    CertExpiring,
+    /// The certificate was reloaded (on SIGHUP)
+    CertReloaded,
+    /// A backend was taken out of the rotation by outlier detection
+    BackendEjected,
+    /// A backend failed its active health checks
+    BackendUnhealthy,
+    /// A backend passed its active health checks again
+    BackendHealthy,
+    /// A connection or error rate crossed an alert threshold
+    ThresholdExceeded,
+}
+
+impl Event {
+    pub const ALL: [Event; 6] = [
+        Event::CertExpiring,
+        Event::CertReloaded,
+        Event::BackendEjected,
+        Event::BackendUnhealthy,
+        Event::BackendHealthy,
+        Event::ThresholdExceeded,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::CertExpiring => "cert_expiring",
+            Event::CertReloaded => "cert_reloaded",
+            Event::BackendEjected => "backend_ejected",
+            Event::BackendUnhealthy => "backend_unhealthy",
+            Event::BackendHealthy => "backend_healthy",
+            Event::ThresholdExceeded => "threshold_exceeded",
+        }
+    }
+}
+
+/// Shell command run when an event fires (`event=command`, `*` matches every event)
+#[derive(Debug, Clone)]
+pub struct EventCommand {
+    pub event: Option<Event>,
+    pub command: String,
+}
+
+/// Parse an event name
+pub fn parse_event(name: &str) -> Result<Event, String> {
+    Event::ALL.into_iter().find(|e| e.name() == name).ok_or_else(|| {
+        let names: Vec<_> = Event::ALL.iter().map(Event::name).collect();
+        format!("unknown event '{}' (expected one of: {})", name, names.join(", "))
+    })
+}
+
+/// Parse an `--on-event` argument of the form `event=command`
+pub fn parse_event_command(s: &str) -> Result<EventCommand, String> {
+    let (name, command) = s.split_once('=').ok_or("expected EVENT=COMMAND")?;
+    if command.trim().is_empty() {
+        return Err("command must not be empty".to_string());
+    }
+    let event = match name {
+        "*" => None,
+        name => Some(parse_event(name)?),
+    };
+    Ok(EventCommand {
+        event,
+        command: command.to_string(),
+    })
+}
+
+/// Hook commands and webhook for operator notifications
+#[derive(Default)]
+pub struct EventHooks {
+    commands: Vec<EventCommand>,
+    webhook: Option<Arc<Webhook>>,
+}
+
+impl EventHooks {
+    pub fn new(commands: Vec<EventCommand>, webhook: Option<Webhook>) -> Self {
+        Self {
+            commands,
+            webhook: webhook.map(Arc::new),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.webhook.is_none()
+    }
+
+    /// Run the commands registered for an event and post it to the webhook, in the background
+    ///
+    /// Event details are passed to commands as environment variables: `HTTPS_WRAPPER_EVENT` holds
+    /// the event name, and each detail is passed as `HTTPS_WRAPPER_<NAME>`.
+    pub fn fire(&self, event: Event, details: &[(&str, String)]) {
+        if let Some(webhook) = self.webhook.as_ref().filter(|webhook| webhook.enabled(event)) {
+            let details = details.iter().map(|(name, value)| (name.to_string(), value.clone())).collect();
+            tokio::spawn(webhook.clone().send(event, details));
+        }
+
+        for hook in self.commands.iter().filter(|hook| hook.event.is_none_or(|e| e == event)) {
+            let mut command = Command::new("/bin/sh");
+            command
+                .arg("-c")
+                .arg(&hook.command)
+                .env("HTTPS_WRAPPER_EVENT", event.name())
+                .stdin(Stdio::null());
+            for (name, value) in details {
+                command.env(format!("HTTPS_WRAPPER_{}", name.to_uppercase()), value);
+            }
+
+            let command_line = hook.command.clone();
+            match command.spawn() {
+                Ok(mut child) => {
+                    tokio::spawn(async move {
+                        match child.wait().await {
+                            Ok(status) if !status.success() => {
+                                error!("Event hook '{}' for {} exited with {}", command_line, event.name(), status)
+                            }
+                            Err(e) => error!("Event hook '{}' for {} failed: {}", command_line, event.name(), e),
+                            Ok(_) => {}
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to run event hook '{}' for {}: {}", command_line, event.name(), e),
+            }
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use crate::warn;
+use crate::certificate;
+use crate::events::{Event, EventHooks};
+use crate::resolver::CertResolver;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval between certificate expiry checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Periodically warn (and fire the `cert_expiring` event) while a served certificate expires within `warning_days`
+pub async fn watch(resolver: Arc<CertResolver>, warning_days: u32, events: Arc<EventHooks>) {
+    loop {
+        for (pattern, certified_key) in resolver.certificates() {
+            let Some((not_after, days)) = certified_key.cert.first().and_then(certificate::expiry) else { continue };
+            if days < warning_days as i32 {
+                let served_for = pattern.as_ref().map(|pattern| format!(" for {}", pattern)).unwrap_or_default();
+                warn!("Certificate{} expires in {} day(s) ({})", served_for, days, not_after);
+                let mut details = vec![("cert_not_after", not_after), ("cert_days_left", days.to_string())];
+                details.extend(pattern.map(|pattern| ("cert_sni", pattern)));
+                events.fire(Event::CertExpiring, &details);
+            }
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
@@ -0,0 +1,121 @@
+use crate::warn;
+use crate::metrics;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Directory listing the open file descriptors of the process
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FD_DIR: &std::ffi::CStr = c"/proc/self/fd";
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const FD_DIR: &std::ffi::CStr = c"/dev/fd";
+
+/// Interval between file descriptor usage checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Share of the limit in use above which a warning is logged
+const WARNING_PERCENT: u64 = 80;
+
+/// Raise the soft `RLIMIT_NOFILE` limit to the hard limit, returns the previous and new soft limit
+pub fn raise_limit() -> std::io::Result<(u64, u64)> {
+    let mut limit = get_limit()?;
+    let previous = limit.rlim_cur;
+    // macOS rejects soft limits above OPEN_MAX, whatever the hard limit says
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let target = limit.rlim_max.min(libc::OPEN_MAX as libc::rlim_t);
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let target = limit.rlim_max;
+    if target > previous {
+        limit.rlim_cur = target;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok((previous as u64, limit.rlim_cur))
+}
+
+fn get_limit() -> std::io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+/// Tracks how many file descriptors the process has open
+///
+/// The descriptor directory is opened up front, so it can still be listed once the sandbox
+/// denies opening files.
+#[derive(Debug)]
+pub struct FdUsage {
+    dir: Mutex<Option<OwnedFd>>,
+}
+
+impl FdUsage {
+    pub fn open() -> Self {
+        let fd = unsafe { libc::open(FD_DIR.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        Self {
+            dir: Mutex::new((fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd) })),
+        }
+    }
+
+    /// Number of open file descriptors, `None` when the platform does not list them
+    pub fn open_count(&self) -> Option<u64> {
+        let dir = self.dir.lock().unwrap();
+        let fd = unsafe { libc::dup(dir.as_ref()?.as_raw_fd()) };
+        if fd < 0 {
+            return None;
+        }
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            unsafe { libc::close(fd) };
+            return None;
+        }
+        // The duplicate shares its position with the original, start over from the first entry
+        unsafe { libc::rewinddir(stream) };
+        let mut entries: u64 = 0;
+        while !unsafe { libc::readdir(stream) }.is_null() {
+            entries += 1;
+        }
+        unsafe { libc::closedir(stream) };
+        // Not counting ".", ".." and the two descriptors used for listing
+        Some(entries.saturating_sub(4))
+    }
+
+    /// Current soft limit on open file descriptors
+    pub fn limit(&self) -> Option<u64> {
+        get_limit().ok().map(|limit| limit.rlim_cur)
+    }
+
+    /// Render the file descriptor usage as metric lines
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if let Some(open) = self.open_count() {
+            metrics::family(&mut out, "open_fds", "gauge", "Open file descriptors");
+            out.push_str(&format!("open_fds {}\n", open));
+        }
+        if let Some(limit) = self.limit() {
+            metrics::family(&mut out, "max_fds", "gauge", "Soft limit on open file descriptors");
+            out.push_str(&format!("max_fds {}\n", limit));
+        }
+        out
+    }
+
+    /// Periodically check the usage, warning once each time it climbs above the warning threshold
+    pub async fn watch(&self) {
+        let mut warned = false;
+        loop {
+            if let (Some(open), Some(limit)) = (self.open_count(), self.limit()) {
+                let high = open * 100 >= limit.saturating_mul(WARNING_PERCENT);
+                if high && !warned {
+                    warn!(
+                        "{} of {} file descriptors in use, new connections fail once the limit is reached",
+                        open, limit
+                    );
+                }
+                warned = high;
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::http::Headers;
+use crate::routing::IpNet;
+use clap::ValueEnum;
+use std::net::{IpAddr, SocketAddr};
+
+/// Headers telling the backend about the client, set by the proxy
+const HEADERS: [&str; 5] = ["Forwarded", "X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host", "X-Real-IP"];
+
+/// Which headers tell the backend about the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ForwardedStyle {
+    /// X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and X-Real-IP
+    XForwarded,
+    /// The standardized `Forwarded` header (RFC 7239)
+    Forwarded,
+    /// Both of them
+    Both,
+}
+
+/// A proxy the request passed before reaching this proxy, as told by the headers it set
+struct Hop {
+    /// Address the proxy received the request from, as written in the header
+    node: String,
+    /// The proxy's `Forwarded` element, passed on as it was sent
+    element: String,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Tell the backend the client's address, that the request arrived over HTTPS and the host it was
+/// sent to. Values sent by the client are replaced, as they could be forged, unless the client is one
+/// of the trusted proxies: then its chain of proxies (from `Forwarded` or `X-Forwarded-For`) is
+/// extended, and the real client is the last address in the chain that is not a trusted proxy.
+pub fn apply(headers: &mut Headers, client: IpAddr, local: SocketAddr, trusted: &[IpNet], style: ForwardedStyle) {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let client = client.to_canonical();
+    let hops = if is_trusted(client) { incoming_hops(headers) } else { Vec::new() };
+    let (mut real_client, mut real_hop) = (client, None);
+    for (index, hop) in hops.iter().enumerate().rev() {
+        let Some(ip) = parse_node(&hop.node) else { break };
+        (real_client, real_hop) = (ip, Some(index));
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    // The scheme and host the real client used, as told by the proxy it connected to
+    let (proto, host) = match real_hop {
+        Some(index) => (hops[index].proto.clone(), hops[index].host.clone()),
+        None => (None, None),
+    };
+    let requested_host = headers.get("Host").map(str::to_string);
+
+    for name in HEADERS {
+        headers.remove(name);
+    }
+    if style != ForwardedStyle::Forwarded {
+        // Plain addresses, as X-Forwarded-For has no ports or brackets
+        let chain: Vec<String> = hops
+            .iter()
+            .map(|hop| parse_node(&hop.node).map_or_else(|| hop.node.clone(), |ip| ip.to_string()))
+            .chain(std::iter::once(client.to_string()))
+            .collect();
+        headers.set("X-Forwarded-For", &chain.join(", "));
+        headers.set("X-Forwarded-Proto", proto.as_deref().unwrap_or("https"));
+        if let Some(host) = host.as_ref().or(requested_host.as_ref()) {
+            headers.set("X-Forwarded-Host", host);
+        }
+        headers.set("X-Real-IP", &real_client.to_string());
+    }
+    if style != ForwardedStyle::XForwarded {
+        let mut element = format!("for={};proto=https", quote(&node(client, None)));
+        if let Some(host) = &requested_host {
+            element.push_str(&format!(";host={}", quote(host)));
+        }
+        element.push_str(&format!(";by={}", quote(&node(local.ip().to_canonical(), Some(local.port())))));
+        let elements: Vec<&str> = hops.iter().map(|hop| hop.element.as_str()).chain(std::iter::once(element.as_str())).collect();
+        headers.set("Forwarded", &elements.join(", "));
+    }
+}
+
+/// The proxies the request passed, from its `Forwarded` headers or else its `X-Forwarded-*` headers
+fn incoming_hops(headers: &Headers) -> Vec<Hop> {
+    let forwarded: Vec<&str> = headers.0.iter().filter(|(name, _)| name.eq_ignore_ascii_case("Forwarded")).map(|(_, value)| value.as_str()).collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .flat_map(|value| split_unquoted(value, ','))
+            .filter(|element| !element.is_empty())
+            .map(|element| {
+                let mut hop = Hop {
+                    node: "unknown".to_string(),
+                    element: element.to_string(),
+                    proto: None,
+                    host: None,
+                };
+                for pair in split_unquoted(element, ';') {
+                    let Some((key, value)) = pair.split_once('=') else { continue };
+                    let value = unquote(value.trim());
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => hop.node = value,
+                        "proto" => hop.proto = Some(value),
+                        "host" => hop.host = Some(value),
+                        _ => {}
+                    }
+                }
+                hop
+            })
+            .collect();
+    }
+    let proto = headers.get("X-Forwarded-Proto").map(str::to_string);
+    let host = headers.get("X-Forwarded-Host").map(str::to_string);
+    headers
+        .tokens("X-Forwarded-For")
+        .map(|node| Hop {
+            node: node.to_string(),
+            element: format!("for={}", quote(node)),
+            proto: proto.clone(),
+            host: host.clone(),
+        })
+        .collect()
+}
+
+/// Address of a node, which may come with a port, in brackets (IPv6), or be `unknown` or an obfuscated name
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let ip = node
+        .parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>())
+        .ok()?;
+    Some(ip.to_canonical())
+}
+
+/// A node of the `Forwarded` header: IPv6 addresses go in brackets
+fn node(ip: IpAddr, port: Option<u16>) -> String {
+    match (ip, port) {
+        (IpAddr::V4(ip), None) => ip.to_string(),
+        (IpAddr::V6(ip), None) => format!("[{}]", ip),
+        (ip, Some(port)) => SocketAddr::new(ip, port).to_string(),
+    }
+}
+
+/// A value of the `Forwarded` header, quoted unless it only consists of token characters
+fn quote(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+/// Split at a separator outside of quoted strings, trimming the parts
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let (mut parts, mut start, mut quoted, mut escaped) = (Vec::new(), 0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(value[start..].trim());
+    parts
+}
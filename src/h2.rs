@@ -0,0 +1,1305 @@
+use crate::hpack::{self, Decoder};
+use crate::http::{self, Body, Response, MAX_HEAD_SIZE};
+use crate::http_forward;
+use crate::log;
+use crate::memory::Buffers;
+use crate::proxy::Proxy;
+use crate::registry::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadHalf};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::Instant;
+
+/// Sent by the client before its first frame
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Streams a client may have open at the same time
+const MAX_STREAMS: usize = 100;
+
+/// Largest frame payload accepted from clients (the HTTP/2 default)
+const MAX_FRAME_SIZE: usize = 16_384;
+
+/// Flow control window of new streams until changed by settings (the HTTP/2 default)
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// Connection-level receive window, larger than the default so uploads on several streams do not stall each other
+const CONNECTION_WINDOW: i64 = 1 << 20;
+
+/// Largest flow control window allowed
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Upper bound for a header block including its CONTINUATION frames, and for its decoded fields
+const MAX_HEADER_BLOCK: usize = 4 * MAX_HEAD_SIZE;
+
+/// Header fields that only apply to a single HTTP/1.1 connection and are not allowed in HTTP/2
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Time a client has to acknowledge a keepalive PING
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Open streams a client may reset per `STREAM_WINDOW`, beyond that it is taken for a rapid reset
+/// attack (CVE-2023-44487), as every stream starts a backend request
+const MAX_CLIENT_RESETS: u32 = 100;
+
+/// Streams that may be refused or rejected per `STREAM_WINDOW`
+const MAX_REFUSED_STREAMS: u32 = 100;
+
+/// Window over which reset and refused streams are counted
+const STREAM_WINDOW: Duration = Duration::from_secs(10);
+
+/// Events counted over a fixed window, to tell abuse from the occasional cancelled request
+struct WindowCounter {
+    started: Instant,
+    count: u32,
+}
+
+impl WindowCounter {
+    fn new() -> Self {
+        Self { started: Instant::now(), count: 0 }
+    }
+
+    /// Count an event, returns whether the count is still within the limit
+    fn add(&mut self, limit: u32) -> bool {
+        if self.started.elapsed() >= STREAM_WINDOW {
+            *self = Self::new();
+        }
+        self.count += 1;
+        self.count <= limit
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// Error ending the whole connection, reported to the client with GOAWAY
+struct ConnectionError {
+    code: u32,
+    message: String,
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        connection_error(INTERNAL_ERROR, e.to_string())
+    }
+}
+
+fn connection_error(code: u32, message: impl Into<String>) -> ConnectionError {
+    ConnectionError { code, message: message.into() }
+}
+
+/// Frames the stream tasks ask the connection to send
+enum Out {
+    Headers { stream: u32, block: Vec<u8>, end: bool },
+    Data { stream: u32, data: Vec<u8>, end: bool },
+    Reset { stream: u32, code: u32 },
+    /// Request body data passed on to the backend, credited to the client's flow control windows
+    Consumed { stream: u32, amount: usize },
+}
+
+/// Send flow control windows, shared between the connection and its stream tasks
+#[derive(Default)]
+struct SendWindows {
+    state: Mutex<WindowState>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct WindowState {
+    connection: i64,
+    streams: HashMap<u32, i64>,
+    /// Window of new streams, from the client's settings
+    initial: i64,
+    /// Largest frame the client accepts
+    max_frame: usize,
+}
+
+impl SendWindows {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(WindowState {
+                connection: DEFAULT_WINDOW,
+                streams: HashMap::new(),
+                initial: DEFAULT_WINDOW,
+                max_frame: MAX_FRAME_SIZE,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Wait until data may be sent on the stream, returns how many of `wanted` bytes may be sent
+    /// in one frame, or 0 once the stream is closed
+    async fn reserve(&self, stream: u32, wanted: usize) -> usize {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                let state = &mut *state;
+                let Some(window) = state.streams.get_mut(&stream) else { return 0 };
+                let available = (*window).min(state.connection).min(state.max_frame as i64).min(wanted as i64);
+                if available > 0 {
+                    *window -= available;
+                    state.connection -= available;
+                    return available as usize;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn max_frame(&self) -> usize {
+        self.state.lock().unwrap().max_frame
+    }
+
+    fn open(&self, stream: u32) {
+        let mut state = self.state.lock().unwrap();
+        let initial = state.initial;
+        state.streams.insert(stream, initial);
+    }
+
+    fn close(&self, stream: u32) {
+        self.state.lock().unwrap().streams.remove(&stream);
+        self.changed.notify_waiters();
+    }
+
+    /// Grow the window of the connection (stream 0) or of a stream, returns false when it overflows
+    fn grow(&self, stream: u32, increment: i64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let window = match stream {
+            0 => &mut state.connection,
+            stream => match state.streams.get_mut(&stream) {
+                Some(window) => window,
+                // Closed in the meantime
+                None => return true,
+            },
+        };
+        *window += increment;
+        let valid = *window <= MAX_WINDOW;
+        drop(state);
+        self.changed.notify_waiters();
+        valid
+    }
+
+    /// Apply a new initial window size to all streams, returns false when a window overflows
+    fn set_initial(&self, initial: i64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let delta = initial - state.initial;
+        state.initial = initial;
+        let mut valid = true;
+        for window in state.streams.values_mut() {
+            *window += delta;
+            valid &= *window <= MAX_WINDOW;
+        }
+        drop(state);
+        self.changed.notify_waiters();
+        valid
+    }
+
+    fn set_max_frame(&self, max_frame: usize) {
+        self.state.lock().unwrap().max_frame = max_frame;
+    }
+}
+
+/// Receiving side of an open stream
+struct Stream {
+    /// Request body data for the stream task, `None` once the client ended the stream
+    body: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Receive window left to the client
+    window: i64,
+    task: AbortHandle,
+}
+
+/// HTTP/1.1 request translated from the header fields of a stream
+struct Request {
+    head: Vec<u8>,
+    method: String,
+    /// Declared body length, checked against the data received
+    length: Option<u64>,
+    /// Whether the body is sent to the backend in chunks, as its length is not known in advance
+    chunked: bool,
+}
+
+/// Terminate HTTP/2 on a client connection, forwarding each stream as an HTTP/1.1 request through
+/// the same handling as HTTP mode, returns whether the connection ended without errors
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Arc<Proxy>,
+    connection: &Arc<Connection>,
+    client: &mut S,
+    buffers: Buffers,
+    prefix: &str,
+) -> bool {
+    let (reader, writer) = tokio::io::split(client);
+    let (sender, outgoing) = mpsc::unbounded_channel();
+    let mut session = Session {
+        proxy,
+        connection,
+        buffers,
+        prefix,
+        writer,
+        out: Vec::new(),
+        decoder: Decoder::default(),
+        streams: HashMap::new(),
+        tasks: JoinSet::new(),
+        windows: Arc::new(SendWindows::new()),
+        sender,
+        window: CONNECTION_WINDOW,
+        last_stream: 0,
+        continuation: None,
+        going_away: false,
+        client_resets: WindowCounter::new(),
+        refused: WindowCounter::new(),
+    };
+    match session.run(reader, outgoing).await {
+        Ok(()) => true,
+        Err(e) => {
+            proxy.error_log.log(format!("{}HTTP/2 connection error: {}", prefix, e.message));
+            let mut payload = session.last_stream.to_be_bytes().to_vec();
+            payload.extend_from_slice(&e.code.to_be_bytes());
+            payload.extend_from_slice(e.message.as_bytes());
+            session.frame(GOAWAY, 0, 0, &payload);
+            // The client may already be gone
+            let _ = session.flush().await;
+            false
+        }
+    }
+}
+
+/// State of an HTTP/2 connection
+struct Session<'a, W> {
+    proxy: &'a Arc<Proxy>,
+    connection: &'a Arc<Connection>,
+    buffers: Buffers,
+    prefix: &'a str,
+    writer: W,
+    /// Frames waiting to be written
+    out: Vec<u8>,
+    decoder: Decoder,
+    streams: HashMap<u32, Stream>,
+    tasks: JoinSet<u32>,
+    windows: Arc<SendWindows>,
+    sender: mpsc::UnboundedSender<Out>,
+    /// Connection-level receive window left to the client
+    window: i64,
+    /// Highest stream the client opened
+    last_stream: u32,
+    /// Header block continued in CONTINUATION frames: stream, END_STREAM flag and the block so far
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    /// Set when the client sent GOAWAY, the connection ends after the open streams
+    going_away: bool,
+    /// Open streams the client reset
+    client_resets: WindowCounter,
+    /// Streams refused or reset for a malformed request
+    refused: WindowCounter,
+}
+
+impl<W: AsyncWrite + Unpin> Session<'_, W> {
+    async fn run<R: AsyncRead + Unpin>(&mut self, reader: R, mut outgoing: mpsc::UnboundedReceiver<Out>) -> Result<(), ConnectionError> {
+        let mut reader = BufReader::with_capacity(self.buffers.client_to_backend, reader);
+        let mut preface = [0; PREFACE.len()];
+        reader.read_exact(&mut preface).await?;
+        if preface != PREFACE {
+            return Err(connection_error(PROTOCOL_ERROR, "invalid connection preface"));
+        }
+
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEAD_SIZE as u32),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        self.frame(SETTINGS, 0, 0, &settings);
+        self.frame(WINDOW_UPDATE, 0, 0, &((CONNECTION_WINDOW - DEFAULT_WINDOW) as u32).to_be_bytes());
+        self.flush().await?;
+
+        // Frames are read by their own future, as reading one is not cancel safe
+        let (frames_sender, mut frames) = mpsc::channel(1);
+        let read = async move {
+            loop {
+                let frame = read_frame(&mut reader).await;
+                let last = !matches!(frame, Ok(Some(_)));
+                if frames_sender.send(frame).await.is_err() || last {
+                    break;
+                }
+            }
+            std::future::pending::<()>().await;
+        };
+        let proxy = self.proxy;
+        let keepalive = proxy.keepalive;
+        let mut deadline = Instant::now() + keepalive.unwrap_or_default();
+        let mut pinged = false;
+        let drive = async {
+            loop {
+                tokio::select! {
+                    biased;
+                    // While draining, the client is asked to open further streams on another connection
+                    _ = proxy.drain.started(), if !self.going_away && self.last_stream > 0 => self.go_away(),
+                    Some(out) = outgoing.recv() => self.send(out),
+                    frame = frames.recv() => {
+                        // Any frame, including the acknowledgement of a PING, shows the client is still there
+                        if let Some(keepalive) = keepalive {
+                            deadline = Instant::now() + keepalive;
+                            pinged = false;
+                        }
+                        match frame {
+                            Some(Ok(Some(frame))) => self.handle(frame)?,
+                            Some(Err(e)) => return Err(e),
+                            // The client closed the connection
+                            Some(Ok(None)) | None => return Ok(()),
+                        }
+                    }
+                    Some(result) = self.tasks.join_next_with_id(), if !self.tasks.is_empty() => self.finished(result),
+                    // A client that went quiet is sent a PING, a dead one never acknowledges it
+                    _ = tokio::time::sleep_until(deadline), if keepalive.is_some() => {
+                        if pinged {
+                            return Err(connection_error(NO_ERROR, "PING not acknowledged"));
+                        }
+                        self.frame(PING, 0, 0, &[0; 8]);
+                        deadline = Instant::now() + PING_TIMEOUT;
+                        pinged = true;
+                    }
+                }
+                while let Ok(out) = outgoing.try_recv() {
+                    self.send(out);
+                }
+                self.flush().await?;
+                if self.going_away && self.streams.is_empty() {
+                    return Ok(());
+                }
+            }
+        };
+        tokio::select! {
+            result = drive => result,
+            _ = read => unreachable!("the frame reader never completes"),
+        }
+    }
+
+    /// Tell the client no new streams are accepted, the connection ends after the open streams
+    fn go_away(&mut self) {
+        let mut payload = self.last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&NO_ERROR.to_be_bytes());
+        self.frame(GOAWAY, 0, 0, &payload);
+        self.going_away = true;
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if self.continuation.as_ref().is_some_and(|(stream, ..)| frame.kind != CONTINUATION || frame.stream != *stream) {
+            return Err(connection_error(PROTOCOL_ERROR, "header block interrupted by another frame"));
+        }
+        match frame.kind {
+            DATA => self.data(frame),
+            HEADERS => self.headers(frame),
+            CONTINUATION => self.continuation(frame),
+            PRIORITY => {
+                if frame.stream == 0 {
+                    return Err(connection_error(PROTOCOL_ERROR, "PRIORITY on stream 0"));
+                }
+                if frame.payload.len() != 5 {
+                    self.reset(frame.stream, FRAME_SIZE_ERROR);
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if frame.stream == 0 || frame.stream > self.last_stream {
+                    return Err(connection_error(PROTOCOL_ERROR, "RST_STREAM on stream 0 or an idle stream"));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(connection_error(FRAME_SIZE_ERROR, "invalid RST_STREAM frame"));
+                }
+                if self.streams.contains_key(&frame.stream) && !self.client_resets.add(MAX_CLIENT_RESETS) {
+                    return Err(connection_error(ENHANCE_YOUR_CALM, "too many streams reset by the client"));
+                }
+                self.close(frame.stream);
+                Ok(())
+            }
+            SETTINGS => self.settings(frame),
+            PUSH_PROMISE => Err(connection_error(PROTOCOL_ERROR, "PUSH_PROMISE from a client")),
+            PING => {
+                if frame.stream != 0 {
+                    return Err(connection_error(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(connection_error(FRAME_SIZE_ERROR, "invalid PING frame"));
+                }
+                if frame.flags & ACK == 0 {
+                    self.frame(PING, ACK, 0, &frame.payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if frame.stream != 0 {
+                    return Err(connection_error(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.window_update(frame),
+            // Unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.stream == 0 {
+            return Err(connection_error(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        if frame.stream > self.last_stream {
+            return Err(connection_error(PROTOCOL_ERROR, "DATA on an idle stream"));
+        }
+        let length = frame.payload.len();
+        self.window -= length as i64;
+        if self.window < 0 {
+            return Err(connection_error(FLOW_CONTROL_ERROR, "connection flow control window exceeded"));
+        }
+        let data = unpad(&frame)?;
+        let padding = length - data.len();
+
+        let Some(stream) = self.streams.get_mut(&frame.stream) else {
+            // Closed (e.g. reset) in the meantime
+            self.credit(0, length);
+            self.frame(RST_STREAM, 0, frame.stream, &STREAM_CLOSED.to_be_bytes());
+            return Ok(());
+        };
+        stream.window -= length as i64;
+        let code = match &stream.body {
+            None => Some(STREAM_CLOSED),
+            Some(_) if stream.window < 0 => Some(FLOW_CONTROL_ERROR),
+            Some(body) if !data.is_empty() && body.send(data.to_vec()).is_err() => Some(STREAM_CLOSED),
+            Some(_) => None,
+        };
+        if let Some(code) = code {
+            self.credit(0, length);
+            self.reset(frame.stream, code);
+            return Ok(());
+        }
+        if frame.flags & END_STREAM != 0 {
+            stream.body = None;
+        }
+        // The stream task credits the data once passed on, padding is credited right away
+        if padding > 0 {
+            self.credit(0, padding);
+            self.credit(frame.stream, padding);
+        }
+        Ok(())
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.stream == 0 {
+            return Err(connection_error(PROTOCOL_ERROR, "HEADERS on stream 0"));
+        }
+        let mut fragment = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            fragment = fragment
+                .get(5..)
+                .ok_or_else(|| connection_error(PROTOCOL_ERROR, "invalid HEADERS frame"))?;
+        }
+        let end_stream = frame.flags & END_STREAM != 0;
+        let block = fragment.to_vec();
+        if frame.flags & END_HEADERS != 0 {
+            self.header_block(frame.stream, end_stream, block)
+        } else {
+            self.continuation = Some((frame.stream, end_stream, block));
+            Ok(())
+        }
+    }
+
+    fn continuation(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let Some((stream, end_stream, mut block)) = self.continuation.take() else {
+            return Err(connection_error(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+        };
+        block.extend_from_slice(&frame.payload);
+        if block.len() > MAX_HEADER_BLOCK {
+            return Err(connection_error(ENHANCE_YOUR_CALM, "header block too large"));
+        }
+        if frame.flags & END_HEADERS != 0 {
+            self.header_block(stream, end_stream, block)
+        } else {
+            self.continuation = Some((stream, end_stream, block));
+            Ok(())
+        }
+    }
+
+    /// Handle a complete header block: a new request, or trailer fields ending a request body
+    fn header_block(&mut self, id: u32, end_stream: bool, block: Vec<u8>) -> Result<(), ConnectionError> {
+        // Decoded even when the stream is refused, as it updates the shared dynamic table
+        let fields = self
+            .decoder
+            .decode(&block, MAX_HEADER_BLOCK)
+            .map_err(|e| connection_error(COMPRESSION_ERROR, e.to_string()))?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // Trailer fields are not passed on to the backend
+            match (&stream.body, end_stream) {
+                (Some(_), true) => stream.body = None,
+                (Some(_), false) => {
+                    self.reset(id, PROTOCOL_ERROR);
+                    return self.refuse();
+                }
+                (None, _) => {
+                    self.reset(id, STREAM_CLOSED);
+                    return self.refuse();
+                }
+            }
+            return Ok(());
+        }
+        if id.is_multiple_of(2) {
+            return Err(connection_error(PROTOCOL_ERROR, "stream opened with an even identifier"));
+        }
+        // Frames for streams closed before are ignored
+        if id <= self.last_stream {
+            return Ok(());
+        }
+        self.last_stream = id;
+        if self.going_away || self.streams.len() >= MAX_STREAMS {
+            self.frame(RST_STREAM, 0, id, &REFUSED_STREAM.to_be_bytes());
+            return self.refuse();
+        }
+        let request = match translate(fields, end_stream) {
+            Ok(request) => request,
+            Err(e) => {
+                self.proxy.error_log.log(format!("{}Malformed HTTP/2 request: {}", self.prefix, e));
+                self.frame(RST_STREAM, 0, id, &PROTOCOL_ERROR.to_be_bytes());
+                return self.refuse();
+            }
+        };
+
+        let (body, body_receiver) = mpsc::unbounded_channel();
+        self.windows.open(id);
+        let task = self.tasks.spawn(log::inherit(stream(
+            self.proxy.clone(),
+            self.connection.clone(),
+            self.buffers,
+            self.prefix.to_string(),
+            id,
+            request,
+            body_receiver,
+            self.sender.clone(),
+            self.windows.clone(),
+        )));
+        self.streams.insert(
+            id,
+            Stream {
+                body: (!end_stream).then_some(body),
+                window: DEFAULT_WINDOW,
+                task,
+            },
+        );
+        Ok(())
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.stream != 0 {
+            return Err(connection_error(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if frame.flags & ACK != 0 {
+            if !frame.payload.is_empty() {
+                return Err(connection_error(FRAME_SIZE_ERROR, "SETTINGS acknowledgement with payload"));
+            }
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(connection_error(FRAME_SIZE_ERROR, "invalid SETTINGS frame"));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(connection_error(PROTOCOL_ERROR, "invalid ENABLE_PUSH setting")),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let valid = value as i64 <= MAX_WINDOW && self.windows.set_initial(value as i64);
+                    if !valid {
+                        return Err(connection_error(FLOW_CONTROL_ERROR, "invalid INITIAL_WINDOW_SIZE setting"));
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return Err(connection_error(PROTOCOL_ERROR, "invalid MAX_FRAME_SIZE setting"));
+                    }
+                    self.windows.set_max_frame(value as usize);
+                }
+                // The header table size only limits the encoder, which does not use the dynamic table
+                _ => {}
+            }
+        }
+        self.frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let Ok(increment) = <[u8; 4]>::try_from(frame.payload.as_slice()) else {
+            return Err(connection_error(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE frame"));
+        };
+        let increment = (u32::from_be_bytes(increment) & 0x7fff_ffff) as i64;
+        match frame.stream {
+            0 if increment == 0 => Err(connection_error(PROTOCOL_ERROR, "WINDOW_UPDATE without increment")),
+            0 if !self.windows.grow(0, increment) => Err(connection_error(FLOW_CONTROL_ERROR, "connection window overflow")),
+            0 => Ok(()),
+            stream if stream > self.last_stream => Err(connection_error(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream")),
+            stream => {
+                if increment == 0 {
+                    self.reset(stream, PROTOCOL_ERROR);
+                } else if !self.windows.grow(stream, increment) {
+                    self.reset(stream, FLOW_CONTROL_ERROR);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Queue the frames a stream task asked for, dropping those of streams closed in the meantime
+    fn send(&mut self, out: Out) {
+        match out {
+            Out::Headers { stream, block, end } if self.streams.contains_key(&stream) => {
+                let max_frame = self.windows.max_frame();
+                let mut fragments = block.chunks(max_frame).peekable();
+                let mut kind = HEADERS;
+                let mut flags = if end { END_STREAM } else { 0 };
+                while let Some(fragment) = fragments.next() {
+                    if fragments.peek().is_none() {
+                        flags |= END_HEADERS;
+                    }
+                    self.frame(kind, flags, stream, fragment);
+                    (kind, flags) = (CONTINUATION, 0);
+                }
+                if block.is_empty() {
+                    self.frame(HEADERS, flags | END_HEADERS, stream, &[]);
+                }
+            }
+            Out::Data { stream, data, end } if self.streams.contains_key(&stream) => {
+                self.frame(DATA, if end { END_STREAM } else { 0 }, stream, &data);
+            }
+            Out::Reset { stream, code } if self.streams.contains_key(&stream) => self.reset(stream, code),
+            Out::Consumed { stream, amount } => {
+                self.credit(0, amount);
+                if self.streams.get(&stream).is_some_and(|stream| stream.body.is_some()) {
+                    self.credit(stream, amount);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Clean up after a stream task, telling a client still sending its request body to stop
+    fn finished(&mut self, result: Result<(tokio::task::Id, u32), tokio::task::JoinError>) {
+        let id = match result {
+            Ok((_, id)) => id,
+            Err(e) => {
+                self.proxy.error_log.log(format!("{}HTTP/2 stream task failed: {}", self.prefix, e));
+                let task = e.id();
+                let Some(id) = self.streams.iter().find(|(_, stream)| stream.task.id() == task).map(|(id, _)| *id) else {
+                    return;
+                };
+                self.reset(id, INTERNAL_ERROR);
+                return;
+            }
+        };
+        if let Some(stream) = self.streams.remove(&id) {
+            if stream.body.is_some() {
+                self.frame(RST_STREAM, 0, id, &NO_ERROR.to_be_bytes());
+            }
+        }
+        self.windows.close(id);
+    }
+
+    /// Give the client back receive window on the connection (stream 0) or a stream
+    fn credit(&mut self, stream: u32, amount: usize) {
+        if amount == 0 {
+            return;
+        }
+        match stream {
+            0 => self.window += amount as i64,
+            stream => match self.streams.get_mut(&stream) {
+                Some(open) => open.window += amount as i64,
+                None => return,
+            },
+        }
+        self.frame(WINDOW_UPDATE, 0, stream, &(amount as u32).to_be_bytes());
+    }
+
+    /// Count a stream refused or reset for the client's fault, ending a connection that keeps at it
+    fn refuse(&mut self) -> Result<(), ConnectionError> {
+        if !self.refused.add(MAX_REFUSED_STREAMS) {
+            return Err(connection_error(ENHANCE_YOUR_CALM, "too many streams refused or rejected"));
+        }
+        Ok(())
+    }
+
+    /// Reset a stream and stop its task
+    fn reset(&mut self, stream: u32, code: u32) {
+        self.frame(RST_STREAM, 0, stream, &code.to_be_bytes());
+        self.close(stream);
+    }
+
+    fn close(&mut self, stream: u32) {
+        if let Some(stream) = self.streams.remove(&stream) {
+            stream.task.abort();
+        }
+        self.windows.close(stream);
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        self.out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.out.extend_from_slice(&[kind, flags]);
+        self.out.extend_from_slice(&stream.to_be_bytes());
+        self.out.extend_from_slice(payload);
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.out).await?;
+        self.out.clear();
+        self.writer.flush().await
+    }
+}
+
+/// Read the next frame, `None` when the client closed the connection
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, ConnectionError> {
+    let mut header = [0; 9];
+    match reader.read(&mut header[..1]).await {
+        Ok(0) => return Ok(None),
+        // Clients often close the connection without TLS close_notify once done
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    };
+    reader.read_exact(&mut header[1..]).await?;
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(connection_error(FRAME_SIZE_ERROR, "frame larger than the maximum frame size"));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+/// Payload of a DATA or HEADERS frame without its padding
+fn unpad(frame: &Frame) -> Result<&[u8], ConnectionError> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let invalid = || connection_error(PROTOCOL_ERROR, "invalid padding");
+    let (&padding, rest) = frame.payload.split_first().ok_or_else(invalid)?;
+    let length = rest.len().checked_sub(padding as usize).ok_or_else(invalid)?;
+    Ok(&rest[..length])
+}
+
+/// Translate the header fields of a request into an HTTP/1.1 request head
+fn translate(fields: Vec<hpack::Field>, end_stream: bool) -> Result<Request, String> {
+    let (mut method, mut scheme, mut authority, mut path, mut host) = (None, None, None, None, None);
+    let mut headers = Vec::new();
+    let mut cookies = Vec::new();
+    let mut length = None;
+    for (name, value) in fields {
+        let name = String::from_utf8(name).map_err(|_| "header name is not valid UTF-8".to_string())?;
+        let value = String::from_utf8(value).map_err(|_| format!("value of {} is not valid UTF-8", name))?;
+        // Line breaks would let the client inject header fields or requests into the HTTP/1.1 stream
+        if value.contains(['\0', '\r', '\n']) {
+            return Err(format!("invalid characters in the value of {}", name));
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() || !cookies.is_empty() || host.is_some() {
+                return Err(format!("pseudo-header {} after regular header fields", name));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                "path" => &mut path,
+                _ => return Err(format!("unknown pseudo-header {}", name)),
+            };
+            if slot.replace(value).is_some() {
+                return Err(format!("duplicate pseudo-header {}", name));
+            }
+            continue;
+        }
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+            return Err(format!("invalid header name '{}'", name));
+        }
+        if CONNECTION_HEADERS.contains(&name.as_str()) {
+            return Err(format!("connection-specific header {}", name));
+        }
+        let value = value.trim().to_string();
+        match name.as_str() {
+            "te" if value != "trailers" => return Err("TE other than trailers".to_string()),
+            // Only meaningful between HTTP/2 peers
+            "te" => {}
+            // Split into several fields for better compression, HTTP/1.1 expects a single one
+            "cookie" => cookies.push(value),
+            "host" => host = Some(value),
+            "content-length" => {
+                let parsed = value.parse::<u64>().map_err(|_| format!("invalid content-length '{}'", value))?;
+                if length.replace(parsed).is_some_and(|previous| previous != parsed) {
+                    return Err("conflicting content-length values".to_string());
+                }
+            }
+            _ => headers.push((name, value)),
+        }
+    }
+
+    let method = method.ok_or("missing :method")?;
+    if method == "CONNECT" {
+        return Err("CONNECT is not supported".to_string());
+    }
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+        return Err(format!("invalid method '{}'", method));
+    }
+    scheme.ok_or("missing :scheme")?;
+    let path = path.ok_or("missing :path")?;
+    if !(path.starts_with('/') || (path == "*" && method == "OPTIONS")) || path.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()) {
+        return Err(format!("invalid :path '{}'", path));
+    }
+    let host = authority.or(host).ok_or("missing :authority")?;
+    if host.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()) {
+        return Err(format!("invalid :authority '{}'", host));
+    }
+    if end_stream && length.is_some_and(|length| length > 0) {
+        return Err("content-length without a body".to_string());
+    }
+
+    let mut head = format!("{} {} HTTP/1.1\r\nhost: {}\r\n", method, path, host);
+    for (name, value) in &headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !cookies.is_empty() {
+        head.push_str(&format!("cookie: {}\r\n", cookies.join("; ")));
+    }
+    let chunked = !end_stream && length.is_none();
+    match length {
+        Some(length) => head.push_str(&format!("content-length: {}\r\n", length)),
+        None if chunked => head.push_str("transfer-encoding: chunked\r\n"),
+        None => {}
+    }
+    head.push_str("\r\n");
+    Ok(Request {
+        head: head.into_bytes(),
+        method,
+        length,
+        chunked,
+    })
+}
+
+/// Header fields of a response for HTTP/2: lowercase and without connection-specific fields
+fn response_fields(response: &Response) -> Vec<(String, String)> {
+    let listed: Vec<String> = response.headers.tokens("Connection").map(str::to_ascii_lowercase).collect();
+    response
+        .headers
+        .0
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()) && !listed.contains(name))
+        .collect()
+}
+
+/// Forward a stream as an HTTP/1.1 request through the HTTP mode handling, over an in-memory pipe,
+/// and send the response back; returns the stream identifier when done
+#[allow(clippy::too_many_arguments)]
+async fn stream(
+    proxy: Arc<Proxy>,
+    connection: Arc<Connection>,
+    buffers: Buffers,
+    prefix: String,
+    id: u32,
+    request: Request,
+    body: mpsc::UnboundedReceiver<Vec<u8>>,
+    sender: mpsc::UnboundedSender<Out>,
+    windows: Arc<SendWindows>,
+) -> u32 {
+    let (mut proxy_end, stream_end) = tokio::io::duplex(buffers.client_to_backend);
+    let forwarding = async move {
+        http_forward::forward(&proxy, &connection, &mut proxy_end, buffers, &prefix).await;
+    };
+    let exchange = exchange(stream_end, id, request, body, &sender, &windows, buffers);
+    let (_, result) = tokio::join!(forwarding, exchange);
+    if let Err(code) = result {
+        let _ = sender.send(Out::Reset { stream: id, code });
+    }
+    id
+}
+
+/// Write the request (and its body) into the pipe and send the response read from it to the client,
+/// fails with the code the stream is reset with
+async fn exchange(
+    pipe: DuplexStream,
+    id: u32,
+    request: Request,
+    mut body: mpsc::UnboundedReceiver<Vec<u8>>,
+    sender: &mpsc::UnboundedSender<Out>,
+    windows: &SendWindows,
+    buffers: Buffers,
+) -> Result<(), u32> {
+    let (reader, mut writer) = tokio::io::split(pipe);
+    let send_request = async move {
+        // Write errors mean the request handling ended early, its response tells what happened
+        if writer.write_all(&request.head).await.is_err() {
+            return Ok(());
+        }
+        let mut received = 0;
+        while let Some(data) = body.recv().await {
+            received += data.len() as u64;
+            if request.length.is_some_and(|length| received > length) {
+                return Err(PROTOCOL_ERROR);
+            }
+            let written = match request.chunked {
+                true => {
+                    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+                    chunk.extend_from_slice(&data);
+                    chunk.extend_from_slice(b"\r\n");
+                    writer.write_all(&chunk).await
+                }
+                false => writer.write_all(&data).await,
+            };
+            if written.is_err() {
+                return Ok(());
+            }
+            let _ = sender.send(Out::Consumed { stream: id, amount: data.len() });
+        }
+        if request.length.is_some_and(|length| received != length) {
+            return Err(PROTOCOL_ERROR);
+        }
+        if request.chunked {
+            let _ = writer.write_all(b"0\r\n\r\n").await;
+        }
+        // Not shutting the pipe down, which would look like a client that went away; it closes
+        // once the response is received
+        Ok(())
+    };
+    let receive_response = respond(reader, id, &request.method, sender, windows, buffers);
+    tokio::pin!(send_request, receive_response);
+    let mut sending = true;
+    loop {
+        tokio::select! {
+            result = &mut send_request, if sending => {
+                result?;
+                sending = false;
+            }
+            result = &mut receive_response => return result,
+        }
+    }
+}
+
+/// Read the response from the pipe and send it as HEADERS and DATA frames within the client's flow control windows
+async fn respond(
+    reader: ReadHalf<DuplexStream>,
+    id: u32,
+    method: &str,
+    sender: &mpsc::UnboundedSender<Out>,
+    windows: &SendWindows,
+    buffers: Buffers,
+) -> Result<(), u32> {
+    let mut reader = BufReader::with_capacity(buffers.backend_to_client, reader);
+    let body = loop {
+        // The request handling answers errors itself, ending without a response means the stream failed
+        let head = http::read_head(&mut reader).await.ok().flatten().ok_or(INTERNAL_ERROR)?;
+        let response = Response::parse(&head).map_err(|_| INTERNAL_ERROR)?;
+        if response.status == 101 {
+            return Err(INTERNAL_ERROR);
+        }
+        let block = hpack::encode(response.status, &response_fields(&response));
+        if (100..200).contains(&response.status) {
+            let _ = sender.send(Out::Headers { stream: id, block, end: false });
+            continue;
+        }
+        let body = response.body(method).map_err(|_| INTERNAL_ERROR)?;
+        let end = body == Body::Length(0);
+        let _ = sender.send(Out::Headers { stream: id, block, end });
+        if end {
+            return Ok(());
+        }
+        break body;
+    };
+
+    // The body is decoded into a second pipe, whose content is sent as DATA frames
+    let (mut decoded, mut payload) = tokio::io::duplex(buffers.backend_to_client);
+    let decode = async move {
+        http::copy_payload(&mut reader, &mut decoded, body).await?;
+        decoded.shutdown().await
+    };
+    let send = async {
+        let mut buffer = vec![0; MAX_FRAME_SIZE];
+        loop {
+            let n = payload.read(&mut buffer).await.map_err(|_| INTERNAL_ERROR)?;
+            if n == 0 {
+                return Ok(());
+            }
+            let mut data = &buffer[..n];
+            while !data.is_empty() {
+                let allowed = windows.reserve(id, data.len()).await;
+                if allowed == 0 {
+                    return Err(INTERNAL_ERROR);
+                }
+                let _ = sender.send(Out::Data { stream: id, data: data[..allowed].to_vec(), end: false });
+                data = &data[allowed..];
+            }
+        }
+    };
+    let (decoded, sent) = tokio::join!(decode, send);
+    // A truncated body must not look complete to the client
+    decoded.map_err(|_| INTERNAL_ERROR)?;
+    sent?;
+    let _ = sender.send(Out::Data { stream: id, data: Vec::new(), end: true });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(list: &[(&str, &str)]) -> Vec<hpack::Field> {
+        list.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+    }
+
+    fn get(extra: &[(&str, &str)]) -> Vec<hpack::Field> {
+        let mut list = vec![(":method", "GET"), (":scheme", "https"), (":authority", "example.com"), (":path", "/index.html")];
+        list.extend_from_slice(extra);
+        fields(&list)
+    }
+
+    fn translated(fields: Vec<hpack::Field>, end_stream: bool) -> String {
+        String::from_utf8(translate(fields, end_stream).unwrap().head).unwrap()
+    }
+
+    fn rejected(fields: Vec<hpack::Field>) -> String {
+        translate(fields, true).err().expect("request should be rejected")
+    }
+
+    async fn frame(bytes: &[u8]) -> Result<Option<Frame>, ConnectionError> {
+        read_frame(&mut &bytes[..]).await
+    }
+
+    #[tokio::test]
+    async fn read_frame_header() {
+        let frame = frame(&[0, 0, 3, HEADERS, END_HEADERS | END_STREAM, 0x80, 0, 0, 5, 1, 2, 3]).await.ok().flatten().unwrap();
+        assert_eq!((frame.kind, frame.flags), (HEADERS, END_HEADERS | END_STREAM));
+        // The reserved bit is ignored
+        assert_eq!(frame.stream, 5);
+        assert_eq!(frame.payload, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn read_frame_end_of_connection() {
+        assert!(frame(&[]).await.ok().unwrap().is_none());
+        // A frame cut off in its header is an error rather than the end of the connection
+        assert_eq!(frame(&[0, 0, 3, DATA]).await.err().unwrap().code, INTERNAL_ERROR);
+        assert_eq!(frame(&[0, 0, 3, DATA, 0, 0, 0, 0, 1, 1]).await.err().unwrap().code, INTERNAL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn read_frame_too_large() {
+        let length = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        let error = frame(&[length[1], length[2], length[3], DATA, 0, 0, 0, 0, 1]).await.err().unwrap();
+        assert_eq!(error.code, FRAME_SIZE_ERROR);
+    }
+
+    #[test]
+    fn padding() {
+        let padded = |payload: &[u8]| Frame { kind: DATA, flags: PADDED, stream: 1, payload: payload.to_vec() };
+        assert_eq!(unpad(&padded(&[2, b'h', b'i', 0, 0])).ok().unwrap(), b"hi");
+        assert_eq!(unpad(&padded(&[0, b'h', b'i'])).ok().unwrap(), b"hi");
+        assert_eq!(unpad(&padded(&[3, 0, 0, 0])).ok().unwrap(), b"");
+        assert_eq!(unpad(&padded(&[4, 0, 0, 0])).err().unwrap().code, PROTOCOL_ERROR);
+        assert_eq!(unpad(&padded(&[])).err().unwrap().code, PROTOCOL_ERROR);
+        let unpadded = Frame { kind: DATA, flags: 0, stream: 1, payload: vec![2, 0, 0] };
+        assert_eq!(unpad(&unpadded).ok().unwrap(), [2, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn send_windows_limit_frames() {
+        let windows = SendWindows::new();
+        windows.open(1);
+        // Limited by the frame size, then by what is left of the stream window
+        assert_eq!(windows.reserve(1, 100_000).await, MAX_FRAME_SIZE);
+        assert_eq!(windows.reserve(1, 10).await, 10);
+        assert_eq!(windows.reserve(1, 100_000).await, MAX_FRAME_SIZE);
+        assert_eq!(windows.reserve(1, 100_000).await, MAX_FRAME_SIZE);
+        assert_eq!(windows.reserve(1, 100_000).await, DEFAULT_WINDOW as usize - 3 * MAX_FRAME_SIZE - 10);
+
+        // The connection window is shared: a new stream waits for it although its own window is open
+        windows.open(3);
+        let waiting = windows.reserve(3, 100);
+        tokio::pin!(waiting);
+        assert!(tokio::time::timeout(Duration::from_millis(50), waiting.as_mut()).await.is_err());
+        assert!(windows.grow(0, 60));
+        assert_eq!(waiting.await, 60);
+    }
+
+    #[tokio::test]
+    async fn send_windows_closed_stream() {
+        let windows = Arc::new(SendWindows::new());
+        windows.set_initial(0);
+        windows.open(1);
+        let waiting = tokio::spawn({
+            let windows = windows.clone();
+            async move { windows.reserve(1, 100).await }
+        });
+        tokio::task::yield_now().await;
+        windows.close(1);
+        assert_eq!(waiting.await.unwrap(), 0);
+        assert_eq!(windows.reserve(5, 100).await, 0);
+        // Updates for closed streams are ignored
+        assert!(windows.grow(5, MAX_WINDOW));
+    }
+
+    #[tokio::test]
+    async fn send_windows_settings() {
+        let windows = SendWindows::new();
+        windows.open(1);
+        windows.set_max_frame(100_000);
+        // A smaller initial window can make open windows negative, until window updates
+        assert!(windows.set_initial(10));
+        assert_eq!(windows.reserve(1, 100_000).await, 10);
+        assert!(windows.set_initial(0));
+        assert!(windows.grow(1, 20));
+        assert_eq!(windows.reserve(1, 100_000).await, 10);
+        assert!(windows.set_initial(DEFAULT_WINDOW));
+        assert_eq!(windows.reserve(1, 100_000).await, DEFAULT_WINDOW as usize - 20);
+        // New streams start with the current initial window
+        windows.set_initial(5);
+        windows.open(3);
+        assert!(windows.grow(0, 100));
+        assert_eq!(windows.reserve(3, 100).await, 5);
+    }
+
+    #[test]
+    fn send_windows_overflow() {
+        let windows = SendWindows::new();
+        windows.open(1);
+        assert!(windows.grow(1, MAX_WINDOW - DEFAULT_WINDOW));
+        assert!(!windows.grow(1, 1));
+        assert!(!windows.grow(0, MAX_WINDOW));
+        let windows = SendWindows::new();
+        windows.open(1);
+        assert!(windows.grow(1, MAX_WINDOW - DEFAULT_WINDOW));
+        assert!(!windows.set_initial(DEFAULT_WINDOW + 1));
+    }
+
+    #[test]
+    fn translate_request() {
+        let head = translated(get(&[("accept", "text/html"), ("cookie", "a=1"), ("te", "trailers"), ("cookie", "b=2")]), true);
+        assert_eq!(head, "GET /index.html HTTP/1.1\r\nhost: example.com\r\naccept: text/html\r\ncookie: a=1; b=2\r\n\r\n");
+
+        let request = translate(get(&[]), true).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!((request.length, request.chunked), (None, false));
+    }
+
+    #[test]
+    fn translate_host() {
+        let mut list = vec![(":method", "GET"), (":scheme", "https"), (":path", "/"), ("host", "example.org")];
+        assert!(translated(fields(&list), true).starts_with("GET / HTTP/1.1\r\nhost: example.org\r\n"));
+        list.push(("x", "y"));
+        list.insert(0, (":authority", "example.com"));
+        assert!(translated(fields(&list), true).starts_with("GET / HTTP/1.1\r\nhost: example.com\r\n"));
+    }
+
+    #[test]
+    fn translate_body() {
+        let request = translate(get(&[("content-length", "5")]), false).unwrap();
+        assert_eq!((request.length, request.chunked), (Some(5), false));
+        assert!(String::from_utf8(request.head).unwrap().ends_with("content-length: 5\r\n\r\n"));
+
+        // Without a declared length the body is sent in chunks
+        let request = translate(get(&[]), false).unwrap();
+        assert_eq!((request.length, request.chunked), (None, true));
+        assert!(String::from_utf8(request.head).unwrap().ends_with("transfer-encoding: chunked\r\n\r\n"));
+
+        assert_eq!(translate(get(&[("content-length", "0")]), true).unwrap().length, Some(0));
+        assert_eq!(rejected(get(&[("content-length", "5")])), "content-length without a body");
+        assert_eq!(rejected(get(&[("content-length", "5"), ("content-length", "6")])), "conflicting content-length values");
+        assert_eq!(rejected(get(&[("content-length", "-1")])), "invalid content-length '-1'");
+    }
+
+    #[test]
+    fn translate_pseudo_headers() {
+        let without = |name: &str| get(&[]).into_iter().filter(|(field, _)| field != name.as_bytes()).collect();
+        assert_eq!(rejected(without(":method")), "missing :method");
+        assert_eq!(rejected(without(":scheme")), "missing :scheme");
+        assert_eq!(rejected(without(":path")), "missing :path");
+        assert_eq!(rejected(without(":authority")), "missing :authority");
+        assert_eq!(rejected(get(&[(":path", "/other")])), "duplicate pseudo-header :path");
+        assert_eq!(rejected(fields(&[(":protocol", "websocket")])), "unknown pseudo-header :protocol");
+        assert_eq!(rejected(fields(&[("accept", "*/*"), (":method", "GET")])), "pseudo-header :method after regular header fields");
+    }
+
+    #[test]
+    fn translate_invalid_requests() {
+        let with = |name: &str, value: &str| {
+            let mut list = get(&[]);
+            list.iter_mut().find(|(field, _)| field == name.as_bytes()).unwrap().1 = value.as_bytes().to_vec();
+            list
+        };
+        assert_eq!(rejected(with(":method", "CONNECT")), "CONNECT is not supported");
+        assert_eq!(rejected(with(":method", "GE T")), "invalid method 'GE T'");
+        assert_eq!(rejected(with(":path", "index.html")), "invalid :path 'index.html'");
+        assert_eq!(rejected(with(":path", "/a b")), "invalid :path '/a b'");
+        assert_eq!(rejected(with(":authority", "example.com\t")), "invalid :authority 'example.com\t'");
+        assert!(translate(fields(&[(":method", "OPTIONS"), (":scheme", "https"), (":authority", "a"), (":path", "*")]), true).is_ok());
+        assert_eq!(rejected(with(":path", "*")), "invalid :path '*'");
+    }
+
+    #[test]
+    fn translate_invalid_header_fields() {
+        assert_eq!(rejected(get(&[("Accept", "*/*")])), "invalid header name 'Accept'");
+        assert_eq!(rejected(get(&[("", "x")])), "invalid header name ''");
+        assert_eq!(rejected(get(&[("x y", "x")])), "invalid header name 'x y'");
+        assert_eq!(rejected(get(&[("connection", "close")])), "connection-specific header connection");
+        assert_eq!(rejected(get(&[("transfer-encoding", "chunked")])), "connection-specific header transfer-encoding");
+        assert_eq!(rejected(get(&[("te", "gzip")])), "TE other than trailers");
+        // Line breaks must not inject header fields into the request to the backend
+        assert_eq!(rejected(get(&[("x", "1\r\ninjected: 1")])), "invalid characters in the value of x");
+        assert_eq!(rejected(get(&[("x", "\0")])), "invalid characters in the value of x");
+        assert_eq!(rejected(vec![(b"x".to_vec(), vec![0xff])]), "value of x is not valid UTF-8");
+    }
+}
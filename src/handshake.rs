@@ -0,0 +1,140 @@
+use crate::warn;
+use rustls::server::ClientHello;
+use rustls::{AlertDescription, Error, InvalidMessage, PeerIncompatible};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Classified reason for a failed TLS handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    NoSharedCipher,
+    UnknownSni,
+    CertRejected,
+    ClientCertificate,
+    ProtocolVersion,
+    PlainHttp,
+    ClientDisconnected,
+    Other,
+}
+
+impl HandshakeFailure {
+    /// Short identifier used in metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            HandshakeFailure::NoSharedCipher => "no_shared_cipher",
+            HandshakeFailure::UnknownSni => "unknown_sni",
+            HandshakeFailure::CertRejected => "cert_rejected",
+            HandshakeFailure::ClientCertificate => "client_certificate",
+            HandshakeFailure::ProtocolVersion => "protocol_version",
+            HandshakeFailure::PlainHttp => "plain_http",
+            HandshakeFailure::ClientDisconnected => "client_disconnected",
+            HandshakeFailure::Other => "other",
+        }
+    }
+
+    /// Explanation of the failure with a hint on how to resolve it
+    pub fn advice(self) -> &'static str {
+        match self {
+            HandshakeFailure::NoSharedCipher => "client and server share no cipher suite, key exchange group or signature scheme (outdated client?)",
+            HandshakeFailure::UnknownSni => "no certificate available for the requested server name (check the SNI the client sends)",
+            HandshakeFailure::CertRejected => "client rejected the certificate (expired, self-signed, missing intermediates or wrong hostname?)",
+            HandshakeFailure::ClientCertificate => "client sent no certificate or one not signed by --client-ca (not coming through the CDN?)",
+            HandshakeFailure::ProtocolVersion => "client does not support TLS 1.2 or 1.3",
+            HandshakeFailure::PlainHttp => "client sent non-TLS data (plain HTTP sent to the HTTPS port? use https://)",
+            HandshakeFailure::ClientDisconnected => "client closed the connection during the handshake",
+            HandshakeFailure::Other => "unclassified handshake failure",
+        }
+    }
+}
+
+/// Determine the failure class of a handshake error
+pub fn classify(error: &io::Error) -> HandshakeFailure {
+    let tls_error = error.get_ref().and_then(|e| e.downcast_ref::<Error>());
+
+    match tls_error {
+        Some(Error::PeerIncompatible(reason)) => match reason {
+            PeerIncompatible::SupportedVersionsExtensionRequired
+            | PeerIncompatible::SignatureAlgorithmsExtensionRequired
+            | PeerIncompatible::Tls12NotOffered
+            | PeerIncompatible::Tls12NotOfferedOrEnabled
+            | PeerIncompatible::ServerDoesNotSupportTls12Or13 => HandshakeFailure::ProtocolVersion,
+            _ => HandshakeFailure::NoSharedCipher,
+        },
+        Some(Error::AlertReceived(alert)) => match alert {
+            AlertDescription::BadCertificate
+            | AlertDescription::UnsupportedCertificate
+            | AlertDescription::CertificateRevoked
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::UnknownCA
+            | AlertDescription::AccessDenied => HandshakeFailure::CertRejected,
+            AlertDescription::ProtocolVersion => HandshakeFailure::ProtocolVersion,
+            AlertDescription::HandshakeFailure | AlertDescription::InsufficientSecurity => HandshakeFailure::NoSharedCipher,
+            AlertDescription::UnrecognisedName => HandshakeFailure::UnknownSni,
+            _ => HandshakeFailure::Other,
+        },
+        // Only client certificates are verified by the server
+        Some(Error::NoCertificatesPresented | Error::InvalidCertificate(_)) => HandshakeFailure::ClientCertificate,
+        Some(Error::InvalidMessage(InvalidMessage::InvalidContentType))
+        | Some(Error::InvalidMessage(InvalidMessage::UnknownProtocolVersion)) => HandshakeFailure::PlainHttp,
+        Some(Error::General(message)) if message.contains("no server certificate") => HandshakeFailure::UnknownSni,
+        Some(_) => HandshakeFailure::Other,
+        None => match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => HandshakeFailure::ClientDisconnected,
+            _ => HandshakeFailure::Other,
+        },
+    }
+}
+
+/// Client hellos logged per minute at most
+const MAX_LOGGED_HELLOS: u32 = 10;
+
+/// Rate-limited log of client hellos asking for server names the certificate does not cover
+#[derive(Debug)]
+pub struct HelloLog {
+    /// Start of the current minute, hellos logged and suppressed in it
+    window: Mutex<(Instant, u32, u64)>,
+}
+
+impl Default for HelloLog {
+    fn default() -> Self {
+        Self {
+            window: Mutex::new((Instant::now(), 0, 0)),
+        }
+    }
+}
+
+impl HelloLog {
+    /// Log the server name, ALPN protocols and cipher suites a client asked for
+    pub fn log(&self, client_hello: &ClientHello<'_>) {
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= Duration::from_secs(60) {
+            if window.2 > 0 {
+                warn!("{} more client hello(s) for unknown server names not logged", window.2);
+            }
+            *window = (Instant::now(), 0, 0);
+        }
+        if window.1 >= MAX_LOGGED_HELLOS {
+            window.2 += 1;
+            return;
+        }
+        window.1 += 1;
+        drop(window);
+
+        let alpn: Vec<String> = client_hello
+            .alpn()
+            .map(|protocols| protocols.map(|p| String::from_utf8_lossy(p).into_owned()).collect())
+            .unwrap_or_default();
+        let cipher_suites: Vec<String> = client_hello.cipher_suites().iter().map(|suite| format!("{:?}", suite)).collect();
+        warn!(
+            "Client hello for server name '{}' not covered by the certificate (ALPN: {}; cipher suites: {})",
+            client_hello.server_name().unwrap_or_default(),
+            if alpn.is_empty() { "none".to_string() } else { alpn.join(", ") },
+            cipher_suites.join(", ")
+        );
+    }
+}
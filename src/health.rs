@@ -0,0 +1,158 @@
+use crate::{info, warn};
+use crate::backend::{self, Backend};
+use crate::dscp::{self, DscpRule};
+use crate::events::{Event, EventHooks};
+use crate::metrics;
+use crate::proxy::Proxy;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+
+/// Most of a health check response that is read to find the status line
+const MAX_STATUS_LINE: usize = 1024;
+
+/// Result of the active health checks of a single backend
+#[derive(Debug)]
+pub struct HealthState {
+    pub healthy: bool,
+    /// Consecutive checks that disagreed with `healthy`
+    streak: u32,
+    /// Why the last check failed
+    pub last_error: Option<String>,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            streak: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// Periodically connects to every backend (and requests a path, when given), taking backends out
+/// of the rotation after `fall` failed checks in a row and back in after `rise` successful ones
+#[derive(Debug)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub rise: u32,
+    pub fall: u32,
+    /// Path requested with GET, expecting a 2xx or 3xx response, instead of a plain TCP connect
+    pub path: Option<String>,
+    /// Source address, DSCP classes and multipath TCP use of the checks, the same as for forwarded connections
+    pub bind: Option<IpAddr>,
+    pub dscp: Vec<DscpRule>,
+    pub mptcp: bool,
+}
+
+impl HealthCheck {
+    /// Check the backends of the proxy forever, as they are after the last configuration reload
+    pub async fn run(self: Arc<Self>, proxy: Arc<Proxy>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            // Backends used by several pools are checked once
+            let upstreams = proxy.upstreams();
+            let mut backends: Vec<&Arc<Backend>> = Vec::new();
+            for backend in upstreams.pools().flat_map(|pool| pool.backends()) {
+                if !backends.iter().any(|checked| Arc::ptr_eq(checked, backend)) {
+                    backends.push(backend);
+                }
+            }
+            let mut checks = JoinSet::new();
+            for backend in backends {
+                let (check, backend) = (self.clone(), backend.clone());
+                checks.spawn(async move {
+                    let result = match tokio::time::timeout(check.timeout, check.probe(&backend.address)).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("no answer within {}s", check.timeout.as_secs())),
+                    };
+                    (backend, result)
+                });
+            }
+            while let Some(Ok((backend, result))) = checks.join_next().await {
+                self.record(&backend, result, &proxy.events);
+            }
+        }
+    }
+
+    /// Connect to the backend and, for HTTP checks, request the path and check the status code
+    async fn probe(&self, address: &str) -> Result<(), String> {
+        let mut stream = backend::connect(address, self.bind, dscp::select(&self.dscp, address), self.mptcp).await.map_err(|e| e.to_string())?;
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: https-wrapper/{}\r\nConnection: close\r\n\r\n",
+            path,
+            address,
+            env!("CARGO_PKG_VERSION")
+        );
+        stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        let mut response = Vec::new();
+        let mut buf = [0u8; 256];
+        while !response.contains(&b'\n') && response.len() < MAX_STATUS_LINE {
+            let n = stream.read(&mut buf).await.map_err(|e| e.to_string())?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        let line = String::from_utf8_lossy(&response);
+        let status = line.lines().next().unwrap_or_default().split(' ').nth(1).unwrap_or_default();
+        match status.as_bytes().first() {
+            Some(b'2' | b'3') if status.len() == 3 => Ok(()),
+            _ if status.is_empty() => Err("no HTTP response".to_string()),
+            _ => Err(format!("status {}", status)),
+        }
+    }
+
+    /// Count the check result towards the backend changing state
+    fn record(&self, backend: &Backend, result: Result<(), String>, events: &EventHooks) {
+        let mut state = backend.health.lock().unwrap();
+        let passed = result.is_ok();
+        state.last_error = result.err();
+        if passed == state.healthy {
+            state.streak = 0;
+            return;
+        }
+        state.streak += 1;
+        if state.streak < if passed { self.rise } else { self.fall } {
+            return;
+        }
+        state.healthy = passed;
+        state.streak = 0;
+        let reason = state.last_error.clone();
+        drop(state);
+
+        let event = match reason {
+            None => {
+                *backend.recovered_at.lock().unwrap() = Some(Instant::now());
+                info!("Backend http://{} is healthy again, back in the rotation", backend.address);
+                (Event::BackendHealthy, vec![("backend", backend.address.clone())])
+            }
+            Some(reason) => {
+                warn!("Backend http://{} failed {} health checks, out of the rotation: {}", backend.address, self.fall, reason);
+                (Event::BackendUnhealthy, vec![("backend", backend.address.clone()), ("reason", reason)])
+            }
+        };
+        events.fire(event.0, &event.1);
+    }
+
+    /// Render the health of each backend as metric lines, healthy if every backend with the address is
+    pub fn render(&self, backends: &BTreeMap<&str, Vec<&Backend>>) -> String {
+        let mut out = String::new();
+        metrics::family(&mut out, "backend_healthy", "gauge", "Whether the backend passes its health checks");
+        for (address, backends) in backends {
+            let healthy = backends.iter().all(|backend| backend.is_healthy());
+            out.push_str(&format!("backend_healthy{{backend=\"{}\"}} {}\n", metrics::escape(address), u8::from(healthy)));
+        }
+        out
+    }
+}
@@ -0,0 +1,86 @@
+use crate::registry::Connection;
+use rustls::ServerConnection;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Maximum size of a request head passed to `on_request`
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// Outcome of a hook deciding whether a connection may proceed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Continue,
+    /// Close the connection, the reason is logged
+    Reject(String),
+}
+
+/// Hook into the connection lifecycle, for embedders adding custom auth, logging or rewriting
+///
+/// All methods have no-op defaults, so implementations only override the phases they need.
+/// Hooks run in registration order; the first rejection closes the connection.
+pub trait ConnectionHook: Send + Sync {
+    /// Called for a new TCP connection, before the TLS handshake
+    fn on_accept(&self, _peer: SocketAddr) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Called after a successful TLS handshake, before connecting to the backend
+    fn on_handshake(&self, _peer: SocketAddr, _session: &ServerConnection) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Called with the head of the first request on the connection (request line and headers,
+    /// including the final empty line), which may be rewritten before it is sent to the backend
+    ///
+    /// In TCP mode later requests on a keep-alive connection are forwarded as raw bytes and not
+    /// passed here, in HTTP mode every request is. Not called with a protocol profile.
+    fn on_request(&self, _connection: &Connection, _head: &mut Vec<u8>) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Called when the connection is closed
+    fn on_close(&self, _connection: &Connection) {}
+}
+
+/// Run a hook phase over all hooks, returning the first rejection reason
+pub fn check(hooks: &[Arc<dyn ConnectionHook>], mut phase: impl FnMut(&dyn ConnectionHook) -> Verdict) -> Result<(), String> {
+    for hook in hooks {
+        if let Verdict::Reject(reason) = phase(hook.as_ref()) {
+            return Err(reason);
+        }
+    }
+    Ok(())
+}
+
+/// Read the head of the first request, returns (head, bytes read past the head)
+///
+/// Returns an empty head when the client closes the connection or sends no valid head
+/// within the size limit; the data read is then forwarded unchanged.
+pub async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_REQUEST_HEAD {
+            return Ok((Vec::new(), buf));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok((Vec::new(), buf));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Method and path (without query string) from the request line of a request head
+pub fn request_target(head: &[u8]) -> Option<(&str, &str)> {
+    let request_line = head.split(|&b| b == b'\r').next()?;
+    let mut parts = std::str::from_utf8(request_line).ok()?.split_whitespace();
+    let method = parts.next()?;
+    let path = parts.next()?.split('?').next()?;
+    Some((method, path))
+}
@@ -0,0 +1,587 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+
+/// Size of the dynamic table the client may use, the HTTP/2 default which the proxy does not change
+pub const TABLE_SIZE: usize = 4096;
+
+/// Header fields every HPACK decoder knows, addressed by index 1 to 61
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Length in bits of the Huffman code of each byte value and of the end-of-string symbol (256)
+///
+/// The codes are canonical, so they follow from the lengths: ordered by length and then by symbol,
+/// each code is the previous one plus one, shifted left when the length grows.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+/// End-of-string symbol, never valid inside a string
+const EOS: u16 = 256;
+
+/// Header field as decoded, names and values need not be valid UTF-8
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// State for decoding the header blocks of one connection, which share the dynamic table
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// Most recently added entry first
+    dynamic: VecDeque<Field>,
+    size: usize,
+    max_size: Option<usize>,
+}
+
+impl Decoder {
+    /// Decode a complete header block, failing once the fields exceed `max_list_size` bytes
+    ///
+    /// Any error leaves the dynamic table out of sync with the client, so it ends the connection.
+    pub fn decode(&mut self, block: &[u8], max_list_size: usize) -> Result<Vec<Field>, Error> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut input = block;
+        while let Some(&first) = input.first() {
+            let field = if first & 0x80 != 0 {
+                let index = integer(&mut input, 7)?;
+                self.entry(index)?
+            } else if first & 0xe0 == 0x20 {
+                let size = integer(&mut input, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(invalid("dynamic table size update beyond the limit"));
+                }
+                self.max_size = Some(size);
+                self.evict(0);
+                continue;
+            } else {
+                // Literal, added to the dynamic table with incremental indexing (01), otherwise not
+                let indexing = first & 0xc0 == 0x40;
+                let index = integer(&mut input, if indexing { 6 } else { 4 })?;
+                let name = match index {
+                    0 => string(&mut input)?,
+                    index => self.entry(index)?.0,
+                };
+                let field = (name, string(&mut input)?);
+                if indexing {
+                    self.insert(field.clone());
+                }
+                field
+            };
+            list_size += field.0.len() + field.1.len() + 32;
+            if list_size > max_list_size {
+                return Err(invalid("header list too large"));
+            }
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    fn entry(&self, index: usize) -> Result<Field, Error> {
+        match index {
+            0 => Err(invalid("header field index 0")),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .dynamic
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| invalid("header field index beyond the dynamic table")),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + 32;
+        self.evict(size);
+        // An entry larger than the table empties it without being added
+        if size <= self.max_size() {
+            self.size += size;
+            self.dynamic.push_front(field);
+        }
+    }
+
+    /// Evict the oldest entries until `additional` bytes fit into the table
+    fn evict(&mut self, additional: usize) {
+        while self.size + additional > self.max_size() {
+            let Some((name, value)) = self.dynamic.pop_back() else { break };
+            self.size -= name.len() + value.len() + 32;
+        }
+    }
+
+    fn max_size(&self) -> usize {
+        self.max_size.unwrap_or(TABLE_SIZE)
+    }
+}
+
+/// Encode response header fields, as literals that leave the client's dynamic table alone
+pub fn encode(status: u16, fields: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    let status = status.to_string();
+    match STATIC_TABLE[7..14].iter().position(|(_, value)| *value == status) {
+        Some(position) => encode_integer(&mut block, 0x80, 7, position + 8),
+        None => {
+            // Literal without indexing, with the name of static entry 8 (:status)
+            encode_integer(&mut block, 0x00, 4, 8);
+            encode_string(&mut block, status.as_bytes());
+        }
+    }
+    for (name, value) in fields {
+        block.push(0x00);
+        encode_string(&mut block, name.as_bytes());
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Decode an integer with an N-bit prefix
+fn integer(input: &mut &[u8], prefix_bits: u32) -> Result<usize, Error> {
+    let (&first, rest) = input.split_first().ok_or_else(|| invalid("truncated integer"))?;
+    *input = rest;
+    let mask = (1usize << prefix_bits) - 1;
+    let mut value = first as usize & mask;
+    if value < mask {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first().ok_or_else(|| invalid("truncated integer"))?;
+        *input = rest;
+        if shift > 21 {
+            return Err(invalid("integer too large"));
+        }
+        value += (byte as usize & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix_bits: u32, mut value: usize) {
+    let mask = (1usize << prefix_bits) - 1;
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decode a string literal, Huffman coded when the first bit is set
+fn string(input: &mut &[u8]) -> Result<Vec<u8>, Error> {
+    let huffman = input.first().is_some_and(|first| first & 0x80 != 0);
+    let length = integer(input, 7)?;
+    if length > input.len() {
+        return Err(invalid("truncated string"));
+    }
+    let (data, rest) = input.split_at(length);
+    *input = rest;
+    if huffman {
+        huffman_decode(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// Encode a string literal without Huffman coding
+fn encode_string(out: &mut Vec<u8>, data: &[u8]) {
+    encode_integer(out, 0x00, 7, data.len());
+    out.extend_from_slice(data);
+}
+
+/// Canonical Huffman decoding table: per code length the first code, the number of codes and
+/// where their symbols start in the symbol list
+struct HuffmanTable {
+    first: [u32; 31],
+    count: [u32; 31],
+    offset: [usize; 31],
+    symbols: Vec<u16>,
+}
+
+fn huffman_table() -> &'static HuffmanTable {
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..=EOS).collect();
+        symbols.sort_by_key(|&symbol| (HUFFMAN_LENGTHS[symbol as usize], symbol));
+        let mut table = HuffmanTable {
+            first: [0; 31],
+            count: [0; 31],
+            offset: [0; 31],
+            symbols,
+        };
+        let mut code = 0u32;
+        let mut length = 0;
+        for (position, &symbol) in table.symbols.iter().enumerate() {
+            let symbol_length = HUFFMAN_LENGTHS[symbol as usize] as usize;
+            if symbol_length != length {
+                code <<= symbol_length - length;
+                length = symbol_length;
+                table.first[length] = code;
+                table.offset[length] = position;
+            }
+            table.count[length] += 1;
+            code += 1;
+        }
+        table
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let table = huffman_table();
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut code, mut length) = (0u32, 0usize);
+    for byte in data {
+        for bit in (0..8).rev() {
+            code = (code << 1) | ((*byte >> bit) & 1) as u32;
+            length += 1;
+            if length > 30 {
+                return Err(invalid("invalid Huffman code"));
+            }
+            let index = code.wrapping_sub(table.first[length]);
+            if index < table.count[length] {
+                match table.symbols[table.offset[length] + index as usize] {
+                    EOS => return Err(invalid("end-of-string symbol in Huffman coded string")),
+                    symbol => out.push(symbol as u8),
+                }
+                (code, length) = (0, 0);
+            }
+        }
+    }
+    // The last byte is padded with the most significant bits of the end-of-string code (all ones)
+    if length > 7 || code != (1 << length) - 1 {
+        return Err(invalid("invalid Huffman padding"));
+    }
+    Ok(out)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    //! Examples from RFC 7541 Appendix C
+
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<Field> {
+        list.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+    }
+
+    fn table(decoder: &Decoder) -> Vec<(&str, &str)> {
+        decoder
+            .dynamic
+            .iter()
+            .map(|(name, value)| (std::str::from_utf8(name).unwrap(), std::str::from_utf8(value).unwrap()))
+            .collect()
+    }
+
+    type Fields = &'static [(&'static str, &'static str)];
+
+    /// Decode the header blocks in turn, checking the fields, the dynamic table and its size after each
+    fn check(decoder: &mut Decoder, blocks: &[(&str, Fields, Fields, usize)]) {
+        for (block, expected, dynamic, size) in blocks {
+            assert_eq!(decoder.decode(&bytes(block), usize::MAX).unwrap(), fields(expected));
+            assert_eq!(table(decoder), *dynamic);
+            assert_eq!(decoder.size, *size);
+        }
+    }
+
+    const REQUEST_1: &[(&str, &str)] = &[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+    const REQUEST_2: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+        ("cache-control", "no-cache"),
+    ];
+    const REQUEST_3: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/index.html"),
+        (":authority", "www.example.com"),
+        ("custom-key", "custom-value"),
+    ];
+    const REQUEST_TABLES: [&[(&str, &str)]; 3] = [
+        &[(":authority", "www.example.com")],
+        &[("cache-control", "no-cache"), (":authority", "www.example.com")],
+        &[("custom-key", "custom-value"), ("cache-control", "no-cache"), (":authority", "www.example.com")],
+    ];
+
+    const RESPONSE_1: &[(&str, &str)] = &[
+        (":status", "302"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_2: &[(&str, &str)] = &[
+        (":status", "307"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_3: &[(&str, &str)] = &[
+        (":status", "200"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+        ("location", "https://www.example.com"),
+        ("content-encoding", "gzip"),
+        ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+    ];
+    const RESPONSE_TABLES: [&[(&str, &str)]; 3] = [
+        &[
+            ("location", "https://www.example.com"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("cache-control", "private"),
+            (":status", "302"),
+        ],
+        &[
+            (":status", "307"),
+            ("location", "https://www.example.com"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("cache-control", "private"),
+        ],
+        &[
+            ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+            ("content-encoding", "gzip"),
+            ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+        ],
+    ];
+
+    /// Decoder with the 256 byte dynamic table the response examples use
+    fn response_decoder() -> Decoder {
+        Decoder {
+            max_size: Some(256),
+            ..Decoder::default()
+        }
+    }
+
+    #[test]
+    fn integers() {
+        // C.1.1 to C.1.3
+        for (value, prefix_bits, encoded) in [(10, 5, "0a"), (1337, 5, "1f9a0a"), (42, 8, "2a")] {
+            let mut out = Vec::new();
+            encode_integer(&mut out, 0, prefix_bits, value);
+            assert_eq!(out, bytes(encoded));
+            assert_eq!(integer(&mut &out[..], prefix_bits).unwrap(), value);
+        }
+        // Flag bits above the prefix are not part of the value
+        assert_eq!(integer(&mut &[0xea][..], 5).unwrap(), 10);
+        assert!(integer(&mut &bytes("1f9a")[..], 5).is_err());
+        assert!(integer(&mut &bytes("1fffffffffff01")[..], 5).is_err());
+    }
+
+    #[test]
+    fn literal_with_indexing() {
+        // C.2.1
+        check(
+            &mut Decoder::default(),
+            &[("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572", &[("custom-key", "custom-header")], &[("custom-key", "custom-header")], 55)],
+        );
+    }
+
+    #[test]
+    fn literal_without_indexing() {
+        // C.2.2
+        check(&mut Decoder::default(), &[("040c 2f73 616d 706c 652f 7061 7468", &[(":path", "/sample/path")], &[], 0)]);
+    }
+
+    #[test]
+    fn literal_never_indexed() {
+        // C.2.3
+        check(&mut Decoder::default(), &[("1008 7061 7373 776f 7264 0673 6563 7265 74", &[("password", "secret")], &[], 0)]);
+    }
+
+    #[test]
+    fn indexed_field() {
+        // C.2.4
+        check(&mut Decoder::default(), &[("82", &[(":method", "GET")], &[], 0)]);
+    }
+
+    #[test]
+    fn requests_without_huffman_coding() {
+        // C.3
+        check(
+            &mut Decoder::default(),
+            &[
+                ("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d", REQUEST_1, REQUEST_TABLES[0], 57),
+                ("8286 84be 5808 6e6f 2d63 6163 6865", REQUEST_2, REQUEST_TABLES[1], 110),
+                ("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65", REQUEST_3, REQUEST_TABLES[2], 164),
+            ],
+        );
+    }
+
+    #[test]
+    fn requests_with_huffman_coding() {
+        // C.4
+        check(
+            &mut Decoder::default(),
+            &[
+                ("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff", REQUEST_1, REQUEST_TABLES[0], 57),
+                ("8286 84be 5886 a8eb 1064 9cbf", REQUEST_2, REQUEST_TABLES[1], 110),
+                ("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf", REQUEST_3, REQUEST_TABLES[2], 164),
+            ],
+        );
+    }
+
+    #[test]
+    fn responses_without_huffman_coding() {
+        // C.5, with entries evicted from the dynamic table
+        check(
+            &mut response_decoder(),
+            &[
+                (
+                    "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    RESPONSE_1,
+                    RESPONSE_TABLES[0],
+                    222,
+                ),
+                ("4803 3330 37c1 c0bf", RESPONSE_2, RESPONSE_TABLES[1], 222),
+                (
+                    "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e 3d31",
+                    RESPONSE_3,
+                    RESPONSE_TABLES[2],
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn responses_with_huffman_coding() {
+        // C.6
+        check(
+            &mut response_decoder(),
+            &[
+                (
+                    "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+                    RESPONSE_1,
+                    RESPONSE_TABLES[0],
+                    222,
+                ),
+                ("4883 640e ffc1 c0bf", RESPONSE_2, RESPONSE_TABLES[1], 222),
+                (
+                    "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+                    RESPONSE_3,
+                    RESPONSE_TABLES[2],
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn table_size_update() {
+        let mut decoder = Decoder::default();
+        check(&mut decoder, &[("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d", REQUEST_1, REQUEST_TABLES[0], 57)]);
+        // Shrinking the table evicts entries, a size beyond the limit is refused
+        check(&mut decoder, &[("20", &[], &[], 0)]);
+        assert!(decoder.decode(&bytes("3fe2 1f"), usize::MAX).is_err());
+    }
+
+    #[test]
+    fn invalid_blocks() {
+        let error = |block: &str| Decoder::default().decode(&bytes(block), usize::MAX).unwrap_err().to_string();
+        assert_eq!(error("80"), "header field index 0");
+        assert_eq!(error("be"), "header field index beyond the dynamic table");
+        assert_eq!(error("4005 6162"), "truncated string");
+        // Padding longer than 7 bits, padding that is not the end-of-string prefix, and the end-of-string symbol itself
+        assert_eq!(error("0081 ffff"), "invalid Huffman padding");
+        assert_eq!(error("0081 00"), "invalid Huffman padding");
+        assert_eq!(error("0084 ffff ffff"), "end-of-string symbol in Huffman coded string");
+        assert_eq!(Decoder::default().decode(&bytes("82"), 10).unwrap_err().to_string(), "header list too large");
+    }
+
+    #[test]
+    fn encoded_responses_decode() {
+        let headers = vec![("content-type".to_string(), "text/html".to_string()), ("x-long".to_string(), "a".repeat(200))];
+        for status in [200, 404, 302, 599] {
+            let mut expected = vec![(b":status".to_vec(), status.to_string().into_bytes())];
+            expected.extend(headers.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())));
+            let mut decoder = Decoder::default();
+            assert_eq!(decoder.decode(&encode(status, &headers), usize::MAX).unwrap(), expected);
+            // The client's dynamic table is left alone
+            assert!(decoder.dynamic.is_empty());
+        }
+        assert_eq!(encode(200, &[]), [0x88]);
+    }
+}
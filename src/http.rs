@@ -0,0 +1,508 @@
+use clap::ValueEnum;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum size of a request or response head
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Maximum length of a chunk size line in a chunked body
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// How client connections are forwarded to the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Forward raw bytes (any protocol on top of TLS)
+    Tcp,
+    /// Parse HTTP/1.1 requests and responses, required for per-request features such as caching
+    Http,
+}
+
+/// Header fields of a request or response, in their original order
+#[derive(Debug, Clone, Default)]
+pub struct Headers(pub Vec<(String, String)>);
+
+impl Headers {
+    /// First value of a header (case-insensitive name)
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// All values of a header, split at commas
+    pub fn tokens<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+    }
+
+    /// Whether a comma separated header contains a token (case-insensitive)
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name).any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    /// Replace all values of a header with a single value
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.0 {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl Request {
+    pub fn parse(head: &[u8]) -> Result<Self, Error> {
+        let (start_line, headers) = parse_head(head)?;
+        let mut parts = start_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid(format!("malformed request line '{}'", start_line)));
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid(format!("unsupported HTTP version '{}'", version)));
+        }
+        Ok(Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    /// Path of the request target, without query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+
+    /// Whether the client keeps the connection open after this request
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    /// Framing of the request body
+    pub fn body(&self) -> Result<Body, Error> {
+        match body_framing(&self.headers)? {
+            Some(body) => Ok(body),
+            // Requests without framing headers have no body
+            None => Ok(Body::Length(0)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl Response {
+    pub fn parse(head: &[u8]) -> Result<Self, Error> {
+        let (start_line, headers) = parse_head(head)?;
+        let mut parts = start_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        let status = parts.next().and_then(|s| s.parse().ok());
+        let (true, Some(status)) = (version.starts_with("HTTP/1."), status) else {
+            return Err(invalid(format!("malformed status line '{}'", start_line)));
+        };
+        Ok(Self {
+            version: version.to_string(),
+            status,
+            reason: parts.next().unwrap_or("").to_string(),
+            headers,
+        })
+    }
+
+    pub fn new(status: u16, reason: &str) -> Self {
+        Self {
+            version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: Headers::default(),
+        }
+    }
+
+    /// Whether the backend keeps the connection open after this response
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    /// Framing of the response body to a request with the given method
+    pub fn body(&self, method: &str) -> Result<Body, Error> {
+        if method == "HEAD" || (100..200).contains(&self.status) || self.status == 204 || self.status == 304 {
+            return Ok(Body::Length(0));
+        }
+        Ok(body_framing(&self.headers)?.unwrap_or(Body::UntilClose))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+/// How the end of a message body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    Length(u64),
+    Chunked,
+    /// The body ends when the sender closes the connection (responses only)
+    UntilClose,
+}
+
+/// Copy of a body kept while forwarding it, abandoned when it grows beyond the limit
+pub struct Capture {
+    pub data: Vec<u8>,
+    limit: usize,
+    pub overflow: bool,
+}
+
+impl Capture {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            limit,
+            overflow: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        if self.overflow {
+            return;
+        }
+        if self.data.len() + data.len() > self.limit {
+            self.overflow = true;
+            self.data = Vec::new();
+        } else {
+            self.data.extend_from_slice(data);
+        }
+    }
+}
+
+/// Read a message head up to and including the empty line, `None` when the peer closed the connection first
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut head = Vec::new();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed in message head"));
+        }
+
+        let mut consumed = 0;
+        let mut complete = false;
+        for &byte in available {
+            consumed += 1;
+            head.push(byte);
+            // Empty lines before a message are allowed and ignored
+            if head == b"\r\n" || head == b"\n" {
+                head.clear();
+            } else if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+                complete = true;
+                break;
+            }
+        }
+        reader.consume(consumed);
+        if complete {
+            return Ok(Some(head));
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(invalid("message head too large".to_string()));
+        }
+    }
+}
+
+/// Forward a message body, keeping a decoded copy in the capture if given
+pub async fn copy_body<R, W>(reader: &mut R, writer: &mut W, body: Body, mut capture: Option<&mut Capture>) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body {
+        Body::Length(length) => copy_exact(reader, writer, length, &mut capture).await?,
+        Body::UntilClose => loop {
+            let available = reader.fill_buf().await?;
+            if available.is_empty() {
+                break;
+            }
+            writer.write_all(available).await?;
+            if let Some(capture) = capture.as_deref_mut() {
+                capture.push(available);
+            }
+            let n = available.len();
+            reader.consume(n);
+        },
+        Body::Chunked => loop {
+            let line = read_line(reader).await?;
+            writer.write_all(&line).await?;
+            let size = chunk_size(&line)?;
+            if size == 0 {
+                // Trailer fields, up to the final empty line
+                loop {
+                    let line = read_line(reader).await?;
+                    writer.write_all(&line).await?;
+                    if line == b"\r\n" || line == b"\n" {
+                        break;
+                    }
+                }
+                break;
+            }
+            copy_exact(reader, writer, size, &mut capture).await?;
+            let line = read_line(reader).await?;
+            if line != b"\r\n" && line != b"\n" {
+                return Err(invalid("missing CRLF after chunk".to_string()));
+            }
+            writer.write_all(&line).await?;
+        },
+    }
+    writer.flush().await
+}
+
+/// Forward the payload of a message body, leaving out the chunked framing and trailer fields
+pub async fn copy_payload<R, W>(reader: &mut R, writer: &mut W, body: Body) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if body != Body::Chunked {
+        return copy_body(reader, writer, body, None).await;
+    }
+    loop {
+        let size = chunk_size(&read_line(reader).await?)?;
+        if size == 0 {
+            while !matches!(read_line(reader).await?.as_slice(), b"\r\n" | b"\n") {}
+            break;
+        }
+        copy_exact(reader, writer, size, &mut None).await?;
+        let line = read_line(reader).await?;
+        if line != b"\r\n" && line != b"\n" {
+            return Err(invalid("missing CRLF after chunk".to_string()));
+        }
+    }
+    writer.flush().await
+}
+
+/// Size of a chunk from its size line, ignoring chunk extensions
+///
+/// Only hex digits are accepted, as signs or whitespace around the size may be read differently by the backend.
+fn chunk_size(line: &[u8]) -> Result<u64, Error> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let size = line.split(|&byte| byte == b';').next().unwrap_or_default();
+    Some(size)
+        .filter(|size| !size.is_empty() && size.iter().all(u8::is_ascii_hexdigit))
+        .and_then(|size| u64::from_str_radix(std::str::from_utf8(size).ok()?, 16).ok())
+        .ok_or_else(|| invalid("malformed chunk size".to_string()))
+}
+
+/// Discard a message body
+pub async fn skip_body<R: AsyncBufRead + Unpin>(reader: &mut R, body: Body) -> Result<(), Error> {
+    copy_body(reader, &mut tokio::io::sink(), body, None).await
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, mut remaining: u64, capture: &mut Option<&mut Capture>) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while remaining > 0 {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed in message body"));
+        }
+        let n = available.len().min(remaining.min(usize::MAX as u64) as usize);
+        writer.write_all(&available[..n]).await?;
+        if let Some(capture) = capture.as_deref_mut() {
+            capture.push(&available[..n]);
+        }
+        reader.consume(n);
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Read a single line including its line ending
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_CHUNK_LINE as u64).read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\n") {
+        return Err(invalid("malformed chunked body".to_string()));
+    }
+    Ok(line)
+}
+
+/// Write a complete response with a body
+pub async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, mut response: Response, body: &[u8], head_only: bool) -> Result<(), Error> {
+    response.headers.remove("Transfer-Encoding");
+    response.headers.set("Content-Length", &body.len().to_string());
+    let mut out = response.to_bytes();
+    if !head_only {
+        out.extend_from_slice(body);
+    }
+    writer.write_all(&out).await?;
+    writer.flush().await
+}
+
+fn parse_head(head: &[u8]) -> Result<(String, Headers), Error> {
+    let head = std::str::from_utf8(head).map_err(|_| invalid("message head is not valid UTF-8".to_string()))?;
+    // Lines end with CRLF only, a bare CR or LF may be taken as a line ending by the backend but not by the proxy
+    let head = head.strip_suffix("\r\n\r\n").unwrap_or(head);
+    if head.split("\r\n").any(|line| line.contains(['\r', '\n'])) {
+        return Err(invalid("bare CR or LF in message head".to_string()));
+    }
+    let mut lines = head.split("\r\n");
+    let start_line = lines.next().unwrap_or("").to_string();
+    let mut headers = Headers::default();
+    for line in lines {
+        // Obsolete line folding and whitespace before the colon enable request smuggling
+        let (name, value) = line
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty() && name.bytes().all(is_token))
+            .ok_or_else(|| invalid(format!("malformed header line '{}'", line)))?;
+        headers.0.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok((start_line, headers))
+}
+
+/// Body framing from Transfer-Encoding / Content-Length, rejecting ambiguous combinations
+fn body_framing(headers: &Headers) -> Result<Option<Body>, Error> {
+    let lengths: Vec<&str> = headers.tokens("Content-Length").collect();
+    if headers.get("Transfer-Encoding").is_some() {
+        if !lengths.is_empty() {
+            return Err(invalid("both Transfer-Encoding and Content-Length present".to_string()));
+        }
+        if headers.tokens("Transfer-Encoding").last().is_some_and(|t| t.eq_ignore_ascii_case("chunked")) {
+            return Ok(Some(Body::Chunked));
+        }
+        return Err(invalid("unsupported Transfer-Encoding".to_string()));
+    }
+    match lengths.first() {
+        None => Ok(None),
+        // Digits only, as a sign is read differently by the backend receiving the header unchanged
+        Some(first) if lengths.iter().all(|l| l == first) => Some(first)
+            .filter(|length| length.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|length| length.parse().ok())
+            .map(|length| Some(Body::Length(length)))
+            .ok_or_else(|| invalid(format!("invalid Content-Length '{}'", first))),
+        Some(_) => Err(invalid("conflicting Content-Length values".to_string())),
+    }
+}
+
+/// Whether the byte may appear in a token such as a header name (RFC 9110 `tchar`)
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+fn keep_alive(version: &str, headers: &Headers) -> bool {
+    if headers.has_token("Connection", "close") {
+        return false;
+    }
+    version != "HTTP/1.0" || headers.has_token("Connection", "keep-alive")
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> Result<Request, Error> {
+        Request::parse(head.as_bytes())
+    }
+
+    fn body(content_length: &str) -> Result<Body, Error> {
+        request(&format!("POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: {}\r\n\r\n", content_length))?.body()
+    }
+
+    #[test]
+    fn parse_request() {
+        let request = request("GET /index.html?q=1 HTTP/1.1\r\nHost: example.com\r\nX-Empty:\r\n\r\n").unwrap();
+        assert_eq!((request.method.as_str(), request.path(), request.version.as_str()), ("GET", "/index.html", "HTTP/1.1"));
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+        assert_eq!(request.headers.get("X-Empty"), Some(""));
+        assert_eq!(request.body().unwrap(), Body::Length(0));
+    }
+
+    #[test]
+    fn bare_line_endings_rejected() {
+        assert!(request("GET / HTTP/1.1\nHost: example.com\n\n").is_err());
+        assert!(request("GET / HTTP/1.1\r\nHost: example.com\nX-Other: 1\r\n\r\n").is_err());
+        assert!(request("GET / HTTP/1.1\r\nHost: example.com\r\n\n").is_err());
+        assert!(request("GET / HTTP/1.1\r\nHost: example.com\rX-Other: 1\r\n\r\n").is_err());
+        assert!(request("GET / HTTP/1.1\r\nX-Value: a\rb\r\n\r\n").is_err());
+        assert!(request("GET / HTTP/1.1\r\r\nHost: example.com\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn header_names_are_tokens() {
+        assert!(request("GET / HTTP/1.1\r\nX-Custom_Name.1~: ok\r\n\r\n").is_ok());
+        for name in ["", "Host ", " Host", "X(Name)", "X/Name", "X\"Name\"", "X@Name", "X[1]", "X{1}", "Na\u{e9}me", "X\tName"] {
+            assert!(request(&format!("GET / HTTP/1.1\r\n{}: value\r\n\r\n", name)).is_err(), "{:?}", name);
+        }
+        // Obsolete line folding
+        assert!(request("GET / HTTP/1.1\r\nX-Value: a\r\n b\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn content_length_digits_only() {
+        assert_eq!(body("5").unwrap(), Body::Length(5));
+        assert_eq!(body("5, 5").unwrap(), Body::Length(5));
+        for length in ["+5", "-5", "5a", "0x5", "5.0", "1e3", "5 5", "99999999999999999999999"] {
+            assert!(body(length).is_err(), "{}", length);
+        }
+        assert!(body("5, 6").is_err());
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(chunk_size(b"0\r\n").unwrap(), 0);
+        assert_eq!(chunk_size(b"1aF\r\n").unwrap(), 0x1af);
+        assert_eq!(chunk_size(b"5;name=value\r\n").unwrap(), 5);
+        assert_eq!(chunk_size(b"5\n").unwrap(), 5);
+        for line in [&b"+5\r\n"[..], b"-5\r\n", b"5 \r\n", b" 5\r\n", b"5 ;ext\r\n", b"0x5\r\n", b"\r\n", b";ext\r\n", b"g\r\n", b"11111111111111111\r\n"] {
+            assert!(chunk_size(line).is_err(), "{:?}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_body() {
+        let mut reader = &b"5;ext\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\nnext"[..];
+        let mut out = Vec::new();
+        copy_payload(&mut reader, &mut out, Body::Chunked).await.unwrap();
+        assert_eq!(out, b"hello world");
+        assert_eq!(reader, b"next");
+
+        let mut reader = &b"+5\r\nhello\r\n0\r\n\r\n"[..];
+        assert!(copy_payload(&mut reader, &mut tokio::io::sink(), Body::Chunked).await.is_err());
+    }
+}
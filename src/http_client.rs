@@ -0,0 +1,157 @@
+use rustls::ClientConfig;
+use rustls_pki_types::ServerName;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// Maximum size of a response
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// System CA bundle locations tried when `SSL_CERT_FILE` is not set
+const CA_BUNDLES: [&str; 4] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+    "/usr/local/share/certs/ca-root-nss.crt",
+];
+
+/// Build a TLS connector trusting the system CA bundle, for posting to https:// URLs
+pub fn tls_connector() -> Result<TlsConnector, Box<dyn std::error::Error + Send + Sync>> {
+    let path = std::env::var("SSL_CERT_FILE")
+        .ok()
+        .or_else(|| CA_BUNDLES.iter().find(|p| Path::new(p).is_file()).map(|p| p.to_string()))
+        .ok_or("No system CA bundle found (set SSL_CERT_FILE)")?;
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(&path)?);
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader) {
+        // Skip certificates the TLS library cannot parse instead of failing on the whole bundle
+        let _ = roots.add(cert?);
+    }
+    if roots.is_empty() {
+        return Err(format!("No CA certificates found in {}", path).into());
+    }
+
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Response to a request sent with `request`
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// First value of a header (case-insensitive name)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Send a POST request over HTTP/1.0 and return the status code and response body
+///
+/// https:// URLs require a TLS connector.
+pub async fn post(
+    url: &str,
+    content_type: &str,
+    body: &[u8],
+    tls: Option<&TlsConnector>,
+) -> Result<(u16, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    let response = request("POST", url, Some(content_type), body, tls).await?;
+    Ok((response.status, response.body))
+}
+
+/// Send a request over HTTP/1.0 and return the response
+///
+/// https:// URLs require a TLS connector.
+pub async fn request(
+    method: &str,
+    url: &str,
+    content_type: Option<&str>,
+    body: &[u8],
+    tls: Option<&TlsConnector>,
+) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+    let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(format!("Unsupported URL '{}'", url).into());
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let default_port = if https { 443 } else { 80 };
+    let (host, address) = match authority.rsplit_once(':') {
+        Some((host, _)) if !authority.ends_with(']') => (host, authority.to_string()),
+        _ => (authority, format!("{}:{}", authority, default_port)),
+    };
+
+    let content_type = content_type.map_or(String::new(), |content_type| format!("Content-Type: {}\r\n", content_type));
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\n{}Content-Length: {}\r\n\r\n",
+        method,
+        path,
+        authority,
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+
+    let stream = TcpStream::connect(&address).await?;
+    let response = if https {
+        let tls = tls.ok_or("https:// URLs are not supported here")?;
+        let server_name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']').to_string())?;
+        exchange(tls.connect(server_name, stream).await?, &request).await?
+    } else {
+        exchange(stream, &request).await?
+    };
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("Malformed HTTP response")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or("");
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Malformed HTTP status line '{}'", status_line))?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(Response {
+        status,
+        headers,
+        body: response[split + 4..].to_vec(),
+    })
+}
+
+/// Write the request and read the response until the server closes the connection
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    match (&mut stream).take(MAX_RESPONSE_SIZE as u64 + 1).read_to_end(&mut response).await {
+        // Many servers close TLS connections without close_notify, the response is complete anyway
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {}
+        result => {
+            result?;
+        }
+    }
+    if response.len() > MAX_RESPONSE_SIZE {
+        return Err(std::io::Error::other("HTTP response too large"));
+    }
+    Ok(response)
+}
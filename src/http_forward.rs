@@ -0,0 +1,498 @@
+use crate::debug;
+use crate::access::{self, Tally};
+use crate::backend::{Backend, Lease};
+use crate::cache::{self, Entry};
+use crate::encoding;
+use crate::forwarded;
+use crate::hooks;
+use crate::http::{self, Body, Capture, Request, Response};
+use crate::http_routing;
+use crate::memory::Buffers;
+use crate::outlier::Outcome;
+use crate::probe;
+use crate::proxy::{self, Proxy};
+use crate::registry::Connection;
+use crate::relay;
+use crate::retry;
+use crate::timeouts::{self, ReadTimeout, Timeouts};
+use crate::trace;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Connection to one of the backends, kept alive between requests
+struct Upstream {
+    backend: Arc<Backend>,
+    stream: BufReader<ReadTimeout<TcpStream>>,
+    _lease: Lease,
+}
+
+/// Forward HTTP/1.1 requests from a client connection to the backend, one request at a time,
+/// returns whether the connection ended without errors
+pub async fn forward<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    connection: &Connection,
+    client: &mut S,
+    buffers: Buffers,
+    prefix: &str,
+) -> bool {
+    let mut client = BufReader::with_capacity(buffers.client_to_backend, Tally::new(client));
+    let mut upstream = None;
+    let mut kept_alive = false;
+    loop {
+        // While draining, idle connections are closed right away instead of waiting for their next request
+        if kept_alive {
+            tokio::select! {
+                biased;
+                _ = client.fill_buf() => {}
+                _ = proxy.drain.started() => return true,
+            }
+        }
+        kept_alive = true;
+        let mut entry = access::Entry::default();
+        let (started, written) = (Instant::now(), client.get_ref().written);
+        let result = handle_request(proxy, connection, &mut client, &mut upstream, &mut entry, buffers, prefix).await;
+        if let (Some(access_log), 1..) = (&proxy.access_log, entry.status) {
+            access_log.log(connection, &entry, client.get_ref().written - written, started.elapsed());
+        }
+        if let (Some(dashboard), 1..) = (&proxy.dashboard, entry.status) {
+            dashboard.record_request(entry.method.is_some().then_some(entry.target.as_str()), entry.status);
+        }
+        match result {
+            Ok(true) => {}
+            Ok(false) => return true,
+            Err(e) => {
+                proxy.error_log.log(format!("{}Proxy forwarding error: {}", prefix, e));
+                return false;
+            }
+        }
+    }
+}
+
+/// Handle a single request, returns whether the client connection stays open
+async fn handle_request<C: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    connection: &Connection,
+    client: &mut BufReader<C>,
+    upstream: &mut Option<Upstream>,
+    entry: &mut access::Entry,
+    buffers: Buffers,
+    prefix: &str,
+) -> Result<bool, Error> {
+    let mut head = match http::read_head(client).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return Ok(false),
+        Err(e) if e.kind() == ErrorKind::InvalidData => return fail(client, entry, e, 400, "Bad Request").await,
+        Err(e) => return Err(e),
+    };
+    connection.trace(|| format!("Request head:\n{}", trace::dump_head(&head)));
+
+    // Answer health probes without involving the backend
+    if let Some(path) = &proxy.probe_path {
+        if let Some(head_only) = probe::matches(&head, path) {
+            entry.status = 200;
+            probe::respond(client, proxy.started, head_only).await?;
+            return Ok(false);
+        }
+    }
+
+    if proxy.maintenance.applies(connection.peer.ip()) {
+        entry.status = 503;
+        proxy.maintenance.respond(client, &head).await?;
+        return Ok(false);
+    }
+
+    if let Err(reason) = hooks::check(&proxy.hooks, |hook| hook.on_request(connection, &mut head)) {
+        let e = Error::other(format!("request rejected by hook: {}", reason));
+        return fail(client, entry, e, 403, "Forbidden").await;
+    }
+
+    let (mut request, request_body) = match Request::parse(&head).and_then(|request| Ok((request.body()?, request))) {
+        Ok((body, request)) => (request, body),
+        Err(e) => return fail(client, entry, e, 400, "Bad Request").await,
+    };
+    // While draining, kept-alive clients are told to close the connection after this response
+    let closing = request.keep_alive() && proxy.drain.is_draining();
+    let keep_alive = request.keep_alive() && !closing;
+    entry.method = Some(request.method.clone());
+    entry.target = request.target.clone();
+    entry.version = request.version.clone();
+    entry.referer = request.headers.get("Referer").map(str::to_string);
+    entry.user_agent = request.headers.get("User-Agent").map(str::to_string);
+
+    // Let the client send its body right away instead of waiting for the backend to ask for it
+    if request.headers.has_token("Expect", "100-continue") {
+        request.headers.remove("Expect");
+        client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        client.flush().await?;
+    }
+
+    if proxy.forwarded_headers {
+        forwarded::apply(&mut request.headers, connection.peer.ip(), connection.local, &proxy.trusted_proxies, proxy.forwarded_style);
+    }
+
+    // Serve from the cache, or prepare revalidating a stale entry
+    let client_request = request.clone();
+    let cache = proxy
+        .cache
+        .as_ref()
+        .filter(|cache| request_body == Body::Length(0) && cache.applies(&request));
+    let mut revalidating = None;
+    let mut stale = None;
+    if let Some(cache) = cache {
+        match cache.lookup(&request).await {
+            Some(cached) if cached.is_fresh() => {
+                cache.hits.fetch_add(1, Ordering::Relaxed);
+                entry.status = serve_cached(proxy, client, &client_request, &cached, closing).await?;
+                connection.trace(|| "Response served from the cache".to_string());
+                return Ok(keep_alive);
+            }
+            Some(cached) => {
+                if request.method == "GET" && cached.has_validators() && !cache::has_conditions(&request.headers) {
+                    cached.add_conditions(&mut request);
+                    revalidating = Some(cached.clone());
+                } else {
+                    cache.misses.fetch_add(1, Ordering::Relaxed);
+                }
+                stale = cache.usable_when_failing(&cached).then_some(cached);
+            }
+            None => {
+                cache.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Requests for other paths or hosts may go to other backends
+    let upstreams = proxy.upstreams();
+    let path_route = http_routing::select_path(&upstreams.path_routes, request.path());
+    let host_route = || request.headers.get("Host").and_then(|host| http_routing::select_host(&upstreams.host_routes, host));
+    let pool = match path_route {
+        Some(route) => &route.backends,
+        None => host_route().map_or(&upstreams.backends, |route| &route.backends),
+    };
+    if let Some(route) = path_route {
+        request.target = route.target(&request.target);
+    }
+
+    // Move on from a backend that is being drained, or does not serve the request's host
+    if upstream
+        .as_ref()
+        .is_some_and(|upstream| upstream.backend.is_draining() || !pool.backends().iter().any(|backend| Arc::ptr_eq(backend, &upstream.backend)))
+    {
+        *upstream = None;
+    }
+
+    // Send the request to the backend, retrying idempotent requests on another backend
+    let timeouts = timeouts::for_path(&proxy.route_timeouts, proxy.timeouts, Some(client_request.path()));
+    let retryable = retry::is_idempotent(&request.method) && request_body == Body::Length(0);
+    let mut tried = Vec::new();
+    proxy.retry_budget.deposit();
+    let response = loop {
+        let backend = match upstream {
+            Some(upstream) => upstream.backend.clone(),
+            None => pool.pick(&tried),
+        };
+        entry.backend = Some(backend.address.clone());
+        let sent = Instant::now();
+        let result = send(proxy, &backend, connection, upstream, client, &request, request_body, timeouts, buffers, prefix).await;
+        let outcome = match &result {
+            Ok(response) if !matches!(response.status, 502..=504) => Outcome::Success(Some(sent.elapsed())),
+            _ => Outcome::Error,
+        };
+        // A client that went away says nothing about the backend
+        if let Err(e) = &result {
+            if e.kind() == ErrorKind::ConnectionAborted {
+                proxy.metrics.requests_cancelled.fetch_add(1, Ordering::Relaxed);
+                debug!("{}Client disconnected, cancelled request to http://{}", prefix, backend.address);
+                entry.status = 499;
+                return Ok(false);
+            }
+        }
+        proxy.record_outcome(pool, &backend, outcome);
+        match &result {
+            Ok(response) => connection.trace(|| {
+                format!(
+                    "Response head from http://{}, {:.1}ms after sending the request:\n{}",
+                    backend.address,
+                    sent.elapsed().as_secs_f64() * 1000.0,
+                    trace::dump_head(&response.to_bytes())
+                )
+            }),
+            Err(e) => connection.trace(|| format!("Request to http://{} failed: {}", backend.address, e)),
+        }
+        let failure = match &result {
+            Ok(response) if matches!(response.status, 502 | 503) => format!("status {}", response.status),
+            Ok(_) => break result,
+            Err(e) if e.kind() == ErrorKind::TimedOut => break result,
+            Err(e) => e.to_string(),
+        };
+        if !retryable || tried.len() >= proxy.retries as usize || !proxy.retry_budget.withdraw() {
+            break result;
+        }
+        proxy.error_log.log(format!("{}Backend http://{} failed ({}), retrying request", prefix, backend.address, failure));
+        *upstream = None;
+        tried.push(backend);
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            if let (Some(cache), Some(cached)) = (cache, &stale) {
+                proxy.error_log.log(format!("{}Backend failed ({}), serving stale cached response", prefix, e));
+                cache.stale_served.fetch_add(1, Ordering::Relaxed);
+                entry.status = serve_cached(proxy, client, &client_request, cached, closing).await?;
+                return Ok(keep_alive);
+            }
+            if e.kind() == ErrorKind::TimedOut {
+                return fail(client, entry, e, 504, "Gateway Timeout").await;
+            }
+            return fail(client, entry, e, 502, "Bad Gateway").await;
+        }
+    };
+
+    // Serve a stale response instead of a backend error
+    if let (Some(cache), Some(cached), 500..=599) = (cache, &stale, response.status) {
+        proxy.error_log.log(format!("{}Backend answered {}, serving stale cached response", prefix, response.status));
+        cache.stale_served.fetch_add(1, Ordering::Relaxed);
+        *upstream = None;
+        entry.status = serve_cached(proxy, client, &client_request, cached, closing).await?;
+        return Ok(keep_alive);
+    }
+
+    let Some(Upstream { stream: backend_stream, .. }) = upstream.as_mut() else {
+        return Err(Error::other("backend connection lost"));
+    };
+
+    // The cached response is still valid
+    if let (Some(cache), Some(cached), 304) = (cache, &revalidating, response.status) {
+        let cached = cache.freshen(&client_request, cached, &response);
+        cache.hits.fetch_add(1, Ordering::Relaxed);
+        if !response.keep_alive() {
+            *upstream = None;
+        }
+        entry.status = serve_cached(proxy, client, &client_request, &cached, closing).await?;
+        return Ok(keep_alive);
+    }
+    if revalidating.is_some() {
+        if let Some(cache) = cache {
+            cache.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Protocol upgrade (e.g. WebSocket): forward raw bytes from here on
+    entry.status = response.status;
+    if response.status == 101 {
+        client.write_all(&response.to_bytes()).await?;
+        client.flush().await?;
+        relay::relay(client, backend_stream, buffers.client_to_backend, buffers.backend_to_client).await?;
+        return Ok(false);
+    }
+
+    let backend_keep_alive = response.keep_alive();
+    let mut response = response;
+    if closing {
+        response.headers.set("Connection", "close");
+    }
+    if let Some(security_headers) = &proxy.security_headers {
+        security_headers.apply(&mut response.headers);
+    }
+    let response_body = match response.body(&request.method) {
+        Ok(body) => body,
+        Err(e) => {
+            *upstream = None;
+            return fail(client, entry, e, 502, "Bad Gateway").await;
+        }
+    };
+    let mut capture = cache
+        .filter(|_| request.method == "GET" && response.status == 200)
+        .map(|cache| Capture::new(cache.max_object()));
+
+    let compression = proxy.compression.as_ref().filter(|compression| compression.applies(&client_request, &response, response_body));
+    let result = match compression {
+        Some(_) => send_compressed(backend_stream, client, &response, response_body, capture.as_mut()).await,
+        None => {
+            client.write_all(&response.to_bytes()).await?;
+            http::copy_body(backend_stream, client, response_body, capture.as_mut()).await
+        }
+    };
+    if result.is_err() || !backend_keep_alive || response_body == Body::UntilClose {
+        *upstream = None;
+    }
+    result?;
+    connection.trace(|| "Response body sent".to_string());
+
+    if let (Some(cache), Some(capture)) = (cache, capture) {
+        if !capture.overflow {
+            cache.store(&client_request, &response, capture.data);
+        }
+    }
+    Ok(keep_alive && response_body != Body::UntilClose)
+}
+
+/// Send a request (and its body) to the backend and read the final response head
+///
+/// A reused backend connection may have been closed by the backend in the meantime, so requests
+/// without a body are retried once on a new connection.
+#[allow(clippy::too_many_arguments)]
+async fn send<C: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    backend: &Arc<Backend>,
+    connection: &Connection,
+    upstream: &mut Option<Upstream>,
+    client: &mut BufReader<C>,
+    request: &Request,
+    request_body: Body,
+    timeouts: Timeouts,
+    buffers: Buffers,
+    prefix: &str,
+) -> Result<Response, Error> {
+    let head = request.to_bytes();
+    loop {
+        let reused = upstream.is_some();
+        let backend_stream = match upstream {
+            Some(upstream) => &mut upstream.stream,
+            None => {
+                let stream = proxy::connect_backend(proxy, backend, connection, timeouts).await?;
+                connection.set_backend(&backend.address);
+                connection.trace(|| format!("Connected to backend http://{}", backend.address));
+                debug!("{}Forwarding request to http://{}", prefix, backend.address);
+                let stream = BufReader::with_capacity(buffers.backend_to_client, ReadTimeout::new(stream, timeouts));
+                let upstream = upstream.insert(Upstream {
+                    backend: backend.clone(),
+                    stream,
+                    _lease: backend.lease(),
+                });
+                &mut upstream.stream
+            }
+        };
+        backend_stream.get_mut().set_timeouts(timeouts);
+
+        let result = async {
+            backend_stream.write_all(&head).await?;
+            http::copy_body(client, backend_stream, request_body, None).await?;
+            read_response_head(backend_stream, client).await
+        }
+        .await;
+
+        match result {
+            Ok(Some(response)) => return Ok(response),
+            Ok(None) if reused && request_body == Body::Length(0) => *upstream = None,
+            Err(e) if reused && request_body == Body::Length(0) && !matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::ConnectionAborted) => {
+                *upstream = None
+            }
+            Ok(None) => {
+                *upstream = None;
+                return Err(Error::new(ErrorKind::UnexpectedEof, "backend closed the connection without a response"));
+            }
+            Err(e) => {
+                *upstream = None;
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Read the final response head, forwarding interim (1xx) responses to the client
+///
+/// Fails with `ConnectionAborted` when the client disconnects while waiting, so the backend
+/// connection is closed instead of letting the backend finish a response nobody receives.
+async fn read_response_head<C: AsyncRead + AsyncWrite + Unpin>(
+    backend: &mut BufReader<ReadTimeout<TcpStream>>,
+    client: &mut BufReader<C>,
+) -> Result<Option<Response>, Error> {
+    loop {
+        let read = http::read_head(backend);
+        tokio::pin!(read);
+        let head = tokio::select! {
+            head = &mut read => head?,
+            _ = client_closed(client) => {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "client disconnected before the response"));
+            }
+        };
+        let Some(head) = head else {
+            return Ok(None);
+        };
+        let response = Response::parse(&head)?;
+        if (100..200).contains(&response.status) && response.status != 101 {
+            client.write_all(&head).await?;
+            client.flush().await?;
+            continue;
+        }
+        return Ok(Some(response));
+    }
+}
+
+/// Read the whole response body and send it gzip-compressed, or unchanged when that does not make it smaller
+async fn send_compressed<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    backend: &mut R,
+    client: &mut W,
+    response: &Response,
+    body: Body,
+    capture: Option<&mut Capture>,
+) -> Result<(), Error> {
+    let mut data = Vec::new();
+    http::copy_body(backend, &mut data, body, capture).await?;
+    // Compressing takes a while, keep it from holding up other connections
+    let mut response = response.clone();
+    let (response, data) = tokio::task::spawn_blocking(move || {
+        let data = encoding::compress(&mut response, &data).unwrap_or(data);
+        (response, data)
+    })
+    .await
+    .map_err(Error::other)?;
+    client.write_all(&response.to_bytes()).await?;
+    client.write_all(&data).await
+}
+
+/// Completes when the client closed the connection; pipelined requests are left in the buffer
+async fn client_closed<C: AsyncRead + Unpin>(client: &mut BufReader<C>) {
+    match client.fill_buf().await {
+        Ok([]) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+/// Send a cached response, or 304 when the client already has it, returns the status sent
+async fn serve_cached<W: AsyncWrite + Unpin>(proxy: &Proxy, client: &mut W, request: &Request, entry: &Entry, closing: bool) -> Result<u16, Error> {
+    if entry.not_modified_for(request) {
+        let mut response = Response::new(304, "Not Modified");
+        if closing {
+            response.headers.set("Connection", "close");
+        }
+        for name in ["ETag", "Cache-Control", "Last-Modified", "Vary"] {
+            if let Some(value) = entry.response.headers.get(name) {
+                response.headers.set(name, value);
+            }
+        }
+        if let Some(security_headers) = &proxy.security_headers {
+            security_headers.apply(&mut response.headers);
+        }
+        client.write_all(&response.to_bytes()).await?;
+        client.flush().await?;
+        return Ok(304);
+    }
+    let mut response = entry.client_response();
+    if closing {
+        response.headers.set("Connection", "close");
+    }
+    if let Some(security_headers) = &proxy.security_headers {
+        security_headers.apply(&mut response.headers);
+    }
+    let status = response.status;
+    http::write_response(client, response, &entry.body, request.method == "HEAD").await?;
+    Ok(status)
+}
+
+/// Answer the client with an error status and close the connection, passing on the error
+async fn fail<W: AsyncWrite + Unpin>(client: &mut W, entry: &mut access::Entry, error: Error, status: u16, reason: &str) -> Result<bool, Error> {
+    entry.status = status;
+    let mut response = Response::new(status, reason);
+    response.headers.set("Content-Type", "text/plain");
+    response.headers.set("Connection", "close");
+    let body = format!("{} {}\n", status, reason);
+    // The client may already be gone, the original error is the one worth reporting
+    let _ = http::write_response(client, response, body.as_bytes(), false).await;
+    Err(error)
+}
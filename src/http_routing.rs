@@ -0,0 +1,128 @@
+use crate::backend::{Pool, Strategy};
+use crate::tags;
+use std::time::Duration;
+
+/// A `--host-route` rule: requests for a host go to other backends
+#[derive(Debug, Clone)]
+pub struct HostRule {
+    /// Exact host name, or wildcard of the form `*.example.com`
+    pub pattern: String,
+    /// Comma-separated backend addresses replacing the default backends
+    pub backend: String,
+}
+
+/// Parse a `<host>=<backend>[,<backend>...]` rule from the command line
+pub fn parse_host_route(s: &str) -> Result<HostRule, String> {
+    let (pattern, backend) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid host route '{}'. Expected format: <host>=<backend>[,<backend>...]", s))?;
+    if pattern.is_empty() || backend.is_empty() {
+        return Err(format!("Invalid host route '{}'. Host and backend must be non-empty", s));
+    }
+    Ok(HostRule {
+        pattern: pattern.trim_end_matches('.').to_ascii_lowercase(),
+        backend: backend.to_string(),
+    })
+}
+
+/// Host rule with the backends it routes to
+#[derive(Debug)]
+pub struct HostRoute {
+    pub rule: HostRule,
+    pub backends: Pool,
+}
+
+impl HostRoute {
+    pub fn new(rule: HostRule, strategy: Strategy, slow_start: Option<Duration>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let backends = Pool::new(&rule.backend, strategy, slow_start)?;
+        Ok(Self { rule, backends })
+    }
+}
+
+/// The route for a request's `Host` header: the exact host name, or else the wildcard with the
+/// longest suffix, so `*.api.example.com` wins over `*.example.com`
+pub fn select_host<'a>(routes: &'a [HostRoute], host: &str) -> Option<&'a HostRoute> {
+    let host = host_name(host);
+    routes.iter().find(|route| route.rule.pattern == host).or_else(|| {
+        routes
+            .iter()
+            .filter(|route| route.rule.pattern.starts_with("*.") && tags::matches_pattern(&route.rule.pattern, &host))
+            .max_by_key(|route| route.rule.pattern.len())
+    })
+}
+
+/// A `--path-route` rule: requests for paths under a prefix go to other backends
+#[derive(Debug, Clone)]
+pub struct PathRule {
+    /// Path prefix, matching whole path segments (`/api` matches `/api` and `/api/users`, not `/apis`)
+    pub prefix: String,
+    /// Comma-separated backend addresses replacing the default backends
+    pub backend: String,
+    /// Remove the prefix from the path before forwarding
+    pub strip: bool,
+}
+
+/// Parse a `<prefix>=<backend>[,<backend>...][;strip]` rule from the command line
+pub fn parse_path_route(s: &str) -> Result<PathRule, String> {
+    let (prefix, backend) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid path route '{}'. Expected format: <prefix>=<backend>[,<backend>...][;strip]", s))?;
+    let (backend, strip) = match backend.split_once(';') {
+        Some((backend, "strip")) => (backend, true),
+        Some((_, option)) => return Err(format!("Invalid path route '{}'. Unknown option '{}', expected strip", s, option)),
+        None => (backend, false),
+    };
+    if !prefix.starts_with('/') || backend.is_empty() {
+        return Err(format!("Invalid path route '{}'. The prefix must start with / and the backend be non-empty", s));
+    }
+    Ok(PathRule {
+        prefix: prefix.trim_end_matches('/').to_string(),
+        backend: backend.to_string(),
+        strip,
+    })
+}
+
+/// Path rule with the backends it routes to
+#[derive(Debug)]
+pub struct PathRoute {
+    pub rule: PathRule,
+    pub backends: Pool,
+}
+
+impl PathRoute {
+    pub fn new(rule: PathRule, strategy: Strategy, slow_start: Option<Duration>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let backends = Pool::new(&rule.backend, strategy, slow_start)?;
+        Ok(Self { rule, backends })
+    }
+
+    /// The request target to forward: without the prefix when stripping it, but still starting with `/`
+    pub fn target(&self, target: &str) -> String {
+        match target.strip_prefix(&self.rule.prefix).filter(|_| self.rule.strip) {
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            Some(rest) => format!("/{}", rest),
+            None => target.to_string(),
+        }
+    }
+}
+
+/// The route with the longest prefix the request's path is under
+pub fn select_path<'a>(routes: &'a [PathRoute], path: &str) -> Option<&'a PathRoute> {
+    routes
+        .iter()
+        .filter(|route| {
+            path.strip_prefix(&route.rule.prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|route| route.rule.prefix.len())
+}
+
+/// Host name of a `Host` header, without port and trailing dot
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = match host.strip_prefix('[') {
+        // IPv6 address, with or without port
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,67 @@
+//! Minimalistic HTTPS reverse proxy that adds TLS encryption to any HTTP server
+//!
+//! The `https-wrapper` binary is built from these modules; embedders can construct a
+//! [`proxy::Proxy`] and pass accepted connections to [`proxy::handle_connection`].
+
+pub mod access;
+pub mod acme;
+pub mod address;
+pub mod admin;
+pub mod alerts;
+pub mod backend;
+pub mod cache;
+pub mod certificate;
+pub mod client_auth;
+pub mod close;
+pub mod compress;
+pub mod config;
+pub mod drain;
+pub mod dscp;
+pub mod encoding;
+pub mod events;
+pub mod expiry;
+pub mod fds;
+pub mod forwarded;
+pub mod h2;
+pub mod handshake;
+pub mod health;
+pub mod hooks;
+pub mod hpack;
+pub mod http;
+pub mod http_client;
+pub mod http_forward;
+pub mod http_routing;
+pub mod json;
+pub mod listener;
+pub mod log;
+pub mod logs;
+pub mod maintenance;
+pub mod memory;
+pub mod metrics;
+pub mod ocsp;
+pub mod outlier;
+pub mod plaintext;
+pub mod privileges;
+pub mod probe;
+pub mod profile;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod records;
+pub mod redact;
+pub mod registry;
+pub mod relay;
+pub mod resolver;
+pub mod retry;
+pub mod routing;
+pub mod sandbox;
+pub mod security;
+pub mod service;
+pub mod session;
+pub mod starttls;
+pub mod supervisor;
+pub mod tags;
+pub mod throttle;
+pub mod timeouts;
+pub mod trace;
+pub mod tui;
+pub mod webhook;
@@ -0,0 +1,351 @@
+use crate::{error, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// First delay between bind attempts, doubled after every failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Upper bound for the delay between bind attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// First delay after a resource exhaustion error in the accept loop
+const INITIAL_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Upper bound for the delay after repeated accept errors
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Interval between keepalive probes once a connection is idle
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Unanswered keepalive probes after which the connection is considered dead
+const KEEPALIVE_PROBES: libc::c_int = 3;
+
+/// Bind a listener, retrying with exponential backoff for up to `retry` when the address is still in use
+pub async fn bind(
+    addr: SocketAddr,
+    reuse_addr: bool,
+    backlog: u32,
+    retry: Option<Duration>,
+    mptcp: bool,
+) -> Result<TcpListener, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        match try_bind(addr, reuse_addr, backlog, mptcp) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => {
+                let remaining = retry
+                    .and_then(|retry| retry.checked_sub(started.elapsed()))
+                    .filter(|remaining| !remaining.is_zero());
+                match remaining {
+                    Some(remaining) if is_retryable(&e) => {
+                        let delay = backoff.min(remaining);
+                        warn!("Failed to bind {}: {} (retrying in {:.1}s)", addr, e, delay.as_secs_f64());
+                        tokio::time::sleep(delay).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                    _ => return Err(format!("Failed to bind {}: {}", addr, e).into()),
+                }
+            }
+        }
+    }
+}
+
+/// Create, configure and bind the listening socket
+fn try_bind(addr: SocketAddr, reuse_addr: bool, backlog: u32, mptcp: bool) -> std::io::Result<TcpListener> {
+    let socket = match tcp_socket(&addr, mptcp) {
+        Ok(socket) => socket,
+        // Fall back to all IPv4 addresses on hosts without IPv6
+        Err(e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) && addr.is_ipv6() && addr.ip().is_unspecified() => {
+            return try_bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port()), reuse_addr, backlog, mptcp);
+        }
+        Err(e) => return Err(e),
+    };
+    socket.set_reuseaddr(reuse_addr)?;
+    // The wildcard address accepts IPv4 clients as well (off by default on the BSDs)
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        setsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Create a socket for the address, a multipath TCP one when asked for and the kernel supports it
+pub fn tcp_socket(addr: &SocketAddr, mptcp: bool) -> std::io::Result<TcpSocket> {
+    if mptcp {
+        if let Some(socket) = mptcp_socket(addr)? {
+            return Ok(socket);
+        }
+    }
+    match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+}
+
+/// Whether the kernel hands out multipath TCP sockets
+pub fn mptcp_supported() -> bool {
+    matches!(mptcp_socket(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))), Ok(Some(_)))
+}
+
+/// A multipath TCP socket, or none when MPTCP is not built into the kernel or disabled with the
+/// `net.mptcp.enabled` sysctl (peers without MPTCP are still served, over a single plain TCP path)
+#[cfg(target_os = "linux")]
+fn mptcp_socket(addr: &SocketAddr) -> std::io::Result<Option<TcpSocket>> {
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, libc::IPPROTO_MPTCP) };
+    if fd < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EPROTONOSUPPORT | libc::ENOPROTOOPT | libc::EINVAL) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok(Some(unsafe { TcpSocket::from_raw_fd(fd) }))
+}
+
+/// Multipath TCP is only available on Linux
+#[cfg(not(target_os = "linux"))]
+fn mptcp_socket(_addr: &SocketAddr) -> std::io::Result<Option<TcpSocket>> {
+    Ok(None)
+}
+
+/// First descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take over a listening socket bound by a launcher: the given descriptor, or the first socket
+/// passed by systemd socket activation (`LISTEN_FDS`), so binding privileged ports needs no root
+pub fn inherited(fd: Option<RawFd>) -> Result<Option<std::net::TcpListener>, Box<dyn std::error::Error + Send + Sync>> {
+    let fd = match fd {
+        Some(fd) => fd,
+        None => {
+            let activated = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id())
+                && std::env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok()).is_some_and(|fds| fds >= 1);
+            // Not meant for commands started by event hooks
+            for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+                std::env::remove_var(name);
+            }
+            if !activated {
+                return Ok(None);
+            }
+            SD_LISTEN_FDS_START
+        }
+    };
+
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN, &mut listening as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    if result != 0 {
+        return Err(format!("File descriptor {} is not a socket: {}", fd, std::io::Error::last_os_error()).into());
+    }
+    if listening == 0 {
+        return Err(format!("Socket {} is not listening", fd).into());
+    }
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// Errors that may resolve by themselves (port released by a predecessor, address not yet assigned)
+fn is_retryable(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable
+    )
+}
+
+/// Accept the next connection, riding out transient errors instead of failing
+///
+/// Aborted handshakes are skipped immediately, while other errors (such as running out of file
+/// descriptors) back off exponentially so the loop does not spin while resources are exhausted.
+pub async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = INITIAL_ACCEPT_BACKOFF;
+
+    loop {
+        match listener.accept().await {
+            Ok(connection) => return connection,
+            Err(e) if is_aborted(&e) => continue,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) => {
+                error!("Accept error: {}, out of file descriptors (retrying in {}ms)", e, backoff.as_millis());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+            Err(e) => {
+                error!("Accept error: {} (retrying in {}ms)", e, backoff.as_millis());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Cap on the number of connections handled at the same time
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    semaphore: Arc<Semaphore>,
+    limit: usize,
+    /// Whether reaching the limit has been logged since the last free slot
+    full: AtomicBool,
+}
+
+impl ConnectionLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit,
+            full: AtomicBool::new(false),
+        }
+    }
+
+    /// Wait for a free slot, held until the returned permit is dropped
+    ///
+    /// New clients are not accepted in the meantime, so they queue in the listen backlog (and are
+    /// refused by the kernel once it is full) instead of each consuming a task and buffers.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let full = self.semaphore.available_permits() == 0;
+        if full && !self.full.swap(true, Ordering::Relaxed) {
+            warn!("Connection limit of {} reached, new clients wait in the listen backlog", self.limit);
+        } else if !full {
+            self.full.store(false, Ordering::Relaxed);
+        }
+        self.semaphore.clone().acquire_owned().await.expect("connection semaphore is never closed")
+    }
+}
+
+/// Errors caused by a single client giving up before the connection was accepted
+fn is_aborted(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Enable TCP keepalive probes on a client connection after it has been idle for `idle`, so dead
+/// peers (e.g. mobile clients that lost coverage) are detected instead of lingering forever
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    let fd = stream.as_raw_fd();
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_keepalive_timing(fd, idle)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos", target_os = "ios"))]
+fn set_keepalive_timing(fd: libc::c_int, idle: Duration) -> std::io::Result<()> {
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let idle_option = libc::TCP_KEEPALIVE;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let idle_option = libc::TCP_KEEPIDLE;
+
+    setsockopt(fd, libc::IPPROTO_TCP, idle_option, idle.as_secs() as libc::c_int)?;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, KEEPALIVE_INTERVAL.as_secs() as libc::c_int)?;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, KEEPALIVE_PROBES)
+}
+
+/// Other platforms (e.g. OpenBSD) only have system-wide keepalive timing
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos", target_os = "ios")))]
+fn set_keepalive_timing(_fd: libc::c_int, _idle: Duration) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Smallest MTU every IPv6 link (and therefore every tunnel) carries
+const MIN_IPV6_MTU: u16 = 1280;
+
+/// Maximum segment size advertised to clients, see `clamp_mss`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mss {
+    /// Segments fitting into the minimum IPv6 MTU, whatever tunnels the path goes through
+    Auto,
+    Bytes(u16),
+}
+
+impl Mss {
+    /// Segment size for connections of the address family
+    pub fn bytes(self, ipv6: bool) -> u16 {
+        match self {
+            // Minus the IP (20 or 40 bytes) and TCP (20 bytes) headers
+            Mss::Auto if ipv6 => MIN_IPV6_MTU - 60,
+            Mss::Auto => MIN_IPV6_MTU - 40,
+            Mss::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// Parse `auto` or a segment size in bytes
+pub fn parse_mss(s: &str) -> Result<Mss, String> {
+    if s == "auto" {
+        return Ok(Mss::Auto);
+    }
+    match s.parse::<u16>() {
+        // The kernel rejects segments too small to carry the TCP options
+        Ok(bytes) if bytes >= 88 => Ok(Mss::Bytes(bytes)),
+        _ => Err(format!("Invalid MSS '{}'. Expected 'auto' or a number of bytes from 88 to 65535", s)),
+    }
+}
+
+/// Clamp the maximum segment size of connections accepted by the listener, which is advertised in
+/// their SYN-ACK and limits the segments sent to clients, so handshakes do not stall behind
+/// tunnels (WireGuard, GRE) whose smaller MTU is hidden by blackholed ICMP messages
+pub fn clamp_mss(listener: &TcpListener, mss: Mss) -> std::io::Result<u16> {
+    let bytes = mss.bytes(listener.local_addr()?.is_ipv6());
+    setsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_MAXSEG, bytes.into())?;
+    Ok(bytes)
+}
+
+/// Set an integer socket option
+pub fn setsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Another listener given with `--listener`, served by the same process
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    /// Address to listen on, like the input address
+    pub input: String,
+    /// Backends to forward to, like the output address
+    pub output: String,
+    /// Certificate file (and key file, for PEM certificates not holding the key themselves), the main certificate when not set
+    pub cert: Option<(String, Option<String>)>,
+}
+
+/// Parse a `<input>=<output>[=<cert>[,<key>]]` listener from the command line
+pub fn parse_mapping(s: &str) -> Result<Mapping, String> {
+    let mut parts = s.splitn(3, '=');
+    let (input, output) = match (parts.next(), parts.next()) {
+        (Some(input), Some(output)) => (input, output),
+        _ => return Err(format!("Invalid listener '{}'. Expected format: <input>=<output>[=<cert>[,<key>]]", s)),
+    };
+    let cert = parts.next().map(|files| match files.split_once(',') {
+        Some((cert, key)) => (cert.to_string(), Some(key.to_string())),
+        None => (files.to_string(), None),
+    });
+    let empty_file = cert.as_ref().is_some_and(|(cert, key)| cert.is_empty() || key.as_deref() == Some(""));
+    if input.is_empty() || output.is_empty() || empty_file {
+        return Err(format!("Invalid listener '{}'. Addresses and files must be non-empty", s));
+    }
+    Ok(Mapping {
+        input: input.to_string(),
+        output: output.to_string(),
+        cert,
+    })
+}
@@ -0,0 +1,153 @@
+use crate::access::rfc3339;
+use crate::admin::json_string;
+use clap::ValueEnum;
+use std::fmt::{Arguments, Display};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Importance of a log line, lines below the configured level are not logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    /// Failures, e.g. of backend connections or TLS handshakes
+    Error,
+    /// Problems the proxy works around, e.g. certificates about to expire
+    Warn,
+    /// Startup, reloads and state changes, e.g. backends leaving the rotation
+    Info,
+    /// Every forwarded request
+    Debug,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain lines, as read by people
+    Text,
+    /// One JSON object per line with the time, level, message and connection fields, for log aggregation
+    Json,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+/// Fields of the connection a task is handling, added to its JSON log lines
+#[derive(Debug, Default)]
+struct Context {
+    fields: Mutex<Vec<(&'static str, String)>>,
+}
+
+/// Set the lowest level logged and the output format, for the whole process
+pub fn init(level: Level, format: LogFormat) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write a line if its level is enabled: errors and warnings to standard error, the rest to
+/// standard output
+pub fn write(level: Level, fields: &[(&str, &dyn Display)], message: Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let line = match JSON.load(Ordering::Relaxed) {
+        true => {
+            let mut line = format!(
+                "{{\"time\":\"{}\",\"level\":\"{}\",\"message\":{}",
+                rfc3339(SystemTime::now()),
+                level.name(),
+                json_string(&message.to_string())
+            );
+            let _ = CONTEXT.try_with(|context| {
+                for (key, value) in context.fields.lock().unwrap().iter() {
+                    line.push_str(&format!(",\"{}\":{}", key, json_string(value)));
+                }
+            });
+            for (key, value) in fields {
+                line.push_str(&format!(",\"{}\":{}", key, json_string(&value.to_string())));
+            }
+            line.push('}');
+            line
+        }
+        // The message already names what it is about
+        false => message.to_string(),
+    };
+    match level {
+        Level::Error | Level::Warn => eprintln!("{}", line),
+        Level::Info | Level::Debug => println!("{}", line),
+    }
+}
+
+/// Run a connection's future with its own log fields, see `set`
+pub async fn scope<F: Future>(future: F) -> F::Output {
+    CONTEXT.scope(Context::default(), future).await
+}
+
+/// Run a future spawned for a connection (e.g. an HTTP/2 stream) with a copy of the current log fields
+pub async fn inherit<F: Future>(future: F) -> F::Output {
+    let fields = CONTEXT.try_with(|context| context.fields.lock().unwrap().clone()).unwrap_or_default();
+    CONTEXT.scope(Context { fields: Mutex::new(fields) }, future).await
+}
+
+/// Set a field of the connection handled by the current task (within `scope`), replacing its
+/// previous value
+pub fn set(key: &'static str, value: impl Display) {
+    let _ = CONTEXT.try_with(|context| {
+        let mut fields = context.fields.lock().unwrap();
+        let value = value.to_string();
+        match fields.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, previous)) => *previous = value,
+            None => fields.push((key, value)),
+        }
+    });
+}
+
+/// Log at the given level, with optional fields before the message: `log!(Level::Info, backend = address; "...", ...)`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+; $($arg:tt)+) => {
+        $crate::log::write($level, &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+], format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::write($level, &[], format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
@@ -0,0 +1,180 @@
+use crate::access::rfc3339;
+use crate::admin::json_string;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// How long the captured output may take to reach the original outputs once the capture ends
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest line kept, longer lines are cut off
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// A line written to standard output or error
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub time: SystemTime,
+    pub stream: &'static str,
+    pub text: String,
+}
+
+/// The most recent log lines, kept in memory for the admin API and the terminal dashboard
+#[derive(Debug)]
+pub struct LogBuffer {
+    lines: Mutex<VecDeque<LogLine>>,
+    capacity: usize,
+    /// Whether captured output is kept from the original outputs, while the dashboard is shown
+    hidden: AtomicBool,
+    /// Original standard output and error, restored when the capture ends
+    originals: OnceLock<(OwnedFd, OwnedFd)>,
+}
+
+/// Standard output and error captured into the buffer, restored when dropped
+pub struct Capture {
+    logs: Arc<LogBuffer>,
+    done: Receiver<()>,
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = std::io::stdout().flush();
+        if let Some((stdout, stderr)) = self.logs.originals.get() {
+            // SAFETY: duplicating valid descriptors onto the standard ones
+            unsafe {
+                libc::dup2(stdout.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(stderr.as_raw_fd(), libc::STDERR_FILENO);
+            }
+        }
+        // Restoring closed the pipes, let the readers pass on what is left (unless a child process still holds them)
+        for _ in 0..2 {
+            let _ = self.done.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            hidden: AtomicBool::new(false),
+            originals: OnceLock::new(),
+        }
+    }
+
+    /// Redirect standard output and error through pipes, keeping their lines while passing them on
+    pub fn capture(self: &Arc<Self>) -> std::io::Result<Capture> {
+        // SAFETY: plain libc calls on the standard descriptors and new pipes
+        let (stdout, stderr) = unsafe { (dup(libc::STDOUT_FILENO)?, dup(libc::STDERR_FILENO)?) };
+        let (sender, done) = mpsc::channel();
+        for (fd, stream, original) in [(libc::STDOUT_FILENO, "stdout", &stdout), (libc::STDERR_FILENO, "stderr", &stderr)] {
+            let mut fds = [0; 2];
+            // SAFETY: the pipe's descriptors are owned by the File and OwnedFd right away
+            let (mut reader, writer) = unsafe {
+                if libc::pipe(fds.as_mut_ptr()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))
+            };
+            let mut output = File::from(original.try_clone()?);
+            let _ = std::io::stdout().flush();
+            // SAFETY: duplicating a valid descriptor onto a standard one
+            if unsafe { libc::dup2(writer.as_raw_fd(), fd) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            // Ends once the standard descriptor is restored, as that closes the last writer
+            let (logs, sender) = (self.clone(), sender.clone());
+            std::thread::spawn(move || {
+                let (mut chunk, mut pending) = ([0u8; 8192], Vec::new());
+                loop {
+                    let n = match reader.read(&mut chunk) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => n,
+                    };
+                    if !logs.hidden.load(Ordering::Relaxed) {
+                        let _ = output.write_all(&chunk[..n]);
+                    }
+                    pending.extend_from_slice(&chunk[..n]);
+                    while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = pending.drain(..=end).collect();
+                        logs.push(stream, &line[..end]);
+                    }
+                    if pending.len() > MAX_LINE_LENGTH {
+                        logs.push(stream, &pending);
+                        pending.clear();
+                    }
+                }
+                if !pending.is_empty() {
+                    logs.push(stream, &pending);
+                }
+                let _ = sender.send(());
+            });
+        }
+        let _ = self.originals.set((stdout, stderr));
+        Ok(Capture { logs: self.clone(), done })
+    }
+
+    fn push(&self, stream: &'static str, line: &[u8]) {
+        let text = String::from_utf8_lossy(&line[..line.len().min(MAX_LINE_LENGTH)]).trim_end_matches('\r').to_string();
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(LogLine {
+            time: SystemTime::now(),
+            stream,
+            text,
+        });
+    }
+
+    /// Keep captured output from (or pass it on to again) the original outputs
+    pub fn set_hidden(&self, hidden: bool) {
+        self.hidden.store(hidden, Ordering::Relaxed);
+    }
+
+    /// The original standard output, to draw the dashboard on while the output is hidden
+    pub fn terminal(&self) -> std::io::Result<File> {
+        match self.originals.get() {
+            Some((stdout, _)) => Ok(File::from(stdout.try_clone()?)),
+            None => Err(std::io::Error::other("standard output is not captured")),
+        }
+    }
+
+    /// The last `count` lines, oldest first
+    pub fn recent(&self, count: usize) -> Vec<LogLine> {
+        let lines = self.lines.lock().unwrap();
+        lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect()
+    }
+
+    /// Render the last `count` lines as a JSON array
+    pub fn render_json(&self, count: usize) -> String {
+        let entries: Vec<String> = self
+            .recent(count)
+            .iter()
+            .map(|line| {
+                format!(
+                    "{{\"time\":{},\"stream\":{},\"line\":{}}}",
+                    json_string(&rfc3339(line.time)),
+                    json_string(line.stream),
+                    json_string(&line.text)
+                )
+            })
+            .collect();
+        format!("[{}]\n", entries.join(","))
+    }
+}
+
+/// Duplicate a descriptor, closing the copy when dropped
+unsafe fn dup(fd: libc::c_int) -> std::io::Result<OwnedFd> {
+    let copy = libc::dup(fd);
+    if copy < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(OwnedFd::from_raw_fd(copy))
+}
@@ -1,28 +1,45 @@
-use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::rustls::ServerConfig;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use clap::Parser;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use clap::{CommandFactory, Parser};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use zeroize::Zeroizing;
+use https_wrapper::{
+    access, acme, address, admin, alerts, backend, cache, certificate, client_auth, close, compress, config, drain, dscp, encoding, events, expiry, fds, forwarded, health, http, http_routing, listener, log, logs, maintenance, memory, metrics, ocsp, outlier, plaintext, privileges,
+    probe, profile, proxy, proxy_protocol, records, redact, registry, resolver, retry, routing, sandbox, security, service, session, starttls, supervisor, tags, throttle, timeouts, trace, tui, webhook,
+};
+use https_wrapper::{error, info, warn};
 
-mod certificate;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "https-wrapper")]
 #[command(about = "Minimalistic HTTPS wrapper to provide TLS layer to your HTTP server", long_about = None)]
 struct Args {
-    /// Input address (HTTPS server listens on this address, format: ip:port)
+    /// Read settings from a TOML file, named after the command line options (which override them)
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Input address (HTTPS server listens on this address, format: ip:port, [ipv6]:port, host:port or a bare port for all addresses)
     #[arg(value_name = "INPUT_ADDRESS")]
     input_address: String,
 
-    /// Output address (HTTP server to forward requests to, format: ip:port)
+    /// Output address (HTTP server to forward requests to, format: ip:port, [ipv6]:port, host:port or a bare port on localhost, comma-separated for several backends used in turn)
     #[arg(value_name = "OUTPUT_ADDRESS")]
     output_address: String,
 
-    /// Path to certificate file (positional argument, .pfx/.p12 or .pem/.crt)
+    /// Path to certificate file (positional argument, PFX/PKCS#12, PEM, DER or PKCS#7 bundle)
     #[arg(value_name = "CERTIFICATE", conflicts_with_all = ["pfx", "cert"])]
     certificate: Option<String>,
 
-    /// Password for PFX or second positional arg as key file for PEM
+    /// Password for PFX or second positional arg as key file for PEM (optional when the PEM file also holds the key)
     #[arg(value_name = "PASSWORD_OR_KEY")]
     password_or_key: Option<String>,
 
@@ -39,91 +56,1402 @@ struct Args {
     #[arg(long, value_name = "KEY_FILE", requires = "cert")]
     key: Option<String>,
 
-    /// Password for PFX file
+    /// Second certificate with another key type (e.g. RSA next to ECDSA), served to clients that do not support the first one's
+    #[arg(long, value_name = "CERT_FILE", requires = "second_key")]
+    second_cert: Option<String>,
+
+    /// Private key of the second certificate
+    #[arg(long, value_name = "KEY_FILE", requires = "second_cert")]
+    second_key: Option<String>,
+
+    /// Serve another certificate to clients asking for a server name (repeatable, format: name=cert[,key], wildcards like *.example.com allowed); the main certificate is the default
+    #[arg(long = "sni-cert", value_name = "NAME=CERT[,KEY]", value_parser = resolver::parse_sni_cert)]
+    sni_certs: Vec<resolver::SniCertFiles>,
+
+    /// Refuse handshakes for server names no certificate covers instead of serving the default certificate
+    #[arg(long)]
+    sni_strict: bool,
+
+    /// Password for PFX file (or encrypted PEM private key)
     #[arg(long, value_name = "PASSWORD")]
     password: Option<String>,
+
+    /// Only accept clients presenting a certificate signed by a CA in this PEM file or http(s):// URL (mutual TLS, e.g. for the origin pulls of a CDN)
+    #[arg(long, value_name = "FILE|URL")]
+    client_ca: Option<String>,
+
+    /// SHA-256 of the --client-ca bundle (hex), required for URLs so only the expected CAs are trusted
+    #[arg(long, value_name = "HEX", requires = "client_ca")]
+    client_ca_sha256: Option<String>,
+
+    /// Only accept connections from a CDN's origin pulls, requiring its client certificate (sets --client-ca to the CDN's published CA)
+    #[arg(long, value_enum, value_name = "CDN", conflicts_with = "client_ca")]
+    origin_pull: Option<client_auth::OriginPull>,
+
+    /// Address for the admin API (disabled when not set, format: ip:port)
+    #[arg(long, value_name = "ADMIN_ADDRESS")]
+    admin: Option<SocketAddr>,
+
+    /// Address serving only the metrics of the admin API at /metrics, for Prometheus to scrape without exposing the admin actions (format: ip:port)
+    #[arg(long, value_name = "METRICS_ADDRESS")]
+    metrics: Option<SocketAddr>,
+
+    /// Log lines kept in memory for GET /logs on the admin API (0 leaves the log output alone)
+    #[arg(long, value_name = "LINES", default_value_t = 1000)]
+    admin_log_lines: usize,
+
+    /// Tag connections by SNI for logging and filtering (repeatable, format: sni=tag, wildcards like *.example.com allowed)
+    #[arg(long = "tag", value_name = "SNI=TAG", value_parser = tags::parse_tag_rule)]
+    tags: Vec<tags::TagRule>,
+
+    /// How to answer plain HTTP requests sent to the HTTPS port
+    #[arg(long, value_enum, value_name = "MODE", default_value = "reject")]
+    plain_http: plaintext::PlainHttpMode,
+
+    /// Development mode: answer plain HTTP requests on the HTTPS port with a temporary redirect explaining the https:// URL, so `curl localhost:8443` shows what to do instead of a TLS error
+    #[arg(long, conflicts_with = "plain_http")]
+    dev: bool,
+
+    /// Also listen for plain HTTP on this address (e.g. 0.0.0.0:80) and redirect every request to the same host and path on the HTTPS port
+    #[arg(long, value_name = "IP:PORT")]
+    redirect_http: Option<SocketAddr>,
+
+    /// Answer ACME HTTP-01 challenges sent to the HTTPS port in plain HTTP from this directory, where an ACME client like `certbot --webroot` writes them (under .well-known/acme-challenge)
+    #[arg(long, value_name = "DIR")]
+    acme_webroot: Option<PathBuf>,
+
+    /// Wait until the backend is connectable before accepting connections (optional timeout in seconds, waits indefinitely when omitted)
+    #[arg(long, value_name = "TIMEOUT", num_args = 0..=1, default_missing_value = "0")]
+    wait_for_backend: Option<u64>,
+
+    /// Serve another listener in this process (repeatable, format: input=output[=cert[,key]]), with the same options; without a certificate the main one is served
+    #[arg(long = "listener", value_name = "INPUT=OUTPUT[=CERT[,KEY]]", value_parser = listener::parse_mapping)]
+    listeners: Vec<listener::Mapping>,
+
+    /// Keep retrying to bind the input address for this many seconds when it is still in use
+    #[arg(long, value_name = "SECONDS")]
+    bind_retry: Option<u64>,
+
+    /// Accept connections on this inherited listening socket (bound by a launcher) instead of binding the input address; systemd socket activation is detected automatically
+    #[arg(long, value_name = "FD")]
+    listen_fd: Option<i32>,
+
+    /// Clamp the TCP maximum segment size of client connections to this many bytes, or `auto` for segments fitting any tunnel (1240 for IPv4, 1220 for IPv6)
+    #[arg(long, value_name = "BYTES", value_parser = listener::parse_mss)]
+    tcp_mss: Option<listener::Mss>,
+
+    /// Set SO_REUSEADDR on the listening socket
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    reuse_addr: bool,
+
+    /// Send TCP keepalive probes (and HTTP/2 PING frames in HTTP mode) to clients idle for this many seconds, closing connections to dead clients (0 disables)
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    client_keepalive: u64,
+
+    /// Set SO_LINGER on client connections: closing waits up to this many seconds for unsent data to be delivered (0 discards it and resets the connection)
+    #[arg(long, value_name = "SECS")]
+    linger: Option<u64>,
+
+    /// Close client connections with a TCP reset instead of a FIN when forwarding fails or is aborted
+    #[arg(long)]
+    reset_on_error: bool,
+
+    /// How to end the TLS session when a connection finishes normally: wait for the client's close_notify, send ours and close, or close without close_notify
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "send")]
+    close_notify: close::CloseNotify,
+
+    /// Raise the soft limit on open file descriptors to the hard limit at startup
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    raise_fd_limit: bool,
+
+    /// Route connections by SNI, ALPN or client address to other backends or timeouts (repeatable, TCP mode, e.g. 'sni=mqtt.example.com => backend=127.0.0.1:1883 read-timeout=600')
+    #[arg(long = "route", value_name = "CONDITIONS => ACTIONS", value_parser = routing::parse_route)]
+    routes: Vec<routing::RouteRule>,
+
+    /// ALPN protocols to advertise in order of preference (e.g. h2,http/1.1); h2 connections are passed to the backend as cleartext HTTP/2 (h2c), or terminated in HTTP mode
+    #[arg(long, value_name = "PROTOCOLS", value_delimiter = ',', value_parser = routing::parse_alpn_protocol)]
+    alpn: Vec<String>,
+
+    /// Handle at most this many connections at the same time, further clients wait in the listen backlog
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    max_connections: Option<u32>,
+
+    /// Seconds open connections get to finish on SIGTERM or SIGINT before they are aborted (0 aborts them right away)
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    drain_timeout: u64,
+
+    /// What to do when the accept loop or a background task (admin API, HTTP redirects, configuration reloading, ...) panics or stops
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = supervisor::RestartPolicy::Restart)]
+    on_task_failure: supervisor::RestartPolicy,
+
+    /// Maximum number of pending connections waiting to be accepted
+    #[arg(long, value_name = "SIZE", default_value_t = 1024)]
+    backlog: u32,
+
+    /// Switch to this user (name or uid) after binding the listeners and loading certificates
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+
+    /// Switch to this group (name or gid) after binding the listeners, defaults to the user's primary group
+    #[arg(long, value_name = "GROUP")]
+    group: Option<String>,
+
+    /// Restrict syscalls and filesystem access after initialization (seccomp and Landlock on Linux, pledge and unveil on OpenBSD)
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Staple OCSP responses fetched from the certificate's OCSP responder (refreshed automatically)
+    #[arg(long)]
+    ocsp_stapling: bool,
+
+    /// Compress the certificate chain in TLS 1.3 handshakes for clients supporting it (RFC 8879)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    cert_compression: bool,
+
+    /// TLS record sizing: small records first for faster page loads (latency) or always full-size records (throughput)
+    #[arg(long, value_enum, value_name = "PROFILE", default_value = "latency")]
+    record_sizing: records::RecordSizing,
+
+    /// Cap on the buffer memory of all connections in MiB; connections get smaller buffers or wait when it is reached
+    #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(1..))]
+    memory_budget: Option<u64>,
+
+    /// Forget TLS sessions and rotate the session ticket keys when the certificate is reloaded (SIGHUP), instead of keeping resumption working
+    #[arg(long)]
+    rotate_sessions_on_reload: bool,
+
+    /// Reload the certificate when its files change, checking every SECS seconds (10 when omitted)
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "10")]
+    watch_cert: Option<u64>,
+
+    /// Run a shell command when an event fires (EVENT=COMMAND, event `*` matches all), can be repeated
+    #[arg(long = "on-event", value_name = "EVENT=COMMAND", value_parser = events::parse_event_command)]
+    event_commands: Vec<events::EventCommand>,
+
+    /// Warn (and fire the cert_expiring event) when the certificate expires within this many days
+    #[arg(long, value_name = "DAYS", default_value_t = 14)]
+    cert_expiry_warning: u32,
+
+    /// Obtain a certificate via ACME when a client asks for a server name matching this pattern (wildcards like *.example.com allowed), can be repeated
+    #[arg(long = "acme-on-demand", value_name = "PATTERN", requires = "acme_dir")]
+    acme_on_demand: Vec<String>,
+
+    /// Directory storing the ACME account key and the certificates obtained on demand
+    #[arg(long, value_name = "DIR")]
+    acme_dir: Option<PathBuf>,
+
+    /// ACME directory URL of the certificate authority
+    #[arg(long, value_name = "URL", default_value = acme::LETS_ENCRYPT)]
+    acme_directory: String,
+
+    /// Contact email registered with the ACME account
+    #[arg(long, value_name = "EMAIL")]
+    acme_email: Option<String>,
+
+    /// Maximum number of certificates requested on demand per hour
+    #[arg(long, value_name = "COUNT", default_value_t = 10)]
+    acme_rate: usize,
+
+    /// Log a warning and fire the threshold_exceeded event when a metric exceeds a limit within a minute (e.g. 'handshake_failures>10%', 'connections>1000'), can be repeated
+    #[arg(long = "alert", value_name = "METRIC>LIMIT", value_parser = alerts::parse_threshold)]
+    alerts: Vec<alerts::Threshold>,
+
+    /// POST events as JSON to this webhook URL (Slack-compatible)
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    /// Do not send this event to the webhook, can be repeated
+    #[arg(long, value_name = "EVENT", value_parser = events::parse_event, requires = "webhook")]
+    webhook_disable: Vec<events::Event>,
+
+    /// Answer GET requests for this path (e.g. /__proxy/health) directly with the proxy status, without involving the backend
+    #[arg(long, value_name = "PATH", value_parser = probe::parse_probe_path)]
+    probe_path: Option<String>,
+
+    /// Time allowed for connecting to the backend in seconds
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+
+    /// Close connections when the backend sends nothing for this many seconds after receiving client data
+    #[arg(long, value_name = "SECS")]
+    read_timeout: Option<u64>,
+
+    /// Close connections (or answer 504 in HTTP mode) when the backend does not start responding to a request within this many seconds
+    #[arg(long, value_name = "SECS")]
+    backend_ttfb_timeout: Option<u64>,
+
+    /// Backend timeouts for requests whose path starts with PREFIX (PREFIX=READ_SECS[,CONNECT_SECS]), can be repeated
+    #[arg(long, value_name = "PREFIX=SECS", value_parser = timeouts::parse_route_timeout)]
+    route_timeout: Vec<timeouts::RouteTimeout>,
+
+    /// Forward raw bytes (tcp) or parse HTTP/1.1 requests (http, required for caching)
+    #[arg(long, value_enum, default_value = "tcp")]
+    mode: http::Mode,
+
+    /// Protocol profile for wrapping non-HTTP services: sets the idle timeout and disables HTTP-specific handling
+    #[arg(long, value_enum, value_name = "PROFILE")]
+    profile: Option<profile::Profile>,
+
+    /// Accept plaintext SMTP or IMAP connections, advertise STARTTLS and upgrade them to TLS before forwarding (implies the matching --profile)
+    #[arg(long, value_enum, value_name = "PROTOCOL")]
+    starttls: Option<starttls::Starttls>,
+
+    /// Close connections without traffic in either direction for this many seconds (0 disables, defaults to the profile's timeout)
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Send failed idempotent requests (connect error, 502 or 503) again this many times, to another backend when possible (HTTP mode)
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    retries: u32,
+
+    /// Percentage of requests that may be retried, so retries cannot multiply the load on failing backends
+    #[arg(long, value_name = "PERCENT", default_value_t = 20)]
+    retry_budget: u32,
+
+    /// Eject a backend from the rotation after this many consecutive errors (connect errors, timeouts, 502-504)
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    outlier_errors: Option<u32>,
+
+    /// Eject a backend whose average response time exceeds this multiple of the other backends' median (HTTP mode)
+    #[arg(long, value_name = "FACTOR")]
+    outlier_latency: Option<f64>,
+
+    /// Seconds a backend stays ejected, multiplied by the number of times it was ejected before
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    outlier_ejection_time: u64,
+
+    /// Maximum percentage of the backends ejected at the same time
+    #[arg(long, value_name = "PERCENT", default_value_t = 50)]
+    outlier_max_ejected: u32,
+
+    /// Ramp up the traffic share of a backend returning to the rotation over this many seconds
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    slow_start: Option<u64>,
+
+    /// How new connections are distributed over multiple backends
+    #[arg(long, value_name = "STRATEGY", value_enum, default_value_t = backend::Strategy::RoundRobin)]
+    balance: backend::Strategy,
+
+    /// Check every backend this often (seconds), taking failing backends out of the rotation
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_interval: Option<u64>,
+
+    /// Request this path with GET in health checks, expecting a 2xx or 3xx response, instead of only connecting
+    #[arg(long, value_name = "PATH", requires = "health_check_interval", value_parser = probe::parse_probe_path)]
+    health_check_path: Option<String>,
+
+    /// Seconds a health check may take before it counts as failed
+    #[arg(long, value_name = "SECS", default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_timeout: u64,
+
+    /// Consecutive successful health checks before a backend returns to the rotation
+    #[arg(long, value_name = "COUNT", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    health_check_rise: u32,
+
+    /// Consecutive failed health checks before a backend is taken out of the rotation
+    #[arg(long, value_name = "COUNT", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    health_check_fall: u32,
+
+    /// Cache GET responses for paths starting with this prefix (honoring Cache-Control and ETag), can be repeated
+    #[arg(long = "cache", value_name = "PREFIX", value_parser = cache::parse_prefix)]
+    cache_prefixes: Vec<String>,
+
+    /// Maximum size of a cached response in KiB
+    #[arg(long, value_name = "KIB", default_value_t = 1024)]
+    cache_max_object: usize,
+
+    /// Memory available to the response cache in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 64)]
+    cache_size: usize,
+
+    /// Serve cached responses up to this many seconds past their freshness when the backend fails or times out
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    cache_max_stale: u64,
+
+    /// Also keep cached responses in this directory, so they survive restarts
+    #[arg(long, value_name = "DIR", requires = "cache_prefixes")]
+    cache_dir: Option<PathBuf>,
+
+    /// Disk space available to the response cache in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 1024)]
+    cache_disk_size: u64,
+
+    /// Start in maintenance mode, answering requests with a 503 page (toggled through the admin API)
+    #[arg(long)]
+    maintenance: bool,
+
+    /// Keep forwarding clients from this address or range (e.g. 10.0.0.0/8) during maintenance, can be repeated
+    #[arg(long, value_name = "IP[/PREFIX]")]
+    maintenance_allow: Vec<routing::IpNet>,
+
+    /// HTML file served as the maintenance page
+    #[arg(long, value_name = "FILE")]
+    maintenance_page: Option<PathBuf>,
+
+    /// Hide data from log output: `ips` masks client addresses
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "none")]
+    log_redact: redact::LogRedact,
+
+    /// Mask client addresses to their /24 (IPv4) or /48 (IPv6) network in logs and the admin API, so logs can be retained under privacy policies
+    #[arg(long)]
+    anonymize_ips: bool,
+
+    /// Lowest level of log lines written: error, warn, info or debug (every forwarded request)
+    #[arg(long, value_enum, value_name = "LEVEL", default_value = "info")]
+    log_level: log::Level,
+
+    /// Log at the debug level, including every forwarded request (same as --log-level debug)
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Write log lines as plain text or as JSON objects with the connection's peer, SNI and backend as fields
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "text")]
+    log_format: log::LogFormat,
+
+    /// Log an identical connection error 3 times per this many seconds, summarizing further repeats at the end (0 logs every error)
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    log_throttle: u64,
+
+    /// Send requests for a host to other backends (repeatable, format: host=backend[,backend...], wildcards like *.example.com allowed, HTTP mode), by the Host header so clients without SNI are routed too
+    #[arg(long = "host-route", value_name = "HOST=BACKEND", value_parser = http_routing::parse_host_route)]
+    host_routes: Vec<http_routing::HostRule>,
+
+    /// Send requests for paths under a prefix to other backends (repeatable, format: /prefix=backend[,backend...][;strip], HTTP mode); the longest matching prefix wins over host routes, and `;strip` removes the prefix from the forwarded path
+    #[arg(long = "path-route", value_name = "PREFIX=BACKEND", value_parser = http_routing::parse_path_route)]
+    path_routes: Vec<http_routing::PathRule>,
+
+    /// Compress responses with gzip for clients accepting it (HTTP mode), skipping already compressed content types and responses the backend encoded itself
+    #[arg(long)]
+    compress: bool,
+
+    /// Never compress this content type (repeatable, e.g. application/x-ndjson or text/*), in addition to the already compressed ones
+    #[arg(long = "compress-skip-type", value_name = "TYPE", requires = "compress")]
+    compress_skip_types: Vec<String>,
+
+    /// Send Strict-Transport-Security with this max-age in seconds (HTTP mode, e.g. 31536000 for a year), so browsers only use HTTPS for the site from then on
+    #[arg(long, value_name = "SECS")]
+    hsts: Option<u64>,
+
+    /// Extend Strict-Transport-Security to all subdomains
+    #[arg(long, requires = "hsts")]
+    hsts_include_subdomains: bool,
+
+    /// Ask for inclusion in the browsers' HSTS preload lists (see hstspreload.org before enabling)
+    #[arg(long, requires = "hsts_include_subdomains")]
+    hsts_preload: bool,
+
+    /// Send X-Content-Type-Options: nosniff, so browsers do not guess content types (HTTP mode)
+    #[arg(long)]
+    nosniff: bool,
+
+    /// Send X-Frame-Options, so other sites cannot show the pages in a frame (HTTP mode)
+    #[arg(long, value_enum, value_name = "POLICY")]
+    frame_options: Option<security::FrameOptions>,
+
+    /// Tell the backend about the client with X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and X-Real-IP headers, or the Forwarded header with --forwarded-style (HTTP mode), replacing those sent by clients
+    #[arg(long)]
+    forwarded_headers: bool,
+
+    /// Headers telling the backend about the client with --forwarded-headers: `x-forwarded`, `forwarded` (RFC 7239) or `both`
+    #[arg(long, value_enum, value_name = "STYLE", default_value = "x-forwarded")]
+    forwarded_style: forwarded::ForwardedStyle,
+
+    /// Proxies in front of this one whose Forwarded and X-Forwarded-* headers are trusted (comma-separated, e.g. 10.0.0.0/8,192.0.2.7): their chain of proxies is extended instead of replaced, and X-Real-IP is the last address in it that is not a trusted proxy
+    #[arg(long, value_name = "IP[/PREFIX],...", value_delimiter = ',', requires = "forwarded_headers")]
+    trusted_proxies: Vec<routing::IpNet>,
+
+    /// Expect a PROXY protocol header (v1 or v2) from a load balancer in front of the proxy on every connection, and use the client address it conveys
+    #[arg(long)]
+    proxy_protocol_in: bool,
+
+    /// Announce the client address to the backend with a PROXY protocol header (`v1` or `v2`) on every backend connection, for backends like HAProxy, Nginx or Postgres that understand it
+    #[arg(long, value_enum, value_name = "VERSION")]
+    proxy_protocol_out: Option<proxy_protocol::ProxyProtocol>,
+
+    /// Connect to the backends from this local IP address (for multi-homed hosts, policy routing or backend allowlists)
+    #[arg(long, value_name = "IP")]
+    backend_bind: Option<IpAddr>,
+
+    /// Use multipath TCP (MPTCP) for listening and backend sockets, so clients can spread a connection over several network paths (Linux only, falls back to plain TCP where the kernel lacks it)
+    #[arg(long)]
+    mptcp: bool,
+
+    /// Mark traffic to clients with this DSCP class (0-63, EF, AF11-AF43, CS0-CS7, LE, VA), prefixed with `INPUT=` for a single listener, can be repeated
+    #[arg(long, value_name = "[INPUT=]CLASS", value_parser = dscp::parse_rule)]
+    dscp: Vec<dscp::DscpRule>,
+
+    /// Mark traffic to the backends with this DSCP class, prefixed with `BACKEND=` for a single backend, can be repeated
+    #[arg(long, value_name = "[BACKEND=]CLASS", value_parser = dscp::parse_rule)]
+    backend_dscp: Vec<dscp::DscpRule>,
+
+    /// Show a live dashboard (connections, requests, top server names and paths, recent log lines) in the terminal instead of the log output
+    #[arg(long)]
+    tui: bool,
+
+    /// Log a line per request in HTTP mode, or per connection in TCP mode (`common`, `combined` or `json`)
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    access_log: Option<access::AccessLogFormat>,
+
+    /// Append the access log to this file instead of standard output, reopened on SIGUSR1 after log rotation
+    #[arg(long, value_name = "FILE", requires = "access_log")]
+    access_log_file: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Parse CLI arguments
-    let args = Args::parse();
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if std::env::args().nth(1).as_deref() == Some("install-service") {
+        return install_service();
+    }
 
-    // Load certificate and private key based on provided arguments
-    let (certs, private_key) = if let Some(pfx_path) = &args.pfx {
-        // Named mode: --pfx [--password] (no extension validation)
-        certificate::load_certificate(pfx_path, args.password.as_deref(), false)?
-    } else if let Some(cert_path) = &args.cert {
-        // Named mode: --cert --key (no extension validation)
-        let key_path = args.key.as_ref().unwrap(); // Safe due to clap's requires constraint
-        certificate::load_pem_certificate(cert_path, key_path)?
-    } else if let Some(cert_path) = &args.certificate {
-        // Positional mode: detect format by extension
-        let cert_type = certificate::detect_cert_type(cert_path)
-            .map_err(|e| format!("Failed to detect certificate type: {}", e))?;
+    // Parse CLI arguments, along with the settings of the config file
+    let mut args = Args::parse_from(config::merge(Args::command(), std::env::args().collect())?);
+    log::init(if args.verbose { log::Level::Debug } else { args.log_level }, args.log_format);
+    check_args(&mut args)?;
+    if args.tui && !std::io::stdout().is_terminal() {
+        return Err("--tui needs standard output to be a terminal".into());
+    }
 
-        match cert_type {
-            certificate::CertType::Pfx => {
-                // PFX format: certificate [password] (with extension validation)
-                certificate::load_certificate(cert_path, args.password_or_key.as_deref(), true)?
+    // Keep the most recent log lines in memory, passing them on to the original outputs
+    let capacity = args.admin.map_or(0, |_| args.admin_log_lines).max(if args.tui { tui::LOG_LINES } else { 0 });
+    let logs = (capacity > 0).then(|| Arc::new(logs::LogBuffer::new(capacity)));
+    let _capture = match &logs {
+        Some(logs) => Some(logs.capture()?),
+        None => None,
+    };
+
+    // Every connection takes one descriptor for the client and one for the backend
+    if args.raise_fd_limit {
+        match fds::raise_limit() {
+            Ok((previous, raised)) if raised > previous => {
+                info!("Raised file descriptor limit from {} to {}", previous, raised)
             }
-            certificate::CertType::Pem => {
-                // PEM format: certificate keyfile (no extension validation needed)
-                let key_path = args.password_or_key.as_ref()
-                    .ok_or("PEM certificate requires a key file as the second argument")?;
-                certificate::load_pem_certificate(cert_path, key_path)?
+            Ok(_) => {}
+            Err(e) => error!("Failed to raise file descriptor limit: {}", e),
+        }
+    }
+
+    // Initialize on a single-threaded runtime, so privileges are dropped and the sandbox is
+    // applied before any worker threads exist (restrictions are inherited by new threads)
+    let startup = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let (proxy, listener, admin_listener) = startup.block_on(setup(&args, logs.clone(), None))?;
+    let mut listeners = vec![(proxy.clone(), listener, args.clone())];
+    for mapping in &args.listeners {
+        let mapped = listener_args(&args, mapping);
+        let (proxy, listener, _) = startup.block_on(setup(&mapped, logs.clone(), Some(&proxy)))?;
+        listeners.push((proxy, listener, mapped));
+    }
+    let redirect_listener = match args.redirect_http {
+        Some(addr) => {
+            let retry = args.bind_retry.map(Duration::from_secs);
+            let redirect_listener = startup.block_on(listener::bind(addr, args.reuse_addr, args.backlog, retry, args.mptcp))?;
+            info!("Redirecting plain HTTP on http://{} to HTTPS", redirect_listener.local_addr()?);
+            Some(redirect_listener.into_std()?)
+        }
+        None => None,
+    };
+    let metrics_listener = match args.metrics {
+        Some(addr) => Some(startup.block_on(admin::bind_metrics(addr))?.into_std()?),
+        None => None,
+    };
+    drop(startup);
+
+    // Drop root privileges now that all privileged resources are acquired
+    if args.user.is_some() || args.group.is_some() {
+        privileges::drop_privileges(args.user.as_deref(), args.group.as_deref())?;
+    }
+    // Capabilities granted to the binary (e.g. CAP_NET_BIND_SERVICE via setcap) are not needed anymore either
+    privileges::drop_capabilities()?;
+
+    // Restrict the process to what it needs from here on
+    if args.sandbox {
+        // Resolve the backend once, so resolver libraries are loaded before file access is restricted
+        for (proxy, _, _) in &listeners {
+            for backend in proxy.upstreams().pools().flat_map(|pool| pool.backends()) {
+                let _ = backend.address.to_socket_addrs();
             }
         }
+        let mut readable: Vec<PathBuf> = listeners.iter().flat_map(|(_, _, args)| certificate_paths(args)).collect();
+        readable.extend(args.acme_webroot.clone());
+        // Read again when the configuration is reloaded
+        readable.extend(args.config.clone());
+        sandbox::apply(&readable)?;
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(serve(listeners, admin_listener, metrics_listener, redirect_listener, args))
+}
+
+/// Complete the arguments and check that they fit together
+fn check_args(args: &mut Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    args.profile = args.profile.or(args.starttls.map(starttls::Starttls::profile));
+    if let Some(origin_pull) = args.origin_pull {
+        args.client_ca = Some(origin_pull.ca().to_string());
+    }
+    if args.sandbox && !args.event_commands.is_empty() {
+        return Err("--on-event cannot be combined with --sandbox, which forbids running commands".into());
+    }
+    if args.sandbox && args.cache_dir.is_some() {
+        return Err("--cache-dir cannot be combined with --sandbox, which forbids writing files".into());
+    }
+    if args.sandbox && !args.acme_on_demand.is_empty() {
+        return Err("--acme-on-demand cannot be combined with --sandbox, which forbids writing files".into());
+    }
+    // Certificate authorities validating on-demand names present no client certificate
+    if args.client_ca.is_some() && !args.acme_on_demand.is_empty() {
+        return Err("--client-ca and --origin-pull cannot be combined with --acme-on-demand".into());
+    }
+    if !args.cache_prefixes.is_empty() && args.mode != http::Mode::Http {
+        return Err("--cache requires --mode http".into());
+    }
+    if args.retries > 0 && args.mode != http::Mode::Http {
+        return Err("--retries requires --mode http".into());
+    }
+    if args.forwarded_headers && args.mode != http::Mode::Http {
+        return Err("--forwarded-headers requires --mode http".into());
+    }
+    if args.compress && args.mode != http::Mode::Http {
+        return Err("--compress requires --mode http".into());
+    }
+    if (args.hsts.is_some() || args.nosniff || args.frame_options.is_some()) && args.mode != http::Mode::Http {
+        return Err("--hsts, --nosniff and --frame-options require --mode http".into());
+    }
+    if !args.host_routes.is_empty() && args.mode != http::Mode::Http {
+        return Err("--host-route requires --mode http".into());
+    }
+    if !args.path_routes.is_empty() && args.mode != http::Mode::Http {
+        return Err("--path-route requires --mode http".into());
+    }
+    if args.outlier_latency.is_some() && args.mode != http::Mode::Http {
+        return Err("--outlier-latency requires --mode http".into());
+    }
+    // Connections from an address the host does not have would all fail
+    if let Some(ip) = args.backend_bind {
+        std::net::UdpSocket::bind((ip, 0)).map_err(|e| format!("Cannot use {} for --backend-bind: {}", ip, e))?;
+    }
+    if args.mptcp && !listener::mptcp_supported() {
+        warn!("Multipath TCP is not available on this system, --mptcp falls back to plain TCP");
+    }
+    if args.watch_cert == Some(0) {
+        return Err("--watch-cert needs an interval of at least 1 second".into());
+    }
+    if args.watch_cert.is_some() && args.ocsp_stapling {
+        return Err("--watch-cert cannot be combined with --ocsp-stapling, which does not support reloading the certificate".into());
+    }
+    // A class for a listener that does not exist is most likely a typo
+    let inputs: Vec<_> = std::iter::once(&args.input_address).chain(args.listeners.iter().map(|mapping| &mapping.input)).collect();
+    if let Some(address) = args.dscp.iter().filter_map(|rule| rule.address.as_ref()).find(|address| !inputs.contains(address)) {
+        return Err(format!("--dscp {}: no listener with this input address", address).into());
+    }
+    if !args.routes.is_empty() && args.mode != http::Mode::Tcp {
+        return Err("--route requires --mode tcp".into());
+    }
+    if args.profile.is_some() {
+        if args.mode != http::Mode::Tcp {
+            return Err("--profile and --starttls require --mode tcp".into());
+        }
+        if args.probe_path.is_some() || !args.route_timeout.is_empty() {
+            return Err("--probe-path and --route-timeout inspect HTTP requests and cannot be combined with --profile or --starttls".into());
+        }
+    }
+    Ok(())
+}
+
+/// Create the backend pools and routes given by the arguments
+fn build_upstreams(args: &Args) -> Result<proxy::Upstreams, Box<dyn std::error::Error + Send + Sync>> {
+    let slow_start = args.slow_start.map(Duration::from_secs);
+    Ok(proxy::Upstreams {
+        backends: backend::Pool::new(&args.output_address, args.balance, slow_start)?,
+        routes: args
+            .routes
+            .iter()
+            .map(|rule| routing::Route::new(rule.clone(), args.balance, slow_start))
+            .collect::<Result<_, _>>()?,
+        host_routes: args
+            .host_routes
+            .iter()
+            .map(|rule| http_routing::HostRoute::new(rule.clone(), args.balance, slow_start))
+            .collect::<Result<_, _>>()?,
+        path_routes: args
+            .path_routes
+            .iter()
+            .map(|rule| http_routing::PathRoute::new(rule.clone(), args.balance, slow_start))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// Log where requests are proxied to
+fn describe_upstreams(upstreams: &proxy::Upstreams) {
+    info!("Proxying to HTTP server at {}", upstreams.backends.describe());
+    for route in &upstreams.host_routes {
+        info!("Proxying requests for {} to {}", route.rule.pattern, route.backends.describe());
+    }
+    for route in &upstreams.path_routes {
+        info!("Proxying requests for {}/ to {}", route.rule.prefix, route.backends.describe());
+    }
+}
+
+/// The arguments of a `--listener`: the main arguments with its addresses and certificate
+fn listener_args(args: &Args, mapping: &listener::Mapping) -> Args {
+    let mut mapped = args.clone();
+    mapped.input_address = mapping.input.clone();
+    mapped.output_address = mapping.output.clone();
+    if let Some((cert, key)) = &mapping.cert {
+        mapped.certificate = Some(cert.clone());
+        mapped.password_or_key = key.clone();
+        (mapped.pfx, mapped.cert, mapped.key) = (None, None, None);
+        (mapped.second_cert, mapped.second_key) = (None, None);
+        mapped.sni_certs.clear();
+    }
+    // Inherited sockets, the admin API, the HTTP redirects and the dashboard belong to the main listener
+    mapped.listen_fd = None;
+    mapped.admin = None;
+    mapped.metrics = None;
+    mapped.redirect_http = None;
+    mapped.tui = false;
+    mapped.listeners.clear();
+    mapped
+}
+
+/// Install a service running the proxy with the arguments following `install-service`
+fn install_service() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let install = service::InstallArgs::parse_from(std::env::args().skip(1));
+    // Catch mistakes now instead of in a failing service
+    let cli = std::iter::once("https-wrapper".to_string()).chain(install.args.iter().cloned()).collect();
+    let args = Args::try_parse_from(config::merge(Args::command(), cli)?).unwrap_or_else(|e| e.exit());
+    if install.run_as.is_some() && (args.user.is_some() || args.group.is_some()) {
+        return Err("--run-as starts the service unprivileged, which cannot switch users with --user or --group".into());
+    }
+    let mut writable: Vec<PathBuf> = [&args.acme_dir, &args.cache_dir].into_iter().flatten().cloned().collect();
+    // The directory, as log rotation replaces the file
+    if let Some(file) = &args.access_log_file {
+        writable.push(file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf());
+    }
+    service::install(&install, &writable)
+}
+
+/// Load certificates and bind the listeners, an extra listener shares the connections, counters
+/// and admin API state of the main one
+async fn setup(
+    args: &Args,
+    logs: Option<Arc<logs::LogBuffer>>,
+    main: Option<&proxy::Proxy>,
+) -> Result<(Arc<proxy::Proxy>, std::net::TcpListener, Option<std::net::TcpListener>), Box<dyn std::error::Error + Send + Sync>> {
+    // Load certificate and private key based on provided arguments
+    let (certs, private_key) = load_certificate(args)?;
+
+    // Certificate Transparency: browsers require SCTs, which rustls can only serve when embedded in the certificate
+    if certs.first().is_some_and(|cert| !certificate::has_embedded_scts(cert) && !certificate::is_self_signed(cert)) {
+        warn!("Certificate has no embedded Signed Certificate Timestamps; clients enforcing Certificate Transparency may reject it");
+    }
+
+    // OCSP Must-Staple certificates are rejected by clients when no response is stapled
+    let must_staple = certs.first().is_some_and(certificate::has_must_staple);
+    if must_staple && !args.ocsp_stapling {
+        return Err("Certificate has the OCSP Must-Staple extension, enable stapling with --ocsp-stapling".into());
+    }
+
+    // Configure TLS
+    let builder = ServerConfig::builder();
+    let builder = match &args.client_ca {
+        Some(ca) => {
+            let verifier = client_auth::verifier(ca, args.client_ca_sha256.as_deref(), builder.crypto_provider().clone()).await?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let certified_key = certificate::certified_key(certs, private_key)?;
+    let mut cert_resolver = resolver::CertResolver::new(certified_key);
+    if let (false, Some(dir)) = (args.acme_on_demand.is_empty(), &args.acme_dir) {
+        let on_demand = acme::OnDemand::new(
+            &args.acme_directory,
+            args.acme_email.clone(),
+            args.acme_on_demand.clone(),
+            dir,
+            args.acme_rate,
+            builder.crypto_provider().clone(),
+        )?;
+        cert_resolver = cert_resolver.with_on_demand(Arc::new(on_demand));
+    }
+    if let Some(second) = load_second_certificate(args)? {
+        let algorithms = (cert_resolver.current().key.algorithm(), second.key.algorithm());
+        if algorithms.0 == algorithms.1 {
+            warn!("Both certificates have {:?} keys, the second certificate is never served", algorithms.0);
+        }
+        cert_resolver = cert_resolver.with_second(second);
+    }
+    cert_resolver = cert_resolver.with_sni(load_sni_certificates(args)?).strict(args.sni_strict);
+    let cert_resolver = Arc::new(cert_resolver);
+    let sessions = session::SessionState::new()?;
+    let mut config = builder.with_cert_resolver(cert_resolver.clone());
+    config.session_storage = sessions.clone();
+    config.ticketer = sessions.clone();
+    // Advertise the configured protocols and those routes are selected by, the negotiated one is only known with ALPN
+    if !args.alpn.is_empty() || args.routes.iter().any(|rule| rule.alpn.is_some()) {
+        config.alpn_protocols = routing::alpn_protocols(&args.alpn, &args.routes);
+    }
+    // Certificate authorities validate on-demand names with a TLS handshake negotiating acme-tls/1
+    if !args.acme_on_demand.is_empty() {
+        if config.alpn_protocols.is_empty() {
+            config.alpn_protocols.push(b"http/1.1".to_vec());
+        }
+        config.alpn_protocols.push(acme::ACME_TLS_ALPN.to_vec());
+    }
+    if args.cert_compression {
+        config.cert_compressors = vec![&compress::ZlibCompressor];
+    }
+
+    // Fetch the initial OCSP response
+    let stapler = if args.ocsp_stapling {
+        let stapler = Arc::new(ocsp::Stapler::new(cert_resolver.clone())?);
+        let delay = match stapler.refresh().await {
+            Ok(delay) => delay,
+            Err(e) if must_staple => return Err(format!("Failed to fetch OCSP response for Must-Staple certificate: {}", e).into()),
+            Err(e) => {
+                warn!("Failed to fetch OCSP response, starting without staple: {}", e);
+                Duration::ZERO
+            }
+        };
+        Some((stapler, delay))
     } else {
-        return Err("No certificate specified. Use either positional arguments or named flags (--pfx or --cert/--key)".into());
+        None
     };
 
-    // Configure TLS
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, private_key)?;
+    // Wait for the backends to come up
+    let upstreams = build_upstreams(args)?;
+    if let Some(timeout) = args.wait_for_backend {
+        let timeout = (timeout > 0).then(|| Duration::from_secs(timeout));
+        for backend in upstreams.pools().flat_map(|pool| pool.backends()) {
+            let dscp = dscp::select(&args.backend_dscp, &backend.address);
+            backend::wait_until_reachable(&backend.address, args.backend_bind, dscp, args.mptcp, timeout).await?;
+        }
+    }
 
-    let addr = args.input_address.parse::<SocketAddr>()?;
-    let listener = TcpListener::bind(addr).await?;
+    let listener = match listener::inherited(args.listen_fd)? {
+        Some(listener) => TcpListener::from_std(listener)?,
+        None => {
+            let addr = address::parse_listen(&args.input_address).map_err(|e| format!("Invalid input address: {}", e))?;
+            listener::bind(addr, args.reuse_addr, args.backlog, args.bind_retry.map(Duration::from_secs), args.mptcp).await?
+        }
+    };
+    let addr = listener.local_addr()?;
+    if let Some(mss) = args.tcp_mss {
+        let bytes = listener::clamp_mss(&listener, mss).map_err(|e| format!("Failed to clamp the TCP MSS on {}: {}", addr, e))?;
+        info!("Clamping the TCP MSS of client connections to {} bytes", bytes);
+    }
     let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
 
-    println!("HTTPS reverse proxy running on https://{}", addr);
-    println!("Proxying to HTTP server at http://{}", args.output_address);
+    info!("HTTPS reverse proxy running on https://{}", addr);
+    describe_upstreams(&upstreams);
+
+    let webhook = match &args.webhook {
+        Some(url) => Some(webhook::Webhook::new(url, args.webhook_disable.clone())?),
+        None => None,
+    };
+
+    let cache = if args.cache_prefixes.is_empty() {
+        None
+    } else {
+        Some(cache::Cache::new(
+            args.cache_prefixes.clone(),
+            args.cache_max_object * 1024,
+            args.cache_size * 1024 * 1024,
+            Duration::from_secs(args.cache_max_stale),
+            args.cache_dir.clone().map(|dir| (dir, args.cache_disk_size * 1024 * 1024)),
+        )?)
+    };
+
+    let page = match &args.maintenance_page {
+        Some(path) => Some(
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read maintenance page {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    let maintenance = match main {
+        Some(main) => main.maintenance.clone(),
+        None => {
+            let maintenance = maintenance::Maintenance::new(args.maintenance_allow.clone(), page);
+            if args.maintenance {
+                maintenance.enable(None);
+            }
+            Arc::new(maintenance)
+        }
+    };
+
+    let log_redact = if args.anonymize_ips { redact::LogRedact::Anonymize } else { args.log_redact };
+    let proxy = Arc::new(proxy::Proxy {
+        tls_acceptor,
+        resolver: cert_resolver,
+        sessions,
+        input_address: addr,
+        upstreams: RwLock::new(Arc::new(upstreams)),
+        registry: main.map_or_else(registry::Registry::new, |main| main.registry.clone()),
+        metrics: main.map_or_else(|| Arc::new(metrics::Metrics::default()), |main| main.metrics.clone()),
+        tag_rules: args.tags.clone(),
+        plain_http: if args.dev { plaintext::PlainHttpMode::Explain } else { args.plain_http },
+        acme_webroot: args.acme_webroot.clone(),
+        stapler,
+        record_sizing: args.record_sizing,
+        memory: match main {
+            Some(main) => main.memory.clone(),
+            None => args.memory_budget.map(|mib| Arc::new(memory::MemoryBudget::new(mib * 1024 * 1024))),
+        },
+        hooks: Vec::new(),
+        events: Arc::new(events::EventHooks::new(args.event_commands.clone(), webhook)),
+        probe_path: args.probe_path.clone(),
+        started: std::time::Instant::now(),
+        timeouts: timeouts::Timeouts {
+            connect: args.connect_timeout.map(Duration::from_secs),
+            read: args.read_timeout.map(Duration::from_secs),
+            first_byte: args.backend_ttfb_timeout.map(Duration::from_secs),
+        },
+        route_timeouts: args.route_timeout.clone(),
+        mode: args.mode,
+        profile: args.profile,
+        starttls: args.starttls,
+        idle_timeout: match args.idle_timeout {
+            Some(secs) => (secs > 0).then(|| Duration::from_secs(secs)),
+            None => args.profile.and_then(profile::Profile::idle_timeout),
+        },
+        keepalive: (args.client_keepalive > 0).then(|| Duration::from_secs(args.client_keepalive)),
+        cache,
+        compression: args.compress.then(|| encoding::Compression::new(&args.compress_skip_types)),
+        security_headers: security::SecurityHeaders::new(args.hsts, args.hsts_include_subdomains, args.hsts_preload, args.nosniff, args.frame_options),
+        forwarded_headers: args.forwarded_headers,
+        forwarded_style: args.forwarded_style,
+        trusted_proxies: args.trusted_proxies.clone(),
+        proxy_protocol_in: args.proxy_protocol_in,
+        proxy_protocol: args.proxy_protocol_out,
+        backend_bind: args.backend_bind,
+        dscp: dscp::select(&args.dscp, &args.input_address),
+        backend_dscp: args.backend_dscp.clone(),
+        mptcp: args.mptcp,
+        retries: args.retries,
+        retry_budget: main.map_or_else(|| Arc::new(retry::RetryBudget::new(args.retry_budget)), |main| main.retry_budget.clone()),
+        outlier: (args.outlier_errors.is_some() || args.outlier_latency.is_some()).then(|| outlier::OutlierDetection {
+            consecutive_errors: args.outlier_errors,
+            latency_factor: args.outlier_latency,
+            ejection_time: Duration::from_secs(args.outlier_ejection_time),
+            max_ejected_percent: args.outlier_max_ejected,
+        }),
+        health_check: args.health_check_interval.map(|interval| {
+            Arc::new(health::HealthCheck {
+                interval: Duration::from_secs(interval),
+                timeout: Duration::from_secs(args.health_check_timeout),
+                rise: args.health_check_rise,
+                fall: args.health_check_fall,
+                path: args.health_check_path.clone(),
+                bind: args.backend_bind,
+                dscp: args.backend_dscp.clone(),
+                mptcp: args.mptcp,
+            })
+        }),
+        close: close::ClosePolicy {
+            close_notify: args.close_notify,
+            reset_on_error: args.reset_on_error,
+        },
+        fds: fds::FdUsage::open(),
+        log_redact,
+        error_log: Arc::new(throttle::ErrorThrottle::new(Duration::from_secs(args.log_throttle))),
+        logs,
+        dashboard: match main {
+            Some(main) => main.dashboard.clone(),
+            None => args.tui.then(|| Arc::new(tui::Dashboard::default())),
+        },
+        access_log: match args.access_log {
+            Some(format) => Some(access::AccessLog::new(format, log_redact, args.access_log_file.as_deref())?),
+            None => None,
+        },
+        alerts: (!args.alerts.is_empty()).then(|| alerts::Alerts::new(args.alerts.clone())),
+        maintenance,
+        drain: main.map_or_else(|| Arc::new(drain::Drain::default()), |main| main.drain.clone()),
+        tracing: main.map_or_else(|| Arc::new(trace::Tracing::default()), |main| main.tracing.clone()),
+    });
+
+    let admin_listener = match args.admin {
+        Some(admin_addr) => Some(admin::bind(admin_addr).await?.into_std()?),
+        None => None,
+    };
+
+    Ok((proxy, listener.into_std()?, admin_listener))
+}
+
+/// Run the admin API, the metrics endpoint and the HTTP redirects, and accept connections on every listener
+async fn serve(
+    listeners: Vec<(Arc<proxy::Proxy>, std::net::TcpListener, Args)>,
+    admin_listener: Option<std::net::TcpListener>,
+    metrics_listener: Option<std::net::TcpListener>,
+    redirect_listener: Option<std::net::TcpListener>,
+    args: Args,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // The listeners share their connections, counters and admin API state, the admin API and the
+    // dashboard show the backends of all of them
+    let proxies: Arc<[Arc<proxy::Proxy>]> = listeners.iter().map(|(listening, ..)| listening.clone()).collect();
+    let proxy = proxies[0].clone();
+    let limit = args.max_connections.map(|max| Arc::new(listener::ConnectionLimit::new(max as usize)));
+    let mut supervisor = supervisor::Supervisor::new(args.on_task_failure);
+
+    // Warn when running out of file descriptors
+    let watched = proxy.clone();
+    supervisor.spawn("file descriptor watch", move || {
+        let watched = watched.clone();
+        async move { watched.fds.watch().await }
+    });
+
+    // Listening right away keeps an early SIGHUP from terminating the process
+    let _ = signal(SignalKind::hangup())?;
+    if args.access_log_file.is_some() {
+        let _ = signal(SignalKind::user_defined1())?;
+    }
+    let keepalive = (args.client_keepalive > 0).then(|| Duration::from_secs(args.client_keepalive));
+    let linger = args.linger.map(Duration::from_secs);
+
+    // Accept connections, each handled by a task that is tracked until it finishes. The tasks are kept
+    // outside of the accept loops, so restarting a loop leaves open connections alone.
+    let connections = Arc::new(Mutex::new(JoinSet::new()));
+    let https_port = listeners[0].1.local_addr()?.port();
+    let reloadable = Arc::new(listeners.iter().map(|(listening, _, listener_args)| (listening.clone(), listener_args.clone())).collect::<Vec<_>>());
+    supervisor.spawn("configuration reloading", move || reload_on_hangup(reloadable.clone()));
+    for (index, (listening, listener, listener_args)) in listeners.into_iter().enumerate() {
+        spawn_listener(&mut supervisor, index, listening, listener, listener_args, &connections, &limit, keepalive, linger)?;
+    }
+
+    // Start admin API
+    if let Some(admin_listener) = admin_listener {
+        let (admin_listener, served) = (Arc::new(TcpListener::from_std(admin_listener)?), proxies.clone());
+        supervisor.spawn("admin API", move || admin::serve(admin_listener.clone(), served.clone(), false));
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        let (metrics_listener, served) = (Arc::new(TcpListener::from_std(metrics_listener)?), proxies.clone());
+        supervisor.spawn("metrics endpoint", move || admin::serve(metrics_listener.clone(), served.clone(), true));
+    }
+
+    // Redirect plain HTTP to the main listener
+    if let Some(redirect_listener) = redirect_listener {
+        let (redirect_listener, webroot) = (Arc::new(TcpListener::from_std(redirect_listener)?), args.acme_webroot.clone());
+        supervisor.spawn("HTTP redirects", move || plaintext::serve_redirects(redirect_listener.clone(), https_port, webroot.clone()));
+    }
+
+    // Show the dashboard instead of the log output, until shutting down
+    if let (Some(dashboard), Some(logs)) = (&proxy.dashboard, &proxy.logs) {
+        dashboard.show(logs)?;
+        let (dashboard, shown) = (dashboard.clone(), proxies.clone());
+        supervisor.spawn("dashboard", move || dashboard.clone().run(shown.clone()));
+    }
+
+    let shutdown = shutdown_signal()?;
+    let result = tokio::select! {
+        result = supervisor.run() => result,
+        _ = shutdown => Ok(()),
+    };
+    // Stops the accept loops, closing the listening sockets, and the other tasks
+    drop(supervisor);
+    if let (Some(dashboard), Some(logs)) = (&proxy.dashboard, &proxy.logs) {
+        dashboard.close(logs);
+    }
+    result?;
+
+    // Give open connections time to finish, winding down kept-alive ones, unless signalled again
+    let mut connections = std::mem::take(&mut *connections.lock().unwrap());
+    while connections.try_join_next().is_some() {}
+    if !connections.is_empty() && args.drain_timeout > 0 {
+        info!("Shutting down, waiting up to {}s for {} open connection(s)", args.drain_timeout, connections.len());
+        proxy.drain.start();
+        let again = shutdown_signal()?;
+        tokio::select! {
+            _ = async { while connections.join_next().await.is_some() {} } => info!("All connections finished"),
+            _ = tokio::time::sleep(Duration::from_secs(args.drain_timeout)) => {}
+            _ = again => info!("Signalled again, not waiting any longer"),
+        }
+    }
+    if !connections.is_empty() {
+        info!("Shutting down, aborting {} open connection(s)", connections.len());
+        connections.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Spawn the accept loop of a listener, along with the tasks keeping its certificate up to date
+#[allow(clippy::too_many_arguments)]
+fn spawn_listener(
+    supervisor: &mut supervisor::Supervisor,
+    index: usize,
+    proxy: Arc<proxy::Proxy>,
+    listener: std::net::TcpListener,
+    args: Args,
+    connections: &Arc<Mutex<JoinSet<()>>>,
+    limit: &Option<Arc<listener::ConnectionLimit>>,
+    keepalive: Option<Duration>,
+    linger: Option<Duration>,
+) -> std::io::Result<()> {
+    // Tasks of the other listeners are told apart by their address
+    let name = |task: &'static str| -> &'static str {
+        match index {
+            0 => task,
+            _ => Box::leak(format!("{} ({})", task, proxy.input_address).into_boxed_str()),
+        }
+    };
+    let listener = Arc::new(TcpListener::from_std(listener)?);
 
-    let output_address = args.output_address.clone();
+    // Keep the OCSP staple fresh
+    if let Some((stapler, delay)) = &proxy.stapler {
+        let (stapler, delay) = (stapler.clone(), *delay);
+        supervisor.spawn(name("OCSP stapling"), move || stapler.clone().run(delay));
+    }
+
+    // Warn about (and notify of) connection and error rates crossing the alert thresholds
+    if proxy.alerts.is_some() {
+        let watched = proxy.clone();
+        supervisor.spawn(name("alert thresholds"), move || {
+            let watched = watched.clone();
+            async move {
+                if let Some(alerts) = &watched.alerts {
+                    alerts.watch(&watched.events).await;
+                }
+            }
+        });
+    }
+
+    // Summarize floods of identical errors
+    if args.log_throttle > 0 {
+        let error_log = proxy.error_log.clone();
+        supervisor.spawn(name("error log throttling"), move || error_log.clone().run());
+    }
+
+    // Take backends failing their health checks out of the rotation
+    if let Some(health_check) = &proxy.health_check {
+        let (health_check, checked) = (health_check.clone(), proxy.clone());
+        supervisor.spawn(name("health checks"), move || health_check.clone().run(checked.clone()));
+    }
+
+    // Warn about (and notify of) upcoming certificate expiry
+    let (resolver, warning, events) = (proxy.resolver.clone(), args.cert_expiry_warning, proxy.events.clone());
+    supervisor.spawn(name("certificate expiry"), move || expiry::watch(resolver.clone(), warning, events.clone()));
+
+    // Reload the certificate when the files change
+    let args = Arc::new(args);
+    if let Some(interval) = args.watch_cert {
+        let (reloaded, watched) = (proxy.clone(), args.clone());
+        let interval = Duration::from_secs(interval);
+        supervisor.spawn(name("certificate file watch"), move || reload_on_change(reloaded.clone(), watched.clone(), interval));
+    }
+
+    // Reopen the access log file on SIGUSR1
+    if args.access_log_file.is_some() {
+        let reopened = proxy.clone();
+        supervisor.spawn(name("access log reopening"), move || reopen_on_user1(reopened.clone()));
+    }
+
+    let (tracked, limit) = (connections.clone(), limit.clone());
+    supervisor.spawn(name("accept loop"), move || {
+        accept_loop(listener.clone(), proxy.clone(), tracked.clone(), limit.clone(), keepalive, linger)
+    });
+    Ok(())
+}
+
+/// Accept clients and spawn a task handling each connection
+async fn accept_loop(
+    listener: Arc<TcpListener>,
+    proxy: Arc<proxy::Proxy>,
+    connections: Arc<Mutex<JoinSet<()>>>,
+    limit: Option<Arc<listener::ConnectionLimit>>,
+    keepalive: Option<Duration>,
+    linger: Option<Duration>,
+) {
     loop {
-        let (client_stream, _) = listener.accept().await?;
-        let tls_acceptor = tls_acceptor.clone();
-        let output_address = output_address.clone();
-
-        tokio::spawn(async move {
-            // TLS handshake
-            let mut tls_stream = match tls_acceptor.accept(client_stream).await {
-                Ok(s) => s,
+        let permit = match &limit {
+            Some(limit) => Some(limit.acquire().await),
+            None => None,
+        };
+        let (client_stream, peer_addr) = listener::accept(&listener).await;
+        if let Some(idle) = keepalive {
+            if let Err(e) = listener::set_keepalive(&client_stream, idle) {
+                warn!("Failed to enable keepalive for {}: {}", proxy.log_redact.client(peer_addr), e);
+            }
+        }
+        if let Some(class) = proxy.dscp {
+            if let Err(e) = dscp::mark(client_stream.as_raw_fd(), peer_addr.is_ipv6(), class) {
+                warn!("Failed to set DSCP class for {}: {}", proxy.log_redact.client(peer_addr), e);
+            }
+        }
+        if linger.is_some() {
+            if let Err(e) = client_stream.set_linger(linger) {
+                warn!("Failed to set linger for {}: {}", proxy.log_redact.client(peer_addr), e);
+            }
+        }
+        let proxy = proxy.clone();
+        let mut connections = connections.lock().unwrap();
+        connections.spawn(async move {
+            proxy::handle_connection(proxy, client_stream, peer_addr).await;
+            drop(permit);
+        });
+
+        // Collect finished tasks, so only running connections are tracked
+        while let Some(result) = connections.try_join_next() {
+            if let Err(e) = result {
+                error!("Connection task failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Completes when SIGTERM or SIGINT is received
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+    })
+}
+
+/// Reload the configuration file (or only the certificate files, without one) whenever SIGHUP is received,
+/// keeping the current configuration when that fails
+async fn reload_on_hangup(listeners: Arc<Vec<(Arc<proxy::Proxy>, Args)>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let reloaded = match &listeners[0].1.config {
+            Some(path) => match reload_config(&listeners) {
+                Ok(reloaded) => {
+                    info!("Reloaded configuration from {}", path.display());
+                    reloaded
+                }
                 Err(e) => {
-                    eprintln!("TLS handshake error: {}", e);
-                    return;
+                    error!("Failed to reload configuration from {}, keeping the current one: {}", path.display(), e);
+                    continue;
                 }
-            };
+            },
+            None => listeners.iter().map(|(_, args)| args.clone()).collect(),
+        };
+        for ((proxy, _), args) in listeners.iter().zip(&reloaded) {
+            // The stapler only knows the OCSP responder and issuer of the certificate loaded at startup
+            if proxy.stapler.is_some() {
+                warn!("Not reloading certificates: they cannot be reloaded with --ocsp-stapling, restart instead");
+                continue;
+            }
+            reload_certificates(proxy, args);
+        }
+    }
+}
 
-            // Connect to backend HTTP server
-            let mut backend_stream = match TcpStream::connect(&output_address).await {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Backend connection error: {}", e);
-                    return;
+/// Read the configuration file again and switch every listener to its backends and routes,
+/// returns the arguments of each listener for reloading the certificates
+fn reload_config(listeners: &[(Arc<proxy::Proxy>, Args)]) -> Result<Vec<Args>, Box<dyn std::error::Error + Send + Sync>> {
+    let started = &listeners[0].1;
+    let mut args = Args::try_parse_from(config::merge(Args::command(), std::env::args().collect())?)?;
+    check_args(&mut args)?;
+    let inputs = |args: &Args| -> Vec<String> { args.listeners.iter().map(|mapping| mapping.input.clone()).collect() };
+    if inputs(&args) != inputs(started) {
+        return Err("adding or removing listeners takes a restart".into());
+    }
+    let mut reloaded = vec![args.clone()];
+    reloaded.extend(args.listeners.iter().map(|mapping| listener_args(&args, mapping)));
+
+    // Everything is created before switching, so a mistake leaves all listeners as they are
+    let upstreams = reloaded.iter().map(build_upstreams).collect::<Result<Vec<_>, _>>()?;
+    for ((proxy, _), upstreams) in listeners.iter().zip(upstreams) {
+        proxy.replace_upstreams(upstreams);
+        describe_upstreams(&proxy.upstreams());
+    }
+
+    // Anything else is only read at startup
+    let mut applied = started.clone();
+    applied.output_address = args.output_address.clone();
+    applied.listeners = args.listeners.clone();
+    applied.routes = args.routes.clone();
+    applied.host_routes = args.host_routes.clone();
+    applied.path_routes = args.path_routes.clone();
+    applied.balance = args.balance;
+    applied.slow_start = args.slow_start;
+    (applied.certificate, applied.password_or_key) = (args.certificate.clone(), args.password_or_key.clone());
+    (applied.pfx, applied.password) = (args.pfx.clone(), args.password.clone());
+    (applied.cert, applied.key) = (args.cert.clone(), args.key.clone());
+    (applied.second_cert, applied.second_key) = (args.second_cert.clone(), args.second_key.clone());
+    applied.sni_certs = args.sni_certs.clone();
+    if format!("{:?}", applied) != format!("{:?}", args) {
+        warn!("Some changed settings only take effect after a restart (only backends, routes and certificates are reloaded)");
+    }
+    // Protocols offered in the TLS handshake are fixed at startup
+    if routing::alpn_protocols(&args.alpn, &args.routes) != routing::alpn_protocols(&started.alpn, &started.routes) {
+        warn!("The routes need other ALPN protocols, which are only offered after a restart");
+    }
+    Ok(reloaded)
+}
+
+/// Reopen the access log file whenever SIGUSR1 is received, e.g. from a logrotate postrotate script
+async fn reopen_on_user1(proxy: Arc<proxy::Proxy>) {
+    let mut user1 = match signal(SignalKind::user_defined1()) {
+        Ok(user1) => user1,
+        Err(e) => {
+            error!("Failed to listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+    while user1.recv().await.is_some() {
+        let Some(access_log) = &proxy.access_log else { continue };
+        match access_log.reopen() {
+            Ok(()) => info!("Reopened the access log"),
+            Err(e) => error!("{}, continuing with the current file", e),
+        }
+    }
+}
+
+/// Reload the certificate files when they change, polling their modification times and sizes
+async fn reload_on_change(proxy: Arc<proxy::Proxy>, args: Arc<Args>, interval: Duration) {
+    let paths = certificate_paths(&args);
+    let mut loaded = file_versions(&paths);
+    let mut seen = loaded.clone();
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = file_versions(&paths);
+        // Renewals write several files, waiting until they stop changing avoids pairing a new certificate with the old key
+        if current != seen {
+            seen = current;
+            continue;
+        }
+        if current != loaded {
+            info!("Certificate files changed, reloading");
+            reload_certificates(&proxy, &args);
+            loaded = current;
+        }
+    }
+}
+
+/// Modification time and size of each file, `None` for files that cannot be read
+fn file_versions(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|metadata| Ok((metadata.modified()?, metadata.len()))).ok())
+        .collect()
+}
+
+/// Load the certificate files again and swap them in for new handshakes, keeping the current certificates when that fails
+fn reload_certificates(proxy: &proxy::Proxy, args: &Args) {
+    let certified_keys = load_certificate(args).and_then(|(certs, private_key)| {
+        Ok((
+            certificate::certified_key(certs, private_key)?,
+            load_second_certificate(args)?,
+            load_sni_certificates(args)?,
+        ))
+    });
+    let (certified_key, second, sni) = match certified_keys {
+        Ok(certified_keys) => certified_keys,
+        Err(e) => {
+            error!("Failed to reload certificate, keeping the current one: {}", e);
+            return;
+        }
+    };
+    let not_after = certified_key.cert.first().and_then(certificate::expiry).map(|(not_after, _)| not_after);
+    proxy.resolver.replace(certified_key);
+    if let Some(second) = second {
+        proxy.resolver.replace_second(second);
+    }
+    proxy.resolver.replace_sni(sni);
+
+    if args.rotate_sessions_on_reload {
+        if let Err(e) = proxy.sessions.rotate() {
+            error!("Failed to rotate TLS session ticket keys: {}", e);
+        }
+        info!("Reloaded certificate, TLS sessions rotated");
+    } else {
+        info!("Reloaded certificate, existing TLS sessions remain resumable");
+    }
+    proxy.events.fire(events::Event::CertReloaded, &[("cert_not_after", not_after.unwrap_or_default())]);
+}
+
+/// Load the certificate chain and private key given on the command line
+fn load_certificate(
+    args: &Args,
+) -> Result<(Vec<CertificateDer<'static>>, Zeroizing<PrivateKeyDer<'static>>), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(pfx_path) = &args.pfx {
+        // Named mode: --pfx [--password]
+        certificate::load_certificate(pfx_path, args.password.as_deref())
+    } else if let Some(cert_path) = &args.cert {
+        // Named mode: --cert --key
+        let key_path = args.key.as_ref().unwrap(); // Safe due to clap's requires constraint
+        certificate::load_pem_certificate(cert_path, key_path, args.password.as_deref())
+    } else if let Some(cert_path) = &args.certificate {
+        // Positional mode: detect format by content
+        let cert_type = certificate::detect_cert_type(cert_path)
+            .map_err(|e| format!("Failed to detect certificate type: {}", e))?;
+
+        match cert_type {
+            certificate::CertType::Pfx => {
+                // PFX format: certificate [password]
+                certificate::load_certificate(cert_path, args.password_or_key.as_deref().or(args.password.as_deref()))
+            }
+            certificate::CertType::Pem => {
+                // PEM format: certificate keyfile, without a keyfile the private key is expected in the certificate file
+                let key_path = args.password_or_key.as_deref().unwrap_or(cert_path);
+                // Not echoing the argument, which may be a password meant for a PFX file
+                if !Path::new(key_path).exists() {
+                    return Err("Key file given as the second argument not found".into());
                 }
-            };
+                certificate::load_pem_certificate(cert_path, key_path, args.password.as_deref())
+            }
+        }
+    } else {
+        Err("No certificate specified. Use either positional arguments or named flags (--pfx or --cert/--key)".into())
+    }
+}
 
-            println!("Forwarding request to http://{}", output_address);
+/// Load the optional second certificate given with --second-cert and --second-key
+fn load_second_certificate(args: &Args) -> Result<Option<CertifiedKey>, Box<dyn std::error::Error + Send + Sync>> {
+    let (Some(cert_path), Some(key_path)) = (&args.second_cert, &args.second_key) else {
+        return Ok(None);
+    };
+    let (certs, private_key) = certificate::load_pem_certificate(cert_path, key_path, args.password.as_deref())?;
+    Ok(Some(certificate::certified_key(certs, private_key)?))
+}
 
-            // Bidirectional TCP forwarding (TLS <-> HTTP)
-            if let Err(e) = tokio::io::copy_bidirectional(
-                &mut tls_stream,
-                &mut backend_stream
-            ).await {
-                eprintln!("Proxy forwarding error: {}", e);
+/// Load the certificates given with --sni-cert, with their server name patterns
+fn load_sni_certificates(args: &Args) -> Result<Vec<(String, CertifiedKey)>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut certificates = Vec::new();
+    for files in &args.sni_certs {
+        let loaded = match certificate::detect_cert_type(&files.cert)? {
+            certificate::CertType::Pfx => certificate::load_certificate(&files.cert, args.password.as_deref()),
+            certificate::CertType::Pem => {
+                let key_path = files.key.as_deref().unwrap_or(&files.cert);
+                certificate::load_pem_certificate(&files.cert, key_path, args.password.as_deref())
             }
-        });
+        };
+        let (certs, private_key) = loaded.map_err(|e| format!("Failed to load certificate for {}: {}", files.pattern, e))?;
+        if certs.first().is_some_and(certificate::has_must_staple) {
+            return Err(format!("Certificate for {} has the OCSP Must-Staple extension, which is only supported for the main certificate", files.pattern).into());
+        }
+        let certified_key = certificate::certified_key(certs, private_key)?;
+        let names = certified_key.cert.first().map(certificate::names).unwrap_or_default();
+        let covered = if files.pattern.starts_with("*.") {
+            names.contains(&files.pattern)
+        } else {
+            certificate::covers(&names, &files.pattern)
+        };
+        if !covered {
+            warn!("Certificate {} does not cover {}, clients checking the hostname will reject it", files.cert, files.pattern);
+        }
+        certificates.push((files.pattern.clone(), certified_key));
+    }
+    Ok(certificates)
+}
+
+/// Certificate and key files given on the command line
+fn certificate_paths(args: &Args) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = [&args.certificate, &args.pfx, &args.cert, &args.key, &args.second_cert, &args.second_key]
+        .into_iter()
+        .flatten()
+        .chain(args.sni_certs.iter().flat_map(|files| std::iter::once(&files.cert).chain(&files.key)))
+        .map(PathBuf::from)
+        .collect();
+    // The second positional argument is a key file for PEM certificates
+    if let Some(key) = &args.password_or_key {
+        if Path::new(key).is_file() {
+            paths.push(PathBuf::from(key));
+        }
     }
+    paths
 }
@@ -0,0 +1,98 @@
+use crate::info;
+use crate::hooks;
+use crate::routing::IpNet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Page served while in maintenance mode when no `--maintenance-page` is given
+const DEFAULT_PAGE: &str = "<html><head><title>503 Service Unavailable</title></head><body>\
+    <h1>Down for maintenance</h1>\
+    <p>This site is undergoing maintenance and will be back shortly.</p>\
+    </body></html>\n";
+
+/// Maintenance mode, answering clients with a 503 page instead of forwarding to the backend
+#[derive(Debug)]
+pub struct Maintenance {
+    /// Set while enabled, with the time maintenance ends automatically
+    state: Mutex<Option<Option<Instant>>>,
+    /// Clients still forwarded to the backend during maintenance
+    allowed: Vec<IpNet>,
+    page: String,
+}
+
+impl Maintenance {
+    pub fn new(allowed: Vec<IpNet>, page: Option<String>) -> Self {
+        Self {
+            state: Mutex::new(None),
+            allowed,
+            page: page.unwrap_or_else(|| DEFAULT_PAGE.to_string()),
+        }
+    }
+
+    /// Enter maintenance mode, until disabled or for the given time
+    pub fn enable(&self, duration: Option<Duration>) {
+        *self.state.lock().unwrap() = Some(duration.map(|duration| Instant::now() + duration));
+        match duration {
+            Some(duration) => info!("Maintenance mode enabled for {}s", duration.as_secs()),
+            None => info!("Maintenance mode enabled"),
+        }
+    }
+
+    /// Leave maintenance mode, returns whether it was enabled
+    pub fn disable(&self) -> bool {
+        let enabled = self.state.lock().unwrap().take().is_some();
+        if enabled {
+            info!("Maintenance mode disabled");
+        }
+        enabled
+    }
+
+    /// Whether maintenance mode is enabled, and the time left when it ends automatically
+    fn status(&self) -> Option<Option<Duration>> {
+        let mut state = self.state.lock().unwrap();
+        let until = (*state)?;
+        match until.map(|until| until.checked_duration_since(Instant::now())) {
+            Some(None) => {
+                *state = None;
+                info!("Maintenance mode ended");
+                None
+            }
+            remaining => Some(remaining.flatten()),
+        }
+    }
+
+    /// Whether the client is to be answered with the maintenance page
+    pub fn applies(&self, peer: IpAddr) -> bool {
+        self.status().is_some() && !self.allowed.iter().any(|net| net.contains(peer))
+    }
+
+    /// Render the state as a JSON object
+    pub fn render_json(&self) -> String {
+        match self.status() {
+            Some(remaining) => format!(
+                "{{\"enabled\":true,\"remaining_secs\":{}}}",
+                remaining.map_or("null".to_string(), |remaining| remaining.as_secs().to_string())
+            ),
+            None => "{\"enabled\":false,\"remaining_secs\":null}".to_string(),
+        }
+    }
+
+    /// Answer a request with the maintenance page, then close the connection
+    pub async fn respond<S: AsyncWrite + Unpin>(&self, stream: &mut S, head: &[u8]) -> std::io::Result<()> {
+        let head_only = hooks::request_target(head).is_some_and(|(method, _)| method == "HEAD");
+        let retry_after = match self.status().flatten() {
+            Some(remaining) => format!("Retry-After: {}\r\n", remaining.as_secs().max(1)),
+            None => String::new(),
+        };
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/html\r\nContent-Length: {}\r\n{}Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+            self.page.len(),
+            retry_after,
+            if head_only { "" } else { &self.page }
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
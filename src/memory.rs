@@ -0,0 +1,117 @@
+use crate::metrics;
+use crate::records;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Buffer sizes used by a single proxied connection
+#[derive(Debug, Clone, Copy)]
+pub struct Buffers {
+    pub client_to_backend: usize,
+    pub backend_to_client: usize,
+    /// Limit on data buffered inside the TLS session before it is written to the client
+    pub tls_send: usize,
+}
+
+impl Buffers {
+    /// Buffers used while the memory budget allows
+    pub const FULL: Buffers = Buffers {
+        client_to_backend: 8 * 1024,
+        backend_to_client: records::THROUGHPUT_BUFFER,
+        tls_send: 64 * 1024,
+    };
+
+    /// Shrunk buffers used when the memory budget is (nearly) exhausted
+    pub const REDUCED: Buffers = Buffers {
+        client_to_backend: 2 * 1024,
+        backend_to_client: 4 * 1024,
+        tls_send: 8 * 1024,
+    };
+
+    /// Total size in KiB, the unit of the budget
+    fn kib(&self) -> u32 {
+        ((self.client_to_backend + self.backend_to_client + self.tls_send) / 1024) as u32
+    }
+}
+
+/// Cap on the buffer memory of all active connections together
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit_kib: u32,
+    semaphore: Arc<Semaphore>,
+    /// Connections that had to use reduced buffers
+    pub reduced_total: AtomicU64,
+    /// Connections that had to wait for memory to become available
+    pub waits_total: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit_bytes: u64) -> Self {
+        let limit_kib = (limit_bytes / 1024).min(Semaphore::MAX_PERMITS as u64) as u32;
+        Self {
+            limit_kib,
+            semaphore: Arc::new(Semaphore::new(limit_kib as usize)),
+            reduced_total: AtomicU64::new(0),
+            waits_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserve buffer memory for a connection
+    ///
+    /// Uses full-size buffers when available, falls back to reduced buffers, and otherwise waits
+    /// until other connections release memory (backpressure on new connections).
+    pub async fn reserve(&self) -> Reservation {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_many_owned(Buffers::FULL.kib()) {
+            return Reservation::new(Buffers::FULL, permit);
+        }
+        self.reduced_total.fetch_add(1, Ordering::Relaxed);
+        if let Ok(permit) = self.semaphore.clone().try_acquire_many_owned(Buffers::REDUCED.kib()) {
+            return Reservation::new(Buffers::REDUCED, permit);
+        }
+        self.waits_total.fetch_add(1, Ordering::Relaxed);
+        let permit = self.semaphore
+            .clone()
+            .acquire_many_owned(Buffers::REDUCED.kib().min(self.limit_kib))
+            .await
+            .expect("memory budget semaphore is never closed");
+        Reservation::new(Buffers::REDUCED, permit)
+    }
+
+    /// Render budget usage as metric lines
+    pub fn render(&self) -> String {
+        let reserved = self.limit_kib as usize - self.semaphore.available_permits();
+        let mut out = String::new();
+        metrics::family(&mut out, "memory_budget_bytes", "gauge", "Memory budget for connection buffers");
+        out.push_str(&format!("memory_budget_bytes {}\n", self.limit_kib as u64 * 1024));
+        metrics::family(&mut out, "memory_reserved_bytes", "gauge", "Buffer memory reserved by open connections");
+        out.push_str(&format!("memory_reserved_bytes {}\n", reserved as u64 * 1024));
+        metrics::family(&mut out, "memory_reduced_buffers_total", "counter", "Connections given reduced buffers");
+        out.push_str(&format!("memory_reduced_buffers_total {}\n", self.reduced_total.load(Ordering::Relaxed)));
+        metrics::family(&mut out, "memory_waits_total", "counter", "Connections that waited for buffer memory");
+        out.push_str(&format!("memory_waits_total {}\n", self.waits_total.load(Ordering::Relaxed)));
+        out
+    }
+}
+
+/// Buffer memory reserved for a connection, released when dropped
+pub struct Reservation {
+    pub buffers: Buffers,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Reservation {
+    fn new(buffers: Buffers, permit: OwnedSemaphorePermit) -> Self {
+        Self {
+            buffers,
+            _permit: Some(permit),
+        }
+    }
+
+    /// Full-size buffers without a memory budget
+    pub fn unlimited() -> Self {
+        Self {
+            buffers: Buffers::FULL,
+            _permit: None,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::handshake::HandshakeFailure;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the handshake duration buckets, in seconds
+const HANDSHAKE_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Durations counted per bucket, like a Prometheus histogram
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; HANDSHAKE_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Process-wide counters, exposed through the admin API
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// TCP connections accepted, before the TLS handshake
+    pub connections_accepted: AtomicU64,
+    /// Connections with a completed TLS handshake
+    pub connections_total: AtomicU64,
+    /// Requests whose backend connection was closed as the client disconnected while waiting (HTTP mode)
+    pub requests_cancelled: AtomicU64,
+    handshake_failures: Mutex<BTreeMap<&'static str, u64>>,
+    tagged_connections: Mutex<BTreeMap<String, u64>>,
+    handshake_durations: Mutex<Histogram>,
+    /// Bytes from and to clients (in and out) by the backend they were last forwarded to
+    backend_bytes: Mutex<BTreeMap<String, (u64, u64)>>,
+}
+
+impl Metrics {
+    /// Count an accepted connection, along with the tags it carries
+    pub fn record_connection(&self, tags: &[String]) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        let mut tagged = self.tagged_connections.lock().unwrap();
+        for tag in tags {
+            *tagged.entry(tag.clone()).or_default() += 1;
+        }
+    }
+
+    /// Record the duration of a completed handshake
+    pub fn record_handshake(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut histogram = self.handshake_durations.lock().unwrap();
+        if let Some(bucket) = HANDSHAKE_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Add the bytes of a closed connection to the backend it was last forwarded to
+    pub fn record_backend_bytes(&self, backend: &str, bytes_in: u64, bytes_out: u64) {
+        let mut backend_bytes = self.backend_bytes.lock().unwrap();
+        let bytes = backend_bytes.entry(backend.to_string()).or_default();
+        bytes.0 += bytes_in;
+        bytes.1 += bytes_out;
+    }
+
+    /// Count a failed handshake by its failure class
+    pub fn record_handshake_failure(&self, failure: HandshakeFailure) {
+        *self.handshake_failures.lock().unwrap().entry(failure.name()).or_default() += 1;
+    }
+
+    /// Failed handshakes of all failure classes
+    pub fn handshake_failures_total(&self) -> u64 {
+        self.handshake_failures.lock().unwrap().values().sum()
+    }
+
+    /// Render all counters as `name{label="value"} count` lines
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        family(&mut out, "connections_accepted_total", "counter", "TCP connections accepted, before the TLS handshake");
+        out.push_str(&format!("connections_accepted_total {}\n", self.connections_accepted.load(Ordering::Relaxed)));
+        family(&mut out, "connections_total", "counter", "Connections with a completed TLS handshake");
+        out.push_str(&format!("connections_total {}\n", self.connections_total.load(Ordering::Relaxed)));
+        family(&mut out, "connections_by_tag_total", "counter", "Connections with a completed TLS handshake per tag");
+        for (tag, count) in self.tagged_connections.lock().unwrap().iter() {
+            out.push_str(&format!("connections_by_tag_total{{tag=\"{}\"}} {}\n", escape(tag), count));
+        }
+        family(&mut out, "handshake_failures_total", "counter", "Failed TLS handshakes per reason");
+        for (reason, count) in self.handshake_failures.lock().unwrap().iter() {
+            out.push_str(&format!("handshake_failures_total{{reason=\"{}\"}} {}\n", escape(reason), count));
+        }
+        family(&mut out, "requests_cancelled_total", "counter", "Requests cancelled as the client disconnected while waiting");
+        out.push_str(&format!("requests_cancelled_total {}\n", self.requests_cancelled.load(Ordering::Relaxed)));
+        let backend_bytes = self.backend_bytes.lock().unwrap();
+        family(&mut out, "backend_bytes_in_total", "counter", "Bytes from clients per backend");
+        for (backend, (bytes_in, _)) in backend_bytes.iter() {
+            out.push_str(&format!("backend_bytes_in_total{{backend=\"{}\"}} {}\n", escape(backend), bytes_in));
+        }
+        family(&mut out, "backend_bytes_out_total", "counter", "Bytes to clients per backend");
+        for (backend, (_, bytes_out)) in backend_bytes.iter() {
+            out.push_str(&format!("backend_bytes_out_total{{backend=\"{}\"}} {}\n", escape(backend), bytes_out));
+        }
+
+        // Buckets are cumulative, the type line lets Prometheus treat them as one histogram
+        let histogram = self.handshake_durations.lock().unwrap();
+        family(&mut out, "handshake_duration_seconds", "histogram", "Duration of completed TLS handshakes");
+        let mut cumulative = 0;
+        for (bound, count) in HANDSHAKE_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            out.push_str(&format!("handshake_duration_seconds_bucket{{le=\"{}\"}} {}\n", bound, cumulative));
+        }
+        out.push_str(&format!("handshake_duration_seconds_bucket{{le=\"+Inf\"}} {}\n", histogram.count));
+        out.push_str(&format!("handshake_duration_seconds_sum {}\n", histogram.sum));
+        out.push_str(&format!("handshake_duration_seconds_count {}\n", histogram.count));
+        out
+    }
+}
+
+/// Start a metric family with its `# HELP` and `# TYPE` lines
+pub fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+/// Escape a label value for the text format: backslash, double quote and newline
+pub fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
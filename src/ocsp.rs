@@ -0,0 +1,156 @@
+use crate::{info, warn};
+use crate::http_client;
+use crate::resolver::CertResolver;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::X509;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time allowed for a request to the OCSP responder
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before retrying a failed refresh
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Bounds for the delay between refreshes
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_REFRESH_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Fetches OCSP responses for the served certificate and keeps the staple fresh
+pub struct Stapler {
+    resolver: Arc<CertResolver>,
+    leaf: X509,
+    issuer: X509,
+    responder: String,
+    /// Expiry (nextUpdate) of the currently stapled response
+    expires: Mutex<Option<SystemTime>>,
+}
+
+impl Stapler {
+    /// Prepare stapling for the certificate chain served by the resolver
+    pub fn new(resolver: Arc<CertResolver>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let certified_key = resolver.current();
+        let leaf = X509::from_der(certified_key.cert.first().ok_or("Empty certificate chain")?)?;
+        let issuer = X509::from_der(
+            certified_key.cert.get(1)
+                .ok_or("OCSP stapling requires the issuer certificate in the chain (use the full chain)")?,
+        )?;
+        let responder = leaf
+            .ocsp_responders()?
+            .iter()
+            .map(|url| url.to_string())
+            .find(|url| url.starts_with("http://"))
+            .ok_or("Certificate does not specify an http:// OCSP responder")?;
+
+        Ok(Self {
+            resolver,
+            leaf,
+            issuer,
+            responder,
+            expires: Mutex::new(None),
+        })
+    }
+
+    /// Fetch a fresh OCSP response and staple it, returns the delay until the next refresh
+    pub async fn refresh(&self) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+        let (response, next_update) = self.fetch().await?;
+        self.resolver.set_ocsp(Some(response));
+        *self.expires.lock().unwrap() = Some(next_update);
+
+        // Refresh halfway through the validity period
+        let remaining = next_update.duration_since(SystemTime::now()).unwrap_or_default();
+        info!("Stapled OCSP response valid for {:.1}h", remaining.as_secs_f64() / 3600.0);
+        Ok((remaining / 2).clamp(MIN_REFRESH_INTERVAL, MAX_REFRESH_INTERVAL))
+    }
+
+    /// Keep refreshing the staple, starting after the given delay
+    pub async fn run(self: Arc<Self>, mut delay: Duration) {
+        loop {
+            tokio::time::sleep(delay).await;
+            delay = match self.refresh().await {
+                Ok(delay) => delay,
+                Err(e) => {
+                    warn!("OCSP refresh from {} failed: {} (retrying in {}s)", self.responder, e, RETRY_INTERVAL.as_secs());
+                    self.drop_expired();
+                    RETRY_INTERVAL
+                }
+            };
+        }
+    }
+
+    /// Stop stapling a response that has expired, as clients reject expired responses
+    fn drop_expired(&self) {
+        let mut expires = self.expires.lock().unwrap();
+        if expires.is_some_and(|expires| expires <= SystemTime::now()) {
+            warn!("Stapled OCSP response expired, no longer stapling (clients requiring stapling will fail)");
+            self.resolver.set_ocsp(None);
+            *expires = None;
+        }
+    }
+
+    /// Request, validate and return the DER OCSP response and its nextUpdate time
+    async fn fetch(&self) -> Result<(Vec<u8>, SystemTime), Box<dyn std::error::Error + Send + Sync>> {
+        let cert_id = || OcspCertId::from_cert(MessageDigest::sha1(), &self.leaf, &self.issuer);
+        let mut request = OcspRequest::new()?;
+        request.add_id(cert_id()?)?;
+        let request = request.to_der()?;
+
+        let request = http_client::post(&self.responder, "application/ocsp-request", &request, None);
+        let (status, body) = tokio::time::timeout(REQUEST_TIMEOUT, request)
+            .await
+            .map_err(|_| "OCSP responder timed out")??;
+        if status != 200 {
+            return Err(format!("OCSP responder replied with status {}", status).into());
+        }
+
+        // Validate before stapling, a bad staple breaks the handshake for strict clients
+        let response = OcspResponse::from_der(&body)?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(format!("OCSP responder returned status {}", response.status().as_raw()).into());
+        }
+        let basic = response.basic()?;
+        let mut chain = Stack::new()?;
+        chain.push(self.issuer.clone())?;
+        basic.verify(&chain, &X509StoreBuilder::new()?.build(), OcspFlag::TRUST_OTHER)?;
+
+        let id = cert_id()?;
+        let status = basic.find_status(&id).ok_or("OCSP response does not cover the certificate")?;
+        if status.status != OcspCertStatus::GOOD {
+            return Err(format!("OCSP status of certificate is not good ({})", status.status.as_raw()).into());
+        }
+        status.check_validity(300, None)?;
+        let next_update = parse_time(&status.next_update.to_string())
+            .ok_or("OCSP response has no valid nextUpdate")?;
+
+        Ok((body, next_update))
+    }
+}
+
+/// Parse an OpenSSL time string such as `Jan  2 15:04:05 2026 GMT`
+fn parse_time(s: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let mut parts = s.split_whitespace();
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month_name)? as i64 + 1;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let mut clock = parts.next()?.split(':');
+    let hour: i64 = clock.next()?.parse().ok()?;
+    let minute: i64 = clock.next()?.parse().ok()?;
+    let second: i64 = clock.next()?.split('.').next()?.parse().ok()?;
+    let year: i64 = parts.next()?.parse().ok()?;
+
+    // Days since the epoch of a proleptic Gregorian date (Howard Hinnant's days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
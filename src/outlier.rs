@@ -0,0 +1,155 @@
+use crate::warn;
+use crate::backend::{Backend, Pool};
+use crate::events::{Event, EventHooks};
+use crate::metrics;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Weight of a new response time in the moving average
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// Responses needed before a backend's response time is compared to the others
+const MIN_LATENCY_SAMPLES: u32 = 10;
+
+/// Ejections beyond this count no longer extend the ejection time
+const MAX_EJECTION_MULTIPLIER: u32 = 10;
+
+/// Result of forwarding to a backend
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    /// The backend answered, in the given time when measured
+    Success(Option<Duration>),
+    /// The backend could not be reached, timed out or answered 502, 503 or 504
+    Error,
+}
+
+/// Passive health of a single backend
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    consecutive_errors: u32,
+    /// Moving average of the response time in seconds, and the number of responses averaged
+    latency: Option<(f64, u32)>,
+    ejected_until: Option<Instant>,
+    pub ejections: u32,
+}
+
+impl OutlierState {
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Temporarily ejects backends from the rotation that fail repeatedly or respond much slower than the others
+#[derive(Debug)]
+pub struct OutlierDetection {
+    /// Consecutive errors after which a backend is ejected
+    pub consecutive_errors: Option<u32>,
+    /// Eject a backend whose average response time exceeds this multiple of the others' median
+    pub latency_factor: Option<f64>,
+    /// Ejection time, multiplied by the number of times the backend was ejected before
+    pub ejection_time: Duration,
+    /// Share of the backends that may be ejected at the same time
+    pub max_ejected_percent: u32,
+}
+
+impl OutlierDetection {
+    /// Record the outcome of forwarding to a backend, ejecting it when it became an outlier
+    pub fn record(&self, pool: &Pool, backend: &Backend, outcome: Outcome, events: &EventHooks) {
+        let reason = {
+            let mut state = backend.outlier.lock().unwrap();
+            match outcome {
+                Outcome::Error => {
+                    state.consecutive_errors += 1;
+                    self.consecutive_errors
+                        .filter(|limit| state.consecutive_errors >= *limit)
+                        .map(|_| format!("{} consecutive errors", state.consecutive_errors))
+                }
+                Outcome::Success(latency) => {
+                    state.consecutive_errors = 0;
+                    if let Some(latency) = latency {
+                        let (average, samples) = state.latency.unwrap_or((latency.as_secs_f64(), 0));
+                        let average = average + LATENCY_WEIGHT * (latency.as_secs_f64() - average);
+                        state.latency = Some((average, samples + 1));
+                    }
+                    None
+                }
+            }
+        };
+        let reason = reason.or_else(|| self.slow(pool, backend));
+        if let Some(reason) = reason {
+            self.eject(pool, backend, &reason, events);
+        }
+    }
+
+    /// Compare the backend's response time to the median of the other backends
+    fn slow(&self, pool: &Pool, backend: &Backend) -> Option<String> {
+        let factor = self.latency_factor?;
+        let latency = |backend: &Backend| {
+            let state = backend.outlier.lock().unwrap();
+            state.latency.filter(|(_, samples)| *samples >= MIN_LATENCY_SAMPLES).map(|(average, _)| average)
+        };
+        let own = latency(backend)?;
+        let mut others: Vec<f64> = pool
+            .backends()
+            .iter()
+            .filter(|other| !std::ptr::eq(other.as_ref(), backend))
+            .filter_map(|other| latency(other))
+            .collect();
+        if others.is_empty() {
+            return None;
+        }
+        others.sort_by(f64::total_cmp);
+        let median = others[others.len() / 2];
+        (own > median * factor).then(|| format!("average response time {:.0}ms, others {:.0}ms", own * 1000.0, median * 1000.0))
+    }
+
+    /// Take the backend out of the rotation, unless too many backends are ejected already
+    fn eject(&self, pool: &Pool, backend: &Backend, reason: &str, events: &EventHooks) {
+        let now = Instant::now();
+        let ejected = pool.backends().iter().filter(|b| b.outlier.lock().unwrap().is_ejected(now)).count();
+        if (ejected + 1) * 100 > pool.backends().len() * self.max_ejected_percent as usize {
+            return;
+        }
+
+        let mut state = backend.outlier.lock().unwrap();
+        if state.is_ejected(now) {
+            return;
+        }
+        state.ejections += 1;
+        let duration = self.ejection_time * state.ejections.min(MAX_EJECTION_MULTIPLIER);
+        state.ejected_until = Some(now + duration);
+        *backend.recovered_at.lock().unwrap() = Some(now + duration);
+        // Start over once the backend is back, so it is judged by its new behavior
+        state.consecutive_errors = 0;
+        state.latency = None;
+        drop(state);
+
+        warn!("Backend http://{} ejected for {}s: {}", backend.address, duration.as_secs(), reason);
+        events.fire(
+            Event::BackendEjected,
+            &[
+                ("backend", backend.address.clone()),
+                ("reason", reason.to_string()),
+                ("ejection_seconds", duration.as_secs().to_string()),
+            ],
+        );
+    }
+
+    /// Render the ejection state of each backend as metric lines, ejected if any backend with the
+    /// address is
+    pub fn render(&self, backends: &BTreeMap<&str, Vec<&Backend>>) -> String {
+        let now = Instant::now();
+        let mut out = String::new();
+        metrics::family(&mut out, "backend_ejected", "gauge", "Whether outlier detection took the backend out of the rotation");
+        for (address, backends) in backends {
+            let ejected = backends.iter().any(|backend| backend.outlier.lock().unwrap().is_ejected(now));
+            out.push_str(&format!("backend_ejected{{backend=\"{}\"}} {}\n", metrics::escape(address), u8::from(ejected)));
+        }
+        metrics::family(&mut out, "backend_ejections_total", "counter", "Times outlier detection took the backend out of the rotation");
+        for (address, backends) in backends {
+            let ejections: u64 = backends.iter().map(|backend| u64::from(backend.outlier.lock().unwrap().ejections)).sum();
+            out.push_str(&format!("backend_ejections_total{{backend=\"{}\"}} {}\n", metrics::escape(address), ejections));
+        }
+        out
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{error, info, warn};
+use crate::listener;
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Maximum size of a plain HTTP request head we are willing to read
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Time a plain HTTP client gets to send its request head
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Path of ACME HTTP-01 challenge requests, followed by the token
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// How to answer plain HTTP requests sent to the TLS port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PlainHttpMode {
+    /// Respond with a 400 page linking to the https:// URL
+    Reject,
+    /// Respond with a 301 redirect to the https:// URL
+    Redirect,
+    /// Respond with a temporary redirect to the https:// URL, explaining it in the body (for `--dev`)
+    #[value(skip)]
+    Explain,
+}
+
+/// Whether the first byte of a connection looks like a plain HTTP request instead of a TLS record
+pub async fn is_plain_http(stream: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    match stream.peek(&mut first).await {
+        // TLS records start with a content type byte (0x16 for a handshake), HTTP methods with an uppercase letter
+        Ok(1) => first[0].is_ascii_uppercase(),
+        _ => false,
+    }
+}
+
+/// Answer every request on a plain HTTP listener (`--redirect-http`) with a redirect to the
+/// https:// URL on the HTTPS port, and ACME HTTP-01 challenges from the webroot
+pub async fn serve_redirects(listener: Arc<TcpListener>, https_port: u16, webroot: Option<PathBuf>) {
+    let webroot = Arc::new(webroot);
+    loop {
+        let (stream, _) = listener::accept(&listener).await;
+        let webroot = webroot.clone();
+        tokio::spawn(async move {
+            // Clients without a Host header are sent to the address they connected to
+            let local_addr = stream.local_addr().map_or_else(|_| "localhost".to_string(), |addr| addr.to_string());
+            if let Err(e) = respond(stream, PlainHttpMode::Redirect, &local_addr, Some(https_port), webroot.as_deref()).await {
+                error!("HTTP redirect error: {}", e);
+            }
+        });
+    }
+}
+
+/// Read the plain HTTP request head and answer it according to the mode, or with the key
+/// authorization of an ACME HTTP-01 challenge found in the webroot. The redirect keeps the port
+/// of the Host header, unless another HTTPS port is given.
+pub async fn respond(
+    mut stream: TcpStream,
+    mode: PlainHttpMode,
+    fallback_host: &str,
+    https_port: Option<u16>,
+    webroot: Option<&Path>,
+) -> std::io::Result<()> {
+    // Read request head
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let read_head = async {
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_SIZE {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    // A partial head is still good enough to build the https:// URL
+    let _ = tokio::time::timeout(READ_TIMEOUT, read_head).await;
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut lines = head.lines();
+    let path = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .filter(|path| path.starts_with('/'))
+        .unwrap_or("/");
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())
+        .filter(|host| !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c)))
+        .unwrap_or(fallback_host);
+    let host = match https_port {
+        Some(port) => with_port(host, port),
+        None => host.to_string(),
+    };
+    let url = format!("https://{}{}", host, sanitize_path(path));
+
+    let challenge = webroot.zip(path.strip_prefix(ACME_CHALLENGE_PATH));
+    let response = match (challenge, mode) {
+        (Some((webroot, token)), _) => challenge_response(webroot, token).await,
+        (None, PlainHttpMode::Reject) => {
+            let body = format!(
+                "<html><head><title>400 Bad Request</title></head><body>\
+                 <h1>400 Bad Request</h1>\
+                 <p>This port expects HTTPS, but a plain HTTP request was received.</p>\
+                 <p>Try <a href=\"{0}\">{0}</a> instead.</p>\
+                 </body></html>\n",
+                url
+            );
+            format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        (None, PlainHttpMode::Redirect) => format!(
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            url
+        ),
+        // Temporary, so browsers do not remember the redirect for a port that may serve plain HTTP again tomorrow
+        (None, PlainHttpMode::Explain) => {
+            let body = format!(
+                "This port expects HTTPS, but a plain HTTP request was received.\n\
+                 Redirecting to {}\n\
+                 (curl follows this with -L, and accepts an untrusted development certificate with -k)\n",
+                url
+            );
+            format!(
+                "HTTP/1.1 307 Temporary Redirect\r\nLocation: {}\r\nContent-Type: text/plain\r\nCache-Control: no-store\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                url,
+                body.len(),
+                body
+            )
+        }
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answer a challenge with the key authorization an ACME client (e.g. `certbot --webroot`) wrote for the token
+async fn challenge_response(webroot: &Path, token: &str) -> String {
+    // Tokens are base64url, anything else could point outside of the challenge directory
+    let valid = !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    let body = match valid {
+        true => tokio::fs::read(webroot.join(&ACME_CHALLENGE_PATH[1..]).join(token)).await.ok(),
+        false => None,
+    };
+    match body {
+        Some(body) => {
+            info!("Answered ACME challenge {}", token);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                String::from_utf8_lossy(&body)
+            )
+        }
+        None => {
+            warn!("No ACME challenge found for token {}", sanitize_path(token));
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 10\r\nConnection: close\r\n\r\nNot found\n".to_string()
+        }
+    }
+}
+
+/// Replace the port of a host (`example.com:80`, `[::1]:80`), leaving it out for the default 443
+fn with_port(host: &str, port: u16) -> String {
+    let name = match host.rsplit_once(':') {
+        Some((name, _)) if !host.ends_with(']') && (name.starts_with('[') || !name.contains(':')) => name,
+        _ => host,
+    };
+    match port {
+        443 => name.to_string(),
+        _ => format!("{}:{}", name, port),
+    }
+}
+
+/// Keep only characters that are safe to echo into a URL, header and HTML attribute
+fn sanitize_path(path: &str) -> String {
+    path.chars()
+        .filter(|c| c.is_ascii_graphic() && !"\"'<>\\`".contains(*c))
+        .collect()
+}
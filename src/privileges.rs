@@ -0,0 +1,135 @@
+use crate::info;
+
+/// Drop root privileges by switching to the given user and/or group
+///
+/// Users and groups can be given by name or numeric id. When only a user is given, its primary
+/// group is used. Supplementary groups are cleared.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+
+    // Group first, as changing it requires the privileges we are about to give up
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(format!("Failed to clear supplementary groups: {}", std::io::Error::last_os_error()).into());
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(format!("Failed to switch to group {}: {}", gid, std::io::Error::last_os_error()).into());
+        }
+    }
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(format!("Failed to switch to user {}: {}", uid, std::io::Error::last_os_error()).into());
+        }
+        // Make sure the switch cannot be undone
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err("Privileges could not be dropped permanently".into());
+        }
+    }
+
+    info!(
+        "Dropped privileges to uid {} gid {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: Option<&str>, _group: Option<&str>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("Dropping privileges (--user/--group) is only supported on Unix".into())
+}
+
+/// Version 3 of the capability interface (64-bit capability sets)
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Clear the capabilities of a non-root process, such as CAP_NET_BIND_SERVICE granted to the
+/// binary with `setcap` to bind port 443
+#[cfg(target_os = "linux")]
+pub fn drop_capabilities() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Root keeps its powers through its uid anyway, see --user
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(format!("Failed to read capabilities: {}", std::io::Error::last_os_error()).into());
+    }
+    if data.iter().all(|set| set.permitted == 0 && set.effective == 0) {
+        return Ok(());
+    }
+
+    let data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(format!("Failed to drop capabilities: {}", std::io::Error::last_os_error()).into());
+    }
+    info!("Dropped capabilities");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_capabilities() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Ok(())
+}
+
+/// Resolve a user name or id to its uid and primary gid
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let name = std::ffi::CString::new(user).map_err(|_| format!("Invalid user name: {}", user))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let found = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) } == 0
+        && !result.is_null();
+    if found {
+        return Ok((passwd.pw_uid, passwd.pw_gid));
+    }
+
+    // Fall back to a numeric uid, which then also serves as gid
+    user.parse::<libc::uid_t>()
+        .map(|uid| (uid, uid))
+        .map_err(|_| format!("Unknown user: {}", user))
+}
+
+/// Resolve a group name or id to its gid
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    let name = std::ffi::CString::new(group).map_err(|_| format!("Invalid group name: {}", group))?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+
+    let found = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) } == 0
+        && !result.is_null();
+    if found {
+        return Ok(entry.gr_gid);
+    }
+
+    group.parse::<libc::gid_t>().map_err(|_| format!("Unknown group: {}", group))
+}
@@ -0,0 +1,40 @@
+use crate::hooks;
+use std::time::Instant;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Check whether a request head is a GET or HEAD request for the probe path, returns whether it is a HEAD request
+pub fn matches(head: &[u8], probe_path: &str) -> Option<bool> {
+    let (method, path) = hooks::request_target(head)?;
+    if path != probe_path {
+        return None;
+    }
+    match method {
+        "GET" => Some(false),
+        "HEAD" => Some(true),
+        _ => None,
+    }
+}
+
+/// Answer a health probe with the proxy version and uptime, then close the connection
+pub async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, started: Instant, head_only: bool) -> std::io::Result<()> {
+    let body = format!(
+        "{{\"status\":\"ok\",\"version\":\"{}\",\"uptime_seconds\":{}}}\n",
+        env!("CARGO_PKG_VERSION"),
+        started.elapsed().as_secs()
+    );
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        if head_only { "" } else { &body }
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Parse the `--probe-path` (or `--health-check-path`) argument
+pub fn parse_probe_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s.contains(char::is_whitespace) {
+        return Err("path must start with / and not contain whitespace".to_string());
+    }
+    Ok(s.to_string())
+}
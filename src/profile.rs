@@ -0,0 +1,27 @@
+use clap::ValueEnum;
+use std::time::Duration;
+
+/// Protocol spoken through the TLS connection, for wrapping non-HTTP services
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Any protocol, no idle timeout
+    Raw,
+    /// MQTT brokers (clients ping at their keep-alive interval, typically 60 seconds)
+    Mqtt,
+    /// IMAP servers (IDLE sessions are refreshed every 29 minutes)
+    Imap,
+    /// SMTP submission and relay (servers wait up to 10 minutes for commands, RFC 5321)
+    Smtp,
+}
+
+impl Profile {
+    /// Time a connection may stay silent in both directions before it is closed
+    pub fn idle_timeout(self) -> Option<Duration> {
+        match self {
+            Profile::Raw => None,
+            Profile::Mqtt => Some(Duration::from_secs(10 * 60)),
+            Profile::Imap => Some(Duration::from_secs(31 * 60)),
+            Profile::Smtp => Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
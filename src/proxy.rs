@@ -0,0 +1,550 @@
+use crate::{debug, info, warn};
+use crate::access::{self, AccessLog};
+use crate::acme;
+use crate::alerts::Alerts;
+use crate::backend::{self, Backend, Pool};
+use crate::cache::Cache;
+use crate::close::{self, ClosePolicy};
+use crate::drain::Drain;
+use crate::dscp::{self, DscpRule};
+use crate::encoding::Compression;
+use crate::events::EventHooks;
+use crate::fds::FdUsage;
+use crate::forwarded::ForwardedStyle;
+use crate::h2;
+use crate::handshake;
+use crate::handshake::HandshakeFailure;
+use crate::health::HealthCheck;
+use crate::hooks::{self, ConnectionHook};
+use crate::http::Mode;
+use crate::http_forward;
+use crate::http_routing::{HostRoute, PathRoute};
+use crate::log;
+use crate::logs::LogBuffer;
+use crate::maintenance::Maintenance;
+use crate::memory::{Buffers, MemoryBudget, Reservation};
+use crate::metrics::Metrics;
+use crate::ocsp::Stapler;
+use crate::outlier::{OutlierDetection, Outcome};
+use crate::plaintext::{self, PlainHttpMode};
+use crate::probe;
+use crate::profile::Profile;
+use crate::proxy_protocol::{self, ProxyProtocol};
+use crate::records::{RecordSizer, RecordSizing};
+use crate::redact::LogRedact;
+use crate::registry::{Connection, Counted, Registry};
+use crate::relay;
+use crate::resolver::CertResolver;
+use crate::retry::RetryBudget;
+use crate::routing::{self, IpNet, Route};
+use crate::security::SecurityHeaders;
+use crate::session::SessionState;
+use crate::starttls::{self, Starttls};
+use crate::tags::{self, TagRule};
+use crate::throttle::ErrorThrottle;
+use crate::timeouts::{self, ReadTimeout, RouteTimeout, Timeouts};
+use crate::trace::{self, Tracing};
+use crate::tui::Dashboard;
+use rustls::ProtocolVersion;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Shared state used by all connection tasks
+pub struct Proxy {
+    pub tls_acceptor: TlsAcceptor,
+    pub resolver: Arc<CertResolver>,
+    /// TLS session cache and ticket keys, kept across certificate reloads
+    pub sessions: Arc<SessionState>,
+    pub input_address: SocketAddr,
+    /// Backends and routes, replaced when the configuration is reloaded
+    pub upstreams: RwLock<Arc<Upstreams>>,
+    /// Active connections and counters, shared by all listeners of the process
+    pub registry: Arc<Registry>,
+    pub metrics: Arc<Metrics>,
+    pub tag_rules: Vec<TagRule>,
+    pub plain_http: PlainHttpMode,
+    /// Directory ACME HTTP-01 challenges sent to the TLS port are answered from
+    pub acme_webroot: Option<PathBuf>,
+    /// OCSP stapler and the delay until its first refresh
+    pub stapler: Option<(Arc<Stapler>, Duration)>,
+    pub record_sizing: RecordSizing,
+    /// Cap on the buffer memory of all connections, of all listeners
+    pub memory: Option<Arc<MemoryBudget>>,
+    /// Hooks called during the connection lifecycle (embedders only)
+    pub hooks: Vec<Arc<dyn ConnectionHook>>,
+    /// Shell commands run on operational events
+    pub events: Arc<EventHooks>,
+    /// Path answered by the proxy itself for health probes
+    pub probe_path: Option<String>,
+    pub started: Instant,
+    /// Default backend timeouts and per-route overrides (by path prefix of the first request)
+    pub timeouts: Timeouts,
+    pub route_timeouts: Vec<RouteTimeout>,
+    pub mode: Mode,
+    /// Protocol profile for non-HTTP services, disables HTTP-specific handling
+    pub profile: Option<Profile>,
+    /// Close connections without traffic in either direction for this long
+    pub idle_timeout: Option<Duration>,
+    /// Send HTTP/2 clients without traffic for this long a PING, closing the connection when unanswered
+    pub keepalive: Option<Duration>,
+    /// Answer the plaintext dialog of a mail protocol and upgrade to TLS on STARTTLS
+    pub starttls: Option<Starttls>,
+    /// Response cache (HTTP mode only)
+    pub cache: Option<Cache>,
+    /// gzip compression of responses (HTTP mode only)
+    pub compression: Option<Compression>,
+    /// Headers such as HSTS added to responses (HTTP mode only)
+    pub security_headers: Option<SecurityHeaders>,
+    /// Set X-Forwarded-* and X-Real-IP headers on requests (HTTP mode only)
+    pub forwarded_headers: bool,
+    /// Which headers tell the backend about the client
+    pub forwarded_style: ForwardedStyle,
+    /// Proxies in front of this one whose forwarded headers are kept and extended
+    pub trusted_proxies: Vec<IpNet>,
+    /// How often failed idempotent requests are sent again (HTTP mode only), within the retry budget
+    pub retries: u32,
+    pub retry_budget: Arc<RetryBudget>,
+    /// Passive health checking of the backends
+    pub outlier: Option<OutlierDetection>,
+    /// Active health checking of the backends
+    pub health_check: Option<Arc<HealthCheck>>,
+    /// How client connections are closed
+    pub close: ClosePolicy,
+    pub fds: FdUsage,
+    /// What to hide from log output
+    pub log_redact: LogRedact,
+    /// Per-connection errors, with floods of identical ones collapsed into summaries
+    pub error_log: Arc<ErrorThrottle>,
+    /// Line per request (HTTP mode only)
+    pub access_log: Option<AccessLog>,
+    /// Whether clients are announced by a PROXY protocol header from a load balancer
+    pub proxy_protocol_in: bool,
+    /// Source address of backend connections
+    pub backend_bind: Option<IpAddr>,
+    /// DSCP class of client connections
+    pub dscp: Option<u8>,
+    /// DSCP classes of backend connections
+    pub backend_dscp: Vec<DscpRule>,
+    /// Whether backend connections use multipath TCP
+    pub mptcp: bool,
+    /// PROXY protocol header sent on every backend connection
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Recent log lines, for the admin API and the dashboard
+    pub logs: Option<Arc<LogBuffer>>,
+    /// Live terminal dashboard shown instead of the log output
+    pub dashboard: Option<Arc<Dashboard>>,
+    /// Connection and error rate thresholds
+    pub alerts: Option<Alerts>,
+    /// Maintenance mode, toggled through the admin API for all listeners
+    pub maintenance: Arc<Maintenance>,
+    /// Winding down client connections, toggled through the admin API for all listeners
+    pub drain: Arc<Drain>,
+    /// Verbose tracing of selected connections, requested through the admin API for all listeners
+    pub tracing: Arc<Tracing>,
+}
+
+impl Proxy {
+    /// Record the outcome of forwarding to a backend of the pool for alerting and outlier detection
+    pub fn record_outcome(&self, pool: &Pool, backend: &Backend, outcome: Outcome) {
+        if let Some(alerts) = &self.alerts {
+            alerts.record_backend(matches!(outcome, Outcome::Success(_)));
+        }
+        if let Some(outlier) = &self.outlier {
+            outlier.record(pool, backend, outcome, &self.events);
+        }
+    }
+
+    /// The current backends and routes
+    pub fn upstreams(&self) -> Arc<Upstreams> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    /// Switch to new backends and routes, open connections keep the ones they started with
+    pub fn replace_upstreams(&self, mut upstreams: Upstreams) {
+        upstreams.adopt(&self.upstreams());
+        *self.upstreams.write().unwrap() = Arc::new(upstreams);
+    }
+}
+
+/// The default backends and the routes selecting other backends
+#[derive(Debug)]
+pub struct Upstreams {
+    pub backends: Pool,
+    /// Rules selecting backends and timeouts by SNI, ALPN and client address (TCP mode only)
+    pub routes: Vec<Route>,
+    /// Backends by `Host` header of requests (HTTP mode only)
+    pub host_routes: Vec<HostRoute>,
+    /// Backends by path prefix of requests, taking precedence over host routes (HTTP mode only)
+    pub path_routes: Vec<PathRoute>,
+}
+
+impl Upstreams {
+    /// The default backends followed by the backends of each route
+    pub fn pools(&self) -> impl Iterator<Item = &Pool> {
+        std::iter::once(&self.backends)
+            .chain(self.routes.iter().filter_map(|route| route.backends.as_ref()))
+            .chain(self.host_routes.iter().map(|route| &route.backends))
+            .chain(self.path_routes.iter().map(|route| &route.backends))
+    }
+
+    /// Keep the backends of `previous` that are still used, along with their connections, health and ejections
+    fn adopt(&mut self, previous: &Upstreams) {
+        let backends: Vec<_> = previous.pools().flat_map(|pool| pool.backends().iter().cloned()).collect();
+        self.backends.adopt(&backends);
+        let pools = self
+            .routes
+            .iter_mut()
+            .filter_map(|route| route.backends.as_mut())
+            .chain(self.host_routes.iter_mut().map(|route| &mut route.backends))
+            .chain(self.path_routes.iter_mut().map(|route| &mut route.backends));
+        pools.for_each(|pool| pool.adopt(&backends));
+    }
+}
+
+/// Handle a single client connection: TLS handshake, backend connect and forwarding
+pub async fn handle_connection(proxy: Arc<Proxy>, client_stream: TcpStream, peer_addr: SocketAddr) {
+    log::scope(serve_connection(proxy, client_stream, peer_addr)).await
+}
+
+async fn serve_connection(proxy: Arc<Proxy>, mut client_stream: TcpStream, peer_addr: SocketAddr) {
+    let accepted = Instant::now();
+    let (mut peer_addr, mut local_addr) = (peer_addr, client_stream.local_addr().unwrap_or(proxy.input_address));
+    log::set("peer", proxy.log_redact.client(peer_addr));
+    proxy.metrics.connections_accepted.fetch_add(1, Ordering::Relaxed);
+
+    // Behind a load balancer the client's address arrives in a PROXY protocol header
+    if proxy.proxy_protocol_in {
+        match proxy_protocol::read_header(&mut client_stream).await {
+            Ok(Some(addresses)) => {
+                (peer_addr, local_addr) = addresses;
+                log::set("peer", proxy.log_redact.client(peer_addr));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                proxy.error_log.log(format!("PROXY protocol error from {}: {}", proxy.log_redact.client(peer_addr), e));
+                return;
+            }
+        }
+    }
+    if let Some(alerts) = &proxy.alerts {
+        alerts.record_connection();
+    }
+    if let Err(reason) = hooks::check(&proxy.hooks, |hook| hook.on_accept(peer_addr)) {
+        proxy.error_log.log(format!("Connection from {} rejected by hook: {}", proxy.log_redact.client(peer_addr), reason));
+        return;
+    }
+
+    // Mail clients connect in plaintext and ask for the upgrade
+    if let Some(protocol) = proxy.starttls {
+        match starttls::negotiate(&mut client_stream, protocol).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                proxy.error_log.log(format!("STARTTLS negotiation with {} failed: {}", proxy.log_redact.client(peer_addr), e));
+                return;
+            }
+        }
+    }
+
+    // Answer plain HTTP requests with a pointer to the https:// URL instead of a failed handshake
+    if proxy.profile.is_none() && plaintext::is_plain_http(&client_stream).await {
+        proxy.metrics.record_handshake_failure(HandshakeFailure::PlainHttp);
+        if let Some(alerts) = &proxy.alerts {
+            alerts.record_handshake(false);
+        }
+        warn!("Plain HTTP request from {} on the TLS port", proxy.log_redact.client(peer_addr));
+        if let Err(e) = plaintext::respond(client_stream, proxy.plain_http, &proxy.input_address.to_string(), None, proxy.acme_webroot.as_deref()).await {
+            proxy.error_log.log(format!("Plain HTTP response error: {}", e));
+        }
+        return;
+    }
+
+    // TLS handshake
+    let fd = client_stream.as_raw_fd();
+    let handshake_started = Instant::now();
+    let mut tls_stream = match proxy.tls_acceptor.accept(client_stream).await {
+        Ok(s) => s,
+        Err(e) => {
+            let failure = handshake::classify(&e);
+            proxy.metrics.record_handshake_failure(failure);
+            if let Some(alerts) = &proxy.alerts {
+                alerts.record_handshake(false);
+            }
+            proxy.error_log.log(format!("TLS handshake error from {} ({}): {}: {}", proxy.log_redact.client(peer_addr), failure.name(), failure.advice(), e));
+            return;
+        }
+    };
+
+    proxy.metrics.record_handshake(handshake_started.elapsed());
+    if let Some(alerts) = &proxy.alerts {
+        alerts.record_handshake(true);
+    }
+
+    // A certificate authority validating an on-demand name only needs the handshake
+    if tls_stream.get_ref().1.alpn_protocol() == Some(acme::ACME_TLS_ALPN) {
+        let _ = tls_stream.shutdown().await;
+        return;
+    }
+
+    if let Err(reason) = hooks::check(&proxy.hooks, |hook| hook.on_handshake(peer_addr, tls_stream.get_ref().1)) {
+        proxy.error_log.log(format!("Connection from {} rejected by hook after handshake: {}", proxy.log_redact.client(peer_addr), reason));
+        return;
+    }
+
+    // Reserve buffer memory, shrinking buffers or waiting when the memory budget is exhausted
+    let reservation = match &proxy.memory {
+        Some(budget) => budget.reserve().await,
+        None => Reservation::unlimited(),
+    };
+    let buffers = reservation.buffers;
+    tls_stream.get_mut().1.set_buffer_limit(Some(buffers.tls_send));
+
+    // Tag connection based on SNI
+    let (_, session) = tls_stream.get_ref();
+    let sni = session.server_name().map(str::to_string);
+    if let Some(sni) = &sni {
+        log::set("sni", sni);
+    }
+    let tags = tags::tags_for(&proxy.tag_rules, sni.as_deref());
+    let alpn = session.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned());
+    let upstreams = proxy.upstreams();
+    let route = routing::select(&upstreams.routes, sni.as_deref(), alpn.as_deref(), peer_addr.ip());
+    let prefix = tags::log_prefix(&tags);
+    proxy.metrics.record_connection(&tags);
+
+    // Register connection for the admin API (deregistered when dropped)
+    let (_, session) = tls_stream.get_ref();
+    let trace = proxy.tracing.claim(peer_addr.ip(), sni.as_deref(), accepted);
+    let cipher_suite = session.negotiated_cipher_suite().map(|suite| suite.suite());
+    let registration = proxy.registry.register(peer_addr, local_addr, sni, tags, protocol_name(session.protocol_version()), alpn, trace);
+    let connection = registration.connection.clone();
+    connection.trace(|| {
+        format!(
+            "TLS handshake with {} done: {}, {:?}, SNI {}, ALPN {}",
+            proxy.log_redact.client(peer_addr),
+            connection.protocol,
+            cipher_suite.unwrap_or(rustls::CipherSuite::Unknown(0)),
+            connection.sni.as_deref().unwrap_or("none"),
+            connection.alpn.as_deref().unwrap_or("none")
+        )
+    });
+    let mut tls_stream = Counted::new(RecordSizer::new(tls_stream, proxy.record_sizing), connection.clone());
+
+    // Forward traffic until done or aborted via the admin API
+    let mut entry = access::Entry::default();
+    let forwarding = async {
+        match proxy.mode {
+            Mode::Tcp => forward(&proxy, &upstreams, route, &connection, &mut tls_stream, &mut entry, buffers, &prefix).await,
+            Mode::Http if connection.alpn.as_deref() == Some("h2") => h2::serve(&proxy, &connection, &mut tls_stream, buffers, &prefix).await,
+            Mode::Http => http_forward::forward(&proxy, &connection, &mut tls_stream, buffers, &prefix).await,
+        }
+    };
+    let clean = tokio::select! {
+        clean = forwarding => clean,
+        _ = connection.terminate.notified() => {
+            info!("{}Connection {} from {} terminated by admin", prefix, connection.id, proxy.log_redact.client(peer_addr));
+            false
+        }
+        _ = timeouts::idle(&connection, proxy.idle_timeout) => {
+            info!("{}Connection {} from {} closed after being idle", prefix, connection.id, proxy.log_redact.client(peer_addr));
+            true
+        }
+    };
+    close::close(&mut tls_stream, fd, clean, proxy.close).await;
+    let backend = connection.backend.lock().unwrap().clone();
+    if !backend.is_empty() {
+        proxy.metrics.record_backend_bytes(&backend, connection.bytes_in.load(Ordering::Relaxed), connection.bytes_out.load(Ordering::Relaxed));
+    }
+    // In TCP mode the connection is logged as a whole, HTTP mode logs every request
+    if let (Some(access_log), Mode::Tcp, 1..) = (&proxy.access_log, proxy.mode, entry.status) {
+        access_log.log(&connection, &entry, connection.bytes_out.load(Ordering::Relaxed), connection.started.elapsed());
+    }
+    connection.trace(|| {
+        format!(
+            "Connection closed ({} bytes in, {} bytes out)",
+            connection.bytes_in.load(Ordering::Relaxed),
+            connection.bytes_out.load(Ordering::Relaxed)
+        )
+    });
+    proxy.hooks.iter().for_each(|hook| hook.on_close(&connection));
+}
+
+/// Connect to a backend within the connect timeout, announcing the client with the PROXY protocol when enabled
+pub async fn connect_backend(proxy: &Proxy, backend: &Backend, connection: &Connection, timeouts: Timeouts) -> std::io::Result<TcpStream> {
+    let started = Instant::now();
+    let mut dial = backend::Dial::default();
+    let connect = backend::dial(&backend.address, proxy.backend_bind, dscp::select(&proxy.backend_dscp, &backend.address), proxy.mptcp, &mut dial);
+    let result = match timeouts.connect {
+        Some(timeout) => tokio::time::timeout(timeout, connect)
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out"))),
+        None => connect.await,
+    };
+    let mut stream = match result {
+        Ok(stream) => stream,
+        Err(error) => {
+            backend.record_connect_error(error.kind());
+            let error = backend::DialError {
+                backend: backend.address.clone(),
+                dial,
+                elapsed: started.elapsed(),
+                error,
+            };
+            return Err(error.into_io());
+        }
+    };
+    if let Some(version) = proxy.proxy_protocol {
+        stream.write_all(&proxy_protocol::header(version, connection)).await?;
+    }
+    Ok(stream)
+}
+
+/// Connect to the backend (of the route, if any) and forward raw bytes in both directions,
+/// returns whether forwarding finished without errors
+///
+/// The access log entry gets a status like those of nginx's stream module: 200 once connected to
+/// the backend (or for a request answered by the proxy), 400 for an unreadable first request, 403
+/// when rejected by a hook, 502 when the backend cannot be reached and 503 during maintenance.
+#[allow(clippy::too_many_arguments)]
+async fn forward<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    upstreams: &Upstreams,
+    route: Option<&Route>,
+    connection: &Connection,
+    client: &mut S,
+    entry: &mut access::Entry,
+    buffers: Buffers,
+    prefix: &str,
+) -> bool {
+    // Only HTTP/1 requests can be inspected or answered by the proxy, not HTTP/2 or other protocols
+    let http1 = proxy.profile.is_none() && connection.alpn.as_deref().is_none_or(|protocol| protocol.starts_with("http/1"));
+
+    // Answer with the maintenance page instead of connecting to the backend (other clients are disconnected)
+    if proxy.maintenance.applies(connection.peer.ip()) {
+        entry.status = 503;
+        if !http1 {
+            info!("{}Connection from {} closed for maintenance", prefix, proxy.log_redact.client(connection.peer));
+            return true;
+        }
+        let result = match hooks::read_request_head(client).await {
+            Ok((head, _)) => proxy.maintenance.respond(client, &head).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            proxy.error_log.log(format!("{}Maintenance response error: {}", prefix, e));
+            return false;
+        }
+        return true;
+    }
+
+    // Inspect the first request when the proxy or hooks need it (never for protocols where the server speaks first)
+    let mut first_request = Vec::new();
+    let mut path = None;
+    let inspect = proxy.probe_path.is_some()
+        || !proxy.hooks.is_empty()
+        || !proxy.route_timeouts.is_empty()
+        || connection.trace.is_some();
+    if inspect && http1 {
+        match first_request_head(proxy, connection, client, entry).await {
+            Ok(Some((data, request_path))) => (first_request, path) = (data, request_path),
+            Ok(None) => return true,
+            Err(e) => {
+                proxy.error_log.log(format!("{}Connection from {} closed: {}", prefix, proxy.log_redact.client(connection.peer), e));
+                return false;
+            }
+        }
+    }
+
+    // Connect to backend HTTP server
+    let pool = route.and_then(|route| route.backends.as_ref()).unwrap_or(&upstreams.backends);
+    let defaults = route.map_or(proxy.timeouts, |route| route.timeouts(proxy.timeouts));
+    let timeouts = timeouts::for_path(&proxy.route_timeouts, defaults, path.as_deref());
+    let backend = pool.pick(&[]);
+    let _lease = backend.lease();
+    connection.set_backend(&backend.address);
+    entry.backend = Some(backend.address.clone());
+    entry.status = 502;
+    let mut backend_stream = match connect_backend(proxy, &backend, connection, timeouts).await {
+        Ok(s) => {
+            proxy.record_outcome(pool, &backend, Outcome::Success(None));
+            connection.trace(|| format!("Connected to backend http://{}", backend.address));
+            ReadTimeout::new(s, timeouts)
+        }
+        Err(e) => {
+            proxy.record_outcome(pool, &backend, Outcome::Error);
+            proxy.error_log.log(format!("{}Backend connection error: {}", prefix, e));
+            return false;
+        }
+    };
+    if let Some(protocol) = proxy.starttls {
+        if let Err(e) = starttls::skip_greeting(&mut backend_stream, protocol).await {
+            proxy.error_log.log(format!("{}Backend greeting error: {}", prefix, e));
+            return false;
+        }
+    }
+    if let Err(e) = backend_stream.write_all(&first_request).await {
+        proxy.error_log.log(format!("{}Proxy forwarding error: {}", prefix, e));
+        return false;
+    }
+    entry.status = 200;
+
+    debug!("{}Forwarding request to http://{}", prefix, backend.address);
+
+    // Bidirectional TCP forwarding (TLS <-> HTTP)
+    let result = relay::relay(client, &mut backend_stream, buffers.client_to_backend, buffers.backend_to_client).await;
+    if let Err(e) = result {
+        proxy.error_log.log(format!("{}Proxy forwarding error: {}", prefix, e));
+        return false;
+    }
+    true
+}
+
+/// Read the first request, answer it when it is for the probe path and pass it through the
+/// `on_request` hooks, returns the data to forward and the request path, or `None` when the
+/// request was handled
+async fn first_request_head<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    connection: &Connection,
+    client: &mut S,
+    entry: &mut access::Entry,
+) -> Result<Option<(Vec<u8>, Option<String>)>, Box<dyn std::error::Error + Send + Sync>> {
+    entry.status = 400;
+    let (mut head, rest) = hooks::read_request_head(client).await?;
+    if head.is_empty() {
+        return Ok(Some((rest, None)));
+    }
+
+    // Answer health probes without involving the backend
+    if let Some(path) = &proxy.probe_path {
+        if let Some(head_only) = probe::matches(&head, path) {
+            entry.status = 200;
+            probe::respond(client, proxy.started, head_only).await?;
+            return Ok(None);
+        }
+    }
+
+    entry.status = 403;
+    hooks::check(&proxy.hooks, |hook| hook.on_request(connection, &mut head))
+        .map_err(|reason| format!("request rejected by hook: {}", reason))?;
+    let path = hooks::request_target(&head).map(|(_, path)| path.to_string());
+    connection.trace(|| format!("Request head:\n{}", trace::dump_head(&head)));
+    head.extend_from_slice(&rest);
+    Ok(Some((head, path)))
+}
+
+/// Human readable name of the negotiated TLS version
+fn protocol_name(version: Option<ProtocolVersion>) -> String {
+    match version {
+        Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+        Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+        Some(other) => format!("{:?}", other),
+        None => "unknown".to_string(),
+    }
+}
@@ -0,0 +1,204 @@
+use crate::registry::Connection;
+use clap::ValueEnum;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Time allowed for the load balancer to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest version 1 header line, including the line ending
+const MAX_V1_LENGTH: usize = 107;
+
+/// Signature starting a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Type-length-value fields of version 2 headers
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+
+/// The client connected over TLS
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+/// Version of the PROXY protocol header sent to backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProxyProtocol {
+    /// Human readable header line, e.g. `PROXY TCP4 203.0.113.7 192.0.2.1 43988 443`
+    V1,
+    /// Binary header, also passing on the server name, ALPN protocol and TLS version
+    V2,
+}
+
+/// Header announcing the client (and the address it connected to) to the backend
+pub fn header(version: ProxyProtocol, connection: &Connection) -> Vec<u8> {
+    let (source, destination) = same_family(connection.peer, connection.local);
+    match version {
+        ProxyProtocol::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut body = Vec::new();
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    body.extend_from_slice(&source_ip.octets());
+                    body.extend_from_slice(&destination_ip.octets());
+                }
+                (source_ip, destination_ip) => {
+                    body.extend_from_slice(&ipv6(source_ip).octets());
+                    body.extend_from_slice(&ipv6(destination_ip).octets());
+                }
+            }
+            body.extend_from_slice(&source.port().to_be_bytes());
+            body.extend_from_slice(&destination.port().to_be_bytes());
+
+            if let Some(alpn) = &connection.alpn {
+                tlv(&mut body, PP2_TYPE_ALPN, alpn.as_bytes());
+            }
+            if let Some(sni) = &connection.sni {
+                tlv(&mut body, PP2_TYPE_AUTHORITY, sni.as_bytes());
+            }
+            // Client flags, then the (unused) client certificate verification result, then the TLS version
+            let mut ssl = vec![PP2_CLIENT_SSL, 0, 0, 0, 0];
+            tlv(&mut ssl, PP2_SUBTYPE_SSL_VERSION, connection.protocol.as_bytes());
+            tlv(&mut body, PP2_TYPE_SSL, &ssl);
+
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2 PROXY command, then TCP over IPv4 or IPv6
+            header.push(0x21);
+            header.push(if source.is_ipv4() { 0x11 } else { 0x21 });
+            header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            header.extend_from_slice(&body);
+            header
+        }
+    }
+}
+
+/// Read the header a load balancer sends ahead of the client's data, returns the client's address and
+/// the address it connected to, or `None` for connections of the load balancer itself (health checks)
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+    tokio::time::timeout(HEADER_TIMEOUT, async {
+        // Read the header only, leaving the client's data for the TLS handshake
+        let mut start = [0u8; 16];
+        stream.read_exact(&mut start[..6]).await?;
+        if &start[..6] == b"PROXY " {
+            let mut line = start[..6].to_vec();
+            while !line.ends_with(b"\r\n") {
+                if line.len() == MAX_V1_LENGTH {
+                    return Err(invalid("PROXY header line too long"));
+                }
+                line.push(stream.read_u8().await?);
+            }
+            return parse_v1(&line[..line.len() - 2]);
+        }
+        stream.read_exact(&mut start[6..]).await?;
+        if start[..12] != V2_SIGNATURE {
+            return Err(invalid("connection does not start with a PROXY protocol header"));
+        }
+        let mut body = vec![0u8; u16::from_be_bytes([start[14], start[15]]) as usize];
+        stream.read_exact(&mut body).await?;
+        parse_v2(start[12], start[13], &body)
+    })
+    .await
+    .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "no PROXY protocol header received")))
+}
+
+/// Parse a version 1 header line without its line ending, e.g. `PROXY TCP4 203.0.113.7 192.0.2.1 43988 443`
+fn parse_v1(line: &[u8]) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| -> std::io::Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("invalid address in PROXY header"))?;
+                let port: u16 = port.parse().map_err(|_| invalid("invalid port in PROXY header"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            let source = address(source, source_port)?;
+            if source.is_ipv4() != (fields[1] == "TCP4") {
+                return Err(invalid("address family does not match the PROXY header"));
+            }
+            Ok(Some((source, address(destination, destination_port)?)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+/// Parse the version 2 header following the signature: version and command, family and protocol, then the addresses
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: sent by the load balancer on its own behalf
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let port = |offset: usize| u16::from_be_bytes([body[offset], body[offset + 1]]);
+    match family {
+        // TCP over IPv4
+        0x11 if body.len() >= 12 => {
+            let source = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let destination = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some((SocketAddr::new(source.into(), port(8)), SocketAddr::new(destination.into(), port(10)))))
+        }
+        // TCP over IPv6
+        0x21 if body.len() >= 36 => {
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&body[16..32]).unwrap());
+            Ok(Some((SocketAddr::new(source.into(), port(32)), SocketAddr::new(destination.into(), port(34)))))
+        }
+        // Unspecified, e.g. unix sockets: there is no client address to use
+        0x00 => Ok(None),
+        0x11 | 0x21 => Err(invalid("PROXY header too short for its addresses")),
+        _ => Err(invalid("unsupported address family in PROXY header")),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Append a type-length-value field
+fn tlv(out: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Both addresses in the same family: IPv4 when both are (mapped) IPv4 addresses, IPv6 otherwise
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let (source, destination) = (canonical(source), canonical(destination));
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    (
+        SocketAddr::new(IpAddr::V6(ipv6(source.ip())), source.port()),
+        SocketAddr::new(IpAddr::V6(ipv6(destination.ip())), destination.port()),
+    )
+}
+
+/// IPv4-mapped IPv6 addresses (from dual-stack listeners) as plain IPv4 addresses
+fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+fn ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
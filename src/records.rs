@@ -0,0 +1,87 @@
+use clap::ValueEnum;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Record payload that fits a single TCP segment (1500 MTU minus IP/TCP headers, options and TLS overhead)
+const SMALL_RECORD: usize = 1369;
+
+/// Amount of data sent in small records before switching to full-size records
+const SMALL_RECORD_BYTES: u64 = 1024 * 1024;
+
+/// Idle time after which the connection starts with small records again
+const IDLE_RESET: Duration = Duration::from_secs(1);
+
+/// Buffer size used for copying backend responses, so full 16 KiB records can be sent
+pub const THROUGHPUT_BUFFER: usize = 16 * 1024;
+
+/// How TLS records sent to clients are sized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RecordSizing {
+    /// Small records at the start of a response (faster first paint), growing to full size for bulk transfer
+    Latency,
+    /// Always full-size records (least overhead)
+    Throughput,
+}
+
+/// Stream wrapper limiting the size of each write, and thereby the TLS records produced from it
+pub struct RecordSizer<S> {
+    inner: S,
+    sizing: RecordSizing,
+    /// Bytes written since the connection started or last went idle
+    sent: u64,
+    last_write: Instant,
+}
+
+impl<S> RecordSizer<S> {
+    pub fn new(inner: S, sizing: RecordSizing) -> Self {
+        Self {
+            inner,
+            sizing,
+            sent: 0,
+            last_write: Instant::now(),
+        }
+    }
+
+    /// Maximum number of bytes to pass on in the next write
+    fn write_limit(&mut self) -> usize {
+        if self.sizing == RecordSizing::Throughput {
+            return usize::MAX;
+        }
+        if self.last_write.elapsed() >= IDLE_RESET {
+            self.sent = 0;
+        }
+        if self.sent < SMALL_RECORD_BYTES {
+            SMALL_RECORD
+        } else {
+            usize::MAX
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordSizer<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordSizer<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let limit = self.write_limit().min(buf.len());
+        let result = Pin::new(&mut self.inner).poll_write(cx, &buf[..limit]);
+        if let Poll::Ready(Ok(written)) = result {
+            self.sent += written as u64;
+            self.last_write = Instant::now();
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,56 @@
+use clap::ValueEnum;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Data hidden from log output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogRedact {
+    /// Log client addresses as they are
+    None,
+    /// Zero the last octet of IPv4 client addresses (the interface identifier of IPv6 ones) and omit the port
+    Ips,
+    /// Keep the /24 network of IPv4 and the /48 network of IPv6 client addresses (`--anonymize-ips`),
+    /// also in the admin API
+    #[value(skip)]
+    Anonymize,
+}
+
+impl LogRedact {
+    /// Client address as it may appear in the log
+    pub fn client(self, addr: SocketAddr) -> String {
+        match self {
+            LogRedact::None => addr.to_string(),
+            LogRedact::Ips => mask(addr.ip(), 24, 64).to_string(),
+            LogRedact::Anonymize => mask(addr.ip(), 24, 48).to_string(),
+        }
+    }
+
+    /// Client address without the port, as written to the access log
+    pub fn client_ip(self, addr: SocketAddr) -> String {
+        match self {
+            LogRedact::None => addr.ip().to_canonical().to_string(),
+            _ => self.client(addr),
+        }
+    }
+
+    /// Client address as reported by the admin API
+    pub fn admin_client(self, addr: SocketAddr) -> String {
+        match self {
+            LogRedact::Anonymize => self.client(addr),
+            _ => addr.to_string(),
+        }
+    }
+}
+
+/// Keep only the network prefix of an address, IPv4-mapped IPv6 addresses are treated as IPv4
+pub fn mask(ip: IpAddr, v4_prefix_len: u32, v6_prefix_len: u32) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - v4_prefix_len.min(32)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - v6_prefix_len.min(128)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::log;
+use crate::trace::Trace;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+/// Information about a single proxied connection
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    pub peer: SocketAddr,
+    /// Address the client connected to
+    pub local: SocketAddr,
+    pub sni: Option<String>,
+    pub tags: Vec<String>,
+    pub protocol: String,
+    pub alpn: Option<String>,
+    /// Address of the backend last forwarded to
+    pub backend: Mutex<String>,
+    pub started: Instant,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Signalled when the connection should be aborted
+    pub terminate: Notify,
+    /// Set when the connection was selected for tracing through the admin API
+    pub trace: Option<Trace>,
+}
+
+impl Connection {
+    /// Log a step of a traced connection, the message is only built when tracing
+    pub fn trace(&self, step: impl FnOnce() -> String) {
+        if let Some(trace) = &self.trace {
+            trace.log(self.id, &step());
+        }
+    }
+
+    pub fn set_backend(&self, address: &str) {
+        log::set("backend", address);
+        *self.backend.lock().unwrap() = address.to_string();
+    }
+}
+
+/// Registry of all active connections, shared between connection tasks and the admin API
+#[derive(Debug, Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<Connection>>>,
+}
+
+impl Registry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Register a connection; it is removed again when the returned guard is dropped
+    #[allow(clippy::too_many_arguments)]
+    pub fn register(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        local: SocketAddr,
+        sni: Option<String>,
+        tags: Vec<String>,
+        protocol: String,
+        alpn: Option<String>,
+        trace: Option<Trace>,
+    ) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let connection = Arc::new(Connection {
+            id,
+            peer,
+            local,
+            sni,
+            tags,
+            protocol,
+            alpn,
+            backend: Mutex::new(String::new()),
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            terminate: Notify::new(),
+            trace,
+        });
+        self.connections.lock().unwrap().insert(id, connection.clone());
+
+        Registration {
+            registry: self.clone(),
+            connection,
+        }
+    }
+
+    /// Snapshot of all active connections, ordered by id
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        let mut connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    /// Abort the connection with the given id, returns whether it existed
+    pub fn terminate(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.terminate.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Abort all connections from the given client IP, returns the number of connections aborted
+    pub fn terminate_ip(&self, ip: IpAddr) -> usize {
+        let connections = self.connections.lock().unwrap();
+        let mut count = 0;
+        for connection in connections.values().filter(|c| c.peer.ip() == ip) {
+            connection.terminate.notify_one();
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Guard that keeps a connection listed in the registry while alive
+pub struct Registration {
+    registry: Arc<Registry>,
+    pub connection: Arc<Connection>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.connection.id);
+    }
+}
+
+/// Stream wrapper that counts bytes read from and written to the client
+pub struct Counted<S> {
+    inner: S,
+    connection: Arc<Connection>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, connection: Arc<Connection>) -> Self {
+        Self { inner, connection }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.connection.bytes_in.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.connection.bytes_out.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,45 @@
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Forward data between client and backend until both have finished sending
+///
+/// When one side stops sending, the write side toward the other is shut down (half-close) while
+/// data keeps flowing in the opposite direction, so request/response streaming protocols that
+/// signal the end of input with EOF keep working. An error in either direction aborts both.
+/// Returns the number of bytes forwarded to the backend and to the client.
+pub async fn relay<C, B>(client: &mut C, backend: &mut B, to_backend: usize, to_client: usize) -> std::io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut backend_reader, mut backend_writer) = tokio::io::split(backend);
+    tokio::try_join!(
+        copy_until_eof(&mut client_reader, &mut backend_writer, to_backend),
+        copy_until_eof(&mut backend_reader, &mut client_writer, to_client),
+    )
+}
+
+/// Copy data until the reader reaches EOF, then shut down the writer
+async fn copy_until_eof<R, W>(reader: &mut R, writer: &mut W, buffer_size: usize) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; buffer_size];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            // A TLS client closing the TCP connection without close_notify still ends its input
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read]).await?;
+        writer.flush().await?;
+        copied += read as u64;
+    }
+    writer.shutdown().await?;
+    Ok(copied)
+}
@@ -0,0 +1,197 @@
+use crate::acme::{self, OnDemand};
+use crate::certificate;
+use crate::handshake::HelloLog;
+use crate::tags;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::SignatureAlgorithm;
+use std::sync::{Arc, RwLock};
+
+/// Certificate files given with `--sni-cert`, served for server names matching the pattern
+#[derive(Debug, Clone)]
+pub struct SniCertFiles {
+    /// Exact server name, or wildcard of the form `*.example.com`
+    pub pattern: String,
+    /// PFX, PEM, DER or PKCS#7 file
+    pub cert: String,
+    /// Key file, for PEM certificates not holding the key themselves
+    pub key: Option<String>,
+}
+
+/// Parse a `<name>=<cert>[,<key>]` certificate assignment from the command line
+pub fn parse_sni_cert(s: &str) -> Result<SniCertFiles, String> {
+    let (pattern, files) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid SNI certificate '{}'. Expected format: <name>=<cert>[,<key>]", s))?;
+    let (cert, key) = match files.split_once(',') {
+        Some((cert, key)) => (cert, Some(key.to_string())),
+        None => (files, None),
+    };
+    if pattern.is_empty() || cert.is_empty() || key.as_deref() == Some("") {
+        return Err(format!("Invalid SNI certificate '{}'. Name and files must be non-empty", s));
+    }
+    Ok(SniCertFiles {
+        pattern: pattern.to_ascii_lowercase(),
+        cert: cert.to_string(),
+        key,
+    })
+}
+
+/// Certificate resolver whose certificate (and stapled OCSP response) can be swapped at runtime
+#[derive(Debug)]
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Certificate with another key type, served to clients that cannot verify the current one
+    second: RwLock<Option<Arc<CertifiedKey>>>,
+    /// Names the current certificate is valid for
+    names: RwLock<Vec<String>>,
+    /// Certificates for server names matching a pattern, taking precedence over the current one
+    sni: RwLock<Vec<(String, Arc<CertifiedKey>)>>,
+    /// Refuse handshakes for server names no certificate covers instead of serving the current one
+    strict: bool,
+    unknown_names: HelloLog,
+    on_demand: Option<Arc<OnDemand>>,
+}
+
+impl CertResolver {
+    pub fn new(certified_key: CertifiedKey) -> Self {
+        Self {
+            names: RwLock::new(leaf_names(&certified_key)),
+            current: RwLock::new(Arc::new(certified_key)),
+            second: RwLock::new(None),
+            sni: RwLock::new(Vec::new()),
+            strict: false,
+            unknown_names: HelloLog::default(),
+            on_demand: None,
+        }
+    }
+
+    /// Also serve a certificate with another key type (e.g. RSA next to ECDSA)
+    pub fn with_second(self, certified_key: CertifiedKey) -> Self {
+        *self.second.write().unwrap() = Some(Arc::new(certified_key));
+        self
+    }
+
+    /// Serve certificates for server names matching their patterns, the current certificate being the default
+    pub fn with_sni(self, certificates: Vec<(String, CertifiedKey)>) -> Self {
+        self.replace_sni(certificates);
+        self
+    }
+
+    /// Refuse handshakes for server names no certificate covers, clients without SNI still get the default
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Obtain certificates via ACME for allowed server names the current certificate does not cover
+    pub fn with_on_demand(mut self, on_demand: Arc<OnDemand>) -> Self {
+        self.on_demand = Some(on_demand);
+        self
+    }
+
+    /// The certificate currently served
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Serve a new certificate from now on
+    pub fn replace(&self, certified_key: CertifiedKey) {
+        *self.names.write().unwrap() = leaf_names(&certified_key);
+        *self.current.write().unwrap() = Arc::new(certified_key);
+    }
+
+    /// Serve a new second certificate from now on
+    pub fn replace_second(&self, certified_key: CertifiedKey) {
+        *self.second.write().unwrap() = Some(Arc::new(certified_key));
+    }
+
+    /// Serve new certificates for server names from now on
+    pub fn replace_sni(&self, certificates: Vec<(String, CertifiedKey)>) {
+        *self.sni.write().unwrap() = certificates
+            .into_iter()
+            .map(|(pattern, certified_key)| (pattern, Arc::new(certified_key)))
+            .collect();
+    }
+
+    /// All certificates served, with the pattern of those given for server names
+    pub fn certificates(&self) -> Vec<(Option<String>, Arc<CertifiedKey>)> {
+        let sni = self.sni.read().unwrap();
+        std::iter::once((None, self.current()))
+            .chain(sni.iter().map(|(pattern, certified_key)| (Some(pattern.clone()), certified_key.clone())))
+            .collect()
+    }
+
+    /// The certificate given for a server name, exact names taking precedence over wildcards
+    fn for_server_name(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let sni = self.sni.read().unwrap();
+        let server_name = server_name.to_ascii_lowercase();
+        let exact = sni.iter().find(|(pattern, _)| *pattern == server_name);
+        exact
+            .or_else(|| sni.iter().find(|(pattern, _)| tags::matches_pattern(pattern, &server_name)))
+            .map(|(_, certified_key)| certified_key.clone())
+    }
+
+    /// The certificate for a client: the ECDSA or EdDSA one when the client supports it, as
+    /// their handshakes are cheaper, otherwise the RSA one
+    fn select(&self, client_hello: &ClientHello<'_>) -> Arc<CertifiedKey> {
+        let current = self.current();
+        let Some(second) = self.second.read().unwrap().clone() else {
+            return current;
+        };
+        let (preferred, fallback) = match current.key.algorithm() {
+            SignatureAlgorithm::RSA => (second, current),
+            _ => (current, second),
+        };
+        match preferred.key.choose_scheme(client_hello.signature_schemes()) {
+            Some(_) => preferred,
+            None => fallback,
+        }
+    }
+
+    /// Replace the stapled OCSP response (or remove it with `None`)
+    pub fn set_ocsp(&self, ocsp: Option<Vec<u8>>) {
+        let mut current = self.current.write().unwrap();
+        let mut certified_key = CertifiedKey::clone(&current);
+        certified_key.ocsp = ocsp;
+        *current = Arc::new(certified_key);
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+        if let (Some(on_demand), Some(server_name)) = (&self.on_demand, server_name) {
+            let mut alpn = client_hello.alpn().into_iter().flatten();
+            if alpn.any(|protocol| protocol == acme::ACME_TLS_ALPN) {
+                return on_demand.challenge(server_name);
+            }
+        }
+        if let Some(certified_key) = server_name.and_then(|server_name| self.for_server_name(server_name)) {
+            return Some(certified_key);
+        }
+        if let Some(server_name) = server_name {
+            if !certificate::covers(&self.names.read().unwrap(), server_name) {
+                if let Some(on_demand) = &self.on_demand {
+                    if let Some(certified_key) = on_demand.certificate(server_name) {
+                        return Some(certified_key);
+                    }
+                    // Served until the certificate is issued
+                    if on_demand.allows(server_name) {
+                        return Some(self.select(&client_hello));
+                    }
+                }
+                self.unknown_names.log(&client_hello);
+                if self.strict {
+                    return None;
+                }
+                // The certificate is served anyway, but clients checking the hostname will reject it
+            }
+        }
+        Some(self.select(&client_hello))
+    }
+}
+
+fn leaf_names(certified_key: &CertifiedKey) -> Vec<String> {
+    certified_key.cert.first().map(certificate::names).unwrap_or_default()
+}
@@ -0,0 +1,65 @@
+use crate::metrics;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Retries allowed in a burst (the budget starts full and never holds more)
+const MAX_BALANCE: f64 = 10.0;
+
+/// Methods whose requests can be repeated without changing the result
+const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+/// Limits retries to a share of the requests, so retries cannot multiply the load on a failing backend
+///
+/// Every request adds `ratio` to the budget and every retry takes one from it.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    balance: Mutex<f64>,
+    pub retries_total: AtomicU64,
+    pub exhausted_total: AtomicU64,
+}
+
+impl RetryBudget {
+    /// Allow retrying `percent` of the requests
+    pub fn new(percent: u32) -> Self {
+        Self {
+            ratio: f64::from(percent) / 100.0,
+            balance: Mutex::new(MAX_BALANCE),
+            retries_total: AtomicU64::new(0),
+            exhausted_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a request to the budget
+    pub fn deposit(&self) {
+        let mut balance = self.balance.lock().unwrap();
+        *balance = (*balance + self.ratio).min(MAX_BALANCE);
+    }
+
+    /// Take a retry from the budget, returns whether it is allowed
+    pub fn withdraw(&self) -> bool {
+        let mut balance = self.balance.lock().unwrap();
+        if *balance < 1.0 {
+            self.exhausted_total.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        *balance -= 1.0;
+        self.retries_total.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Render the counters as metric lines
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        metrics::family(&mut out, "backend_retries_total", "counter", "Requests retried after a backend failed");
+        out.push_str(&format!("backend_retries_total {}\n", self.retries_total.load(Ordering::Relaxed)));
+        metrics::family(&mut out, "backend_retry_budget_exhausted_total", "counter", "Retries skipped as the retry budget ran out");
+        out.push_str(&format!("backend_retry_budget_exhausted_total {}\n", self.exhausted_total.load(Ordering::Relaxed)));
+        out
+    }
+}
+
+/// Whether a request with this method may be sent again after a failure
+pub fn is_idempotent(method: &str) -> bool {
+    IDEMPOTENT_METHODS.contains(&method)
+}
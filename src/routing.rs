@@ -0,0 +1,179 @@
+use crate::backend::{Pool, Strategy};
+use crate::tags;
+use crate::timeouts::Timeouts;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Conditions and per-connection options of a `--route` rule
+#[derive(Debug, Clone, Default)]
+pub struct RouteRule {
+    /// Exact server name, or wildcard of the form `*.example.com`
+    pub sni: Option<String>,
+    /// Negotiated ALPN protocol
+    pub alpn: Option<String>,
+    /// Client address range
+    pub ip: Option<IpNet>,
+    /// Comma-separated backend addresses replacing the default backends
+    pub backend: Option<String>,
+    /// Timeouts replacing the defaults
+    pub timeouts: Timeouts,
+}
+
+impl RouteRule {
+    /// Whether the connection satisfies all conditions of the rule
+    pub fn matches(&self, sni: Option<&str>, alpn: Option<&str>, peer: IpAddr) -> bool {
+        self.sni.as_ref().is_none_or(|pattern| sni.is_some_and(|sni| tags::matches_pattern(pattern, sni)))
+            && self.alpn.as_ref().is_none_or(|protocol| alpn == Some(protocol.as_str()))
+            && self.ip.as_ref().is_none_or(|net| net.contains(peer))
+    }
+}
+
+/// Parse a `--route` argument of the form `CONDITION... => ACTION...`
+///
+/// Conditions are `sni=<name>`, `alpn=<protocol>` and `ip=<address>[/<prefix length>]`, actions
+/// are `backend=<address>[,<address>...]`, `connect-timeout=<secs>`, `read-timeout=<secs>` and
+/// `ttfb-timeout=<secs>`, each separated by whitespace.
+pub fn parse_route(s: &str) -> Result<RouteRule, String> {
+    let (conditions, actions) = s.split_once("=>").ok_or("expected CONDITION... => ACTION...")?;
+    let seconds = |value: &str| {
+        value
+            .parse::<u64>()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|_| format!("invalid number of seconds '{}'", value))
+    };
+
+    let mut rule = RouteRule::default();
+    for condition in conditions.split_whitespace() {
+        match condition.split_once('=') {
+            Some(("sni", pattern)) if !pattern.is_empty() => rule.sni = Some(pattern.to_ascii_lowercase()),
+            Some(("alpn", protocol)) if !protocol.is_empty() => rule.alpn = Some(protocol.to_string()),
+            Some(("ip", net)) => rule.ip = Some(net.parse()?),
+            _ => return Err(format!("invalid condition '{}', expected sni=, alpn= or ip=", condition)),
+        }
+    }
+    if rule.sni.is_none() && rule.alpn.is_none() && rule.ip.is_none() {
+        return Err("a route needs at least one condition".to_string());
+    }
+
+    for action in actions.split_whitespace() {
+        match action.split_once('=') {
+            Some(("backend", addresses)) if !addresses.is_empty() => rule.backend = Some(addresses.to_string()),
+            Some(("connect-timeout", secs)) => rule.timeouts.connect = seconds(secs)?,
+            Some(("read-timeout", secs)) => rule.timeouts.read = seconds(secs)?,
+            Some(("ttfb-timeout", secs)) => rule.timeouts.first_byte = seconds(secs)?,
+            _ => {
+                return Err(format!(
+                    "invalid action '{}', expected backend=, connect-timeout=, read-timeout= or ttfb-timeout=",
+                    action
+                ))
+            }
+        }
+    }
+    if rule.backend.is_none() && rule.timeouts.connect.is_none() && rule.timeouts.read.is_none() && rule.timeouts.first_byte.is_none() {
+        return Err("a route needs at least one action".to_string());
+    }
+    Ok(rule)
+}
+
+/// Rule with the backends it routes to
+#[derive(Debug)]
+pub struct Route {
+    pub rule: RouteRule,
+    pub backends: Option<Pool>,
+}
+
+impl Route {
+    pub fn new(rule: RouteRule, strategy: Strategy, slow_start: Option<Duration>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let backends = rule.backend.as_deref().map(|addresses| Pool::new(addresses, strategy, slow_start)).transpose()?;
+        Ok(Self { rule, backends })
+    }
+
+    /// Timeouts for connections taking this route, falling back to the defaults
+    pub fn timeouts(&self, defaults: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.rule.timeouts.connect.or(defaults.connect),
+            read: self.rule.timeouts.read.or(defaults.read),
+            first_byte: self.rule.timeouts.first_byte.or(defaults.first_byte),
+        }
+    }
+}
+
+/// The first route whose conditions the connection satisfies
+pub fn select<'a>(routes: &'a [Route], sni: Option<&str>, alpn: Option<&str>, peer: IpAddr) -> Option<&'a Route> {
+    routes.iter().find(|route| route.rule.matches(sni, alpn, peer))
+}
+
+/// ALPN protocols to advertise: the configured ones in order of preference, followed by those the
+/// routes' ALPN conditions match
+pub fn alpn_protocols(configured: &[String], rules: &[RouteRule]) -> Vec<Vec<u8>> {
+    let mut protocols: Vec<Vec<u8>> = Vec::new();
+    let routed = rules.iter().filter_map(|rule| rule.alpn.as_deref());
+    // Without configured protocols, HTTP/1.1 stays available to clients that do not offer any of the routed protocols
+    let fallback = configured.is_empty().then_some("http/1.1");
+    for protocol in configured.iter().map(String::as_str).chain(routed).chain(fallback) {
+        if !protocols.iter().any(|p| p == protocol.as_bytes()) {
+            protocols.push(protocol.as_bytes().to_vec());
+        }
+    }
+    protocols
+}
+
+/// Parse a protocol of the `--alpn` list
+pub fn parse_alpn_protocol(s: &str) -> Result<String, String> {
+    let protocol = s.trim();
+    if protocol.is_empty() || protocol.len() > 255 {
+        return Err(format!("invalid ALPN protocol '{}' (1 to 255 bytes)", protocol));
+    }
+    Ok(protocol.to_string())
+}
+
+/// Address range of the form `10.0.0.0/8` or `2001:db8::/32`, a plain address matches itself only
+#[derive(Debug, Clone, Copy)]
+pub struct IpNet {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| format!("invalid IP address '{}'", address))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("invalid prefix length '{}'", len))?,
+            None => max,
+        };
+        Ok(Self { address, prefix_len })
+    }
+}
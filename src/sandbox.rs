@@ -0,0 +1,261 @@
+use std::path::PathBuf;
+
+/// Files the resolver may need to read when connecting to a backend by host name
+const RESOLVER_FILES: &[&str] = &["/etc/hosts", "/etc/resolv.conf", "/etc/nsswitch.conf", "/etc/host.conf", "/etc/gai.conf"];
+
+/// Restrict the process to the system calls and files it needs after initialization
+///
+/// On Linux this applies a seccomp filter denying process execution, debugging and system
+/// administration syscalls, and a Landlock ruleset only allowing reads of the given files (and
+/// resolver configuration). On OpenBSD it uses pledge and unveil to the same effect. Must be
+/// called while the process is single-threaded, as restrictions are inherited by new threads.
+pub fn apply(read_paths: &[PathBuf]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut paths: Vec<PathBuf> = read_paths.to_vec();
+    paths.extend(RESOLVER_FILES.iter().map(PathBuf::from).filter(|p| p.exists()));
+
+    platform::apply(&paths)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use crate::{info, warn};
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    /// Syscalls a TLS proxy never needs once it is running
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_personality,
+        libc::SYS_userfaultfd,
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+
+    #[repr(C)]
+    struct LandlockRulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct LandlockPathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub fn apply(read_paths: &[PathBuf]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Required to install filters as an unprivileged user, and prevents regaining privileges
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(format!("Failed to set no_new_privs: {}", std::io::Error::last_os_error()).into());
+        }
+
+        match landlock(read_paths) {
+            Ok(abi) => info!("Landlock filesystem sandbox applied (ABI v{})", abi),
+            Err(e) => warn!("Landlock filesystem sandbox not applied: {}", e),
+        }
+        seccomp()?;
+        info!("Seccomp syscall filter applied");
+        Ok(())
+    }
+
+    /// Deny all filesystem access except reading the given files, returns the Landlock ABI version
+    fn landlock(read_paths: &[PathBuf]) -> Result<i64, String> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<LandlockRulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(format!("not supported by the kernel ({})", std::io::Error::last_os_error()));
+        }
+
+        // Handle every access right known to this ABI version, so all of them are denied by default
+        let handled_access_fs = match abi {
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            3 | 4 => (1 << 15) - 1,
+            _ => (1 << 16) - 1,
+        };
+        let attr = LandlockRulesetAttr { handled_access_fs };
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const LandlockRulesetAttr,
+                std::mem::size_of::<LandlockRulesetAttr>(),
+                0u32,
+            )
+        } as libc::c_int;
+        if ruleset < 0 {
+            return Err(format!("failed to create ruleset: {}", std::io::Error::last_os_error()));
+        }
+
+        let result = add_read_rules(ruleset, read_paths).and_then(|_| {
+            if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32) } != 0 {
+                return Err(format!("failed to restrict process: {}", std::io::Error::last_os_error()));
+            }
+            Ok(abi)
+        });
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    /// Allow reading each of the files in the ruleset
+    fn add_read_rules(ruleset: libc::c_int, read_paths: &[PathBuf]) -> Result<(), String> {
+        for path in read_paths {
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| format!("invalid path {}", path.display()))?;
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(format!("failed to open {}: {}", path.display(), std::io::Error::last_os_error()));
+            }
+            let rule = LandlockPathBeneathAttr {
+                allowed_access: LANDLOCK_ACCESS_FS_READ_FILE,
+                parent_fd: fd,
+            };
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset,
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const LandlockPathBeneathAttr,
+                    0u32,
+                )
+            };
+            let error = std::io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            if result != 0 {
+                return Err(format!("failed to allow {}: {}", path.display(), error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Install a seccomp filter making the denied syscalls fail with EPERM
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+        let arch_offset = std::mem::offset_of!(libc::seccomp_data, arch) as u32;
+        let nr_offset = std::mem::offset_of!(libc::seccomp_data, nr) as u32;
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        // Kill the process on a foreign architecture, where syscall numbers would be misinterpreted
+        let mut filter = vec![
+            stmt((BPF_LD | BPF_W | BPF_ABS) as u16, arch_offset),
+            jump((BPF_JMP | BPF_JEQ | BPF_K) as u16, AUDIT_ARCH, 1, 0),
+            stmt((BPF_RET | BPF_K) as u16, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt((BPF_LD | BPF_W | BPF_ABS) as u16, nr_offset),
+        ];
+        // Deny x32 syscalls, which would bypass the syscall numbers below
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump((BPF_JMP | libc::BPF_JGE | BPF_K) as u16, 0x4000_0000, 0, 1),
+            stmt((BPF_RET | BPF_K) as u16, deny),
+        ]);
+        for syscall in DENIED_SYSCALLS {
+            filter.push(jump((BPF_JMP | BPF_JEQ | BPF_K) as u16, *syscall as u32, 0, 1));
+            filter.push(stmt((BPF_RET | BPF_K) as u16, deny));
+        }
+        filter.push(stmt((BPF_RET | BPF_K) as u16, libc::SECCOMP_RET_ALLOW));
+
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        if result != 0 {
+            return Err(format!("Failed to apply seccomp filter: {}", std::io::Error::last_os_error()).into());
+        }
+        Ok(())
+    }
+
+    /// BPF statement instruction
+    fn stmt(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter { code, jt: 0, jf: 0, k }
+    }
+
+    /// BPF conditional jump instruction
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code, jt, jf, k }
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Err("Seccomp filter is only available on x86_64 and aarch64".into())
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod platform {
+    use crate::info;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    pub fn apply(read_paths: &[PathBuf]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Only the given files remain visible, read-only
+        for path in read_paths {
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            if unsafe { libc::unveil(c_path.as_ptr(), c"r".as_ptr()) } != 0 {
+                return Err(format!("Failed to unveil {}: {}", path.display(), std::io::Error::last_os_error()).into());
+            }
+        }
+        if unsafe { libc::unveil(std::ptr::null(), std::ptr::null()) } != 0 {
+            return Err(format!("Failed to lock unveil: {}", std::io::Error::last_os_error()).into());
+        }
+
+        if unsafe { libc::pledge(c"stdio rpath inet dns".as_ptr(), std::ptr::null()) } != 0 {
+            return Err(format!("Failed to pledge: {}", std::io::Error::last_os_error()).into());
+        }
+        info!("Pledge and unveil sandbox applied");
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+mod platform {
+    use std::path::PathBuf;
+
+    pub fn apply(_read_paths: &[PathBuf]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Err("Sandboxing (--sandbox) is only supported on Linux and OpenBSD".into())
+    }
+}
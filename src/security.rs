@@ -0,0 +1,52 @@
+use crate::http::Headers;
+use clap::ValueEnum;
+
+/// Value of the `X-Frame-Options` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FrameOptions {
+    /// Pages may not be shown in a frame at all
+    Deny,
+    /// Pages may only be framed by pages of the same origin
+    Sameorigin,
+}
+
+/// Security headers added to responses (HTTP mode), for backends that know nothing about TLS
+#[derive(Debug, Default)]
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+}
+
+impl SecurityHeaders {
+    /// Headers for the options, `None` when no header is asked for
+    pub fn new(hsts_max_age: Option<u64>, include_subdomains: bool, preload: bool, nosniff: bool, frame_options: Option<FrameOptions>) -> Option<Self> {
+        let mut headers = Vec::new();
+        if let Some(max_age) = hsts_max_age {
+            let mut value = format!("max-age={}", max_age);
+            if include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if preload {
+                value.push_str("; preload");
+            }
+            headers.push(("Strict-Transport-Security", value));
+        }
+        if nosniff {
+            headers.push(("X-Content-Type-Options", "nosniff".to_string()));
+        }
+        match frame_options {
+            Some(FrameOptions::Deny) => headers.push(("X-Frame-Options", "DENY".to_string())),
+            Some(FrameOptions::Sameorigin) => headers.push(("X-Frame-Options", "SAMEORIGIN".to_string())),
+            None => {}
+        }
+        (!headers.is_empty()).then_some(Self { headers })
+    }
+
+    /// Add the headers the backend did not set itself
+    pub fn apply(&self, headers: &mut Headers) {
+        for (name, value) in &self.headers {
+            if headers.get(name).is_none() {
+                headers.0.push((name.to_string(), value.clone()));
+            }
+        }
+    }
+}
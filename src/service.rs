@@ -0,0 +1,186 @@
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Arguments of the `install-service` subcommand
+#[derive(Parser, Debug)]
+#[command(name = "install-service", bin_name = "https-wrapper install-service")]
+#[command(about = "Install a service running https-wrapper with the given arguments on boot (systemd on Linux, launchd on macOS)")]
+pub struct InstallArgs {
+    /// Name of the service
+    #[arg(long, default_value = "https-wrapper")]
+    pub name: String,
+
+    /// Run the service as this user instead of root, granting it only the capability to bind privileged ports
+    #[arg(long, value_name = "USER")]
+    pub run_as: Option<String>,
+
+    /// Print the service definition instead of installing it
+    #[arg(long)]
+    pub print: bool,
+
+    /// Arguments for https-wrapper, as they would be given on the command line
+    #[arg(value_name = "ARGS", trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    pub args: Vec<String>,
+}
+
+/// Generate the service definition and install it, or print it with `--print`
+///
+/// `writable` lists the directories the proxy writes to, which stay writable in the otherwise
+/// read-only file system of the systemd service.
+pub fn install(install: &InstallArgs, writable: &[PathBuf]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if install.name.is_empty() || !install.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c)) {
+        return Err(format!("Invalid service name '{}'", install.name).into());
+    }
+    let executable = std::env::current_exe().map_err(|e| format!("Failed to determine the path of https-wrapper: {}", e))?;
+    let directory = std::env::current_dir().map_err(|e| format!("Failed to determine the working directory: {}", e))?;
+
+    let (path, definition) = if cfg!(target_os = "macos") {
+        let label = format!("com.github.jvtubergen.{}", install.name);
+        let path = PathBuf::from(format!("/Library/LaunchDaemons/{}.plist", label));
+        (path, launchd_plist(&label, install, &executable, &directory))
+    } else if cfg!(target_os = "linux") {
+        let path = PathBuf::from(format!("/etc/systemd/system/{}.service", install.name));
+        (path, systemd_unit(install, &executable, &directory, writable))
+    } else {
+        return Err("install-service supports systemd (Linux) and launchd (macOS), use --print as a starting point elsewhere".into());
+    };
+
+    if install.print {
+        print!("{}", definition);
+        return Ok(());
+    }
+    std::fs::write(&path, definition).map_err(|e| {
+        format!("Failed to write {}: {} (run with sudo, or use --print to inspect the definition)", path.display(), e)
+    })?;
+    println!("Wrote {}", path.display());
+
+    if cfg!(target_os = "macos") {
+        run("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+    } else {
+        run("systemctl", &["daemon-reload"])?;
+        run("systemctl", &["enable", "--now", &format!("{}.service", install.name)])?;
+    }
+    println!("Service {} installed and started", install.name);
+    Ok(())
+}
+
+/// Run a service manager command, failing when it does not succeed
+fn run(program: &str, args: &[&str]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{} {} exited with {}", program, args.join(" "), status).into());
+    }
+    Ok(())
+}
+
+/// systemd unit with sandboxing directives, allowing little more than binding ports and reading files
+fn systemd_unit(install: &InstallArgs, executable: &Path, directory: &Path, writable: &[PathBuf]) -> String {
+    let command: Vec<String> = std::iter::once(executable.to_string_lossy().into_owned())
+        .chain(install.args.iter().cloned())
+        .map(|arg| systemd_quote(&arg))
+        .collect();
+    let mut unit = format!(
+        "[Unit]\n\
+         Description=https-wrapper TLS proxy\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={command}\n\
+         ExecReload=/bin/kill -HUP $MAINPID\n\
+         WorkingDirectory={directory}\n\
+         Restart=on-failure\n\
+         RestartSec=2\n\
+         LimitNOFILE=65536\n",
+        command = command.join(" "),
+        directory = systemd_quote(&directory.to_string_lossy()),
+    );
+    match &install.run_as {
+        Some(user) => {
+            unit.push_str(&format!("User={}\n", user));
+            unit.push_str("AmbientCapabilities=CAP_NET_BIND_SERVICE\n");
+            unit.push_str("CapabilityBoundingSet=CAP_NET_BIND_SERVICE\n");
+        }
+        // Root only needs to switch users (--user) on top of binding ports
+        None => unit.push_str("CapabilityBoundingSet=CAP_NET_BIND_SERVICE CAP_SETUID CAP_SETGID\n"),
+    }
+    unit.push_str(
+        "NoNewPrivileges=yes\n\
+         ProtectSystem=strict\n\
+         ProtectHome=read-only\n\
+         PrivateTmp=yes\n\
+         PrivateDevices=yes\n\
+         ProtectKernelTunables=yes\n\
+         ProtectKernelModules=yes\n\
+         ProtectKernelLogs=yes\n\
+         ProtectControlGroups=yes\n\
+         ProtectClock=yes\n\
+         ProtectHostname=yes\n\
+         RestrictAddressFamilies=AF_INET AF_INET6 AF_UNIX AF_NETLINK\n\
+         RestrictNamespaces=yes\n\
+         RestrictRealtime=yes\n\
+         RestrictSUIDSGID=yes\n\
+         LockPersonality=yes\n\
+         MemoryDenyWriteExecute=yes\n\
+         SystemCallArchitectures=native\n",
+    );
+    for path in writable {
+        unit.push_str(&format!("ReadWritePaths={}\n", systemd_quote(&directory.join(path).to_string_lossy())));
+    }
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+/// Quote a command line argument for systemd, escaping its specifiers and variable expansion
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:,=@+[]".contains(c)) {
+        escaped
+    } else {
+        format!("\"{}\"", escaped)
+    }
+}
+
+/// launchd property list starting the proxy at boot and restarting it when it exits
+fn launchd_plist(label: &str, install: &InstallArgs, executable: &Path, directory: &Path) -> String {
+    let arguments: String = std::iter::once(executable.to_string_lossy().into_owned())
+        .chain(install.args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(&arg)))
+        .collect();
+    let user = install
+        .run_as
+        .as_ref()
+        .map(|user| format!("    <key>UserName</key>\n    <string>{}</string>\n", xml_escape(user)))
+        .unwrap_or_default();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n{}\x20   </array>\n\
+         \x20   <key>WorkingDirectory</key>\n\
+         \x20   <string>{}</string>\n\
+         {}\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <true/>\n\
+         </dict>\n\
+         </plist>\n",
+        xml_escape(label),
+        arguments,
+        xml_escape(&directory.to_string_lossy()),
+        user
+    )
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,76 @@
+use rustls::server::{ProducesTickets, ServerSessionMemoryCache, StoresServerSessions};
+use std::sync::{Arc, RwLock};
+
+/// Number of sessions kept for stateful resumption
+const SESSION_CACHE_SIZE: usize = 4096;
+
+/// TLS session resumption state (session cache and ticket keys)
+///
+/// Shared by all TLS configurations, so resumption keeps working when the certificate is
+/// reloaded. It can be rotated to make sessions established before unresumable.
+#[derive(Debug)]
+pub struct SessionState {
+    cache: RwLock<Arc<ServerSessionMemoryCache>>,
+    ticketer: RwLock<Arc<dyn ProducesTickets>>,
+}
+
+impl SessionState {
+    pub fn new() -> Result<Arc<Self>, rustls::Error> {
+        Ok(Arc::new(Self {
+            cache: RwLock::new(ServerSessionMemoryCache::new(SESSION_CACHE_SIZE)),
+            ticketer: RwLock::new(rustls::crypto::aws_lc_rs::Ticketer::new()?),
+        }))
+    }
+
+    /// Forget all cached sessions and replace the ticket keys
+    pub fn rotate(&self) -> Result<(), rustls::Error> {
+        let ticketer = rustls::crypto::aws_lc_rs::Ticketer::new()?;
+        *self.ticketer.write().unwrap() = ticketer;
+        *self.cache.write().unwrap() = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+        Ok(())
+    }
+
+    fn cache(&self) -> Arc<ServerSessionMemoryCache> {
+        self.cache.read().unwrap().clone()
+    }
+
+    fn ticketer(&self) -> Arc<dyn ProducesTickets> {
+        self.ticketer.read().unwrap().clone()
+    }
+}
+
+impl StoresServerSessions for SessionState {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.cache().put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.cache().get(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.cache().take(key)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+impl ProducesTickets for SessionState {
+    fn enabled(&self) -> bool {
+        self.ticketer().enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.ticketer().lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.ticketer().encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.ticketer().decrypt(cipher)
+    }
+}
@@ -0,0 +1,154 @@
+use crate::profile::Profile;
+use clap::ValueEnum;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Time a client gets to ask for STARTTLS, and a backend to send its greeting
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum length of a command or greeting line
+const MAX_LINE: usize = 1024;
+
+/// Mail protocol whose STARTTLS command is answered by the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Starttls {
+    Smtp,
+    Imap,
+}
+
+impl Starttls {
+    /// Profile for the protocol spoken after the upgrade
+    pub fn profile(self) -> Profile {
+        match self {
+            Starttls::Smtp => Profile::Smtp,
+            Starttls::Imap => Profile::Imap,
+        }
+    }
+}
+
+/// Greet a plaintext client and answer its commands until it issues STARTTLS
+///
+/// Returns `false` when the client quit or disconnected before upgrading. Commands other than
+/// the harmless ones are refused, as nothing may reach the backend unencrypted.
+pub async fn negotiate(stream: &mut TcpStream, protocol: Starttls) -> std::io::Result<bool> {
+    tokio::time::timeout(NEGOTIATION_TIMEOUT, dialog(stream, protocol))
+        .await
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "client did not issue STARTTLS in time")))
+}
+
+async fn dialog(stream: &mut TcpStream, protocol: Starttls) -> std::io::Result<bool> {
+    let host = hostname();
+    let mut reader = BufReader::new(&mut *stream);
+    let greeting = match protocol {
+        Starttls::Smtp => format!("220 {} ESMTP\r\n", host),
+        Starttls::Imap => "* OK [CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED] Ready\r\n".to_string(),
+    };
+    reader.get_mut().write_all(greeting.as_bytes()).await?;
+
+    loop {
+        let Some(line) = read_line(&mut reader).await? else {
+            return Ok(false);
+        };
+        let (reply, upgrade, quit) = match protocol {
+            Starttls::Smtp => smtp_reply(&line, &host),
+            Starttls::Imap => imap_reply(&line),
+        };
+        if upgrade && !reader.buffer().is_empty() {
+            // Commands pipelined after STARTTLS would otherwise be treated as if sent encrypted
+            return Err(Error::new(ErrorKind::InvalidData, "client sent data after STARTTLS"));
+        }
+        reader.get_mut().write_all(reply.as_bytes()).await?;
+        if upgrade || quit {
+            return Ok(upgrade);
+        }
+    }
+}
+
+/// Reply to an SMTP command, and whether to upgrade or close
+fn smtp_reply(line: &str, host: &str) -> (String, bool, bool) {
+    let verb = line.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+    match verb.as_str() {
+        "EHLO" => (format!("250-{}\r\n250 STARTTLS\r\n", host), false, false),
+        "HELO" => (format!("250 {}\r\n", host), false, false),
+        "STARTTLS" => ("220 2.0.0 Ready to start TLS\r\n".to_string(), true, false),
+        "NOOP" | "RSET" => ("250 2.0.0 OK\r\n".to_string(), false, false),
+        "QUIT" => ("221 2.0.0 Bye\r\n".to_string(), false, true),
+        _ => ("530 5.7.0 Must issue a STARTTLS command first\r\n".to_string(), false, false),
+    }
+}
+
+/// Reply to an IMAP command, and whether to upgrade or close
+fn imap_reply(line: &str) -> (String, bool, bool) {
+    let mut words = line.split_whitespace();
+    let tag = words.next().unwrap_or("*");
+    let command = words.next().unwrap_or("").to_ascii_uppercase();
+    match command.as_str() {
+        "CAPABILITY" => (
+            format!("* CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED\r\n{} OK CAPABILITY completed\r\n", tag),
+            false,
+            false,
+        ),
+        "STARTTLS" => (format!("{} OK Begin TLS negotiation now\r\n", tag), true, false),
+        "NOOP" => (format!("{} OK NOOP completed\r\n", tag), false, false),
+        "LOGOUT" => (format!("* BYE Logging out\r\n{} OK LOGOUT completed\r\n", tag), false, true),
+        _ => (format!("{} BAD STARTTLS required\r\n", tag), false, false),
+    }
+}
+
+/// Read a CRLF terminated line, `None` at EOF
+async fn read_line(reader: &mut BufReader<&mut TcpStream>) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = (&mut *reader).take(MAX_LINE as u64).read_until(b'\n', &mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(Error::new(ErrorKind::InvalidData, "command line too long"));
+    }
+    Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()))
+}
+
+/// Read and discard the backend's greeting, the client was already greeted by the proxy
+///
+/// Reads byte by byte, so nothing the backend sends after its greeting is consumed.
+pub async fn skip_greeting<S: AsyncRead + Unpin>(backend: &mut S, protocol: Starttls) -> std::io::Result<()> {
+    let skip = async {
+        loop {
+            let mut line = Vec::new();
+            while !line.ends_with(b"\n") {
+                if line.len() >= MAX_LINE {
+                    return Err(Error::new(ErrorKind::InvalidData, "backend greeting line too long"));
+                }
+                line.push(backend.read_u8().await?);
+            }
+            let complete = match protocol {
+                // Multi-line replies continue with "220-"
+                Starttls::Smtp if line.starts_with(b"220-") => false,
+                Starttls::Smtp if line.starts_with(b"220") => true,
+                Starttls::Imap if line.starts_with(b"* OK") => true,
+                _ => {
+                    let line = String::from_utf8_lossy(&line);
+                    return Err(Error::other(format!("unexpected backend greeting: {}", line.trim_end())));
+                }
+            };
+            if complete {
+                return Ok(());
+            }
+        }
+    };
+    tokio::time::timeout(NEGOTIATION_TIMEOUT, skip)
+        .await
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "backend did not send its greeting in time")))
+}
+
+/// Host name of the machine, used in the SMTP greeting
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
@@ -0,0 +1,113 @@
+use crate::{error, info};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::task::{Id, JoinSet};
+
+/// Delay before restarting a task that failed right after starting, doubled for each further failure
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound for the delay between restarts
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// A task running at least this long before failing is restarted without delay
+const STABLE_RUNTIME: Duration = Duration::from_secs(60);
+
+/// What to do when a supervised task panics or stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RestartPolicy {
+    /// Log the failure and start the task again, with backoff when it keeps failing
+    Restart,
+    /// Log the failure and exit, leaving the restart to a service manager
+    Exit,
+}
+
+type TaskFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Long-running task and how to start it
+struct Task {
+    name: &'static str,
+    start: Box<dyn FnMut() -> TaskFuture + Send>,
+    started: Instant,
+    /// Delay before the next restart
+    delay: Duration,
+}
+
+/// What a future in the join set stands for
+enum Entry {
+    Running(Task),
+    /// Waiting out the delay before the task is started again
+    Restarting(Task),
+}
+
+/// Runs the acceptor and background tasks, noticing when one of them panics or stops
+pub struct Supervisor {
+    policy: RestartPolicy,
+    running: JoinSet<()>,
+    entries: HashMap<Id, Entry>,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            running: JoinSet::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Start a task that is meant to run until the process exits
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, mut start: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.start(Task {
+            name,
+            start: Box::new(move || Box::pin(start())),
+            started: Instant::now(),
+            delay: INITIAL_RESTART_DELAY,
+        });
+    }
+
+    fn start(&mut self, mut task: Task) {
+        task.started = Instant::now();
+        let handle = self.running.spawn((task.start)());
+        self.entries.insert(handle.id(), Entry::Running(task));
+    }
+
+    /// Supervise the tasks until one fails while the policy is to exit
+    pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        while let Some(result) = self.running.join_next_with_id().await {
+            let (id, failure) = match result {
+                Ok((id, ())) => (id, "stopped unexpectedly".to_string()),
+                Err(e) => (e.id(), e.to_string()),
+            };
+            let mut task = match self.entries.remove(&id) {
+                Some(Entry::Running(task)) => task,
+                Some(Entry::Restarting(task)) => {
+                    info!("Restarting task '{}'", task.name);
+                    self.start(task);
+                    continue;
+                }
+                None => continue,
+            };
+            if self.policy == RestartPolicy::Exit {
+                return Err(format!("Task '{}' failed: {}", task.name, failure).into());
+            }
+
+            // Back off while the task keeps failing right after starting
+            if task.started.elapsed() >= STABLE_RUNTIME {
+                task.delay = INITIAL_RESTART_DELAY;
+            }
+            error!("Task '{}' failed: {}, restarting in {}s", task.name, failure, task.delay.as_secs());
+            let delay = task.delay;
+            task.delay = (task.delay * 2).min(MAX_RESTART_DELAY);
+            let handle = self.running.spawn(tokio::time::sleep(delay));
+            self.entries.insert(handle.id(), Entry::Restarting(task));
+        }
+        Ok(())
+    }
+}
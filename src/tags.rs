@@ -0,0 +1,63 @@
+/// Tag attached to connections whose SNI matches a pattern
+#[derive(Debug, Clone)]
+pub struct TagRule {
+    /// Exact server name, or wildcard of the form `*.example.com`
+    pub pattern: String,
+    pub tag: String,
+}
+
+impl TagRule {
+    /// Whether the rule applies to the given server name
+    pub fn matches(&self, sni: &str) -> bool {
+        matches_pattern(&self.pattern, sni)
+    }
+}
+
+/// Whether a server name matches an exact (lowercase) name or a wildcard of the form `*.example.com`
+pub fn matches_pattern(pattern: &str, sni: &str) -> bool {
+    let sni = sni.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => sni.len() > suffix.len() + 1
+            && sni.ends_with(suffix)
+            && sni.as_bytes()[sni.len() - suffix.len() - 1] == b'.',
+        None => sni == pattern,
+    }
+}
+
+/// Parse a `<sni>=<tag>` rule from the command line
+pub fn parse_tag_rule(s: &str) -> Result<TagRule, String> {
+    let (pattern, tag) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid tag '{}'. Expected format: <sni>=<tag>", s))?;
+    if pattern.is_empty() || tag.is_empty() {
+        return Err(format!("Invalid tag '{}'. Both SNI and tag must be non-empty", s));
+    }
+
+    Ok(TagRule {
+        pattern: pattern.to_ascii_lowercase(),
+        tag: tag.to_string(),
+    })
+}
+
+/// Collect the tags of all rules matching the server name
+pub fn tags_for(rules: &[TagRule], sni: Option<&str>) -> Vec<String> {
+    let Some(sni) = sni else {
+        return Vec::new();
+    };
+    let mut tags: Vec<String> = Vec::new();
+    for rule in rules.iter().filter(|r| r.matches(sni)) {
+        if !tags.contains(&rule.tag) {
+            tags.push(rule.tag.clone());
+        }
+    }
+    tags
+}
+
+/// Log line prefix for a set of tags, e.g. `[admin,internal] `
+pub fn log_prefix(tags: &[String]) -> String {
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!("[{}] ", tags.join(","))
+    }
+}
@@ -0,0 +1,85 @@
+use crate::error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Times an identical line is logged per window before further repeats are only counted
+const BURST: u32 = 3;
+
+/// Distinct lines tracked at most, lines beyond that are logged without throttling
+const MAX_TRACKED: usize = 1024;
+
+/// How often a line was logged and suppressed since the start of its window
+#[derive(Debug)]
+struct Repeats {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+/// Collapses floods of identical error lines (e.g. a backend refusing every connection) into a
+/// few lines and a summary per window, keeping the log usable during incidents
+#[derive(Debug)]
+pub struct ErrorThrottle {
+    /// Length of a window, zero logs every line
+    window: Duration,
+    repeats: Mutex<HashMap<String, Repeats>>,
+}
+
+impl ErrorThrottle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            repeats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Log the line to standard error, unless it was logged often enough in the current window
+    pub fn log(&self, line: String) {
+        if self.window.is_zero() {
+            error!("{}", line);
+            return;
+        }
+        let mut repeats = self.repeats.lock().unwrap();
+        if let Some(repeated) = repeats.get_mut(&line) {
+            if repeated.logged >= BURST {
+                repeated.suppressed += 1;
+                return;
+            }
+            repeated.logged += 1;
+        } else if repeats.len() < MAX_TRACKED {
+            repeats.insert(
+                line.clone(),
+                Repeats {
+                    started: Instant::now(),
+                    logged: 1,
+                    suppressed: 0,
+                },
+            );
+        }
+        drop(repeats);
+        error!("{}", line);
+    }
+
+    /// Summarize the lines whose window ended, forgetting them so they are logged again
+    fn flush(&self) {
+        let mut repeats = self.repeats.lock().unwrap();
+        repeats.retain(|line, repeated| {
+            let ended = repeated.started.elapsed() >= self.window;
+            if ended && repeated.suppressed > 0 {
+                error!("{} (repeated {} more times in the last {}s)", line, repeated.suppressed, self.window.as_secs());
+            }
+            !ended
+        });
+    }
+
+    /// Summarize repeated lines as their windows end, forever
+    pub async fn run(self: Arc<Self>) {
+        // Windows start at different times, checking every second keeps summaries close to their end
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.flush();
+        }
+    }
+}
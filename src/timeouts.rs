@@ -0,0 +1,182 @@
+use crate::registry::Connection;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Timeouts toward the backend
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Time allowed for connecting to the backend
+    pub connect: Option<Duration>,
+    /// Time the backend may stay silent after receiving data from the client
+    pub read: Option<Duration>,
+    /// Time the backend may take to start responding to a request (instead of `read`)
+    pub first_byte: Option<Duration>,
+}
+
+/// Timeout overrides for requests whose path starts with a prefix
+#[derive(Debug, Clone)]
+pub struct RouteTimeout {
+    pub prefix: String,
+    pub timeouts: Timeouts,
+}
+
+/// Parse a `--route-timeout` argument of the form `PREFIX=READ_SECS[,CONNECT_SECS]`
+pub fn parse_route_timeout(s: &str) -> Result<RouteTimeout, String> {
+    let (prefix, values) = s.split_once('=').ok_or("expected PREFIX=READ_SECS[,CONNECT_SECS]")?;
+    if !prefix.starts_with('/') {
+        return Err("path prefix must start with /".to_string());
+    }
+    let seconds = |value: &str| {
+        value
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|_| format!("invalid number of seconds '{}'", value))
+    };
+    let (read, connect) = match values.split_once(',') {
+        Some((read, connect)) => (seconds(read)?, Some(seconds(connect)?)),
+        None => (seconds(values)?, None),
+    };
+    Ok(RouteTimeout {
+        prefix: prefix.to_string(),
+        timeouts: Timeouts {
+            connect,
+            read: Some(read),
+            first_byte: None,
+        },
+    })
+}
+
+/// Timeouts for a request path: the longest matching route prefix overrides the defaults
+pub fn for_path(routes: &[RouteTimeout], defaults: Timeouts, path: Option<&str>) -> Timeouts {
+    let route = path.and_then(|path| {
+        routes
+            .iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len())
+    });
+    match route {
+        Some(route) => Timeouts {
+            connect: route.timeouts.connect.or(defaults.connect),
+            read: route.timeouts.read.or(defaults.read),
+            first_byte: route.timeouts.first_byte.or(defaults.first_byte),
+        },
+        None => defaults,
+    }
+}
+
+/// Backend stream wrapper failing reads when the backend does not answer in time
+///
+/// The timer starts when data is written to the backend and stops when the backend sends data,
+/// so idle keep-alive connections and long-lived streams that keep sending are not affected.
+/// Until the backend sent its first byte, the first byte timeout applies instead (when set).
+pub struct ReadTimeout<S> {
+    inner: S,
+    timeouts: Timeouts,
+    /// Whether the backend started responding
+    responded: bool,
+    deadline: Option<(Pin<Box<Sleep>>, Duration)>,
+}
+
+impl<S> ReadTimeout<S> {
+    pub fn new(inner: S, timeouts: Timeouts) -> Self {
+        Self {
+            inner,
+            timeouts,
+            responded: false,
+            deadline: None,
+        }
+    }
+
+    /// Change the timeouts for the next request on a reused connection
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+        self.responded = false;
+        self.deadline = None;
+    }
+
+    /// Timeout for the backend's next answer
+    fn current_timeout(&self) -> Option<Duration> {
+        match self.responded {
+            false => self.timeouts.first_byte.or(self.timeouts.read),
+            true => self.timeouts.read,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ReadTimeout<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if result.is_err() || buf.filled().len() > filled {
+                    self.responded = true;
+                    self.deadline = None;
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                let expired = self.deadline.as_mut().is_some_and(|(deadline, _)| deadline.as_mut().poll(cx).is_ready());
+                if let (true, Some((_, timeout))) = (expired, &self.deadline) {
+                    let message = match self.responded {
+                        false => format!("backend did not start responding within {}s", timeout.as_secs()),
+                        true => format!("backend did not respond within {}s", timeout.as_secs()),
+                    };
+                    return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::TimedOut, message)));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ReadTimeout<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(timeout)) = (&result, self.current_timeout()) {
+            if *written > 0 {
+                // Restart the timer, the backend may wait for the complete request before answering
+                let deadline = tokio::time::Instant::now() + timeout;
+                match &mut self.deadline {
+                    Some((sleep, armed)) => {
+                        sleep.as_mut().reset(deadline);
+                        *armed = timeout;
+                    }
+                    None => self.deadline = Some((Box::pin(tokio::time::sleep_until(deadline)), timeout)),
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Resolves once no bytes were exchanged with the client for `idle`, never without a timeout
+pub async fn idle(connection: &Connection, idle: Option<Duration>) {
+    let Some(idle) = idle else {
+        return std::future::pending().await;
+    };
+    let transferred = || connection.bytes_in.load(Ordering::Relaxed) + connection.bytes_out.load(Ordering::Relaxed);
+    let check_interval = (idle / 4).max(Duration::from_secs(1));
+    let mut last = (transferred(), Instant::now());
+    loop {
+        tokio::time::sleep(check_interval).await;
+        let now = transferred();
+        if now != last.0 {
+            last = (now, Instant::now());
+        } else if last.1.elapsed() >= idle {
+            return;
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::info;
+use crate::admin::json_string;
+use crate::routing::IpNet;
+use crate::tags;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Headers whose values are left out of trace dumps
+const SECRET_HEADERS: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+/// Connections selected for tracing
+#[derive(Debug, Clone)]
+pub enum TraceFilter {
+    Any,
+    /// Client address or range
+    Ip(IpNet),
+    /// Exact server name, or wildcard of the form `*.example.com`
+    Sni(String),
+}
+
+impl TraceFilter {
+    fn matches(&self, peer: IpAddr, sni: Option<&str>) -> bool {
+        match self {
+            TraceFilter::Any => true,
+            TraceFilter::Ip(net) => net.contains(peer),
+            TraceFilter::Sni(pattern) => sni.is_some_and(|sni| tags::matches_pattern(pattern, sni)),
+        }
+    }
+}
+
+impl std::fmt::Display for TraceFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceFilter::Any => write!(f, "any client"),
+            TraceFilter::Ip(net) => write!(f, "client {}", net),
+            TraceFilter::Sni(pattern) => write!(f, "server name {}", pattern),
+        }
+    }
+}
+
+/// Verbose tracing of the next connections matching a filter, requested through the admin API
+#[derive(Debug, Default)]
+pub struct Tracing {
+    /// Filter and number of connections still to trace
+    pending: Mutex<Option<(TraceFilter, u32)>>,
+}
+
+impl Tracing {
+    /// Trace the next `count` connections matching the filter, replacing an earlier request
+    pub fn start(&self, filter: TraceFilter, count: u32) {
+        info!("Tracing the next {} connection(s) from {}", count, filter);
+        *self.pending.lock().unwrap() = Some((filter, count));
+    }
+
+    /// Stop tracing new connections, returns whether tracing was active
+    pub fn stop(&self) -> bool {
+        self.pending.lock().unwrap().take().is_some()
+    }
+
+    /// Trace a new connection when it matches the filter, disabling tracing after the last one
+    pub fn claim(&self, peer: IpAddr, sni: Option<&str>, accepted: Instant) -> Option<Trace> {
+        let mut pending = self.pending.lock().unwrap();
+        let (filter, remaining) = pending.as_mut()?;
+        if !filter.matches(peer, sni) {
+            return None;
+        }
+        *remaining -= 1;
+        if *remaining == 0 {
+            *pending = None;
+            info!("Tracing finished, the last matching connection is being traced");
+        }
+        Some(Trace { accepted })
+    }
+
+    /// Render the pending trace request as a JSON object
+    pub fn render_json(&self) -> String {
+        match &*self.pending.lock().unwrap() {
+            Some((filter, remaining)) => {
+                let (ip, sni) = match filter {
+                    TraceFilter::Any => (None, None),
+                    TraceFilter::Ip(net) => (Some(net.to_string()), None),
+                    TraceFilter::Sni(pattern) => (None, Some(pattern.clone())),
+                };
+                format!(
+                    "{{\"active\":true,\"remaining\":{},\"ip\":{},\"sni\":{}}}",
+                    remaining,
+                    ip.as_deref().map_or("null".to_string(), json_string),
+                    sni.as_deref().map_or("null".to_string(), json_string)
+                )
+            }
+            None => "{\"active\":false,\"remaining\":0,\"ip\":null,\"sni\":null}".to_string(),
+        }
+    }
+}
+
+/// Tracing state of a single connection
+#[derive(Debug)]
+pub struct Trace {
+    accepted: Instant,
+}
+
+impl Trace {
+    /// Log a step with the time since the connection was accepted
+    pub fn log(&self, id: u64, step: &str) {
+        info!("[trace {}] +{:.1}ms {}", id, self.accepted.elapsed().as_secs_f64() * 1000.0, step);
+    }
+}
+
+/// Render an HTTP head as indented lines, leaving out credentials and cookies
+pub fn dump_head(head: &[u8]) -> String {
+    let head = String::from_utf8_lossy(head);
+    let lines: Vec<String> = head
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once(':') {
+            Some((name, value)) if SECRET_HEADERS.contains(&name.trim().to_ascii_lowercase().as_str()) => {
+                format!("    {}: <{} bytes hidden>", name, value.trim().len())
+            }
+            _ => format!("    {}", line),
+        })
+        .collect();
+    lines.join("\n")
+}
@@ -0,0 +1,239 @@
+use crate::logs::LogBuffer;
+use crate::proxy::Proxy;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the dashboard is redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Requests are counted per path over this window (and the one before)
+const PATH_WINDOW: Duration = Duration::from_secs(10);
+
+/// Upper bound for the paths counted in a window, further paths are counted together
+const MAX_PATHS: usize = 1000;
+
+/// Entries shown in the top server names and paths
+const TOP_ENTRIES: usize = 5;
+
+/// Log lines kept for the dashboard
+pub const LOG_LINES: usize = 100;
+
+/// Live terminal dashboard (`--tui`), fed by the connection and request handling
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    requests: Mutex<Requests>,
+    /// Terminal the dashboard is drawn on while the log output is hidden
+    terminal: Mutex<Option<File>>,
+}
+
+/// Requests handled in HTTP mode
+#[derive(Debug, Default)]
+struct Requests {
+    total: u64,
+    server_errors: u64,
+    /// Requests per path in the current and the previous window
+    paths: HashMap<String, u64>,
+    previous_paths: HashMap<String, u64>,
+    window_start: Option<Instant>,
+}
+
+/// Counters at the previous redraw, to compute rates
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    connections: u64,
+    handshake_failures: u64,
+    requests: u64,
+    server_errors: u64,
+}
+
+impl Dashboard {
+    /// Count a request answered in HTTP mode
+    pub fn record_request(&self, path: Option<&str>, status: u16) {
+        let mut requests = self.requests.lock().unwrap();
+        requests.total += 1;
+        if status >= 500 {
+            requests.server_errors += 1;
+        }
+        let Some(path) = path else { return };
+        let path = path.split('?').next().unwrap_or(path);
+        if requests.paths.len() < MAX_PATHS || requests.paths.contains_key(path) {
+            *requests.paths.entry(path.to_string()).or_default() += 1;
+        } else {
+            *requests.paths.entry("(other paths)".to_string()).or_default() += 1;
+        }
+    }
+
+    /// Draw the dashboard on the terminal, keeping the log output from it
+    pub fn show(&self, logs: &LogBuffer) -> std::io::Result<()> {
+        *self.terminal.lock().unwrap() = Some(logs.terminal()?);
+        logs.set_hidden(true);
+        Ok(())
+    }
+
+    /// Clear the dashboard, so the log output continues on an empty screen
+    pub fn close(&self, logs: &LogBuffer) {
+        if let Some(mut terminal) = self.terminal.lock().unwrap().take() {
+            let _ = terminal.write_all(b"\x1b[H\x1b[2J");
+        }
+        logs.set_hidden(false);
+    }
+
+    /// Redraw the dashboard of all listeners (the main one first) every second while the log output is captured
+    pub async fn run(self: Arc<Self>, proxies: Arc<[Arc<Proxy>]>) {
+        let mut previous = self.sample(&proxies[0]);
+        loop {
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+            let current = self.sample(&proxies[0]);
+            let mut terminal = self.terminal.lock().unwrap();
+            let Some(terminal) = terminal.as_mut() else { continue };
+            let (columns, rows) = size(terminal);
+            let screen = self.render(&proxies, previous, current, columns, rows);
+            // Errors mean the terminal is gone, there is nothing left to show the dashboard on
+            let _ = terminal.write_all(screen.as_bytes());
+            previous = current;
+        }
+    }
+
+    fn sample(&self, proxy: &Proxy) -> Sample {
+        let requests = self.requests.lock().unwrap();
+        Sample {
+            at: Instant::now(),
+            connections: proxy.metrics.connections_total.load(Ordering::Relaxed),
+            handshake_failures: proxy.metrics.handshake_failures_total(),
+            requests: requests.total,
+            server_errors: requests.server_errors,
+        }
+    }
+
+    /// Render the screen, the connections and counters are shared by all listeners
+    fn render(&self, proxies: &[Arc<Proxy>], previous: Sample, current: Sample, columns: usize, rows: usize) -> String {
+        let proxy = &proxies[0];
+        let elapsed = current.at.duration_since(previous.at).as_secs_f64().max(0.001);
+        let rate = |current: u64, previous: u64| current.saturating_sub(previous) as f64 / elapsed;
+        let uptime = proxy.started.elapsed().as_secs();
+        let active = proxy.registry.list();
+        let upstreams: Vec<_> = proxies.iter().map(|listening| listening.upstreams()).collect();
+
+        let mut lines = vec![format!(
+            "https-wrapper {}  up {}h {:02}m {:02}s",
+            env!("CARGO_PKG_VERSION"),
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60
+        )];
+        for (listening, upstreams) in proxies.iter().zip(&upstreams) {
+            lines.push(format!("Listener     {} -> {}", listening.input_address, upstreams.backends.describe()));
+        }
+        lines.extend([
+            String::new(),
+            format!(
+                "Connections  {} active   {:.1}/s new   {} total   {:.1}/s handshake failures",
+                active.len(),
+                rate(current.connections, previous.connections),
+                current.connections,
+                rate(current.handshake_failures, previous.handshake_failures)
+            ),
+        ]);
+        if proxy.mode == crate::http::Mode::Http {
+            let requests = rate(current.requests, previous.requests);
+            let errors = rate(current.server_errors, previous.server_errors);
+            let percent = if requests > 0.0 { errors / requests * 100.0 } else { 0.0 };
+            lines.push(format!(
+                "Requests     {:.1}/s   {} total   {:.1}/s 5xx ({:.1}%)",
+                requests, current.requests, errors, percent
+            ));
+        } else {
+            lines.push("Requests     not parsed in tcp mode".to_string());
+        }
+        let now = Instant::now();
+        for pool in upstreams.iter().flat_map(|upstreams| upstreams.pools()) {
+            for backend in pool.backends() {
+                let state = if backend.is_draining() {
+                    "draining"
+                } else if backend.outlier.lock().unwrap().is_ejected(now) {
+                    "ejected"
+                } else {
+                    "ok"
+                };
+                lines.push(format!(
+                    "Backend      {}  {}  {} open connection(s)",
+                    backend.address,
+                    state,
+                    backend.connections.load(Ordering::Relaxed)
+                ));
+            }
+        }
+
+        let mut names: HashMap<&str, u64> = HashMap::new();
+        for connection in &active {
+            *names.entry(connection.sni.as_deref().unwrap_or("(no SNI)")).or_default() += 1;
+        }
+        lines.push(String::new());
+        lines.push("Top server names (active connections)".to_string());
+        lines.extend(top(names.into_iter().map(|(name, count)| (name.to_string(), count))));
+
+        if proxy.mode == crate::http::Mode::Http {
+            let mut requests = self.requests.lock().unwrap();
+            if requests.window_start.is_none_or(|start| start.elapsed() >= PATH_WINDOW) {
+                requests.previous_paths = std::mem::take(&mut requests.paths);
+                requests.window_start = Some(Instant::now());
+            }
+            let mut paths = requests.previous_paths.clone();
+            for (path, count) in &requests.paths {
+                *paths.entry(path.clone()).or_default() += count;
+            }
+            lines.push(String::new());
+            lines.push(format!("Top paths (last {}-{}s)", PATH_WINDOW.as_secs(), 2 * PATH_WINDOW.as_secs()));
+            lines.extend(top(paths.into_iter()));
+        }
+
+        lines.push(String::new());
+        lines.push("Recent log (Ctrl-C stops the proxy)".to_string());
+        if let Some(logs) = &proxy.logs {
+            let room = rows.saturating_sub(lines.len() + 1);
+            lines.extend(logs.recent(room).iter().map(|line| format!("  {}", line.text)));
+        }
+
+        // Redraw in place, clearing what is left of each line and below the last one
+        let mut screen = String::from("\x1b[H");
+        for line in lines.iter().take(rows.saturating_sub(1)) {
+            let line: String = line.chars().filter(|c| !c.is_control()).take(columns).collect();
+            screen.push_str(&line);
+            screen.push_str("\x1b[K\n");
+        }
+        screen.push_str("\x1b[J");
+        screen
+    }
+}
+
+/// Lines for the entries with the highest counts
+fn top(entries: impl Iterator<Item = (String, u64)>) -> Vec<String> {
+    let mut entries: Vec<(String, u64)> = entries.collect();
+    if entries.is_empty() {
+        return vec!["  -".to_string()];
+    }
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    entries
+        .into_iter()
+        .take(TOP_ENTRIES)
+        .map(|(name, count)| format!("  {:>7}  {}", count, name))
+        .collect()
+}
+
+/// Columns and rows of the terminal, 80x24 when unknown
+fn size(terminal: &File) -> (usize, usize) {
+    // SAFETY: TIOCGWINSZ only fills in the winsize struct
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(terminal.as_raw_fd(), libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 && size.ws_row > 0 {
+            return (size.ws_col as usize, size.ws_row as usize);
+        }
+    }
+    (80, 24)
+}
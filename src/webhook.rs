@@ -0,0 +1,89 @@
+use crate::{error, warn};
+use crate::admin::json_string;
+use crate::events::Event;
+use crate::http_client;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::TlsConnector;
+
+/// Number of delivery attempts per event
+const ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry, doubled for each further retry
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Time allowed for a single delivery attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Webhook receiving JSON event payloads (compatible with Slack incoming webhooks)
+pub struct Webhook {
+    url: String,
+    disabled: Vec<Event>,
+    tls: Option<TlsConnector>,
+}
+
+impl Webhook {
+    pub fn new(url: &str, disabled: Vec<Event>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let tls = if url.starts_with("https://") {
+            Some(http_client::tls_connector()?)
+        } else if url.starts_with("http://") {
+            None
+        } else {
+            return Err(format!("Webhook URL must start with http:// or https:// (got '{}')", url).into());
+        };
+        Ok(Self {
+            url: url.to_string(),
+            disabled,
+            tls,
+        })
+    }
+
+    /// Whether the event is sent to the webhook
+    pub fn enabled(&self, event: Event) -> bool {
+        !self.disabled.contains(&event)
+    }
+
+    /// Deliver an event, retrying with backoff on failure
+    pub async fn send(self: Arc<Self>, event: Event, details: Vec<(String, String)>) {
+        let body = payload(event, &details);
+        let mut delay = INITIAL_RETRY_DELAY;
+        for attempt in 1..=ATTEMPTS {
+            let request = http_client::post(&self.url, "application/json", body.as_bytes(), self.tls.as_ref());
+            let error = match tokio::time::timeout(REQUEST_TIMEOUT, request).await {
+                Ok(Ok((status, _))) if (200..300).contains(&status) => return,
+                // Client errors (other than rate limiting) will not succeed on retry
+                Ok(Ok((status, _))) if (400..500).contains(&status) && status != 429 => {
+                    warn!("Webhook rejected {} event with status {}", event.name(), status);
+                    return;
+                }
+                Ok(Ok((status, _))) => format!("status {}", status),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+
+            if attempt == ATTEMPTS {
+                error!("Webhook delivery of {} event failed after {} attempts: {}", event.name(), ATTEMPTS, error);
+                return;
+            }
+            warn!("Webhook delivery of {} event failed ({}), retrying in {}s", event.name(), error, delay.as_secs());
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+/// JSON payload with a human readable `text` (shown by Slack) and the structured event
+fn payload(event: Event, details: &[(String, String)]) -> String {
+    let summary: Vec<String> = details.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    let text = format!("https-wrapper: {} ({})", event.name(), summary.join(", "));
+    let details: Vec<String> = details
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+        .collect();
+    format!(
+        "{{\"text\":{},\"event\":{},\"details\":{{{}}}}}",
+        json_string(&text),
+        json_string(event.name()),
+        details.join(",")
+    )
+}
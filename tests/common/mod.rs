@@ -0,0 +1,193 @@
+//! Helpers for the integration tests: a self-signed certificate, the proxy binary running on a
+//! free port and a TLS client trusting any certificate
+
+#![allow(dead_code)]
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Time a test waits for the proxy to start or for data to arrive
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A directory of its own for each test, removed when dropped
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("https-wrapper-test-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn file(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write a self-signed ECDSA certificate for localhost and its key, returning their paths
+pub fn certificate(dir: &TempDir) -> (String, String) {
+    let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "localhost").unwrap();
+    let name = name.build();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new().dns("localhost").build(&builder.x509v3_context(None, None)).unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let (cert_path, key_path) = (dir.file("cert.pem"), dir.file("key.pem"));
+    std::fs::write(&cert_path, builder.build().to_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (cert_path, key_path)
+}
+
+/// The proxy binary listening on a free port, killed when dropped
+pub struct Proxy {
+    child: Child,
+    pub addr: SocketAddr,
+    /// Addresses of the listeners added with `--listener`
+    pub listeners: Vec<SocketAddr>,
+    /// Address of the admin API, when started with `--admin`
+    pub admin: Option<SocketAddr>,
+    _dir: TempDir,
+}
+
+impl Proxy {
+    /// Start the proxy in front of the backend with a fresh certificate and the extra arguments,
+    /// listen addresses (of `--listener` and `--admin`) should use port 0
+    pub fn start(backend: SocketAddr, args: &[&str]) -> Self {
+        let dir = TempDir::new();
+        let (cert, key) = certificate(&dir);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_https-wrapper"))
+            .args(["127.0.0.1:0", &backend.to_string(), &cert, &key])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        // The startup log names the ports the proxy got, the main listener first
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let (mut listeners, mut admin) = (Vec::new(), None);
+        let expected = 1 + args.iter().filter(|arg| **arg == "--listener").count();
+        while listeners.len() < expected || (args.contains(&"--admin") && admin.is_none()) {
+            let line = lines.next().expect("proxy exited before listening").unwrap();
+            if let Some(addr) = line.strip_prefix("HTTPS reverse proxy running on https://") {
+                listeners.push(addr.parse().unwrap());
+            } else if let Some(addr) = line.strip_prefix("Admin API running on http://") {
+                admin = Some(addr.parse().unwrap());
+            }
+        }
+        // Keep reading, so the proxy never blocks on a full pipe
+        std::thread::spawn(move || lines.for_each(drop));
+        let addr = listeners.remove(0);
+        Self { child, addr, listeners, admin, _dir: dir }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Accepts any server certificate, as the tests only check what passes through the proxy
+#[derive(Debug)]
+struct AcceptAny(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Connect to the proxy over TLS, offering the ALPN protocols
+pub async fn connect(addr: SocketAddr, alpn: &[&[u8]]) -> TlsStream<TcpStream> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAny(provider)))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap()
+}
+
+/// Read until EOF, failing the test when it takes too long
+pub async fn read_to_end<R: AsyncRead + Unpin>(reader: &mut R) -> Vec<u8> {
+    let mut data = Vec::new();
+    tokio::time::timeout(TIMEOUT, reader.read_to_end(&mut data)).await.expect("timed out waiting for EOF").unwrap();
+    data
+}
+
+/// Read until the data ends with the marker (e.g. the end of a response head)
+pub async fn read_until<R: AsyncRead + Unpin>(reader: &mut R, marker: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut byte = [0u8; 1];
+    while !data.ends_with(marker) {
+        let read = tokio::time::timeout(TIMEOUT, reader.read(&mut byte)).await.expect("timed out waiting for data").unwrap();
+        assert!(read > 0, "EOF before {:?}, got {:?}", String::from_utf8_lossy(marker), String::from_utf8_lossy(&data));
+        data.push(byte[0]);
+    }
+    data
+}
@@ -0,0 +1,274 @@
+//! HTTP/2 clients in HTTP mode, with each stream forwarded to an HTTP/1.1 backend
+
+mod common;
+
+use common::{connect, Proxy, TIMEOUT};
+use https_wrapper::hpack::Decoder;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+const CANCEL: u32 = 0x8;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// A backend answering every request with its request line followed by its body, after a
+/// moment so the client is done sending first, until the test ends
+async fn backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                // Requests of reset streams are dropped by the proxy before they are complete
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(byte) => head.push(byte),
+                        Err(_) => return,
+                    }
+                }
+                let head = String::from_utf8(head).unwrap();
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut answer = head.lines().next().unwrap().as_bytes().to_vec();
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+                answer.extend_from_slice(&body);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", answer.len());
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&answer).await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend_from_slice(&[kind, flags]);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Frame {
+    let mut header = [0; 9];
+    tokio::time::timeout(TIMEOUT, reader.read_exact(&mut header)).await.expect("timed out waiting for a frame").unwrap();
+    let mut payload = vec![0; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+    reader.read_exact(&mut payload).await.unwrap();
+    Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]),
+        payload,
+    }
+}
+
+/// Header block of a GET request, with every field a literal without indexing
+fn request_block(path: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+    request_block_with_method("GET", path, extra)
+}
+
+fn request_block_with_method(method: &str, path: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in [(":method", method), (":scheme", "https"), (":authority", "localhost"), (":path", path)].iter().chain(extra) {
+        block.push(0x00);
+        for string in [name, value] {
+            assert!(string.len() < 127);
+            block.push(string.len() as u8);
+            block.extend_from_slice(string.as_bytes());
+        }
+    }
+    block
+}
+
+/// Open an HTTP/2 connection through the proxy
+async fn open(proxy: &Proxy) -> impl AsyncRead + AsyncWrite + Unpin {
+    let mut stream = connect(proxy.addr, &[b"h2"]).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    stream.write_all(PREFACE).await.unwrap();
+    stream.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+    stream
+}
+
+/// Read the response of a stream, returning its status and body
+async fn response<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, id: u32) -> (String, Vec<u8>) {
+    let mut decoder = Decoder::default();
+    let mut status = None;
+    let mut body = Vec::new();
+    loop {
+        let frame = read_frame(stream).await;
+        assert_ne!(frame.kind, GOAWAY, "connection closed: {}", String::from_utf8_lossy(&frame.payload));
+        if frame.stream != id {
+            continue;
+        }
+        match frame.kind {
+            HEADERS => {
+                assert_ne!(frame.flags & END_HEADERS, 0);
+                let fields = decoder.decode(&frame.payload, usize::MAX).unwrap();
+                let (_, value) = fields.into_iter().find(|(name, _)| name == b":status").unwrap();
+                status = Some(String::from_utf8(value).unwrap());
+            }
+            DATA => body.extend_from_slice(&frame.payload),
+            kind => panic!("unexpected frame type {} on stream {}", kind, id),
+        }
+        if frame.flags & END_STREAM != 0 {
+            return (status.expect("response without HEADERS"), body);
+        }
+    }
+}
+
+#[tokio::test]
+async fn request() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    stream.write_all(&frame(HEADERS, END_STREAM | END_HEADERS, 1, &request_block("/index.html", &[]))).await.unwrap();
+    assert_eq!(response(&mut stream, 1).await, ("200".to_string(), b"GET /index.html HTTP/1.1".to_vec()));
+}
+
+/// A client that sent its whole request body is still waiting for the response, not gone
+#[tokio::test]
+async fn request_with_body() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    let block = request_block_with_method("POST", "/upload", &[("content-length", "5")]);
+    stream.write_all(&frame(HEADERS, END_HEADERS, 1, &block)).await.unwrap();
+    stream.write_all(&frame(DATA, END_STREAM, 1, b"hello")).await.unwrap();
+    assert_eq!(response(&mut stream, 1).await, ("200".to_string(), b"POST /upload HTTP/1.1hello".to_vec()));
+}
+
+#[tokio::test]
+async fn header_block_in_continuation_frames() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    let block = request_block("/continued", &[("x-padding", &"x".repeat(100))]);
+    let (first, rest) = block.split_at(20);
+    let (second, third) = rest.split_at(50);
+    stream.write_all(&frame(HEADERS, END_STREAM, 1, first)).await.unwrap();
+    stream.write_all(&frame(CONTINUATION, 0, 1, second)).await.unwrap();
+    stream.write_all(&frame(CONTINUATION, END_HEADERS, 1, third)).await.unwrap();
+    assert_eq!(response(&mut stream, 1).await, ("200".to_string(), b"GET /continued HTTP/1.1".to_vec()));
+}
+
+#[tokio::test]
+async fn header_block_interrupted() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    let block = request_block("/", &[]);
+    stream.write_all(&frame(HEADERS, END_STREAM, 1, &block[..10])).await.unwrap();
+    stream.write_all(&frame(PING, 0, 0, &[0; 8])).await.unwrap();
+    loop {
+        let frame = read_frame(&mut stream).await;
+        if frame.kind == GOAWAY {
+            // PROTOCOL_ERROR
+            assert_eq!(frame.payload[4..8], [0, 0, 0, 1]);
+            break;
+        }
+        assert_ne!(frame.kind, PING, "PING answered within a header block");
+    }
+}
+
+#[tokio::test]
+async fn keepalive_ping() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2", "--client-keepalive", "1"]);
+    let mut stream = open(&proxy).await;
+
+    // Acknowledged PINGs keep the connection open, and it is pinged again once idle
+    for _ in 0..2 {
+        let ping = loop {
+            let frame = read_frame(&mut stream).await;
+            if frame.kind == PING {
+                break frame;
+            }
+        };
+        assert_eq!((ping.flags, ping.stream, ping.payload.len()), (0, 0, 8));
+        stream.write_all(&frame(PING, ACK, 0, &ping.payload)).await.unwrap();
+    }
+    stream.write_all(&frame(HEADERS, END_STREAM | END_HEADERS, 1, &request_block("/", &[]))).await.unwrap();
+    assert_eq!(response(&mut stream, 1).await.0, "200");
+}
+
+/// Read frames until the GOAWAY closing the connection, returning its error code
+async fn go_away<R: AsyncRead + Unpin>(reader: &mut R) -> u32 {
+    loop {
+        let frame = read_frame(reader).await;
+        if frame.kind == GOAWAY {
+            return u32::from_be_bytes(frame.payload[4..8].try_into().unwrap());
+        }
+    }
+}
+
+/// Streams opened and reset right away start backend requests without occupying a stream slot,
+/// so a client doing so in a loop is disconnected (CVE-2023-44487)
+#[tokio::test]
+async fn rapid_reset() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    // One more than allowed within 10 seconds, sent at once
+    let mut flood = Vec::new();
+    for id in (1..=201).step_by(2) {
+        flood.extend_from_slice(&frame(HEADERS, END_STREAM | END_HEADERS, id, &request_block("/", &[])));
+        flood.extend_from_slice(&frame(RST_STREAM, 0, id, &CANCEL.to_be_bytes()));
+    }
+    stream.write_all(&flood).await.unwrap();
+    assert_eq!(go_away(&mut stream).await, ENHANCE_YOUR_CALM);
+}
+
+/// A client cancelling a few requests keeps its connection
+#[tokio::test]
+async fn occasional_reset() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    for id in (1..20).step_by(2) {
+        stream.write_all(&frame(HEADERS, END_STREAM | END_HEADERS, id, &request_block("/", &[]))).await.unwrap();
+        stream.write_all(&frame(RST_STREAM, 0, id, &CANCEL.to_be_bytes())).await.unwrap();
+    }
+    stream.write_all(&frame(HEADERS, END_STREAM | END_HEADERS, 21, &request_block("/", &[]))).await.unwrap();
+    assert_eq!(response(&mut stream, 21).await.0, "200");
+}
+
+/// Malformed requests are reset by the proxy, a client sending nothing else is disconnected
+#[tokio::test]
+async fn rejected_stream_flood() {
+    let proxy = Proxy::start(backend().await, &["--mode", "http", "--alpn", "h2"]);
+    let mut stream = open(&proxy).await;
+
+    let mut flood = Vec::new();
+    for id in (1..=201).step_by(2) {
+        flood.extend_from_slice(&frame(HEADERS, END_STREAM | END_HEADERS, id, &request_block("", &[])));
+    }
+    stream.write_all(&flood).await.unwrap();
+    assert_eq!(go_away(&mut stream).await, ENHANCE_YOUR_CALM);
+}
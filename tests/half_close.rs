@@ -0,0 +1,98 @@
+//! When one side shuts down its write half, the other direction keeps flowing until EOF
+
+mod common;
+
+use common::{connect, read_to_end, read_until, Proxy};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const UPGRADE_REQUEST: &[u8] = b"GET /stream HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+const UPGRADE_RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+
+/// A backend accepting a single connection, answering upgrade requests in HTTP mode before
+/// running the exchange, which returns what the backend received
+async fn backend<F, Fut>(http: bool, exchange: F) -> (std::net::SocketAddr, JoinHandle<Vec<u8>>)
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Vec<u8>> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        if http {
+            read_until(&mut stream, b"\r\n\r\n").await;
+            stream.write_all(UPGRADE_RESPONSE).await.unwrap();
+        }
+        exchange(stream).await
+    });
+    (addr, task)
+}
+
+/// Open a connection through the proxy, upgrading it in HTTP mode
+async fn client(proxy: &Proxy, http: bool) -> impl AsyncRead + AsyncWrite + Unpin {
+    let mut stream = connect(proxy.addr, &[]).await;
+    if http {
+        stream.write_all(UPGRADE_REQUEST).await.unwrap();
+        let head = read_until(&mut stream, b"\r\n\r\n").await;
+        assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+    }
+    stream
+}
+
+/// The client stops sending first: the backend sees EOF and its answer still reaches the client
+async fn client_closes_first(http: bool) {
+    let (addr, backend) = backend(http, |mut stream| async move {
+        let request = read_to_end(&mut stream).await;
+        stream.write_all(b"answer to ").await.unwrap();
+        stream.write_all(&request).await.unwrap();
+        stream.shutdown().await.unwrap();
+        request
+    })
+    .await;
+    let proxy = Proxy::start(addr, if http { &["--mode", "http"] } else { &[] });
+
+    let mut stream = client(&proxy, http).await;
+    stream.write_all(b"question").await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut stream).await, b"answer to question");
+    assert_eq!(backend.await.unwrap(), b"question");
+}
+
+/// The backend stops sending first: the client sees EOF and can still send to the backend
+async fn backend_closes_first(http: bool) {
+    let (addr, backend) = backend(http, |mut stream| async move {
+        stream.write_all(b"greeting").await.unwrap();
+        stream.shutdown().await.unwrap();
+        read_to_end(&mut stream).await
+    })
+    .await;
+    let proxy = Proxy::start(addr, if http { &["--mode", "http"] } else { &[] });
+
+    let mut stream = client(&proxy, http).await;
+    assert_eq!(read_to_end(&mut stream).await, b"greeting");
+    stream.write_all(b"sent after EOF").await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert_eq!(backend.await.unwrap(), b"sent after EOF");
+}
+
+#[tokio::test]
+async fn tcp_client_closes_first() {
+    client_closes_first(false).await;
+}
+
+#[tokio::test]
+async fn tcp_backend_closes_first() {
+    backend_closes_first(false).await;
+}
+
+#[tokio::test]
+async fn http_upgrade_client_closes_first() {
+    client_closes_first(true).await;
+}
+
+#[tokio::test]
+async fn http_upgrade_backend_closes_first() {
+    backend_closes_first(true).await;
+}
@@ -0,0 +1,105 @@
+//! Loading RSA, ECDSA and Ed25519 keys from PEM and PFX files, the fixtures are in
+//! `tests/fixtures/keys` (PFX files are protected with the password `secret`)
+
+use https_wrapper::certificate::{certified_key, load_certificate, load_pem_certificate};
+use rustls_pki_types::PrivateKeyDer;
+
+const PFX_PASSWORD: &str = "secret";
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/keys/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Load the PEM certificate and key, checking rustls can sign with the key
+fn load_pem(name: &str) -> PrivateKeyDer<'static> {
+    let (certs, key) = load_pem_certificate(&fixture(&format!("{}.crt", name)), &fixture(&format!("{}.key", name)), None).unwrap();
+    let key_der = key.clone_key();
+    certified_key(certs, key).unwrap();
+    key_der
+}
+
+/// Load the PFX file, checking rustls can sign with its key
+fn load_pfx(name: &str) -> PrivateKeyDer<'static> {
+    let (certs, key) = load_certificate(&fixture(&format!("{}.pfx", name)), Some(PFX_PASSWORD)).unwrap();
+    assert_eq!(certs.len(), 1);
+    let key_der = key.clone_key();
+    certified_key(certs, key).unwrap();
+    key_der
+}
+
+fn pem_error(name: &str) -> String {
+    load_pem_certificate(&fixture(&format!("{}.crt", name)), &fixture(&format!("{}.key", name)), None).unwrap_err().to_string()
+}
+
+#[test]
+fn rsa_pkcs1_pem() {
+    assert!(matches!(load_pem("rsa"), PrivateKeyDer::Pkcs1(_)));
+}
+
+#[test]
+fn rsa_pfx() {
+    assert!(matches!(load_pfx("rsa"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn ecdsa_p256_sec1_pem_is_converted_to_pkcs8() {
+    assert!(matches!(load_pem("ecdsa-p256"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn ecdsa_p256_pfx() {
+    assert!(matches!(load_pfx("ecdsa-p256"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn ecdsa_p384_pkcs8_pem() {
+    assert!(matches!(load_pem("ecdsa-p384"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn ecdsa_p384_pfx() {
+    assert!(matches!(load_pfx("ecdsa-p384"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn ed25519_pem() {
+    assert!(matches!(load_pem("ed25519"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn ed25519_pfx() {
+    assert!(matches!(load_pfx("ed25519"), PrivateKeyDer::Pkcs8(_)));
+}
+
+#[test]
+fn pfx_with_wrong_password() {
+    let error = load_certificate(&fixture("rsa.pfx"), Some("wrong")).unwrap_err().to_string();
+    assert!(error.starts_with("Failed to parse PFX file with provided password"), "{}", error);
+}
+
+#[test]
+fn pfx_without_password() {
+    let error = load_certificate(&fixture("rsa.pfx"), None).unwrap_err().to_string();
+    assert!(error.contains("This file may require a password. Use --password option."), "{}", error);
+}
+
+#[test]
+fn rsa_key_too_small() {
+    assert_eq!(pem_error("rsa-1024"), "RSA keys need 2048 to 8192 bits, this one has 1024");
+}
+
+#[test]
+fn unsupported_curve() {
+    assert_eq!(pem_error("ecdsa-secp256k1"), "Elliptic curve secp256k1 is not supported, use P-256 or P-384");
+}
+
+#[test]
+fn ed448_pem() {
+    assert_eq!(pem_error("ed448"), "Ed448 keys are not supported, use an RSA, ECDSA (P-256 or P-384) or Ed25519 key");
+}
+
+#[test]
+fn ed448_pfx() {
+    let error = load_certificate(&fixture("ed448.pfx"), Some(PFX_PASSWORD)).unwrap_err().to_string();
+    assert_eq!(error, "Ed448 keys are not supported, use an RSA, ECDSA (P-256 or P-384) or Ed25519 key");
+}
@@ -0,0 +1,109 @@
+//! Several listeners in one process, shown together by the admin API
+
+mod common;
+
+use common::{connect, read_to_end, read_until, Proxy};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A backend answering every request on a kept-alive connection with `ok`, until the test ends
+async fn backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                loop {
+                    read_until(&mut stream, b"\r\n\r\n").await;
+                    if stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Send a request on a kept-alive connection, returning the response head
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> String {
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let head = String::from_utf8(read_until(stream, b"\r\n\r\n").await).unwrap();
+    if head.contains("Content-Length: 2") {
+        read_until(stream, b"ok").await;
+    }
+    head
+}
+
+/// Send a request to the admin API, returning the response body
+async fn admin(proxy: &Proxy, method: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(proxy.admin.unwrap()).await.unwrap();
+    stream.write_all(format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).as_bytes()).await.unwrap();
+    let response = String::from_utf8(read_to_end(&mut stream).await).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    body.to_string()
+}
+
+#[tokio::test]
+async fn admin_api_covers_all_listeners() {
+    let (main_backend, other_backend) = (backend().await, backend().await);
+    let other = format!("127.0.0.1:0={}", other_backend);
+    let proxy = Proxy::start(main_backend, &["--mode", "http", "--admin", "127.0.0.1:0", "--listener", &other]);
+
+    let mut main_client = connect(proxy.addr, &[]).await;
+    let mut other_client = connect(proxy.listeners[0], &[]).await;
+    assert!(get(&mut main_client).await.starts_with("HTTP/1.1 200"));
+    assert!(get(&mut other_client).await.starts_with("HTTP/1.1 200"));
+
+    // Connection ids are unique across the listeners
+    let connections = admin(&proxy, "GET", "/connections").await;
+    assert!(connections.contains("\"id\":1,") && connections.contains("\"id\":2,"), "{}", connections);
+    assert!(admin(&proxy, "GET", "/metrics").await.lines().any(|line| line == "connections_active 2"));
+
+    let backends = admin(&proxy, "GET", "/backends").await;
+    for backend in [main_backend, other_backend] {
+        assert!(backends.contains(&format!("\"address\":\"{}\"", backend)), "{}", backends);
+    }
+
+    // Draining waits for the connections of every listener
+    assert_eq!(admin(&proxy, "GET", "/drain").await, "{\"draining\":false,\"connections\":2}\n");
+
+    // Maintenance mode applies to every listener
+    admin(&proxy, "POST", "/maintenance").await;
+    let mut maintained = connect(proxy.listeners[0], &[]).await;
+    assert!(get(&mut maintained).await.starts_with("HTTP/1.1 503"));
+}
+
+/// Every metric family is announced once and its lines are contiguous, with backends shared by
+/// the listeners reported once
+#[tokio::test]
+async fn metrics_families_span_all_listeners() {
+    let backend = backend().await;
+    let other = format!("127.0.0.1:0={}", backend);
+    let args = ["--mode", "http", "--admin", "127.0.0.1:0", "--listener", &other, "--health-check-interval", "1", "--tag", "localhost=a\"b\\c"];
+    let proxy = Proxy::start(backend, &args);
+    let mut client = connect(proxy.listeners[0], &[]).await;
+    assert!(get(&mut client).await.starts_with("HTTP/1.1 200"));
+
+    let metrics = admin(&proxy, "GET", "/metrics").await;
+    assert!(metrics.lines().any(|line| line == "connections_by_tag_total{tag=\"a\\\"b\\\\c\"} 1"), "{}", metrics);
+    let healthy = format!("backend_healthy{{backend=\"{}\"}} ", backend);
+    assert_eq!(metrics.lines().filter(|line| line.starts_with(&healthy)).count(), 1, "{}", metrics);
+
+    let mut families = Vec::new();
+    for line in metrics.lines() {
+        if let Some(family) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = family.split_once(' ').unwrap();
+            assert!(["counter", "gauge", "histogram"].contains(&kind), "{}", line);
+            assert!(!families.contains(&name), "family {} announced twice", name);
+            families.push(name);
+        } else if !line.starts_with("# HELP ") {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = families.last().expect("metric before any # TYPE line");
+            assert!(name == *family || name.strip_prefix(family).is_some_and(|suffix| ["_bucket", "_sum", "_count"].contains(&suffix)), "{} outside its family", line);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Range requests and partial content in HTTP mode, against a backend serving a large file
+
+mod common;
+
+use common::{connect, read_until, Proxy, TIMEOUT};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+/// Size of the file served by the backend
+const FILE_SIZE: usize = 8 * 1024 * 1024;
+
+fn file() -> Vec<u8> {
+    (0..FILE_SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+/// A backend serving the file, with ranges of the form `bytes=<first>-<last>` answered as partial
+/// content; the request heads it receives are passed on to the test
+async fn file_backend() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (heads, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let file = file();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (file, heads) = (file.clone(), heads.clone());
+            tokio::spawn(async move {
+                loop {
+                    let head = String::from_utf8(read_until(&mut stream, b"\r\n\r\n").await).unwrap();
+                    let range = head.lines().find_map(|line| line.strip_prefix("Range: bytes="));
+                    let response = match range.and_then(|range| range.split_once('-')) {
+                        Some((first, last)) => {
+                            let (first, last): (usize, usize) = (first.parse().unwrap(), last.parse().unwrap());
+                            let mut response = format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\r\n",
+                                first,
+                                last,
+                                FILE_SIZE,
+                                last - first + 1
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(&file[first..=last]);
+                            response
+                        }
+                        None => {
+                            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nETag: \"v1\"\r\n\r\n", FILE_SIZE).into_bytes();
+                            response.extend_from_slice(&file);
+                            response
+                        }
+                    };
+                    heads.send(head).unwrap();
+                    if stream.write_all(&response).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+/// Send a GET request with the extra header lines, returning the response head and body
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, headers: &str) -> (String, Vec<u8>) {
+    let request = format!("GET /video.mp4 HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let head = String::from_utf8(read_until(stream, b"\r\n\r\n").await).unwrap();
+    let length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .expect("response without Content-Length")
+        .parse()
+        .unwrap();
+    let mut body = vec![0; length];
+    tokio::time::timeout(TIMEOUT, stream.read_exact(&mut body)).await.expect("timed out reading the body").unwrap();
+    (head, body)
+}
+
+#[tokio::test]
+async fn partial_content_passes_through() {
+    let (addr, mut received) = file_backend().await;
+    let proxy = Proxy::start(addr, &["--mode", "http"]);
+    let mut stream = connect(proxy.addr, &[]).await;
+
+    let (head, body) = get(&mut stream, "Range: bytes=1000000-1999999\r\nIf-Range: \"v1\"\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", head);
+    assert!(head.contains(&format!("Content-Range: bytes 1000000-1999999/{}\r\n", FILE_SIZE)), "{}", head);
+    assert_eq!(body, file()[1_000_000..2_000_000]);
+
+    // The conditions reach the backend as the client sent them
+    let request = received.recv().await.unwrap();
+    assert!(request.contains("\r\nRange: bytes=1000000-1999999\r\n"), "{}", request);
+    assert!(request.contains("\r\nIf-Range: \"v1\"\r\n"), "{}", request);
+
+    // Resuming a download at the end of the file, on the same connection
+    let (head, body) = get(&mut stream, &format!("Range: bytes={}-{}\r\n", FILE_SIZE - 10, FILE_SIZE - 1)).await;
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    assert_eq!(body, file()[FILE_SIZE - 10..]);
+}
+
+#[tokio::test]
+async fn whole_file_passes_through() {
+    let (addr, _received) = file_backend().await;
+    let proxy = Proxy::start(addr, &["--mode", "http"]);
+    let mut stream = connect(proxy.addr, &[]).await;
+
+    let (head, body) = get(&mut stream, "").await;
+    assert!(head.starts_with("HTTP/1.1 200") && head.contains("Accept-Ranges: bytes\r\n"), "{}", head);
+    assert!(body == file());
+}
+
+#[tokio::test]
+async fn range_requests_skip_the_cache() {
+    let (addr, mut received) = file_backend().await;
+    let proxy = Proxy::start(addr, &["--mode", "http", "--cache", "/"]);
+    let mut stream = connect(proxy.addr, &[]).await;
+
+    for _ in 0..2 {
+        let (head, body) = get(&mut stream, "Range: bytes=0-99\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+        assert_eq!(body, file()[..100]);
+        assert!(received.recv().await.unwrap().contains("\r\nRange: bytes=0-99\r\n"));
+    }
+}
+
+/// The client receives the start of a partial content response while the backend still holds back
+/// the rest, so the body is streamed rather than buffered
+#[tokio::test]
+async fn partial_content_is_streamed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (release, released) = oneshot::channel::<()>();
+    let backend = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_until(&mut stream, b"\r\n\r\n").await;
+        let file = file();
+        let head = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-{}/{}\r\nContent-Length: {}\r\n\r\n", FILE_SIZE - 1, FILE_SIZE, FILE_SIZE);
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&file[..65536]).await.unwrap();
+        released.await.unwrap();
+        stream.write_all(&file[65536..]).await.unwrap();
+    });
+    let proxy = Proxy::start(addr, &["--mode", "http"]);
+    let mut stream = connect(proxy.addr, &[]).await;
+
+    stream.write_all(b"GET /video.mp4 HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-\r\n\r\n").await.unwrap();
+    let head = String::from_utf8(read_until(&mut stream, b"\r\n\r\n").await).unwrap();
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    let mut start = vec![0; 65536];
+    tokio::time::timeout(TIMEOUT, stream.read_exact(&mut start)).await.expect("the start of the body was held back").unwrap();
+    assert_eq!(start, file()[..65536]);
+
+    release.send(()).unwrap();
+    let mut rest = vec![0; FILE_SIZE - 65536];
+    tokio::time::timeout(TIMEOUT, stream.read_exact(&mut rest)).await.expect("timed out reading the body").unwrap();
+    assert!(rest == file()[65536..]);
+    backend.await.unwrap();
+}